@@ -0,0 +1,60 @@
+/// Well-known authenticator models, sorted by AAGUID for binary search.
+/// Covers common security keys and the passkey providers that are not listed
+/// in the FIDO Metadata Service.
+const NAMES: &[(&str, &str)] = &[
+    ("08987058-cadc-4b81-b6e1-30de50dcbe96", "Windows Hello"),
+    (
+        "0bb43545-fd2c-4185-87dd-feb0b2916ace",
+        "Security Key NFC by Yubico - Enterprise Edition",
+    ),
+    ("149a2021-8ef6-4133-96b8-81f8d5b7f1f5", "Security Key by Yubico with NFC"),
+    ("2fc0579f-8113-47ea-b116-bb5a8db9202a", "YubiKey 5 Series with NFC"),
+    ("42b4fb4a-2866-43b2-9bf7-6c6669c2e5d3", "Google Titan Security Key v2"),
+    ("531126d6-e717-415c-9320-3d9aa6981239", "Dashlane"),
+    ("53414d53-554e-4700-0000-000000000000", "Samsung Pass"),
+    ("6028b017-b1d4-4c02-b4b3-afcdafc96bb2", "Windows Hello"),
+    ("6d44ba9b-f6ec-2e49-b930-0c8fe920cb73", "Security Key by Yubico with NFC"),
+    ("73bb0cd4-e502-49b8-9c6f-b59445bf720b", "YubiKey 5 FIPS Series"),
+    (
+        "8876631b-d4a0-427f-5773-0ec71c9e0279",
+        "Solo Secp256R1 FIDO2 CTAP2 Authenticator",
+    ),
+    ("9ddd1817-af5a-4672-a2b9-3e3dd95000a9", "Windows Hello"),
+    ("a4e9fc6d-4cbe-4758-b8ba-37598bb5bbaa", "Security Key NFC by Yubico"),
+    ("adce0002-35bc-c60a-648b-0b25f1f05503", "Chrome on Mac"),
+    ("b84e4048-15dc-4dd0-8640-f4f60813c8af", "NordPass"),
+    ("b92c3f9a-c014-4056-887f-140a2501163b", "Security Key by Yubico"),
+    ("bada5566-a7aa-401f-bd96-45619a55120d", "1Password"),
+    ("c1f9a0bc-1dd2-404a-b27f-8e29047a43fd", "YubiKey 5 FIPS Series with NFC"),
+    ("c5ef55ff-ad9a-4b9f-b580-adebafe026d0", "YubiKey 5Ci"),
+    ("cb69481e-8ff7-4039-93ec-0a2729a154a8", "YubiKey 5 Series"),
+    ("d548826e-79b4-db40-a3d8-11116f7e8349", "Bitwarden"),
+    ("d8522d9f-575b-4866-88a9-ba99fa02f35b", "YubiKey Bio Series"),
+    ("dd4ec289-e01d-41c9-bb89-70fa845d4bf2", "iCloud Keychain (Managed)"),
+    ("ea9b8d66-4d01-1d21-3ce4-b6b48cb575d4", "Google Password Manager"),
+    ("ee882879-721c-4913-9775-3dfcce97072a", "YubiKey 5 Series"),
+    ("f8a011f3-8c0a-4d15-8006-17111f9edc7d", "Security Key by Yubico"),
+    ("fa2b99dc-9e39-4257-8f92-4a30d23c4118", "YubiKey 5 Series with NFC"),
+    ("fbfc3007-154e-4ecc-8c0b-6e020557d7bd", "iCloud Keychain"),
+    ("fdb141b2-5d84-443e-8a35-4698c205a502", "KeePassXC"),
+];
+
+/// Built-in name for a lowercase, hyphenated AAGUID.
+pub fn lookup(aaguid: &str) -> Option<&'static str> {
+    NAMES.binary_search_by(|(key, _)| (*key).cmp(aaguid)).ok().map(|i| NAMES[i].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_is_sorted_for_binary_search() {
+        assert!(NAMES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(NAMES
+            .iter()
+            .all(|(aaguid, _)| aaguid.len() == 36 && *aaguid == aaguid.to_ascii_lowercase()));
+        assert_eq!(lookup("fbfc3007-154e-4ecc-8c0b-6e020557d7bd"), Some("iCloud Keychain"));
+        assert_eq!(lookup("00000000-0000-0000-0000-000000000000"), None);
+    }
+}
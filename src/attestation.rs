@@ -0,0 +1,423 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ciborium::Value;
+use sha2::{Digest, Sha256};
+use x509_cert::der::Decode;
+use x509_cert::spki::ObjectIdentifier;
+use x509_cert::Certificate;
+
+use crate::errors::AppError;
+use crate::mds::{check_issued_by, check_validity, verify_signature, Scheme};
+use crate::storage::{Conveyance, MdsCache, StoredAttestation};
+
+/// id-fido-gen-ce-aaguid: the authenticator model a certificate attests
+const FIDO_GEN_CE_AAGUID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.45724.1.1.4");
+
+/// Looks up a text key of a CBOR map.
+fn get<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k.as_text() == Some(key)).map(|(_, v)| v)
+}
+
+/// Extracts the attestation statement (format, algorithm, certificate chain
+/// and signature) from a registration response, so device provenance can be
+/// re-verified later. The statement itself was already verified by
+/// webauthn_rp; this only keeps a copy.
+pub fn parse_statement(client_response: &str, aaguid: &str) -> Option<StoredAttestation> {
+    let response: serde_json::Value = serde_json::from_str(client_response).ok()?;
+    let object_b64 = response.get("response")?.get("attestationObject")?.as_str()?;
+    let object_bytes = URL_SAFE_NO_PAD.decode(object_b64.trim_end_matches('=')).ok()?;
+    let object: Value = ciborium::from_reader(object_bytes.as_slice()).ok()?;
+    let object = object.as_map()?;
+
+    let fmt = get(object, "fmt")?.as_text()?.to_string();
+    let statement = get(object, "attStmt").and_then(Value::as_map);
+    let field = |key: &str| statement.and_then(|s| get(s, key));
+
+    Some(StoredAttestation {
+        fmt,
+        aaguid: aaguid.to_string(),
+        alg: field("alg").and_then(Value::as_integer).and_then(|alg| i64::try_from(alg).ok()),
+        x5c: field("x5c")
+            .and_then(Value::as_array)
+            .map(|chain| {
+                chain
+                    .iter()
+                    .filter_map(Value::as_bytes)
+                    .map(|cert| URL_SAFE_NO_PAD.encode(cert))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        sig: field("sig").and_then(Value::as_bytes).map(|sig| URL_SAFE_NO_PAD.encode(sig)),
+    })
+}
+
+fn invalid(message: impl std::fmt::Display) -> AppError {
+    AppError::WebAuthn(format!("Invalid attestation: {}", message))
+}
+
+/// Certificate checks report metadata errors; here they reject a registration.
+fn as_webauthn(error: AppError) -> AppError {
+    match error {
+        AppError::Metadata(message) => invalid(message),
+        other => other,
+    }
+}
+
+/// Checks a packed statement with a certificate chain: the leaf signs
+/// authData || SHA-256(clientDataJSON), names the same model as authData if it
+/// names one, and each certificate is signed by the next. The chain is not
+/// anchored to a trust root; it is returned, leaf first, for callers that do.
+fn verify_packed_x5c(statement: &[(Value, Value)], auth_data: &[u8], client_data: &[u8], now: u64) -> Result<Vec<Certificate>, AppError> {
+    let alg = get(statement, "alg")
+        .and_then(Value::as_integer)
+        .and_then(|alg| i64::try_from(alg).ok());
+    let scheme = match alg {
+        Some(-7) => Scheme::EcdsaSha256,
+        Some(-35) => Scheme::EcdsaSha384,
+        Some(-257) => Scheme::RsaSha256,
+        _ => return Err(invalid("unsupported algorithm")),
+    };
+    let sig = get(statement, "sig")
+        .and_then(Value::as_bytes)
+        .ok_or_else(|| invalid("missing signature"))?;
+    let chain = get(statement, "x5c")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|cert| {
+            let der = cert.as_bytes().ok_or_else(|| invalid("certificate is not a byte string"))?;
+            Certificate::from_der(der).map_err(|e| invalid(format!("bad certificate: {}", e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let leaf = chain.first().ok_or_else(|| invalid("empty certificate chain"))?;
+
+    for cert in &chain {
+        check_validity(cert, now).map_err(as_webauthn)?;
+    }
+    for pair in chain.windows(2) {
+        check_issued_by(&pair[0], &pair[1]).map_err(as_webauthn)?;
+    }
+
+    let mut message = auth_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data));
+    if !verify_signature(&leaf.tbs_certificate.subject_public_key_info, scheme, &message, sig, true) {
+        return Err(invalid("signature does not match the attestation certificate"));
+    }
+
+    let extension = leaf
+        .tbs_certificate
+        .extensions
+        .iter()
+        .flatten()
+        .find(|ext| ext.extn_id == FIDO_GEN_CE_AAGUID);
+    if let Some(extension) = extension {
+        let aaguid = x509_cert::der::asn1::OctetString::from_der(extension.extn_value.as_bytes())
+            .map_err(|e| invalid(format!("bad AAGUID extension: {}", e)))?;
+        if auth_data.get(37..53) != Some(aaguid.as_bytes()) {
+            return Err(invalid("certificate is for a different authenticator model"));
+        }
+    }
+    Ok(chain)
+}
+
+fn malformed() -> AppError {
+    AppError::InvalidInput("Invalid client response: malformed attestation object".to_string())
+}
+
+/// The parts of a registration response that attestation checks read.
+struct Decoded {
+    response: serde_json::Value,
+    client_data: Vec<u8>,
+    fmt: String,
+    statement: Vec<(Value, Value)>,
+    auth_data: Vec<u8>,
+}
+
+fn decode(client_response: &str) -> Result<Decoded, AppError> {
+    let response: serde_json::Value =
+        serde_json::from_str(client_response).map_err(|e| AppError::InvalidInput(format!("Invalid client response: {}", e)))?;
+    let field = |name: &str| {
+        response["response"][name]
+            .as_str()
+            .and_then(|b64| URL_SAFE_NO_PAD.decode(b64.trim_end_matches('=')).ok())
+            .ok_or_else(malformed)
+    };
+    let client_data = field("clientDataJSON")?;
+    let object: Value = ciborium::from_reader(field("attestationObject")?.as_slice()).map_err(|_| malformed())?;
+    let object = object.as_map().ok_or_else(malformed)?;
+    let fmt = get(object, "fmt").and_then(Value::as_text).ok_or_else(malformed)?.to_string();
+    let statement = get(object, "attStmt").and_then(Value::as_map).ok_or_else(malformed)?.clone();
+    let auth_data = get(object, "authData").and_then(Value::as_bytes).ok_or_else(malformed)?.clone();
+    Ok(Decoded {
+        response,
+        client_data,
+        fmt,
+        statement,
+        auth_data,
+    })
+}
+
+/// Applies the attestation depth a registration asked for and returns the
+/// client response to hand to webauthn_rp, which reads only `none` and self
+/// attestation:
+///
+/// - `none`: left entirely to webauthn_rp
+/// - `indirect`: packed certificate chains are verified here; statements in
+///   other formats are accepted unverified
+/// - `direct`: as `indirect`, but other formats are rejected
+/// - `enterprise`: as `direct`, and a certificate chain is required
+///
+/// Statements verified here are then replaced by `none`.
+pub fn verify_statement(client_response: &str, conveyance: Conveyance, now: u64) -> Result<String, AppError> {
+    if conveyance == Conveyance::None {
+        return Ok(client_response.to_string());
+    }
+    let Decoded {
+        mut response,
+        client_data,
+        fmt,
+        statement,
+        auth_data,
+    } = decode(client_response)?;
+
+    match fmt.as_str() {
+        "packed" if get(&statement, "x5c").is_some() => {
+            verify_packed_x5c(&statement, &auth_data, &client_data, now)?;
+        }
+        "none" | "packed" if conveyance == Conveyance::Enterprise => {
+            return Err(invalid("enterprise attestation requires a certificate chain"));
+        }
+        "none" | "packed" => return Ok(client_response.to_string()),
+        other if conveyance != Conveyance::Indirect => return Err(invalid(format!("unsupported format {}", other))),
+        _ => {}
+    }
+
+    let stripped = Value::Map(vec![
+        (Value::Text("fmt".into()), Value::Text("none".into())),
+        (Value::Text("attStmt".into()), Value::Map(Vec::new())),
+        (Value::Text("authData".into()), Value::Bytes(auth_data)),
+    ]);
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&stripped, &mut bytes).map_err(|e| AppError::WebAuthn(e.to_string()))?;
+    response["response"]["attestationObject"] = URL_SAFE_NO_PAD.encode(bytes).into();
+    Ok(response.to_string())
+}
+
+/// Rejects a registration whose attestation does not vouch for the
+/// authenticator model: `none` and self attestation, formats not verified
+/// here, and packed certificate chains that do not lead to an attestation
+/// root the cached FIDO metadata lists for the model.
+pub fn check_trusted(client_response: &str, cache: Option<&MdsCache>, now: u64) -> Result<(), AppError> {
+    let decoded = decode(client_response)?;
+    match decoded.fmt.as_str() {
+        "none" => {
+            return Err(AppError::AttestationUntrusted(
+                "the authenticator sent no attestation".to_string(),
+            ));
+        }
+        "packed" if get(&decoded.statement, "x5c").is_none() => {
+            return Err(AppError::AttestationUntrusted(
+                "self attestation does not identify the authenticator model".to_string(),
+            ));
+        }
+        "packed" => {}
+        other => {
+            return Err(AppError::AttestationUntrusted(format!(
+                "{} attestation cannot be verified",
+                other
+            )));
+        }
+    }
+    let chain = verify_packed_x5c(&decoded.statement, &decoded.auth_data, &decoded.client_data, now)?;
+    let aaguid = decoded
+        .auth_data
+        .get(37..53)
+        .and_then(|bytes| uuid::Uuid::from_slice(bytes).ok())
+        .ok_or_else(malformed)?
+        .to_string();
+
+    let roots = cache
+        .and_then(|cache| cache.entries.get(&aaguid))
+        .map(|entry| entry.attestation_roots.as_slice())
+        .unwrap_or_default();
+    if roots.is_empty() {
+        return Err(AppError::AttestationUntrusted(format!(
+            "no attestation root is known for authenticator model {}; run mds update",
+            aaguid
+        )));
+    }
+    let top = chain.last().ok_or_else(|| invalid("empty certificate chain"))?;
+    let anchored = roots
+        .iter()
+        .filter_map(|root| STANDARD.decode(root).ok())
+        .filter_map(|der| Certificate::from_der(&der).ok())
+        .any(|root| check_validity(&root, now).is_ok() && (root == *top || check_issued_by(top, &root).is_ok()));
+    if !anchored {
+        return Err(AppError::AttestationUntrusted(format!(
+            "certificate chain does not lead to an attestation root of authenticator model {}",
+            aaguid
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_with(object: Value) -> String {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&object, &mut bytes).unwrap();
+        serde_json::json!({ "response": { "attestationObject": URL_SAFE_NO_PAD.encode(bytes) } }).to_string()
+    }
+
+    #[test]
+    fn test_parse_packed_statement() {
+        let statement = Value::Map(vec![
+            (Value::Text("alg".into()), Value::Integer((-7).into())),
+            (Value::Text("sig".into()), Value::Bytes(vec![1, 2, 3])),
+            (
+                Value::Text("x5c".into()),
+                Value::Array(vec![Value::Bytes(vec![4, 5]), Value::Bytes(vec![6])]),
+            ),
+        ]);
+        let object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("packed".into())),
+            (Value::Text("attStmt".into()), statement),
+            (Value::Text("authData".into()), Value::Bytes(vec![0; 37])),
+        ]);
+
+        let parsed = parse_statement(&response_with(object), "aaguid").unwrap();
+        assert_eq!(parsed.fmt, "packed");
+        assert_eq!(parsed.alg, Some(-7));
+        assert_eq!(parsed.sig.as_deref(), Some("AQID"));
+        assert_eq!(parsed.x5c, ["BAU", "Bg"]);
+    }
+
+    #[test]
+    fn test_parse_none_statement() {
+        let object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+        ]);
+        let parsed = parse_statement(&response_with(object), "aaguid").unwrap();
+        assert_eq!(parsed.fmt, "none");
+        assert!(parsed.x5c.is_empty() && parsed.sig.is_none() && parsed.alg.is_none());
+
+        assert!(parse_statement("{}", "aaguid").is_none());
+    }
+
+    fn registration(fmt: &str, statement: Vec<(Value, Value)>, auth_data: &[u8], client_data: &[u8]) -> String {
+        let object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text(fmt.into())),
+            (Value::Text("attStmt".into()), Value::Map(statement)),
+            (Value::Text("authData".into()), Value::Bytes(auth_data.to_vec())),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&object, &mut bytes).unwrap();
+        serde_json::json!({
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "attestationObject": URL_SAFE_NO_PAD.encode(bytes),
+            }
+        })
+        .to_string()
+    }
+
+    fn packed_x5c(auth_data: &[u8], client_data: &[u8]) -> Vec<(Value, Value)> {
+        use crate::mds::tests::{leaf_key, LEAF, ROOT};
+        use p256::ecdsa::signature::Signer;
+
+        let mut message = auth_data.to_vec();
+        message.extend_from_slice(&Sha256::digest(client_data));
+        let signature: p256::ecdsa::Signature = leaf_key().sign(&message);
+        vec![
+            (Value::Text("alg".into()), Value::Integer((-7).into())),
+            (Value::Text("sig".into()), Value::Bytes(signature.to_der().as_bytes().to_vec())),
+            (
+                Value::Text("x5c".into()),
+                Value::Array(vec![
+                    Value::Bytes(STANDARD.decode(LEAF).unwrap()),
+                    Value::Bytes(STANDARD.decode(ROOT).unwrap()),
+                ]),
+            ),
+        ]
+    }
+
+    fn fmt_of(response: &str) -> String {
+        let response: serde_json::Value = serde_json::from_str(response).unwrap();
+        let bytes = URL_SAFE_NO_PAD
+            .decode(response["response"]["attestationObject"].as_str().unwrap())
+            .unwrap();
+        let object: Value = ciborium::from_reader(bytes.as_slice()).unwrap();
+        get(object.as_map().unwrap(), "fmt").unwrap().as_text().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_packed_chain_is_verified_then_stripped() {
+        use crate::mds::tests::NOW;
+        let (auth_data, client_data) = ([9u8; 55], br#"{"type":"webauthn.create"}"#);
+        let response = registration("packed", packed_x5c(&auth_data, client_data), &auth_data, client_data);
+
+        for conveyance in [Conveyance::Indirect, Conveyance::Direct, Conveyance::Enterprise] {
+            assert_eq!(fmt_of(&verify_statement(&response, conveyance, NOW).unwrap()), "none");
+        }
+        assert_eq!(verify_statement(&response, Conveyance::None, NOW).unwrap(), response);
+
+        let forged = registration("packed", packed_x5c(&[8u8; 55], client_data), &auth_data, client_data);
+        assert!(matches!(
+            verify_statement(&forged, Conveyance::Direct, NOW),
+            Err(AppError::WebAuthn(_))
+        ));
+    }
+
+    #[test]
+    fn test_depth_decides_what_is_accepted() {
+        let (auth_data, client_data) = ([9u8; 55], b"{}");
+        let none = registration("none", Vec::new(), &auth_data, client_data);
+        assert_eq!(verify_statement(&none, Conveyance::Direct, 0).unwrap(), none);
+        assert!(verify_statement(&none, Conveyance::Enterprise, 0).is_err());
+
+        let tpm = registration("tpm", Vec::new(), &auth_data, client_data);
+        assert_eq!(fmt_of(&verify_statement(&tpm, Conveyance::Indirect, 0).unwrap()), "none");
+        assert!(verify_statement(&tpm, Conveyance::Direct, 0).is_err());
+    }
+
+    #[test]
+    fn test_only_anchored_chains_are_trusted() {
+        use crate::mds::tests::{LEAF, NOW, ROOT};
+        use crate::storage::MdsEntry;
+
+        let (auth_data, client_data) = ([9u8; 55], b"{}");
+        let untrusted = |response: &str, cache: Option<&MdsCache>| {
+            matches!(check_trusted(response, cache, NOW), Err(AppError::AttestationUntrusted(_)))
+        };
+        let packed = registration("packed", packed_x5c(&auth_data, client_data), &auth_data, client_data);
+        let self_attested = registration(
+            "packed",
+            vec![(Value::Text("alg".into()), Value::Integer((-7).into()))],
+            &auth_data,
+            client_data,
+        );
+        assert!(untrusted(&registration("none", Vec::new(), &auth_data, client_data), None));
+        assert!(untrusted(&self_attested, None));
+        assert!(untrusted(&registration("tpm", Vec::new(), &auth_data, client_data), None));
+        // A verified chain still needs a root from the model's metadata
+        assert!(untrusted(&packed, None));
+
+        let cache = |root: &str| {
+            let mut cache = MdsCache::default();
+            cache.entries.insert(
+                "09090909-0909-0909-0909-090909090909".to_string(),
+                MdsEntry {
+                    description: String::new(),
+                    status: None,
+                    attestation_roots: vec![root.to_string()],
+                },
+            );
+            cache
+        };
+        assert!(check_trusted(&packed, Some(&cache(ROOT)), NOW).is_ok());
+        assert!(untrusted(&packed, Some(&cache(LEAF))));
+    }
+}
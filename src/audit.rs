@@ -0,0 +1,237 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::errors::AppError;
+use crate::secret::ct_eq;
+use crate::storage::{AuditAnchor, AuditEntry, AuditHead, StorageProvider};
+
+/// `prev_hash` of the first entry
+const GENESIS_HASH: &str = "";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 (hex) over the entry as JSON with an empty `hash` and no `mac`.
+fn entry_hash(entry: &AuditEntry) -> String {
+    let unhashed = AuditEntry {
+        hash: String::new(),
+        mac: None,
+        ..entry.clone()
+    };
+    let json = serde_json::to_vec(&unhashed).expect("audit entries always serialize");
+    hex(&Sha256::digest(json))
+}
+
+/// HMAC-SHA256 (hex) of `message`. Entry, head and anchor MACs use
+/// different prefixes, so one cannot be passed off as another.
+fn mac(key: &[u8], prefix: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(prefix.as_bytes());
+    mac.update(message.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+fn head_mac(key: &[u8], seq: u64, hash: &str) -> String {
+    mac(key, "head:", &format!("{}:{}", seq, hash))
+}
+
+fn anchor_mac(key: &[u8], seq: u64, prev_hash: &str) -> String {
+    mac(key, "start:", &format!("{}:{}", seq, prev_hash))
+}
+
+/// Appends an administrative action to the audit log.
+pub fn record(
+    storage: &dyn StorageProvider,
+    actor: Option<&str>,
+    action: &str,
+    target: &str,
+    details: serde_json::Value,
+) -> Result<(), AppError> {
+    let key = storage
+        .audit_key(true)?
+        .ok_or_else(|| AppError::Storage("Audit key could not be created".to_string()))?;
+    storage.append_audit_entry(&mut |last| {
+        let mut entry = AuditEntry {
+            seq: last.map_or(1, |l| l.seq + 1),
+            time: crate::clock::format(crate::clock::now(), crate::clock::Precision::Millis),
+            actor: actor.map(str::to_string),
+            trace_id: crate::trace::current(),
+            action: action.to_string(),
+            target: target.to_string(),
+            details: details.clone(),
+            prev_hash: last.map_or_else(|| GENESIS_HASH.to_string(), |l| l.hash.clone()),
+            hash: String::new(),
+            mac: None,
+        };
+        entry.hash = entry_hash(&entry);
+        entry.mac = Some(mac(&key, "entry:", &entry.hash));
+        let head = AuditHead {
+            seq: entry.seq,
+            hash: entry.hash.clone(),
+            mac: head_mac(&key, entry.seq, &entry.hash),
+        };
+        (entry, head)
+    })?;
+    // The entry is safely recorded; a failed rotation is retried next time
+    if let Err(e) = rotate(storage) {
+        crate::syslog::warning(&format!("Audit log rotation failed: {}", e));
+    }
+    Ok(())
+}
+
+/// Rotates and prunes the audit log as configured, anchoring the chain at
+/// the first entry kept. Without the audit key nothing is pruned, as no
+/// anchor could be vouched for.
+pub fn rotate(storage: &dyn StorageProvider) -> Result<(), AppError> {
+    let Some(key) = storage.audit_key(false)? else {
+        return Ok(());
+    };
+    storage.rotate_audit_log(&mut |first| AuditAnchor {
+        seq: first.seq,
+        prev_hash: first.prev_hash.clone(),
+        mac: anchor_mac(&key, first.seq, &first.prev_hash),
+    })
+}
+
+/// Checks the hash chain, from the first entry or from where `anchor` says
+/// a pruned log starts; returns the sequence number of the first entry that
+/// was altered, removed or inserted out of order.
+pub fn verify_chain(entries: &[AuditEntry], anchor: Option<&AuditAnchor>) -> Result<(), u64> {
+    let (first_seq, mut prev_hash) = anchor.map_or((1, GENESIS_HASH), |a| (a.seq, a.prev_hash.as_str()));
+    for (index, entry) in entries.iter().enumerate() {
+        let seq = first_seq + index as u64;
+        if entry.seq != seq || entry.prev_hash != prev_hash || entry.hash != entry_hash(entry) {
+            return Err(seq);
+        }
+        prev_hash = &entry.hash;
+    }
+    Ok(())
+}
+
+/// Checks the entry MACs; returns the sequence number of the first entry
+/// whose MAC is wrong or missing. Without the key, no MAC can be checked.
+pub fn verify_macs(entries: &[AuditEntry], key: Option<&[u8]>) -> Result<(), u64> {
+    for entry in entries {
+        let valid = match (key, &entry.mac) {
+            (Some(key), Some(found)) => ct_eq(found, &mac(key, "entry:", &entry.hash)),
+            _ => false,
+        };
+        if !valid {
+            return Err(entry.seq);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the anchor of a pruned log was written with the audit key.
+pub fn verify_anchor(anchor: &AuditAnchor, key: Option<&[u8]>) -> bool {
+    key.is_some_and(|key| ct_eq(&anchor.mac, &anchor_mac(key, anchor.seq, &anchor.prev_hash)))
+}
+
+/// Whether entries were cut from the end of the log: the authenticated head
+/// must name its last entry, and a log with entries must have a head.
+pub fn is_truncated(entries: &[AuditEntry], head: Option<&AuditHead>, key: Option<&[u8]>) -> bool {
+    match (head, key) {
+        (None, _) => !entries.is_empty(),
+        (Some(_), None) => true,
+        (Some(head), Some(key)) => {
+            !ct_eq(&head.mac, &head_mac(key, head.seq, &head.hash))
+                || entries.last().is_none_or(|last| last.seq != head.seq || last.hash != head.hash)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_chain_detects_tampering() {
+        let storage = MemoryStorage::default();
+        record(&storage, Some("admin"), "credential_delete", "a", serde_json::json!({})).unwrap();
+        record(
+            &storage,
+            None,
+            "credential_rename",
+            "b",
+            serde_json::json!({ "newName": "Key" }),
+        )
+        .unwrap();
+        record(&storage, None, "credential_delete", "c", serde_json::Value::Null).unwrap();
+
+        let entries = storage.load_audit_log().unwrap();
+        assert_eq!(entries[1].prev_hash, entries[0].hash);
+        assert_eq!(verify_chain(&entries, None), Ok(()));
+
+        let mut edited = entries.clone();
+        edited[1].target = "x".to_string();
+        assert_eq!(verify_chain(&edited, None), Err(2));
+
+        let mut removed = entries.clone();
+        removed.remove(0);
+        assert_eq!(verify_chain(&removed, None), Err(1));
+
+        let truncated = &entries[..2];
+        assert_eq!(verify_chain(truncated, None), Ok(()));
+
+        // A pruned log verifies from its anchor
+        let key = storage.audit_key(false).unwrap().unwrap();
+        let anchor = AuditAnchor {
+            seq: 2,
+            prev_hash: entries[0].hash.clone(),
+            mac: anchor_mac(&key, 2, &entries[0].hash),
+        };
+        assert!(verify_anchor(&anchor, Some(&key)));
+        assert!(!verify_anchor(&anchor, Some(b"other key")));
+        assert_eq!(verify_chain(&entries[1..], Some(&anchor)), Ok(()));
+        assert_eq!(verify_chain(&entries[2..], Some(&anchor)), Err(2));
+    }
+
+    #[test]
+    fn test_key_detects_rehashing_and_truncation() {
+        let storage = MemoryStorage::default();
+        for target in ["a", "b", "c"] {
+            record(&storage, None, "credential_delete", target, serde_json::Value::Null).unwrap();
+        }
+        let entries = storage.load_audit_log().unwrap();
+        let head = storage.load_audit_head().unwrap();
+        let key = storage.audit_key(false).unwrap().unwrap();
+        assert_eq!(verify_macs(&entries, Some(&key)), Ok(()));
+        assert!(!is_truncated(&entries, head.as_ref(), Some(&key)));
+
+        // Without the key the chain can be recomputed, but not the MACs
+        let mut forged = entries.clone();
+        forged[1].target = "x".to_string();
+        for i in 1..forged.len() {
+            forged[i].prev_hash = forged[i - 1].hash.clone();
+            forged[i].hash = entry_hash(&forged[i]);
+        }
+        assert_eq!(verify_chain(&forged, None), Ok(()));
+        assert_eq!(verify_macs(&forged, Some(&key)), Err(2));
+        assert_eq!(verify_macs(&entries, Some(b"other key")), Err(1));
+        assert_eq!(verify_macs(&entries, None), Err(1));
+
+        assert!(is_truncated(&entries[..2], head.as_ref(), Some(&key)));
+        assert!(is_truncated(&entries, None, Some(&key)));
+        let mut moved = head.clone().unwrap();
+        moved.seq = 2;
+        moved.hash = entries[1].hash.clone();
+        assert!(is_truncated(&entries[..2], Some(&moved), Some(&key)));
+
+        // Nor can an edit hide behind stripped MACs and a deleted head
+        let mut stripped: Vec<AuditEntry> = entries.iter().map(|e| AuditEntry { mac: None, ..e.clone() }).collect();
+        stripped[1].target = "x".to_string();
+        for i in 1..stripped.len() {
+            stripped[i].prev_hash = stripped[i - 1].hash.clone();
+            stripped[i].hash = entry_hash(&stripped[i]);
+        }
+        assert_eq!(verify_chain(&stripped, None), Ok(()));
+        assert_eq!(verify_macs(&stripped, Some(&key)), Err(1));
+        assert_eq!(verify_macs(&stripped, None), Err(1));
+        assert!(is_truncated(&stripped, None, Some(&key)));
+        assert!(is_truncated(&stripped, None, None));
+        assert!(!is_truncated(&[], None, Some(&key)));
+    }
+}
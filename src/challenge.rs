@@ -0,0 +1,117 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::errors::AppError;
+
+/// Size of a WebAuthn challenge as generated by webauthn_rp (a `u128`)
+pub const CHALLENGE_LEN: usize = 16;
+
+/// Offsets at which the encoded server states start with their challenge:
+/// authentication states lead with it, registration states after a one-byte
+/// mediation requirement.
+const CHALLENGE_OFFSETS: [usize; 2] = [0, 1];
+
+/// A fresh random (v4 UUID) ID for a stored challenge.
+pub fn new_challenge_id() -> String {
+    #[cfg(feature = "deterministic")]
+    if let Some(bytes) = crate::deterministic::bytes(16) {
+        let bytes: [u8; 16] = bytes.try_into().expect("16 bytes were requested");
+        return uuid::Builder::from_random_bytes(bytes).into_uuid().to_string();
+    }
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Rejects a caller-supplied challenge ID that is not in the form
+/// [`new_challenge_id`] gives out, before it names a file or a replay cache
+/// entry.
+pub fn check_challenge_id(challenge_id: &str) -> Result<(), AppError> {
+    match uuid::Uuid::try_parse(challenge_id) {
+        Ok(id) if id.to_string() == challenge_id => Ok(()),
+        _ => Err(AppError::InvalidInput(format!("Invalid challenge ID: {:?}", challenge_id))),
+    }
+}
+
+/// Replaces the random challenge of a freshly started ceremony with one the
+/// caller generated and already bound to its own session.
+///
+/// webauthn_rp never lets a challenge be constructed from outside, so the
+/// challenge is swapped in the encoded server state (little-endian `u128`,
+/// the same byte order as the base64url form sent to the client) and in the
+/// client options.
+pub fn substitute_challenge(state_bytes: &mut [u8], client_options: &mut serde_json::Value, external_b64: &str) -> Result<(), AppError> {
+    let external = URL_SAFE_NO_PAD
+        .decode(external_b64)
+        .map_err(|e| AppError::InvalidInput(format!("Invalid challenge: {}", e)))?;
+    if external.len() != CHALLENGE_LEN {
+        return Err(AppError::InvalidInput(format!(
+            "Challenge must be exactly {} bytes, got {}",
+            CHALLENGE_LEN,
+            external.len()
+        )));
+    }
+
+    let generated = client_options
+        .get("challenge")
+        .and_then(|c| c.as_str())
+        .and_then(|c| URL_SAFE_NO_PAD.decode(c).ok())
+        .ok_or_else(|| AppError::WebAuthn("Client options carry no challenge".to_string()))?;
+    let offset = CHALLENGE_OFFSETS
+        .into_iter()
+        .find(|&o| state_bytes.get(o..o + CHALLENGE_LEN) == Some(generated.as_slice()))
+        .ok_or_else(|| AppError::WebAuthn("Challenge not found in server state".to_string()))?;
+
+    state_bytes[offset..offset + CHALLENGE_LEN].copy_from_slice(&external);
+    client_options["challenge"] = serde_json::Value::String(URL_SAFE_NO_PAD.encode(&external));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_challenge_id() {
+        assert!(check_challenge_id(&new_challenge_id()).is_ok());
+        for id in [
+            "",
+            "../../etc/passwd",
+            "0e4b8a3c-1d2f-4e5a-9b6c-7d8e9f0a1b2c/../x",
+            "0E4B8A3C-1D2F-4E5A-9B6C-7D8E9F0A1B2C",
+            "0e4b8a3c1d2f4e5a9b6c7d8e9f0a1b2c",
+            "{0e4b8a3c-1d2f-4e5a-9b6c-7d8e9f0a1b2c}",
+        ] {
+            assert!(matches!(check_challenge_id(id), Err(AppError::InvalidInput(_))), "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_substitute_challenge() {
+        let generated = [7u8; CHALLENGE_LEN];
+        let mut state = vec![1u8];
+        state.extend_from_slice(&generated);
+        state.extend_from_slice(&[9, 9, 9]);
+        let mut options = serde_json::json!({ "challenge": URL_SAFE_NO_PAD.encode(generated) });
+
+        let external = URL_SAFE_NO_PAD.encode([42u8; CHALLENGE_LEN]);
+        substitute_challenge(&mut state, &mut options, &external).unwrap();
+        assert_eq!(&state[1..17], &[42u8; CHALLENGE_LEN]);
+        assert_eq!(state[0], 1);
+        assert_eq!(&state[17..], &[9, 9, 9]);
+        assert_eq!(options["challenge"], external);
+    }
+
+    #[test]
+    fn test_substitute_challenge_rejects_bad_input() {
+        let generated = [7u8; CHALLENGE_LEN];
+        let mut state = generated.to_vec();
+        let mut options = serde_json::json!({ "challenge": URL_SAFE_NO_PAD.encode(generated) });
+
+        assert!(substitute_challenge(&mut state, &mut options, "not base64!").is_err());
+        let too_short = URL_SAFE_NO_PAD.encode([1u8; 8]);
+        assert!(substitute_challenge(&mut state, &mut options, &too_short).is_err());
+
+        let mut unrelated = vec![0u8; 32];
+        let external = URL_SAFE_NO_PAD.encode([42u8; CHALLENGE_LEN]);
+        assert!(substitute_challenge(&mut unrelated, &mut options, &external).is_err());
+    }
+}
@@ -1,4 +1,19 @@
 pub struct Cli {
+    pub config_path: Option<String>,
+    pub api_version: Option<String>,
+    pub origins_file: Option<String>,
+    /// Who is performing an administrative action, for the audit log
+    pub actor: Option<String>,
+    /// Correlates this invocation's response, log lines and audit record
+    pub trace_id: Option<String>,
+    /// Descriptor to read the request body from instead of stdin
+    pub input_fd: Option<i32>,
+    /// File to write the response to instead of stdout
+    pub out: Option<String>,
+    /// Remote credential store to use instead of the local files
+    pub storage: Option<String>,
+    /// Skip flushing local writes to disk, for benchmarks
+    pub no_sync: bool,
     pub command: Commands,
 }
 
@@ -7,31 +22,242 @@ pub enum Commands {
         username: String,
         rp_id: String,
         user_verification: String,
+        allow_reregistration: bool,
+        user_handle: Option<String>,
+        challenge: Option<String>,
+        client_format: Option<String>,
+        hints: Option<String>,
+        qr: Option<String>,
+        enroll_url: Option<String>,
+        attestation: Option<String>,
+        rp_name: Option<String>,
     },
     RegisterFinish {
         challenge_id: String,
         origin: String,
         device_name: String,
+        /// Reject none, self and untrusted attestation
+        require_attestation: bool,
+        client_ip: Option<String>,
+        user_agent: Option<String>,
     },
     LoginBegin {
         username: String,
         rp_id: String,
+        user_verification: Option<String>,
+        allow_credentials: Vec<String>,
+        challenge: Option<String>,
+        client_format: Option<String>,
     },
     LoginFinish {
         challenge_id: String,
         origin: String,
+        client_ip: Option<String>,
+        user_agent: Option<String>,
+    },
+    /// Step-up re-authentication of a logged-in frontend session
+    ReauthBegin {
+        session: String,
+        username: String,
+        rp_id: String,
+        credential_id: String,
+        client_format: Option<String>,
+    },
+    ReauthFinish {
+        challenge_id: String,
+        session: String,
+        origin: String,
+        client_ip: Option<String>,
+        user_agent: Option<String>,
+    },
+    VerifyAssertion {
+        credential_id: String,
+        challenge: String,
+        origin: String,
+        rp_id: Option<String>,
     },
     CredentialManage {
         action: CredentialAction,
     },
-    HealthCheck,
+    Storage {
+        action: StorageAction,
+    },
+    HealthCheck {
+        /// Sample again every this many seconds, one JSON line each
+        watch: Option<u64>,
+        /// With `watch`, stop after this many samples
+        count: Option<usize>,
+    },
+    Stats,
+    /// Register and log in with a software authenticator against a throwaway store
+    SelfTest,
+    /// Time registration, login and store access on this hardware
+    Bench {
+        iterations: usize,
+    },
+    PinnedOrigins {
+        action: PinnedOriginsAction,
+    },
+    Audit {
+        action: AuditAction,
+    },
+    Backup {
+        action: BackupAction,
+    },
+    Challenge {
+        action: ChallengeAction,
+    },
+    Nonce {
+        action: NonceAction,
+    },
+    Recovery {
+        action: RecoveryAction,
+    },
+    UserManage {
+        action: UserAction,
+    },
+    /// Reads and writes the helper's own configuration file
+    Config {
+        action: ConfigAction,
+    },
+    Mds {
+        action: MdsAction,
+    },
+    /// Interactive register-begin/register-finish walkthrough for debugging
+    Wizard {
+        username: String,
+        rp_id: String,
+        origin: String,
+        device_name: String,
+    },
+    /// Long-running mode answering rpcd-style calls on a Unix socket
+    Serve {
+        foreground: bool,
+        socket: String,
+        idle_exit: u64,
+    },
+    InitScript {
+        binary: String,
+        socket: String,
+    },
+    /// rpcd plugin protocol: `list`
+    RpcdList,
+    /// rpcd plugin protocol: `call <method>` with JSON arguments on stdin
+    RpcdCall {
+        method: String,
+    },
+    RpcdAcl {
+        object: String,
+    },
+    /// JSON Schema documents of the responses, for frontend codegen
+    Schema {
+        action: SchemaAction,
+    },
 }
 
 pub enum CredentialAction {
-    List { username: String },
-    Delete { id: String },
-    Update { id: String, name: String },
+    List {
+        username: String,
+        rp_id: Option<String>,
+        format: Option<String>,
+    },
+    Delete {
+        id: String,
+    },
+    Show {
+        id: String,
+    },
+    ExportKey {
+        id: String,
+        format: String,
+    },
+    Update {
+        id: String,
+        name: Option<String>,
+        admin: Option<bool>,
+    },
+    Disable {
+        id: String,
+    },
+    Enable {
+        id: String,
+    },
     Cleanup,
+    Dedupe {
+        remove: bool,
+    },
+}
+
+pub enum PinnedOriginsAction {
+    List,
+    Clear { rp_id: Option<String> },
+}
+
+pub enum AuditAction {
+    List { action: Option<String>, limit: Option<usize> },
+    Verify,
+}
+
+pub enum BackupAction {
+    Import {
+        format: String,
+        username: String,
+        rp_id: String,
+        user_handle: Option<String>,
+        device_name: String,
+    },
+    Export {
+        format: String,
+        username: Option<String>,
+        rp_id: Option<String>,
+    },
+    Push {
+        url: Option<String>,
+        token_file: Option<String>,
+        passphrase_file: Option<String>,
+    },
+}
+
+pub enum ChallengeAction {
+    Create { kind: String, payload: Option<String> },
+    Consume { challenge_id: String, kind: String },
+}
+
+pub enum NonceAction {
+    Create { bind: Option<String> },
+    Verify { nonce: String, bind: Option<String> },
+}
+
+pub enum RecoveryAction {
+    Generate { username: String, count: usize },
+    Verify { username: String },
+}
+
+pub enum UserAction {
+    List,
+    SetRoles { username: String, roles: Vec<String> },
+    SetGroups { username: String, groups: Vec<String> },
+}
+
+pub enum ConfigAction {
+    List,
+    Get { key: String },
+    Set { key: String, value: String },
+}
+
+pub enum SchemaAction {
+    Dump,
+}
+
+pub enum MdsAction {
+    Update { file: Option<String>, offline: bool },
+}
+
+pub enum StorageAction {
+    FixPerms,
+    Compact,
+    Maintain,
+    Stats,
 }
 
 fn print_help() -> ! {
@@ -43,11 +269,42 @@ fn print_help() -> ! {
          \x20 register-finish    Verify registration and save credential\n\
          \x20 login-begin        Generate a login challenge\n\
          \x20 login-finish       Verify login signature\n\
+         \x20 reauth-begin       Generate a step-up challenge for a logged-in session\n\
+         \x20 reauth-finish      Verify a step-up signature and issue a single-use token\n\
+         \x20 verify-assertion   Verify an assertion against a caller-managed challenge\n\
          \x20 credential-manage  Credential management\n\
-         \x20 health-check       Health check\n\n\
+         \x20 storage            Storage maintenance (fix-perms, compact, maintain, stats)\n\
+         \x20 health-check       Health check, or NDJSON samples with --watch <secs>\n\
+         \x20 stats              Credential counts by transport, model, algorithm and recent activity\n\
+         \x20 self-test          Register and log in with a software authenticator in a temporary store\n\
+         \x20 bench              Time registration, login and store access on this hardware\n\
+         \x20 pinned-origins     Origins pinned in trust-on-first-use mode (list, clear)\n\
+         \x20 audit              Admin action audit log (list, verify)\n\
+         \x20 backup             Move credentials between WebAuthn stacks, push off-device backups (import, export, push)\n\
+         \x20 challenge          Single-use challenges for other flows (create, consume)\n\
+         \x20 nonce              Single-use anti-CSRF nonces (create, verify)\n\
+         \x20 recovery           Break-glass recovery codes (generate, verify)\n\
+         \x20 user-manage        Users and the roles and groups returned on login (list, set-roles, set-groups)\n\
+         \x20 config             Helper configuration file (list, get, set)\n\
+         \x20 mds                FIDO Metadata Service cache (update)\n\
+         \x20 wizard             Walk through a registration by hand, explaining each check\n\
+         \x20 serve              Answer rpcd-style calls on a Unix socket until SIGTERM\n\
+         \x20 init-script        Print a procd init script for serve\n\
+         \x20 list, call         rpcd plugin protocol (install into /usr/libexec/rpcd)\n\
+         \x20 rpcd-acl           Print the rpcd ACL for the plugin methods\n\
+         \x20 schema             JSON Schema of the responses (dump)\n\n\
          Options:\n\
-         \x20 -h, --help     Print help\n\
-         \x20 -V, --version  Print version"
+         \x20 --config <PATH>        Configuration file (default: /etc/webauthn/config.json)\n\
+         \x20 --api-version <N>      JSON output contract version, 1 or 2 (default: 1)\n\
+         \x20 --origins-file <PATH>  Extra accepted origins, one per line\n\
+         \x20 --actor <NAME>         Who performs an admin action, for the audit log\n\
+         \x20 --trace-id <ID>        Trace ID for the response, logs and audit log (default: random)\n\
+         \x20 --fd <N>               Read the request body from descriptor N instead of stdin\n\
+         \x20 --out <PATH>           Write the response to PATH (atomically) instead of stdout\n\
+         \x20 --storage <URL>        Credential store on a Redis server (redis://[[user]:password@]host[:port][/db])\n\
+         \x20 --no-sync              Do not flush writes to disk (benchmarks only)\n\
+         \x20 -h, --help             Print help\n\
+         \x20 -V, --version          Print version"
     );
     std::process::exit(0);
 }
@@ -72,6 +329,21 @@ fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
     None
 }
 
+/// Every value of an option that may be repeated, in order.
+fn take_all(args: &mut Vec<String>, name: &str) -> Vec<String> {
+    std::iter::from_fn(|| take_option(args, name)).collect()
+}
+
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|a| a == name) {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
 fn require_option(args: &mut Vec<String>, name: &str) -> String {
     take_option(args, name).unwrap_or_else(|| missing_arg(name))
 }
@@ -85,18 +357,312 @@ fn parse_credential_manage(args: &mut Vec<String>) -> CredentialAction {
     match sub.as_str() {
         "list" => {
             let username = require_option(args, "--username");
-            CredentialAction::List { username }
+            let rp_id = take_option(args, "--rp-id");
+            let format = take_option(args, "--format");
+            CredentialAction::List { username, rp_id, format }
         }
         "delete" => {
             let id = require_option(args, "--id");
             CredentialAction::Delete { id }
         }
+        "show" => {
+            let id = require_option(args, "--id");
+            CredentialAction::Show { id }
+        }
+        "export-key" => {
+            let id = require_option(args, "--id");
+            let format = take_option(args, "--format").unwrap_or_else(|| "pem".to_string());
+            CredentialAction::ExportKey { id, format }
+        }
         "update" => {
             let id = require_option(args, "--id");
-            let name = require_option(args, "--name");
-            CredentialAction::Update { id, name }
+            let name = take_option(args, "--name");
+            let admin = take_option(args, "--admin").map(|admin| match admin.as_str() {
+                "true" => true,
+                "false" => false,
+                _ => {
+                    eprintln!("error: invalid value '{admin}' for '--admin'");
+                    std::process::exit(2);
+                }
+            });
+            CredentialAction::Update { id, name, admin }
+        }
+        "disable" => {
+            let id = require_option(args, "--id");
+            CredentialAction::Disable { id }
+        }
+        "enable" => {
+            let id = require_option(args, "--id");
+            CredentialAction::Enable { id }
         }
         "cleanup" => CredentialAction::Cleanup,
+        "dedupe" => CredentialAction::Dedupe {
+            remove: take_flag(args, "--remove"),
+        },
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_mds(args: &mut Vec<String>) -> MdsAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for mds");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "update" => {
+            let file = take_option(args, "--file");
+            let offline = take_flag(args, "--offline");
+            MdsAction::Update { file, offline }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_pinned_origins(args: &mut Vec<String>) -> PinnedOriginsAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for pinned-origins");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "list" => PinnedOriginsAction::List,
+        "clear" => {
+            let rp_id = take_option(args, "--rp-id");
+            PinnedOriginsAction::Clear { rp_id }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_audit(args: &mut Vec<String>) -> AuditAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for audit");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "list" => {
+            let action = take_option(args, "--action");
+            let limit = take_option(args, "--limit").map(|limit| {
+                limit.parse().unwrap_or_else(|_| {
+                    eprintln!("error: invalid value '{limit}' for '--limit'");
+                    std::process::exit(2);
+                })
+            });
+            AuditAction::List { action, limit }
+        }
+        "verify" => AuditAction::Verify,
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_challenge(args: &mut Vec<String>) -> ChallengeAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for challenge");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "create" => {
+            let kind = require_option(args, "--type");
+            let payload = take_option(args, "--payload");
+            ChallengeAction::Create { kind, payload }
+        }
+        "consume" => {
+            let challenge_id = require_option(args, "--challenge-id");
+            let kind = require_option(args, "--type");
+            ChallengeAction::Consume { challenge_id, kind }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_nonce(args: &mut Vec<String>) -> NonceAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for nonce");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "create" => {
+            let bind = take_option(args, "--bind");
+            NonceAction::Create { bind }
+        }
+        "verify" => {
+            let nonce = require_option(args, "--nonce");
+            let bind = take_option(args, "--bind");
+            NonceAction::Verify { nonce, bind }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_recovery(args: &mut Vec<String>) -> RecoveryAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for recovery");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "generate" => {
+            let username = require_option(args, "--username");
+            let count = take_option(args, "--count").map_or(crate::commands::recovery::DEFAULT_COUNT, |count| {
+                count.parse().unwrap_or_else(|_| {
+                    eprintln!("error: invalid value '{count}' for '--count'");
+                    std::process::exit(2);
+                })
+            });
+            RecoveryAction::Generate { username, count }
+        }
+        "verify" => {
+            let username = require_option(args, "--username");
+            RecoveryAction::Verify { username }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_user(args: &mut Vec<String>) -> UserAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for user-manage");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "list" => UserAction::List,
+        "set-roles" => {
+            let username = require_option(args, "--username");
+            let roles = take_all(args, "--role");
+            UserAction::SetRoles { username, roles }
+        }
+        "set-groups" => {
+            let username = require_option(args, "--username");
+            let groups = take_all(args, "--group");
+            UserAction::SetGroups { username, groups }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_schema(args: &mut Vec<String>) -> SchemaAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for schema");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "dump" => SchemaAction::Dump,
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_config(args: &mut Vec<String>) -> ConfigAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for config");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "list" => ConfigAction::List,
+        "get" => {
+            let key = require_option(args, "--key");
+            ConfigAction::Get { key }
+        }
+        "set" => {
+            let key = require_option(args, "--key");
+            let value = require_option(args, "--value");
+            ConfigAction::Set { key, value }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_backup(args: &mut Vec<String>) -> BackupAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for backup");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "import" => {
+            let format = require_option(args, "--format");
+            let username = require_option(args, "--username");
+            let rp_id = require_option(args, "--rp-id");
+            let user_handle = take_option(args, "--user-handle");
+            let device_name = take_option(args, "--device-name").unwrap_or_else(|| "Imported".to_string());
+            BackupAction::Import {
+                format,
+                username,
+                rp_id,
+                user_handle,
+                device_name,
+            }
+        }
+        "export" => {
+            let format = take_option(args, "--format").unwrap_or_else(|| "cxf".to_string());
+            let username = take_option(args, "--username");
+            let rp_id = take_option(args, "--rp-id");
+            BackupAction::Export { format, username, rp_id }
+        }
+        "push" => {
+            let url = take_option(args, "--url");
+            let token_file = take_option(args, "--token-file");
+            let passphrase_file = take_option(args, "--passphrase-file");
+            BackupAction::Push {
+                url,
+                token_file,
+                passphrase_file,
+            }
+        }
+        _ => {
+            eprintln!("error: unrecognized subcommand '{sub}'");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn parse_storage(args: &mut Vec<String>) -> StorageAction {
+    if args.is_empty() {
+        eprintln!("error: a subcommand is required for storage");
+        std::process::exit(2);
+    }
+    let sub = args.remove(0);
+    match sub.as_str() {
+        "fix-perms" => StorageAction::FixPerms,
+        "compact" => StorageAction::Compact,
+        "maintain" => StorageAction::Maintain,
+        "stats" => StorageAction::Stats,
         _ => {
             eprintln!("error: unrecognized subcommand '{sub}'");
             std::process::exit(2);
@@ -104,6 +670,31 @@ fn parse_credential_manage(args: &mut Vec<String>) -> CredentialAction {
     }
 }
 
+impl Commands {
+    /// Whether the command changes the credential store when it succeeds,
+    /// which counts towards the next automatic backup.
+    pub fn changes_store(&self) -> bool {
+        matches!(
+            self,
+            Commands::RegisterFinish { .. }
+                | Commands::CredentialManage {
+                    action: CredentialAction::Delete { .. }
+                        | CredentialAction::Update { .. }
+                        | CredentialAction::Disable { .. }
+                        | CredentialAction::Enable { .. }
+                        | CredentialAction::Dedupe { remove: true }
+                }
+                | Commands::Backup {
+                    action: BackupAction::Import { .. }
+                }
+                | Commands::Recovery { .. }
+                | Commands::UserManage {
+                    action: UserAction::SetRoles { .. } | UserAction::SetGroups { .. }
+                }
+        )
+    }
+}
+
 impl Cli {
     pub fn parse() -> Self {
         let mut args: Vec<String> = std::env::args().skip(1).collect();
@@ -125,49 +716,280 @@ impl Cli {
             print_version();
         }
 
+        let config_path = take_option(&mut args, "--config");
+        let api_version = take_option(&mut args, "--api-version");
+        let origins_file = take_option(&mut args, "--origins-file");
+        let actor = take_option(&mut args, "--actor");
+        let trace_id = take_option(&mut args, "--trace-id");
+        let input_fd = take_option(&mut args, "--fd").map(|fd| {
+            fd.parse::<i32>().ok().filter(|fd| *fd >= 0).unwrap_or_else(|| {
+                eprintln!("error: invalid value '{fd}' for '--fd'");
+                std::process::exit(2);
+            })
+        });
+        let out = take_option(&mut args, "--out");
+        let storage = take_option(&mut args, "--storage");
+        let no_sync = take_flag(&mut args, "--no-sync");
+        if args.is_empty() {
+            eprintln!("error: a subcommand is required");
+            std::process::exit(2);
+        }
+
         let subcmd = args.remove(0);
         let command = match subcmd.as_str() {
             "register-begin" => {
                 let username = require_option(&mut args, "--username");
                 let rp_id = require_option(&mut args, "--rp-id");
                 let user_verification = take_option(&mut args, "--user-verification").unwrap_or_else(|| "preferred".to_string());
+                let allow_reregistration = take_flag(&mut args, "--allow-reregistration");
+                let user_handle = take_option(&mut args, "--user-handle");
+                let challenge = take_option(&mut args, "--challenge");
+                let client_format = take_option(&mut args, "--client-format");
+                let hints = take_option(&mut args, "--hints");
+                let qr = take_option(&mut args, "--qr");
+                let enroll_url = take_option(&mut args, "--enroll-url");
+                let attestation = take_option(&mut args, "--attestation");
+                let rp_name = take_option(&mut args, "--rp-name");
                 Commands::RegisterBegin {
                     username,
                     rp_id,
                     user_verification,
+                    allow_reregistration,
+                    user_handle,
+                    challenge,
+                    client_format,
+                    hints,
+                    qr,
+                    enroll_url,
+                    attestation,
+                    rp_name,
                 }
             }
             "register-finish" => {
                 let challenge_id = require_option(&mut args, "--challenge-id");
                 let origin = require_option(&mut args, "--origin");
                 let device_name = require_option(&mut args, "--device-name");
+                let require_attestation = take_flag(&mut args, "--require-attestation");
+                let client_ip = take_option(&mut args, "--client-ip");
+                let user_agent = take_option(&mut args, "--user-agent");
                 Commands::RegisterFinish {
                     challenge_id,
                     origin,
                     device_name,
+                    require_attestation,
+                    client_ip,
+                    user_agent,
                 }
             }
             "login-begin" => {
                 let username = require_option(&mut args, "--username");
                 let rp_id = require_option(&mut args, "--rp-id");
-                Commands::LoginBegin { username, rp_id }
+                let user_verification = take_option(&mut args, "--user-verification");
+                let allow_credentials = take_all(&mut args, "--allow-credential");
+                let challenge = take_option(&mut args, "--challenge");
+                let client_format = take_option(&mut args, "--client-format");
+                Commands::LoginBegin {
+                    username,
+                    rp_id,
+                    user_verification,
+                    allow_credentials,
+                    challenge,
+                    client_format,
+                }
             }
             "login-finish" => {
                 let challenge_id = require_option(&mut args, "--challenge-id");
                 let origin = require_option(&mut args, "--origin");
-                Commands::LoginFinish { challenge_id, origin }
+                let client_ip = take_option(&mut args, "--client-ip");
+                let user_agent = take_option(&mut args, "--user-agent");
+                Commands::LoginFinish {
+                    challenge_id,
+                    origin,
+                    client_ip,
+                    user_agent,
+                }
+            }
+            "reauth-begin" => {
+                let session = require_option(&mut args, "--session");
+                let username = require_option(&mut args, "--username");
+                let rp_id = require_option(&mut args, "--rp-id");
+                let credential_id = require_option(&mut args, "--credential-id");
+                let client_format = take_option(&mut args, "--client-format");
+                Commands::ReauthBegin {
+                    session,
+                    username,
+                    rp_id,
+                    credential_id,
+                    client_format,
+                }
+            }
+            "reauth-finish" => {
+                let challenge_id = require_option(&mut args, "--challenge-id");
+                let session = require_option(&mut args, "--session");
+                let origin = require_option(&mut args, "--origin");
+                let client_ip = take_option(&mut args, "--client-ip");
+                let user_agent = take_option(&mut args, "--user-agent");
+                Commands::ReauthFinish {
+                    challenge_id,
+                    session,
+                    origin,
+                    client_ip,
+                    user_agent,
+                }
+            }
+            "verify-assertion" => {
+                let credential_id = require_option(&mut args, "--credential-id");
+                let challenge = require_option(&mut args, "--challenge");
+                let origin = require_option(&mut args, "--origin");
+                let rp_id = take_option(&mut args, "--rp-id");
+                Commands::VerifyAssertion {
+                    credential_id,
+                    challenge,
+                    origin,
+                    rp_id,
+                }
             }
             "credential-manage" => {
                 let action = parse_credential_manage(&mut args);
                 Commands::CredentialManage { action }
             }
-            "health-check" => Commands::HealthCheck,
+            "storage" => {
+                let action = parse_storage(&mut args);
+                Commands::Storage { action }
+            }
+            "health-check" => {
+                let watch = take_option(&mut args, "--watch").map(|secs| {
+                    secs.parse().ok().filter(|&secs: &u64| secs > 0).unwrap_or_else(|| {
+                        eprintln!("error: invalid value '{secs}' for '--watch'");
+                        std::process::exit(2);
+                    })
+                });
+                let count = take_option(&mut args, "--count").map(|count| {
+                    count.parse().ok().filter(|&count: &usize| count > 0).unwrap_or_else(|| {
+                        eprintln!("error: invalid value '{count}' for '--count'");
+                        std::process::exit(2);
+                    })
+                });
+                if count.is_some() && watch.is_none() {
+                    eprintln!("error: '--count' requires '--watch'");
+                    std::process::exit(2);
+                }
+                Commands::HealthCheck { watch, count }
+            }
+            "stats" => Commands::Stats,
+            "self-test" => Commands::SelfTest,
+            "bench" => {
+                let iterations = take_option(&mut args, "--iterations").map_or(crate::commands::bench::DEFAULT_ITERATIONS, |n| {
+                    n.parse().unwrap_or_else(|_| {
+                        eprintln!("error: invalid value '{n}' for '--iterations'");
+                        std::process::exit(2);
+                    })
+                });
+                Commands::Bench { iterations }
+            }
+            "serve" => {
+                let foreground = take_flag(&mut args, "--foreground");
+                let socket = take_option(&mut args, "--socket").unwrap_or_else(|| crate::config::DEFAULT_SOCKET_PATH.to_string());
+                let idle_exit = take_option(&mut args, "--idle-exit")
+                    .map(|secs| {
+                        secs.parse().unwrap_or_else(|_| {
+                            eprintln!("error: invalid value '{secs}' for '--idle-exit'");
+                            std::process::exit(2);
+                        })
+                    })
+                    .unwrap_or(0);
+                Commands::Serve {
+                    foreground,
+                    socket,
+                    idle_exit,
+                }
+            }
+            "init-script" => {
+                let binary = take_option(&mut args, "--binary").unwrap_or_else(|| "/usr/bin/webauthn-helper".to_string());
+                let socket = take_option(&mut args, "--socket").unwrap_or_else(|| crate::config::DEFAULT_SOCKET_PATH.to_string());
+                Commands::InitScript { binary, socket }
+            }
+            "audit" => {
+                let action = parse_audit(&mut args);
+                Commands::Audit { action }
+            }
+            "config" => {
+                let action = parse_config(&mut args);
+                Commands::Config { action }
+            }
+            "backup" => {
+                let action = parse_backup(&mut args);
+                Commands::Backup { action }
+            }
+            "challenge" => {
+                let action = parse_challenge(&mut args);
+                Commands::Challenge { action }
+            }
+            "nonce" => {
+                let action = parse_nonce(&mut args);
+                Commands::Nonce { action }
+            }
+            "recovery" => {
+                let action = parse_recovery(&mut args);
+                Commands::Recovery { action }
+            }
+            "user-manage" => {
+                let action = parse_user(&mut args);
+                Commands::UserManage { action }
+            }
+            "wizard" => {
+                let username = require_option(&mut args, "--username");
+                let rp_id = require_option(&mut args, "--rp-id");
+                let origin = require_option(&mut args, "--origin");
+                let device_name = take_option(&mut args, "--device-name").unwrap_or_else(|| "Wizard".to_string());
+                Commands::Wizard {
+                    username,
+                    rp_id,
+                    origin,
+                    device_name,
+                }
+            }
+            "mds" => {
+                let action = parse_mds(&mut args);
+                Commands::Mds { action }
+            }
+            "pinned-origins" => {
+                let action = parse_pinned_origins(&mut args);
+                Commands::PinnedOrigins { action }
+            }
+            "list" => Commands::RpcdList,
+            "call" => {
+                if args.is_empty() {
+                    eprintln!("error: call requires a method name");
+                    std::process::exit(2);
+                }
+                Commands::RpcdCall { method: args.remove(0) }
+            }
+            "rpcd-acl" => {
+                let object = take_option(&mut args, "--object").unwrap_or_else(|| "webauthn-helper".to_string());
+                Commands::RpcdAcl { object }
+            }
+            "schema" => {
+                let action = parse_schema(&mut args);
+                Commands::Schema { action }
+            }
             other => {
                 eprintln!("error: unrecognized subcommand '{other}'");
                 std::process::exit(2);
             }
         };
 
-        Cli { command }
+        Cli {
+            config_path,
+            api_version,
+            origins_file,
+            actor,
+            trace_id,
+            input_fd,
+            out,
+            storage,
+            no_sync,
+            command,
+        }
     }
 }
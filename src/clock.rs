@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use time::format_description::well_known::iso8601::{Config, EncodedConfig, TimePrecision};
+use time::format_description::well_known::{Iso8601, Rfc3339};
+use time::OffsetDateTime;
+
+/// Where the current time comes from. Everything that stamps or ages a
+/// record asks [`now`], so tests can swap in a [`FixedClock`].
+pub trait Clock {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The system's wall clock, or with the `deterministic` feature the time in
+/// `WEBAUTHN_HELPER_NOW`.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        #[cfg(feature = "deterministic")]
+        if let Some(now) = crate::deterministic::now() {
+            return OffsetDateTime::from_unix_timestamp(now as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        }
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// A clock that stands still.
+#[cfg(test)]
+pub struct FixedClock(pub OffsetDateTime);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> OffsetDateTime {
+        self.0
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Rc<dyn Clock>> = RefCell::new(Rc::new(SystemClock));
+}
+
+/// Makes `clock` the time source of the calling thread.
+#[cfg(test)]
+pub fn set(clock: impl Clock + 'static) {
+    CLOCK.with(|current| *current.borrow_mut() = Rc::new(clock));
+}
+
+pub fn now() -> OffsetDateTime {
+    let clock = CLOCK.with(|current| current.borrow().clone());
+    clock.now()
+}
+
+/// Current time in Unix seconds.
+pub fn now_secs() -> u64 {
+    u64::try_from(now().unix_timestamp()).unwrap_or_default()
+}
+
+/// How many fractional digits [`format`] writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+/// RFC 3339 in UTC with a `Z` suffix, e.g. `2025-01-01T12:00:00Z` or, with
+/// `Precision::Millis`, `2025-01-01T12:00:00.250Z`.
+pub fn format(time: OffsetDateTime, precision: Precision) -> String {
+    const fn config(digits: u8) -> EncodedConfig {
+        Config::DEFAULT
+            .set_time_precision(TimePrecision::Second {
+                decimal_digits: std::num::NonZeroU8::new(digits),
+            })
+            .encode()
+    }
+    const SECONDS: EncodedConfig = config(0);
+    const MILLIS: EncodedConfig = config(3);
+    const MICROS: EncodedConfig = config(6);
+    let time = time.to_offset(time::UtcOffset::UTC);
+    let formatted = match precision {
+        Precision::Seconds => time.format(&Iso8601::<SECONDS>),
+        Precision::Millis => time.format(&Iso8601::<MILLIS>),
+        Precision::Micros => time.format(&Iso8601::<MICROS>),
+    };
+    formatted.expect("UTC times within year 0000-9999 always format")
+}
+
+/// The current time as stored in records: RFC 3339 to the second, which
+/// releases before fractional seconds were accepted can still read.
+pub fn now_rfc3339() -> String {
+    format(now(), Precision::Seconds)
+}
+
+/// Strictly parses an RFC 3339 timestamp (`T` separator, an explicit `Z` or
+/// offset, optional fractional seconds) into Unix seconds, rounding down.
+/// Times before the epoch are rejected.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let time = OffsetDateTime::parse(s, &Rfc3339).ok()?;
+    u64::try_from(time.unix_timestamp()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_and_parse() {
+        let time = OffsetDateTime::from_unix_timestamp_nanos(951_914_096_250_000_000).unwrap();
+        assert_eq!(format(time, Precision::Seconds), "2000-03-01T12:34:56Z");
+        assert_eq!(format(time, Precision::Millis), "2000-03-01T12:34:56.250Z");
+        assert_eq!(format(time, Precision::Micros), "2000-03-01T12:34:56.250000Z");
+
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2000-03-01T12:34:56Z"), Some(951_914_096));
+        assert_eq!(parse_rfc3339("2000-03-01T12:34:56.999Z"), Some(951_914_096));
+        assert_eq!(parse_rfc3339("2000-03-01T14:34:56+02:00"), Some(951_914_096));
+        assert_eq!(parse_rfc3339("2025-13-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2025-02-30T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2025-01-01 00:00:00"), None);
+        assert_eq!(parse_rfc3339("2025-01-01T00:00:00"), None);
+        assert_eq!(parse_rfc3339("1969-12-31T23:59:59Z"), None);
+    }
+
+    #[test]
+    fn test_fixed_clock() {
+        set(FixedClock(OffsetDateTime::from_unix_timestamp(951_914_096).unwrap()));
+        assert_eq!(now_secs(), 951_914_096);
+        assert_eq!(now_rfc3339(), "2000-03-01T12:34:56Z");
+        assert_eq!(parse_rfc3339(&now_rfc3339()), Some(now_secs()));
+    }
+}
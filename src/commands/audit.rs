@@ -0,0 +1,64 @@
+use crate::audit::{is_truncated, verify_anchor, verify_chain, verify_macs};
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::storage::{AuditEntry, StorageProvider};
+
+fn entry_json(entry: &AuditEntry) -> serde_json::Value {
+    serde_json::json!({
+        "seq": entry.seq,
+        "time": entry.time,
+        "actor": entry.actor,
+        "action": entry.action,
+        "target": entry.target,
+        "details": entry.details,
+        "hash": entry.hash,
+    })
+}
+
+/// Lists audit entries, oldest first, optionally only one action and only
+/// the most recent `limit`.
+pub fn list(storage: &dyn StorageProvider, action: Option<&str>, limit: Option<usize>) -> Result<String, AppError> {
+    let entries = storage.load_audit_log()?;
+    let matching: Vec<&AuditEntry> = entries.iter().filter(|e| action.is_none_or(|a| e.action == a)).collect();
+    let skip = limit.map_or(0, |limit| matching.len().saturating_sub(limit));
+    let items: Vec<serde_json::Value> = matching[skip..].iter().map(|e| entry_json(e)).collect();
+
+    let response = SuccessResponse::new(items);
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Checks the audit log. A pruned log is checked from its anchor on;
+/// entries left over from before it are skipped.
+pub fn verify(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let mut entries = storage.load_audit_log()?;
+    let head = storage.load_audit_head()?;
+    let anchor = storage.load_audit_anchor()?;
+    let key = storage.audit_key(false)?;
+    let key = key.as_deref().map(Vec::as_slice);
+    if let Some(anchor) = &anchor {
+        entries.retain(|entry| entry.seq >= anchor.seq);
+    }
+    let forged_anchor = anchor
+        .as_ref()
+        .filter(|anchor| !verify_anchor(anchor, key))
+        .map(|anchor| anchor.seq);
+    let broken_at = [
+        forged_anchor,
+        verify_chain(&entries, anchor.as_ref()).err(),
+        verify_macs(&entries, key).err(),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+    let truncated = is_truncated(&entries, head.as_ref(), key);
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "entries": entries.len(),
+        "firstSeq": entries.first().map(|e| e.seq),
+        "valid": broken_at.is_none() && !truncated,
+        "brokenAt": broken_at,
+        "truncated": truncated,
+        "headHash": entries.last().map(|e| &e.hash),
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
@@ -0,0 +1,981 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::Deserialize;
+use webauthn_rp::bin::{Decode, Encode};
+use webauthn_rp::response::register::DynamicState;
+use webauthn_rp::response::{AuthenticatorAttachment, Backup, CredentialId};
+
+use crate::audit;
+use crate::commands::register::{decode_user_handle, format_aaguid, make_rp_id, random_user_handle};
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::input::read_client_input;
+use crate::public_key::PublicKey;
+use crate::schemas::SuccessResponse;
+use crate::secret::Zeroizing;
+use crate::storage::*;
+
+/// Credential exports `backup import` understands.
+#[derive(Clone, Copy)]
+enum ImportFormat {
+    /// Serialized `Passkey` (or bare `Credential`) values of webauthn-rs
+    WebauthnRs,
+    /// python-fido2 `AttestedCredentialData`, websafe-base64 encoded
+    PythonFido2,
+    /// Credential ID and COSE public key, e.g. from a hand-written export
+    RawCose,
+}
+
+impl ImportFormat {
+    fn parse(value: &str) -> Result<Self, AppError> {
+        match value {
+            "webauthn-rs" => Ok(Self::WebauthnRs),
+            "python-fido2" => Ok(Self::PythonFido2),
+            "raw-cose" => Ok(Self::RawCose),
+            other => Err(AppError::InvalidInput(format!(
+                "Unsupported import format: {} (expected webauthn-rs, python-fido2 or raw-cose)",
+                other
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::WebauthnRs => "webauthn-rs",
+            Self::PythonFido2 => "python-fido2",
+            Self::RawCose => "raw-cose",
+        }
+    }
+
+    /// Parses an export holding one record or an array of them.
+    fn read(self, data: &str) -> Result<Vec<Foreign>, AppError> {
+        fn records<T: for<'de> Deserialize<'de>>(data: &str) -> Result<Vec<T>, AppError> {
+            #[derive(Deserialize)]
+            #[serde(untagged)]
+            enum Records<T> {
+                Many(Vec<T>),
+                One(T),
+            }
+            match serde_json::from_str(data).map_err(|e| AppError::InvalidInput(format!("Unrecognized export: {}", e)))? {
+                Records::Many(records) => Ok(records),
+                Records::One(record) => Ok(vec![record]),
+            }
+        }
+        match self {
+            Self::WebauthnRs => records::<WebauthnRsRecord>(data)?
+                .into_iter()
+                .map(WebauthnRsRecord::convert)
+                .collect(),
+            Self::PythonFido2 => records::<PythonFido2Record>(data)?
+                .into_iter()
+                .map(PythonFido2Record::convert)
+                .collect(),
+            Self::RawCose => records::<RawCoseRecord>(data)?
+                .into_iter()
+                .map(RawCoseRecord::convert)
+                .collect(),
+        }
+    }
+}
+
+/// Binary value as the exporting stacks spell it: base64 in either alphabet,
+/// with or without padding, or an array of bytes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Binary {
+    Base64(String),
+    Bytes(Vec<u8>),
+}
+
+impl Binary {
+    fn decode(&self, what: &str) -> Result<Vec<u8>, AppError> {
+        match self {
+            Self::Base64(text) => {
+                let url_safe: String = text
+                    .trim_end_matches('=')
+                    .chars()
+                    .map(|c| match c {
+                        '+' => '-',
+                        '/' => '_',
+                        c => c,
+                    })
+                    .collect();
+                URL_SAFE_NO_PAD
+                    .decode(url_safe)
+                    .map_err(|e| AppError::InvalidInput(format!("Invalid {}: {}", what, e)))
+            }
+            Self::Bytes(bytes) => Ok(bytes.clone()),
+        }
+    }
+}
+
+/// A credential read from a foreign export.
+struct Foreign {
+    credential_id: Vec<u8>,
+    public_key: PublicKey,
+    sign_count: u32,
+    /// base64url, checked to be a size this helper can authenticate
+    user_handle: Option<String>,
+    user_verified: bool,
+    backup_eligible: bool,
+    aaguid: String,
+}
+
+fn user_handle(value: Option<&Binary>) -> Result<Option<String>, AppError> {
+    value
+        .map(|handle| {
+            let encoded = URL_SAFE_NO_PAD.encode(handle.decode("user handle")?);
+            decode_user_handle(&encoded)?;
+            Ok(encoded)
+        })
+        .transpose()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WebauthnRsRecord {
+    Passkey { cred: WebauthnRsCredential },
+    Credential(WebauthnRsCredential),
+}
+
+#[derive(Deserialize)]
+struct WebauthnRsCredential {
+    cred_id: Binary,
+    cred: WebauthnRsCoseKey,
+    counter: u32,
+    #[serde(default)]
+    user_verified: bool,
+    #[serde(default)]
+    backup_eligible: bool,
+}
+
+#[derive(Deserialize)]
+struct WebauthnRsCoseKey {
+    key: WebauthnRsKey,
+}
+
+#[derive(Deserialize)]
+enum WebauthnRsKey {
+    #[serde(rename = "EC_EC2")]
+    Ec2 { curve: String, x: Binary, y: Binary },
+    #[serde(rename = "EC_OKP")]
+    Okp { curve: String, x: Binary },
+    #[serde(rename = "RSA")]
+    Rsa { n: Binary, e: Binary },
+}
+
+impl WebauthnRsRecord {
+    fn convert(self) -> Result<Foreign, AppError> {
+        let (Self::Passkey { cred } | Self::Credential(cred)) = self;
+        let public_key = match &cred.cred.key {
+            WebauthnRsKey::Ec2 { curve, x, y } => {
+                let crv = match curve.as_str() {
+                    "SECP256R1" => 1,
+                    "SECP384R1" => 2,
+                    other => return Err(AppError::InvalidInput(format!("Unsupported curve: {}", other))),
+                };
+                PublicKey::ec2(crv, &x.decode("public key")?, &y.decode("public key")?)?
+            }
+            WebauthnRsKey::Okp { curve, x } if curve == "ED25519" => PublicKey::ed25519(&x.decode("public key")?)?,
+            WebauthnRsKey::Okp { curve, .. } => return Err(AppError::InvalidInput(format!("Unsupported curve: {}", curve))),
+            WebauthnRsKey::Rsa { n, e } => PublicKey::rsa(&n.decode("public key")?, &e.decode("public key")?)?,
+        };
+        Ok(Foreign {
+            credential_id: cred.cred_id.decode("credential ID")?,
+            public_key,
+            sign_count: cred.counter,
+            // webauthn-rs leaves user handles to the application
+            user_handle: None,
+            user_verified: cred.user_verified,
+            backup_eligible: cred.backup_eligible,
+            aaguid: String::new(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PythonFido2Record {
+    Data(String),
+    Stored {
+        credential_data: Binary,
+        #[serde(default)]
+        sign_count: u32,
+        user_handle: Option<Binary>,
+    },
+}
+
+impl PythonFido2Record {
+    fn convert(self) -> Result<Foreign, AppError> {
+        let (data, sign_count, handle) = match self {
+            Self::Data(data) => (Binary::Base64(data), 0, None),
+            Self::Stored {
+                credential_data,
+                sign_count,
+                user_handle,
+            } => (credential_data, sign_count, user_handle),
+        };
+        // aaguid (16) || credentialIdLength (2) || credentialId || COSE key
+        let data = data.decode("credential data")?;
+        let truncated = || AppError::InvalidInput("Attested credential data is truncated".to_string());
+        let id_len = usize::from(u16::from_be_bytes(
+            data.get(16..18).ok_or_else(truncated)?.try_into().expect("2 bytes"),
+        ));
+        let credential_id = data.get(18..18 + id_len).ok_or_else(truncated)?.to_vec();
+        Ok(Foreign {
+            credential_id,
+            public_key: PublicKey::from_cose(&data[18 + id_len..])?,
+            sign_count,
+            user_handle: user_handle(handle.as_ref())?,
+            user_verified: false,
+            backup_eligible: false,
+            aaguid: format_aaguid(&data[..16]),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawCoseRecord {
+    credential_id: Binary,
+    public_key: Binary,
+    #[serde(default)]
+    sign_count: u32,
+    user_handle: Option<Binary>,
+    #[serde(default)]
+    backup_eligible: bool,
+}
+
+impl RawCoseRecord {
+    fn convert(self) -> Result<Foreign, AppError> {
+        Ok(Foreign {
+            credential_id: self.credential_id.decode("credential ID")?,
+            public_key: PublicKey::from_cose(&self.public_key.decode("public key")?)?,
+            sign_count: self.sign_count,
+            user_handle: user_handle(self.user_handle.as_ref())?,
+            user_verified: false,
+            backup_eligible: self.backup_eligible,
+            aaguid: String::new(),
+        })
+    }
+}
+
+impl Foreign {
+    fn into_stored(self, rp_id: &str, device_name: &str, user_handle: &str) -> Result<StoredCredential, AppError> {
+        CredentialId::<Vec<u8>>::decode(self.credential_id.clone())
+            .map_err(|e| AppError::InvalidInput(format!("Invalid credential ID: {}", e)))?;
+        let dynamic_state = DynamicState {
+            user_verified: self.user_verified,
+            backup: if self.backup_eligible { Backup::Eligible } else { Backup::NotEligible },
+            sign_count: self.sign_count,
+            authenticator_attachment: AuthenticatorAttachment::None,
+        };
+        Ok(StoredCredential {
+            credential_id: URL_SAFE_NO_PAD.encode(&self.credential_id),
+            rp_id: rp_id.to_string(),
+            device_name: device_name.to_string(),
+            static_state: URL_SAFE_NO_PAD.encode(self.public_key.static_state()?),
+            dynamic_state: URL_SAFE_NO_PAD.encode(dynamic_state.encode().expect("DynamicState encode is infallible")),
+            user_handle: user_handle.to_string(),
+            transports: 0,
+            created_at: crate::clock::now_rfc3339(),
+            last_used_at: None,
+            backup_eligible: self.backup_eligible,
+            user_verified: self.user_verified,
+            sign_count: self.sign_count,
+            use_count: 0,
+            failed_attempts: 0,
+            last_user_verified: None,
+            backed_up: None,
+            uv_initialized: None,
+            cred_protect: None,
+            aaguid: self.aaguid,
+            attestation: None,
+            seen_attachments: vec![],
+            transport_drift_at: None,
+            last_client_ip: None,
+            last_user_agent: None,
+            enabled: true,
+            admin: false,
+        })
+    }
+}
+
+/// Adds credentials exported by another WebAuthn stack to `username`. The
+/// user keeps their handle; a new user takes the one given, the one in the
+/// export, or a fresh one. Nothing is stored unless every record converts.
+#[allow(clippy::too_many_arguments)]
+pub fn import(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    format: &str,
+    username: &str,
+    rp_id: &str,
+    user_handle: Option<&str>,
+    device_name: &str,
+    input: &mut dyn std::io::Read,
+    actor: Option<&str>,
+) -> Result<String, AppError> {
+    let format = ImportFormat::parse(format)?;
+    make_rp_id(rp_id)?;
+    let given = user_handle
+        .map(|handle| decode_user_handle(handle).map(|bytes| URL_SAFE_NO_PAD.encode(bytes.as_slice())))
+        .transpose()?;
+    let foreign = format.read(&read_client_input(input, config.max_input_bytes)?)?;
+    if foreign.is_empty() {
+        return Err(AppError::InvalidInput("The export holds no credentials".to_string()));
+    }
+
+    let exported: Vec<String> = foreign.iter().filter_map(|f| f.user_handle.clone()).collect();
+    let handle = storage
+        .load_user(username)?
+        .map(|record| record.user_id)
+        .or_else(|| given.clone())
+        .or_else(|| exported.first().cloned())
+        .unwrap_or_else(|| URL_SAFE_NO_PAD.encode(random_user_handle(config.user_handle_len).as_slice()));
+    if let Some(other) = given.iter().chain(&exported).find(|h| **h != handle) {
+        return Err(AppError::InvalidInput(format!(
+            "User handle {} does not match {}'s handle {}",
+            other, username, handle
+        )));
+    }
+
+    let credentials = foreign
+        .into_iter()
+        .map(|f| f.into_stored(rp_id, device_name, &handle))
+        .collect::<Result<Vec<_>, _>>()?;
+    let ids: Vec<String> = credentials.iter().map(|c| c.credential_id.clone()).collect();
+    update_store(storage, |store| {
+        let mut known: std::collections::HashSet<&str> = store
+            .users
+            .values()
+            .flat_map(|u| &u.credentials)
+            .map(|c| c.credential_id.as_str())
+            .collect();
+        if let Some(id) = ids.iter().find(|id| !known.insert(id.as_str())) {
+            return Err(AppError::InvalidInput(format!("Credential {} is already registered", id)));
+        }
+        store
+            .users
+            .entry(username.to_string())
+            .or_insert_with(|| UserRecord {
+                user_id: handle.clone(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![],
+            })
+            .credentials
+            .extend(credentials);
+        Ok(())
+    })?;
+    audit::record(
+        storage,
+        actor,
+        "credential_import",
+        username,
+        serde_json::json!({ "format": format.name(), "rpId": rp_id, "credentialIds": ids }),
+    )?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "username": username,
+        "rpId": rp_id,
+        "imported": ids.len(),
+        "credentialIds": ids,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Version of the FIDO Credential Exchange Format the export follows
+const CXF_VERSION: (u32, u32) = (1, 0);
+
+/// One CXF item per credential. Without the private key it is a reference
+/// to a passkey held by an authenticator, not a passkey that can be moved.
+/// Credentials from before RP namespacing take the RP ID filtered on.
+fn cxf_item(username: &str, credential: &StoredCredential, rp_id: Option<&str>) -> serde_json::Value {
+    let rp_id = Some(credential.rp_id.as_str())
+        .filter(|rp| !rp.is_empty())
+        .or(rp_id)
+        .unwrap_or_default();
+    let created = crate::clock::parse_rfc3339(&credential.created_at);
+    let mut item = serde_json::json!({
+        "id": credential.credential_id,
+        "creationAt": created,
+        "modifiedAt": credential.last_used_at.as_deref().and_then(crate::clock::parse_rfc3339).or(created),
+        "title": credential.device_name,
+        "credentials": [{
+            "type": "passkey",
+            "credentialId": credential.credential_id,
+            "rpId": rp_id,
+            "username": username,
+            "userDisplayName": username,
+            "userHandle": credential.user_handle,
+        }],
+    });
+    if !rp_id.is_empty() {
+        item["scope"] = serde_json::json!({ "urls": [format!("https://{}", rp_id)], "androidApps": [] });
+    }
+    item
+}
+
+/// Lists stored credentials as a FIDO Credential Exchange Format header,
+/// one account per user, for inventory tooling and password managers.
+/// Only metadata is exported: the private keys never leave the
+/// authenticators, so the `key` of each passkey is absent.
+pub fn export(storage: &dyn StorageProvider, format: &str, username: Option<&str>, rp_id: Option<&str>) -> Result<String, AppError> {
+    if format != "cxf" {
+        return Err(AppError::InvalidInput(format!(
+            "Unsupported export format: {} (expected cxf)",
+            format
+        )));
+    }
+    let store = storage.load_credentials()?;
+    let mut users: Vec<(&String, &UserRecord)> = store
+        .users
+        .iter()
+        .filter(|(name, _)| username.is_none_or(|u| u == *name))
+        .collect();
+    users.sort_by_key(|(name, _)| *name);
+
+    let accounts: Vec<serde_json::Value> = users
+        .into_iter()
+        .map(|(name, record)| {
+            let items: Vec<serde_json::Value> = record
+                .credentials
+                .iter()
+                .filter(|c| rp_id.is_none_or(|rp| c.belongs_to(rp)))
+                .map(|c| cxf_item(name, c, rp_id))
+                .collect();
+            (name, record, items)
+        })
+        .filter(|(_, _, items)| !items.is_empty())
+        .map(|(name, record, items)| {
+            serde_json::json!({
+                "id": record.user_id,
+                "username": name,
+                "email": "",
+                "collections": [],
+                "items": items,
+            })
+        })
+        .collect();
+    let response = SuccessResponse::new(serde_json::json!({
+        "version": { "major": CXF_VERSION.0, "minor": CXF_VERSION.1 },
+        "exporterRpId": rp_id.map(str::to_string).unwrap_or_else(hostname),
+        "exporterDisplayName": "webauthn-helper",
+        "timestamp": crate::clock::parse_rfc3339(&crate::clock::now_rfc3339()),
+        "accounts": accounts,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// The router's hostname; empty where the kernel does not tell.
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// `format` of the documents `backup push` sends
+const BACKUP_FORMAT: &str = "webauthn-helper-backup";
+const BACKUP_VERSION: u32 = 1;
+
+/// Longest a push may take, connecting included
+const PUSH_TIMEOUT_SECS: u64 = 30;
+
+/// Reads a token or passphrase file, without the line break editors end it with.
+fn read_secret(path: &str, what: &str) -> Result<Zeroizing<String>, AppError> {
+    let data = Zeroizing::new(
+        std::fs::read_to_string(path).map_err(|e| AppError::Config(format!("Failed to read the backup {} from {}: {}", what, path, e)))?,
+    );
+    let secret = Zeroizing::new(data.trim_end_matches(['\r', '\n']).to_string());
+    if secret.is_empty() {
+        return Err(AppError::Config(format!("The backup {} in {} is empty", what, path)));
+    }
+    Ok(secret)
+}
+
+/// The document a push sends: the whole credential store, or with a
+/// passphrase the store encrypted with XChaCha20-Poly1305 under a key
+/// Argon2id derives from the passphrase. Everything needed to derive the key
+/// again, except the passphrase, travels with it.
+fn backup_document(store: &CredentialStore, passphrase: Option<&str>) -> Result<serde_json::Value, AppError> {
+    let mut document = serde_json::json!({
+        "format": BACKUP_FORMAT,
+        "version": BACKUP_VERSION,
+        "createdAt": crate::clock::now_rfc3339(),
+        "host": hostname(),
+    });
+    let Some(passphrase) = passphrase else {
+        document["store"] = serde_json::to_value(store)?;
+        return Ok(document);
+    };
+
+    let salt = random_user_handle(16);
+    let nonce = XNonce::from(<[u8; 24]>::try_from(&random_user_handle(32)[..24]).expect("24 of 32 bytes"));
+    let params = argon2::Params::default();
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, key.as_mut_slice())
+        .map_err(|e| AppError::Backup(format!("Failed to derive the backup key: {}", e)))?;
+    let plaintext = Zeroizing::new(serde_json::to_vec(store)?);
+    let ciphertext = XChaCha20Poly1305::new_from_slice(key.as_slice())
+        .expect("32-byte key")
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| AppError::Backup("Failed to encrypt the backup".to_string()))?;
+    document["encryption"] = serde_json::json!({
+        "cipher": "xchacha20poly1305",
+        "nonce": URL_SAFE_NO_PAD.encode(nonce),
+        "kdf": "argon2id",
+        "salt": URL_SAFE_NO_PAD.encode(salt.as_slice()),
+        "memoryKib": params.m_cost(),
+        "iterations": params.t_cost(),
+        "parallelism": params.p_cost(),
+    });
+    document["ciphertext"] = serde_json::json!(URL_SAFE_NO_PAD.encode(ciphertext));
+    Ok(document)
+}
+
+/// Double-quoted string in curl's config file syntax.
+fn curl_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Settings that make curl POST `body` to `url`.
+fn curl_config(url: &str, token: Option<&str>, body: &[u8]) -> Zeroizing<String> {
+    let mut config = Zeroizing::new(format!(
+        "url = {}\nheader = \"Content-Type: application/json\"\n",
+        curl_quote(url)
+    ));
+    if let Some(token) = token {
+        config.push_str(&format!(
+            "header = {}\n",
+            curl_quote(&format!("Authorization: Bearer {}", token))
+        ));
+    }
+    config.push_str(&format!("data-binary = {}\n", curl_quote(&String::from_utf8_lossy(body))));
+    config
+}
+
+/// Sends the document with curl, which unlike `uclient-fetch` can set an
+/// Authorization header. The settings go in on stdin so the token never
+/// shows in the process list.
+fn upload(url: &str, token: Option<&str>, body: &[u8]) -> Result<(), AppError> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time"])
+        .arg(PUSH_TIMEOUT_SECS.to_string())
+        .args(["--config", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| AppError::Backup(format!("Failed to run curl: {}", e)))?;
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(curl_config(url, token, body).as_bytes());
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(AppError::Backup(format!(
+            "Failed to push the backup to {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    written?;
+    Ok(())
+}
+
+/// Sends the whole credential store to `url`, or `backup_url`, for
+/// off-device recovery: encrypted when a passphrase file is given or
+/// configured, with a bearer token when a token file is. Restarts the count
+/// towards the next automatic push.
+pub fn push(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    url: Option<&str>,
+    token_file: Option<&str>,
+    passphrase_file: Option<&str>,
+) -> Result<String, AppError> {
+    let url = url
+        .or(config.backup_url.as_deref())
+        .ok_or_else(|| AppError::InvalidInput("No backup URL: pass --url or set backup_url".to_string()))?;
+    if !(url.starts_with("https://") || url.starts_with("http://")) || url.chars().any(char::is_control) {
+        return Err(AppError::InvalidInput(format!(
+            "Backup URL must be an http(s) URL, got {}",
+            url
+        )));
+    }
+    let token = token_file
+        .or(config.backup_token_file.as_deref())
+        .map(|path| read_secret(path, "token"))
+        .transpose()?;
+    if token.as_ref().is_some_and(|token| !token.bytes().all(|b| b.is_ascii_graphic())) {
+        return Err(AppError::Config(
+            "The backup token must be printable ASCII without spaces".to_string(),
+        ));
+    }
+    let passphrase = passphrase_file
+        .or(config.backup_passphrase_file.as_deref())
+        .map(|path| read_secret(path, "passphrase"))
+        .transpose()?;
+
+    let store = storage.load_credentials()?;
+    let document = backup_document(&store, passphrase.as_ref().map(|p| p.as_str()))?;
+    let body = Zeroizing::new(serde_json::to_vec(&document)?);
+    upload(url, token.as_ref().map(|t| t.as_str()), &body)?;
+    // A read-only store has nowhere to keep the count
+    if !storage.read_only() {
+        let pushed_at = document["createdAt"].as_str().map(str::to_string);
+        storage.modify_backup_state(&mut |state| {
+            state.pending_changes = 0;
+            state.last_push_at = pushed_at.clone();
+            Ok(())
+        })?;
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "url": url,
+        "encrypted": passphrase.is_some(),
+        "users": store.users.len(),
+        "credentials": store.users.values().map(|u| u.credentials.len()).sum::<usize>(),
+        "bytes": body.len(),
+        "pushedAt": document["createdAt"],
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Counts a successful change to the credential store and pushes a backup
+/// once `backup_push_every` of them have piled up. A failed push is only
+/// logged: the change itself went through, and the next one tries again.
+pub fn note_change(storage: &dyn StorageProvider, config: &Config) {
+    if config.backup_push_every == 0 {
+        return;
+    }
+    let mut due = false;
+    let pushed = storage
+        .modify_backup_state(&mut |state| {
+            state.pending_changes += 1;
+            due = state.pending_changes >= config.backup_push_every;
+            Ok(())
+        })
+        .and_then(|()| if due { push(storage, config, None, None, None).map(drop) } else { Ok(()) });
+    if let Err(e) = pushed {
+        crate::syslog::warning(&format!("Automatic backup failed: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::register::tests::registration_response;
+    use crate::commands::register::{register_begin, register_finish};
+    use ciborium::Value;
+
+    /// The key and credential ID `registration_response` registers.
+    fn exported_key() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let key = p256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer((-7).into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (Value::Integer((-2).into()), Value::Bytes(point.x().unwrap().to_vec())),
+            (Value::Integer((-3).into()), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let mut cose = Vec::new();
+        ciborium::into_writer(&cose_key, &mut cose).unwrap();
+        (vec![9u8; 16], cose, point.x().unwrap().to_vec())
+    }
+
+    fn import_into(storage: &MemoryStorage, format: &str, username: &str, export: &str) -> Result<String, AppError> {
+        import(
+            storage,
+            &Config::default(),
+            format,
+            username,
+            "router.lan",
+            None,
+            "Imported",
+            &mut export.as_bytes(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_raw_cose_import_matches_registration() {
+        let registered = MemoryStorage::default();
+        let config = Config::default();
+        let begin = register_begin(
+            &registered,
+            &config,
+            "root",
+            "router.lan",
+            "preferred",
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let begin: serde_json::Value = serde_json::from_str(&begin).unwrap();
+        let response = registration_response(
+            begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+            "https://router.lan",
+            "router.lan",
+            0x45,
+        );
+        register_finish(
+            &registered,
+            &config,
+            begin["data"]["challengeId"].as_str().unwrap(),
+            "https://router.lan",
+            "key",
+            false,
+            &ClientInfo::default(),
+            &mut response.as_bytes(),
+        )
+        .unwrap();
+        let expected = &registered.load_user("root").unwrap().unwrap().credentials[0];
+
+        let (id, cose, _) = exported_key();
+        let storage = MemoryStorage::default();
+        let export = serde_json::json!([{
+            "credentialId": URL_SAFE_NO_PAD.encode(&id),
+            "publicKey": URL_SAFE_NO_PAD.encode(&cose),
+            "signCount": 12,
+            "userHandle": URL_SAFE_NO_PAD.encode([1u8; 16]),
+        }]);
+        let imported: serde_json::Value =
+            serde_json::from_str(&import_into(&storage, "raw-cose", "root", &export.to_string()).unwrap()).unwrap();
+        assert_eq!(imported["data"]["imported"], 1);
+
+        let user = storage.load_user("root").unwrap().unwrap();
+        assert_eq!(user.user_id, URL_SAFE_NO_PAD.encode([1u8; 16]));
+        let credential = &user.credentials[0];
+        assert_eq!(credential.credential_id, expected.credential_id);
+        assert_eq!(credential.static_state, expected.static_state);
+        assert_eq!(credential.sign_count, 12);
+
+        // The same credential cannot be imported twice
+        let again = import_into(&storage, "raw-cose", "admin", &export.to_string());
+        assert!(matches!(again, Err(AppError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_python_fido2_and_webauthn_rs_exports() {
+        let (id, cose, x) = exported_key();
+        let aaguid = [0xcbu8; 16];
+        let data = [&aaguid[..], &(id.len() as u16).to_be_bytes(), &id, &cose].concat();
+        let storage = MemoryStorage::default();
+        let export = serde_json::json!({
+            "credential_data": base64::engine::general_purpose::STANDARD.encode(&data),
+            "sign_count": 3,
+            "user_handle": URL_SAFE_NO_PAD.encode([2u8; 32]),
+        });
+        import_into(&storage, "python-fido2", "root", &export.to_string()).unwrap();
+        let credential = &storage.load_user("root").unwrap().unwrap().credentials[0];
+        assert_eq!(credential.aaguid, "cbcbcbcb-cbcb-cbcb-cbcb-cbcbcbcbcbcb");
+        assert_eq!(credential.sign_count, 3);
+
+        // A user keeps their handle
+        let export = serde_json::json!(URL_SAFE_NO_PAD.encode([&aaguid[..], &[0, 16], &[8u8; 16], &cose].concat()));
+        let other_handle = import(
+            &storage,
+            &Config::default(),
+            "python-fido2",
+            "root",
+            "router.lan",
+            Some(&URL_SAFE_NO_PAD.encode([3u8; 16])),
+            "Imported",
+            &mut export.to_string().as_bytes(),
+            None,
+        );
+        assert!(matches!(other_handle, Err(AppError::InvalidInput(_))));
+
+        let key = p256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        assert_eq!(point.x().unwrap().to_vec(), x);
+        let passkey = serde_json::json!({"cred": {
+            "cred_id": URL_SAFE_NO_PAD.encode([5u8; 16]),
+            "cred": {"type_": "ES256", "key": {"EC_EC2": {
+                "curve": "SECP256R1",
+                "x": URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+                "y": URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+            }}},
+            "counter": 41,
+            "transports": null,
+            "user_verified": true,
+            "backup_eligible": true,
+            "backup_state": true,
+        }});
+        import_into(&storage, "webauthn-rs", "admin", &passkey.to_string()).unwrap();
+        let credential = &storage.load_user("admin").unwrap().unwrap().credentials[0];
+        let static_state = crate::commands::login::decode_static_state(credential).unwrap();
+        assert_eq!(crate::public_key::algorithm(&static_state.credential_public_key), "ES256");
+        assert!(credential.backup_eligible && credential.user_verified);
+        assert_eq!(credential.sign_count, 41);
+
+        let unknown = import_into(&storage, "yubico", "root", "[]");
+        assert!(matches!(unknown, Err(AppError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_cxf_export_lists_metadata() {
+        let storage = MemoryStorage::default();
+        let mut legacy = crate::wal::tests::credential("legacy");
+        legacy.rp_id = String::new();
+        let mut other = crate::wal::tests::credential("other");
+        other.rp_id = "nas.lan".to_string();
+        storage
+            .save_credentials(&CredentialStore {
+                users: [(
+                    "root".to_string(),
+                    UserRecord {
+                        user_id: "dXNlcg".to_string(),
+                        recovery_codes: vec![],
+                        roles: vec![],
+                        groups: vec![],
+                        credentials: vec![legacy, other],
+                    },
+                )]
+                .into(),
+            })
+            .unwrap();
+
+        let all: serde_json::Value = serde_json::from_str(&export(&storage, "cxf", None, None).unwrap()).unwrap();
+        let header = &all["data"];
+        assert_eq!(header["version"]["major"], 1);
+        let account = &header["accounts"][0];
+        assert_eq!(account["id"], "dXNlcg");
+        assert_eq!(account["items"].as_array().unwrap().len(), 2);
+
+        let filtered: serde_json::Value =
+            serde_json::from_str(&export(&storage, "cxf", Some("root"), Some("router.lan")).unwrap()).unwrap();
+        let items = filtered["data"]["accounts"][0]["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        let passkey = &items[0]["credentials"][0];
+        assert_eq!(passkey["type"], "passkey");
+        assert_eq!(passkey["rpId"], "router.lan");
+        assert_eq!(passkey["credentialId"], "legacy");
+        assert!(passkey.get("key").is_none());
+        assert_eq!(items[0]["scope"]["urls"][0], "https://router.lan");
+
+        let none: serde_json::Value = serde_json::from_str(&export(&storage, "cxf", Some("admin"), None).unwrap()).unwrap();
+        assert!(none["data"]["accounts"].as_array().unwrap().is_empty());
+        assert!(matches!(export(&storage, "csv", None, None), Err(AppError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_backup_document_decrypts_with_passphrase() {
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "dXNlcg".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        let plain = backup_document(&store, None).unwrap();
+        assert_eq!(plain["format"], BACKUP_FORMAT);
+        assert_eq!(plain["store"]["users"]["root"]["credentials"][0]["credential_id"], "a");
+
+        let sealed = backup_document(&store, Some("correct horse")).unwrap();
+        assert!(sealed.get("store").is_none());
+        let encryption = &sealed["encryption"];
+        let decrypt = |passphrase: &str| {
+            let params = argon2::Params::new(
+                encryption["memoryKib"].as_u64().unwrap() as u32,
+                encryption["iterations"].as_u64().unwrap() as u32,
+                encryption["parallelism"].as_u64().unwrap() as u32,
+                None,
+            )
+            .unwrap();
+            let salt = URL_SAFE_NO_PAD.decode(encryption["salt"].as_str().unwrap()).unwrap();
+            let mut key = [0u8; 32];
+            Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                .unwrap();
+            let nonce = URL_SAFE_NO_PAD.decode(encryption["nonce"].as_str().unwrap()).unwrap();
+            let ciphertext = URL_SAFE_NO_PAD.decode(sealed["ciphertext"].as_str().unwrap()).unwrap();
+            XChaCha20Poly1305::new_from_slice(&key).unwrap().decrypt(
+                &XNonce::from(<[u8; 24]>::try_from(nonce.as_slice()).unwrap()),
+                ciphertext.as_slice(),
+            )
+        };
+        let opened: CredentialStore = serde_json::from_slice(&decrypt("correct horse").unwrap()).unwrap();
+        assert_eq!(opened.users["root"], store.users["root"]);
+        assert!(decrypt("wrong horse").is_err());
+    }
+
+    #[test]
+    fn test_curl_config_quotes_values() {
+        let config = curl_config("https://backup.lan/r\"1", Some("t0k\\en"), br#"{"a":"b\"c"}"#);
+        assert_eq!(
+            config.as_str(),
+            "url = \"https://backup.lan/r\\\"1\"\n\
+             header = \"Content-Type: application/json\"\n\
+             header = \"Authorization: Bearer t0k\\\\en\"\n\
+             data-binary = \"{\\\"a\\\":\\\"b\\\\\\\"c\\\"}\"\n"
+        );
+    }
+
+    #[test]
+    fn test_changes_count_towards_automatic_push() {
+        let storage = MemoryStorage::default();
+        let pending = || {
+            let mut pending = 0;
+            storage
+                .modify_backup_state(&mut |state| {
+                    pending = state.pending_changes;
+                    Ok(())
+                })
+                .unwrap();
+            pending
+        };
+        note_change(&storage, &Config::default());
+        assert_eq!(pending(), 0);
+
+        // Nothing listens on the discard port, so the push fails and the count stands
+        let config = Config {
+            backup_url: Some("http://127.0.0.1:9/".to_string()),
+            backup_push_every: 2,
+            ..Config::default()
+        };
+        note_change(&storage, &config);
+        assert_eq!(pending(), 1);
+        note_change(&storage, &config);
+        assert_eq!(pending(), 2);
+
+        let missing = push(&storage, &Config::default(), None, None, None);
+        assert!(matches!(missing, Err(AppError::InvalidInput(_))));
+        let ftp = push(&storage, &Config::default(), Some("ftp://backup.lan/"), None, None);
+        assert!(matches!(ftp, Err(AppError::InvalidInput(_))));
+    }
+}
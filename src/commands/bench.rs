@@ -0,0 +1,151 @@
+use std::time::Instant;
+
+use crate::commands::login::{login_begin, login_finish};
+use crate::commands::register::{register_begin, register_finish};
+use crate::commands::selftest::{SoftAuthenticator, ORIGIN, RP_ID};
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::storage::{ClientInfo, CredentialStore, StorageProvider};
+
+/// Rounds measured unless `--iterations` says otherwise
+pub const DEFAULT_ITERATIONS: usize = 20;
+
+/// Most rounds accepted; every round adds a user to the scratch store
+const MAX_ITERATIONS: usize = 10_000;
+
+/// Runs `f`, adding how long it took in microseconds to `samples`.
+fn timed<T>(samples: &mut Vec<u64>, f: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+    let started = Instant::now();
+    let result = f()?;
+    samples.push(started.elapsed().as_micros() as u64);
+    Ok(result)
+}
+
+fn summary(mut samples: Vec<u64>) -> serde_json::Value {
+    samples.sort_unstable();
+    let total: u64 = samples.iter().sum();
+    serde_json::json!({
+        "minUs": samples.first(),
+        "medianUs": samples.get(samples.len() / 2),
+        "meanUs": total / samples.len().max(1) as u64,
+        "maxUs": samples.last(),
+    })
+}
+
+fn challenge_of(output: &str) -> Result<(String, String), AppError> {
+    let output: serde_json::Value = serde_json::from_str(output)?;
+    let field = |value: &serde_json::Value| value.as_str().map(str::to_string);
+    field(&output["data"]["challengeId"])
+        .zip(field(&output["data"]["publicKey"]["challenge"]))
+        .ok_or_else(|| AppError::Storage("begin output lacks a challenge".to_string()))
+}
+
+/// Times `iterations` registrations and logins with software authenticators
+/// against `storage`, which should be empty and throwaway, then parsing,
+/// loading and saving the store they produced. Each round uses its own user,
+/// so the store grows to `iterations` users.
+pub fn run(storage: &dyn StorageProvider, iterations: usize) -> Result<String, AppError> {
+    if iterations == 0 || iterations > MAX_ITERATIONS {
+        return Err(AppError::InvalidInput(format!(
+            "Iterations must be between 1 and {}",
+            MAX_ITERATIONS
+        )));
+    }
+    // Defaults only: the installed policy and hooks are not what is measured
+    let config = Config {
+        policy_file: String::new(),
+        ..Config::default()
+    };
+    let client = ClientInfo::default();
+
+    let mut registration = Vec::with_capacity(iterations);
+    let mut authenticators = Vec::with_capacity(iterations);
+    for i in 0..iterations {
+        let username = format!("bench-{}", i);
+        let begin = register_begin(
+            storage,
+            &config,
+            &username,
+            RP_ID,
+            "preferred",
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        let (challenge_id, challenge) = challenge_of(&begin)?;
+        let authenticator = SoftAuthenticator::new();
+        let response = authenticator.register(&challenge, ORIGIN, RP_ID);
+        timed(&mut registration, || {
+            register_finish(
+                storage,
+                &config,
+                &challenge_id,
+                ORIGIN,
+                "Bench",
+                false,
+                &client,
+                &mut response.as_bytes(),
+            )
+        })?;
+        authenticators.push((username, authenticator));
+    }
+
+    let mut assertion = Vec::with_capacity(iterations);
+    for (username, authenticator) in &mut authenticators {
+        let begin = login_begin(storage, username, RP_ID, None, &[], None, None)?;
+        let (challenge_id, challenge) = challenge_of(&begin)?;
+        let response = authenticator.sign(&challenge, ORIGIN, RP_ID);
+        timed(&mut assertion, || {
+            login_finish(storage, &config, &challenge_id, ORIGIN, &client, &mut response.as_bytes())
+        })?;
+    }
+
+    let (mut json_parse, mut store_load, mut store_save) = (Vec::new(), Vec::new(), Vec::new());
+    let store = storage.load_credentials()?;
+    let json = serde_json::to_string(&store)?;
+    for _ in 0..iterations {
+        timed(&mut json_parse, || Ok(serde_json::from_str::<CredentialStore>(&json)?))?;
+        timed(&mut store_load, || storage.load_credentials())?;
+        timed(&mut store_save, || storage.save_credentials(&store))?;
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "arch": std::env::consts::ARCH,
+        "iterations": iterations,
+        "jsonBytes": json.len(),
+        "registration": summary(registration),
+        "assertion": summary(assertion),
+        "jsonParse": summary(json_parse),
+        "storeLoad": summary(store_load),
+        "storeSave": summary(store_save),
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_bench_reports_every_measurement() {
+        let storage = MemoryStorage::default();
+        let output: serde_json::Value = serde_json::from_str(&run(&storage, 2).unwrap()).unwrap();
+        for key in ["registration", "assertion", "jsonParse", "storeLoad", "storeSave"] {
+            let stats = &output["data"][key];
+            assert!(
+                stats["minUs"].as_u64().unwrap() <= stats["maxUs"].as_u64().unwrap(),
+                "{}",
+                output
+            );
+        }
+        assert_eq!(storage.load_credentials().unwrap().users.len(), 2);
+        assert!(matches!(run(&storage, 0), Err(AppError::InvalidInput(_))));
+    }
+}
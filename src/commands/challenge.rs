@@ -0,0 +1,151 @@
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::secret::client_data_digest;
+use crate::storage::{ChallengeState, ChallengeType, Conveyance, StorageProvider};
+
+/// Longest accepted challenge type
+const MAX_TYPE_LEN: usize = 64;
+
+/// Types are chosen by the integrator, e.g. `account_recovery`; keeping them
+/// to a plain alphabet makes them safe to log and compare.
+fn check_type(kind: &str) -> Result<(), AppError> {
+    let valid = !kind.is_empty()
+        && kind.len() <= MAX_TYPE_LEN
+        && kind
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'_' | b'-' | b'.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::InvalidInput(format!(
+            "Invalid challenge type: {:?} (expected 1-{} characters of a-z, 0-9, _, - and .)",
+            kind, MAX_TYPE_LEN
+        )))
+    }
+}
+
+/// Saves a generic challenge; returns its ID and state.
+pub(crate) fn store(
+    storage: &dyn StorageProvider,
+    kind: &str,
+    payload: Option<serde_json::Value>,
+) -> Result<(String, ChallengeState), AppError> {
+    let challenge_id = crate::challenge::new_challenge_id();
+    let state = ChallengeState {
+        challenge_type: ChallengeType::Generic,
+        username: String::new(),
+        rp_id: String::new(),
+        state: String::new(),
+        created_at: crate::clock::now_rfc3339(),
+        user_handle_len: None,
+        attestation: Conveyance::None,
+        purpose: Some(kind.to_string()),
+        payload,
+    };
+    storage.save_challenge(&challenge_id, &state)?;
+    Ok((challenge_id, state))
+}
+
+/// Removes a generic challenge of type `kind` and returns it; a challenge
+/// can be taken at most once.
+pub(crate) fn take(storage: &dyn StorageProvider, challenge_id: &str, kind: &str) -> Result<ChallengeState, AppError> {
+    crate::challenge::check_challenge_id(challenge_id)?;
+    let challenge = storage.load_challenge(challenge_id)?;
+    if challenge.challenge_type != ChallengeType::Generic || challenge.purpose.as_deref() != Some(kind) {
+        return Err(AppError::InvalidInput(format!("Challenge is not a {} challenge", kind)));
+    }
+    // The replay cache makes redemption atomic when two callers race
+    if !storage.record_client_data(&client_data_digest(format!("challenge:{}", challenge_id).as_bytes()))? {
+        return Err(AppError::ChallengeNotFound(challenge_id.to_string()));
+    }
+    storage.delete_challenge(challenge_id)?;
+    Ok(challenge)
+}
+
+/// Stores a single-use challenge of an integrator-defined type with an
+/// optional JSON payload, under the same lifetime as WebAuthn challenges.
+pub fn create(storage: &dyn StorageProvider, kind: &str, payload: Option<&str>) -> Result<String, AppError> {
+    check_type(kind)?;
+    let payload = payload
+        .map(serde_json::from_str::<serde_json::Value>)
+        .transpose()
+        .map_err(|e| AppError::InvalidInput(format!("Invalid payload: {}", e)))?;
+    let (challenge_id, state) = store(storage, kind, payload)?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "challengeId": challenge_id,
+        "type": kind,
+        "createdAt": state.created_at,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Redeems a challenge made by [`create`] and returns its payload. It must
+/// be of the expected type, and succeeds at most once.
+pub fn consume(storage: &dyn StorageProvider, challenge_id: &str, kind: &str) -> Result<String, AppError> {
+    let challenge = take(storage, challenge_id, kind)?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "type": kind,
+        "payload": challenge.payload,
+        "createdAt": challenge.created_at,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_challenge_is_consumed_once_with_its_type() {
+        let storage = MemoryStorage::default();
+        let created = create(&storage, "account_recovery", Some(r#"{"user":"root"}"#)).unwrap();
+        let created: serde_json::Value = serde_json::from_str(&created).unwrap();
+        let challenge_id = created["data"]["challengeId"].as_str().unwrap();
+
+        let wrong_type = consume(&storage, challenge_id, "email_change");
+        assert!(matches!(wrong_type, Err(AppError::InvalidInput(_))));
+
+        let consumed: serde_json::Value = serde_json::from_str(&consume(&storage, challenge_id, "account_recovery").unwrap()).unwrap();
+        assert_eq!(consumed["data"]["payload"]["user"], "root");
+        assert!(matches!(
+            consume(&storage, challenge_id, "account_recovery"),
+            Err(AppError::ChallengeNotFound(_))
+        ));
+        // Only IDs as handed out reach the storage
+        assert!(matches!(
+            consume(&storage, "../credentials", "account_recovery"),
+            Err(AppError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            crate::commands::nonce::verify(&storage, "../credentials", None),
+            Err(AppError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_validates_type_and_payload() {
+        let storage = MemoryStorage::default();
+        assert!(matches!(create(&storage, "Recovery!", None), Err(AppError::InvalidInput(_))));
+        assert!(matches!(
+            create(&storage, "recovery", Some("{")),
+            Err(AppError::InvalidInput(_))
+        ));
+
+        // A generic challenge cannot stand in for a WebAuthn one
+        let created: serde_json::Value = serde_json::from_str(&create(&storage, "recovery", None).unwrap()).unwrap();
+        let register = crate::commands::register::register_finish(
+            &storage,
+            &crate::config::Config::default(),
+            created["data"]["challengeId"].as_str().unwrap(),
+            "https://router.lan",
+            "key",
+            false,
+            &crate::storage::ClientInfo::default(),
+            &mut b"{}".as_slice(),
+        );
+        assert!(matches!(register, Err(AppError::InvalidInput(_))));
+    }
+}
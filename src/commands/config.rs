@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::output::write_atomic;
+use crate::schemas::SuccessResponse;
+
+/// The settings written in `path`; a missing file has none.
+fn read_settings(path: &Path) -> Result<Map<String, Value>, AppError> {
+    if !path.exists() {
+        return Ok(Map::new());
+    }
+    let data = fs::read_to_string(path)?;
+    match serde_json::from_str(&data) {
+        Ok(Value::Object(settings)) => Ok(settings),
+        Ok(_) => Err(AppError::Config(format!("{} must hold a JSON object", path.display()))),
+        Err(e) => Err(AppError::Config(format!("Failed to parse {}: {}", path.display(), e))),
+    }
+}
+
+/// Every setting with its effective value, defaults filled in.
+fn effective(settings: &Map<String, Value>) -> Result<Map<String, Value>, AppError> {
+    let config: Config = serde_json::from_value(Value::Object(settings.clone())).map_err(|e| AppError::Config(e.to_string()))?;
+    config.validate()?;
+    match serde_json::to_value(config)? {
+        Value::Object(values) => Ok(values),
+        _ => unreachable!("Config serializes to an object"),
+    }
+}
+
+fn check_key(values: &Map<String, Value>, key: &str) -> Result<(), AppError> {
+    if values.contains_key(key) {
+        Ok(())
+    } else {
+        Err(AppError::InvalidInput(format!("Unknown configuration key: {}", key)))
+    }
+}
+
+fn write_settings(path: &Path, settings: &Map<String, Value>) -> Result<(), AppError> {
+    let mut data = serde_json::to_vec_pretty(settings)?;
+    data.push(b'\n');
+    write_atomic(path, &data)?;
+    Ok(())
+}
+
+/// All settings with their effective values, and which of them the file sets.
+pub fn list(path: &Path) -> Result<String, AppError> {
+    let settings = read_settings(path)?;
+    let values = effective(&settings)?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "path": path.display().to_string(),
+        "settings": values,
+        "explicit": settings.keys().collect::<Vec<_>>(),
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+pub fn get(path: &Path, key: &str) -> Result<String, AppError> {
+    let settings = read_settings(path)?;
+    let values = effective(&settings)?;
+    check_key(&values, key)?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "key": key,
+        "value": values[key],
+        "default": !settings.contains_key(key),
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Sets `key` to `raw`, read as JSON when it parses as a value of the right
+/// type and as a plain string otherwise; `null` restores the default. The
+/// file is only rewritten if the whole configuration still validates, and
+/// keeps its other settings.
+pub fn set(path: &Path, key: &str, raw: &str) -> Result<String, AppError> {
+    check_key(&effective(&Map::new())?, key)?;
+    let mut settings = read_settings(path)?;
+    let mut candidates = Vec::new();
+    if let Ok(value) = serde_json::from_str::<Value>(raw) {
+        candidates.push(value);
+    }
+    candidates.push(Value::String(raw.to_string()));
+
+    // A value that parses as JSON explains a failure better than its string
+    let mut first_error = None;
+    for value in candidates {
+        let mut updated = settings.clone();
+        if value.is_null() {
+            updated.remove(key);
+        } else {
+            updated.insert(key.to_string(), value);
+        }
+        match effective(&updated) {
+            Ok(values) => {
+                settings = updated;
+                write_settings(path, &settings)?;
+                let response = SuccessResponse::new(serde_json::json!({
+                    "key": key,
+                    "value": values[key],
+                    "default": !settings.contains_key(key),
+                }));
+                return Ok(serde_json::to_string(&response)?);
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.expect("at least one candidate was tried"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn data(output: &str) -> Value {
+        serde_json::from_str::<Value>(output).unwrap()["data"].clone()
+    }
+
+    #[test]
+    fn test_set_validates_and_keeps_other_settings() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, r#"{"usage_wal": true}"#).unwrap();
+
+        assert_eq!(data(&get(&path, "user_handle_len").unwrap())["default"], true);
+        assert_eq!(data(&set(&path, "user_handle_len", "32").unwrap())["value"], 32);
+        // Bare words are taken as strings
+        assert_eq!(data(&set(&path, "challenge_file_mode", "0700").unwrap())["value"], "0700");
+        assert!(matches!(set(&path, "challenge_dir_mode", "0750"), Err(AppError::Config(_))));
+        assert!(matches!(set(&path, "user_handle_len", "20"), Err(AppError::Config(_))));
+        assert!(matches!(set(&path, "no_such_key", "1"), Err(AppError::InvalidInput(_))));
+
+        let listed = data(&list(&path).unwrap());
+        assert_eq!(listed["settings"]["usage_wal"], true);
+        assert_eq!(listed["settings"]["user_handle_len"], 32);
+        assert_eq!(Config::load(&path).unwrap().user_handle_len, 32);
+
+        // null goes back to the default
+        assert_eq!(data(&set(&path, "user_handle_len", "null").unwrap())["default"], true);
+        assert!(!read_settings(&path).unwrap().contains_key("user_handle_len"));
+    }
+}
@@ -1,50 +1,129 @@
+use crate::audit;
 use crate::errors::AppError;
-use crate::schemas::{CredentialListItem, CredentialUpdateData, SuccessResponse};
+use crate::public_key::{export, KeyFormat};
+use crate::schemas::{render_table, CredentialDetail, CredentialListItem, CredentialUpdateData, OutputFormat, SuccessResponse};
+use crate::secret::ct_eq;
 use crate::storage::*;
 
-pub fn list_credentials(storage: &dyn StorageProvider, username: &str) -> Result<String, AppError> {
-    let store = storage.load_credentials()?;
+fn list_item(username: &str, c: &StoredCredential, mds: Option<&MdsCache>) -> CredentialListItem {
+    let aaguid = match &c.attestation {
+        Some(attestation) if c.aaguid.is_empty() => &attestation.aaguid,
+        _ => &c.aaguid,
+    };
+    CredentialListItem {
+        credential_id: c.credential_id.clone(),
+        username: username.to_string(),
+        rp_id: (!c.rp_id.is_empty()).then(|| c.rp_id.clone()),
+        device_name: c.device_name.clone(),
+        created_at: c.created_at.clone(),
+        last_used_at: c.last_used_at.clone(),
+        last_client_ip: c.last_client_ip.clone(),
+        last_user_agent: c.last_user_agent.clone(),
+        backup_eligible: c.backup_eligible,
+        backed_up: c.backed_up,
+        user_verified: c.user_verified,
+        sign_count: c.sign_count,
+        use_count: c.use_count,
+        failed_attempts: c.failed_attempts,
+        last_user_verified: c.last_user_verified.clone(),
+        uv_initialized: c.uv_initialized,
+        cred_protect: c.cred_protect.clone(),
+        uv_capable: c.uv_capable(),
+        enabled: c.enabled,
+        admin: c.admin,
+        transports: c.auth_transports(),
+        seen_attachments: c.seen_attachments.clone(),
+        transport_drift: c.transport_drift_at.clone(),
+        aaguid: (!aaguid.is_empty()).then(|| aaguid.clone()),
+        authenticator: crate::mds::describe(mds, aaguid),
+    }
+}
+
+/// Metadata for naming authenticators; listing never fails for lack of it.
+fn mds_cache(storage: &dyn StorageProvider) -> Option<MdsCache> {
+    storage.load_mds_cache().ok().flatten()
+}
 
-    let items: Vec<CredentialListItem> = if let Some(user_record) = store.users.get(username) {
+pub fn list_credentials(
+    storage: &dyn StorageProvider,
+    username: &str,
+    rp_id: Option<&str>,
+    format: OutputFormat,
+) -> Result<String, AppError> {
+    let mds = mds_cache(storage);
+    let items: Vec<CredentialListItem> = if let Some(user_record) = storage.load_user(username)? {
         user_record
             .credentials
             .iter()
-            .map(|c| CredentialListItem {
-                credential_id: c.credential_id.clone(),
-                username: username.to_string(),
-                device_name: c.device_name.clone(),
-                created_at: c.created_at.clone(),
-                last_used_at: c.last_used_at.clone(),
-                backup_eligible: c.backup_eligible,
-                user_verified: c.user_verified,
-            })
+            .filter(|c| rp_id.is_none_or(|rp| c.belongs_to(rp)))
+            .map(|c| list_item(username, c, mds.as_ref()))
             .collect()
     } else {
         vec![]
     };
 
-    let response = SuccessResponse::new(items);
-    Ok(serde_json::to_string(&response)?)
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string(&SuccessResponse::new(items))?),
+        OutputFormat::Table => Ok(credential_table(&items)),
+    }
+}
+
+fn credential_table(items: &[CredentialListItem]) -> String {
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|item| {
+            vec![
+                item.credential_id.clone(),
+                item.device_name.clone(),
+                item.rp_id.clone().unwrap_or_else(|| "-".to_string()),
+                item.created_at.clone(),
+                item.last_used_at.clone().unwrap_or_else(|| "never".to_string()),
+                item.authenticator.clone().unwrap_or_else(|| "-".to_string()),
+                if item.uv_capable { "yes" } else { "no" }.to_string(),
+                if item.enabled { "yes" } else { "no" }.to_string(),
+                if item.admin { "yes" } else { "no" }.to_string(),
+            ]
+        })
+        .collect();
+    render_table(
+        &[
+            "CREDENTIAL ID",
+            "DEVICE",
+            "RP ID",
+            "CREATED",
+            "LAST USED",
+            "AUTHENTICATOR",
+            "UV",
+            "ENABLED",
+            "ADMIN",
+        ],
+        &rows,
+    )
 }
 
-pub fn delete_credential(storage: &dyn StorageProvider, credential_id: &str) -> Result<String, AppError> {
+pub fn delete_credential(storage: &dyn StorageProvider, credential_id: &str, actor: Option<&str>) -> Result<String, AppError> {
     let mut store = storage.load_credentials()?;
-    let mut found = false;
+    let mut owner = None;
 
-    for user_record in store.users.values_mut() {
+    for (username, user_record) in store.users.iter_mut() {
         let original_len = user_record.credentials.len();
-        user_record.credentials.retain(|c| c.credential_id != credential_id);
+        user_record.credentials.retain(|c| !ct_eq(&c.credential_id, credential_id));
         if user_record.credentials.len() < original_len {
-            found = true;
+            owner = Some(username.clone());
             break;
         }
     }
 
-    if !found {
-        return Err(AppError::CredentialNotFound(credential_id.to_string()));
-    }
+    let owner = owner.ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))?;
 
     storage.save_credentials(&store)?;
+    audit::record(
+        storage,
+        actor,
+        "credential_delete",
+        credential_id,
+        serde_json::json!({ "username": owner }),
+    )?;
 
     let response = SuccessResponse::new(serde_json::json!({
         "credentialId": credential_id,
@@ -53,36 +132,243 @@ pub fn delete_credential(storage: &dyn StorageProvider, credential_id: &str) ->
     Ok(serde_json::to_string(&response)?)
 }
 
-pub fn update_credential(storage: &dyn StorageProvider, credential_id: &str, new_name: &str) -> Result<String, AppError> {
-    let mut store = storage.load_credentials()?;
-    let mut old_name = None;
-
-    for user_record in store.users.values_mut() {
-        for cred in &mut user_record.credentials {
-            if cred.credential_id == credential_id {
-                old_name = Some(cred.device_name.clone());
-                cred.device_name = new_name.to_string();
-                break;
-            }
-        }
-        if old_name.is_some() {
-            break;
-        }
+pub fn show_credential(storage: &dyn StorageProvider, credential_id: &str) -> Result<String, AppError> {
+    let store = storage.load_credentials()?;
+    let detail = store
+        .users
+        .iter()
+        .find_map(|(username, user_record)| {
+            let cred = user_record
+                .credentials
+                .iter()
+                .find(|c| ct_eq(&c.credential_id, credential_id))?;
+            Some(CredentialDetail {
+                item: list_item(username, cred, mds_cache(storage).as_ref()),
+                attestation: cred.attestation.clone(),
+            })
+        })
+        .ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))?;
+
+    let response = SuccessResponse::new(detail);
+    Ok(serde_json::to_string(&response)?)
+}
+
+pub fn export_key(storage: &dyn StorageProvider, credential_id: &str, format: &str) -> Result<String, AppError> {
+    let format = KeyFormat::parse(format)?;
+    let store = storage.load_credentials()?;
+    let cred = store
+        .users
+        .values()
+        .flat_map(|user_record| &user_record.credentials)
+        .find(|c| ct_eq(&c.credential_id, credential_id))
+        .ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))?;
+
+    let static_state = super::login::decode_static_state(cred)?;
+    let (key, algorithm) = export(&static_state.credential_public_key, format)?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "credentialId": credential_id,
+        "algorithm": algorithm,
+        "publicKey": key,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Suspends or re-admits a credential without deleting it. Disabled
+/// credentials are left out of login-begin and rejected by login-finish.
+pub fn set_enabled(storage: &dyn StorageProvider, credential_id: &str, enabled: bool, actor: Option<&str>) -> Result<String, AppError> {
+    let (owner, changed) = update_store(storage, |store| {
+        store
+            .users
+            .iter_mut()
+            .find_map(|(username, user_record)| {
+                let cred = user_record
+                    .credentials
+                    .iter_mut()
+                    .find(|c| ct_eq(&c.credential_id, credential_id))?;
+                let changed = cred.enabled != enabled;
+                cred.enabled = enabled;
+                Some((username.clone(), changed))
+            })
+            .ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))
+    })?;
+    if changed {
+        audit::record(
+            storage,
+            actor,
+            if enabled { "credential_enable" } else { "credential_disable" },
+            credential_id,
+            serde_json::json!({ "username": owner }),
+        )?;
     }
 
-    let old_name = old_name.ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "credentialId": credential_id,
+        "enabled": enabled,
+        "changed": changed,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
 
-    storage.save_credentials(&store)?;
+/// Renames a credential and/or designates it for configuration changes
+/// (`admin`); at least one of the two must be given.
+pub fn update_credential(
+    storage: &dyn StorageProvider,
+    credential_id: &str,
+    new_name: Option<&str>,
+    admin: Option<bool>,
+    actor: Option<&str>,
+) -> Result<String, AppError> {
+    if new_name.is_none() && admin.is_none() {
+        return Err(AppError::InvalidInput(
+            "Nothing to update: give a new name or the admin flag".to_string(),
+        ));
+    }
+    let (owner, old_name, was_admin) = update_store(storage, |store| {
+        store
+            .users
+            .iter_mut()
+            .find_map(|(username, user_record)| {
+                let cred = user_record
+                    .credentials
+                    .iter_mut()
+                    .find(|c| ct_eq(&c.credential_id, credential_id))?;
+                let old = (username.clone(), cred.device_name.clone(), cred.admin);
+                if let Some(new_name) = new_name {
+                    cred.device_name = new_name.to_string();
+                }
+                cred.admin = admin.unwrap_or(cred.admin);
+                Some(old)
+            })
+            .ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))
+    })?;
+
+    if let Some(new_name) = new_name {
+        audit::record(
+            storage,
+            actor,
+            "credential_rename",
+            credential_id,
+            serde_json::json!({ "oldName": old_name, "newName": new_name }),
+        )?;
+    }
+    let admin = admin.unwrap_or(was_admin);
+    if admin != was_admin {
+        audit::record(
+            storage,
+            actor,
+            "credential_admin",
+            credential_id,
+            serde_json::json!({ "username": owner, "admin": admin }),
+        )?;
+    }
 
     let data = CredentialUpdateData {
         credential_id: credential_id.to_string(),
+        new_name: new_name.map_or_else(|| old_name.clone(), str::to_string),
         old_name,
-        new_name: new_name.to_string(),
+        admin,
     };
     let response = SuccessResponse::new(data);
     Ok(serde_json::to_string(&response)?)
 }
 
+/// The recency of a credential copy: last use, then registration.
+fn recency(c: &StoredCredential) -> (Option<u64>, Option<u64>) {
+    (
+        c.last_used_at.as_deref().and_then(crate::clock::parse_rfc3339),
+        crate::clock::parse_rfc3339(&c.created_at),
+    )
+}
+
+/// Reports credential IDs stored more than once, under one user or across
+/// several. With `remove`, every copy but the most recently used one is
+/// dropped in the same locked pass that found them.
+pub fn dedupe(storage: &dyn StorageProvider, remove: bool, actor: Option<&str>) -> Result<String, AppError> {
+    if remove && storage.read_only() {
+        return Err(read_only_error());
+    }
+    let scan = |store: &mut CredentialStore| {
+        let mut copies: std::collections::BTreeMap<String, Vec<(String, usize)>> = std::collections::BTreeMap::new();
+        for (username, user_record) in &store.users {
+            for (index, c) in user_record.credentials.iter().enumerate() {
+                copies
+                    .entry(c.credential_id.clone())
+                    .or_default()
+                    .push((username.clone(), index));
+            }
+        }
+        let mut duplicates = Vec::new();
+        let mut dropped: Vec<(String, String, usize)> = Vec::new();
+        for (credential_id, mut found) in copies.into_iter().filter(|(_, found)| found.len() > 1) {
+            found.sort();
+            let credential = |(username, index): &(String, usize)| &store.users[username].credentials[*index];
+            let kept = found
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, copy)| recency(credential(copy)))
+                .map(|(position, _)| position)
+                .expect("duplicates have copies");
+            let listed: Vec<serde_json::Value> = found
+                .iter()
+                .enumerate()
+                .map(|(position, copy)| {
+                    let c = credential(copy);
+                    serde_json::json!({
+                        "username": copy.0,
+                        "rpId": (!c.rp_id.is_empty()).then(|| c.rp_id.clone()),
+                        "deviceName": c.device_name,
+                        "createdAt": c.created_at,
+                        "lastUsedAt": c.last_used_at,
+                        "kept": position == kept,
+                    })
+                })
+                .collect();
+            duplicates.push(serde_json::json!({ "credentialId": credential_id, "copies": listed }));
+            dropped.extend(
+                found
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(position, _)| *position != kept)
+                    .map(|(_, (username, index))| (credential_id.clone(), username, index)),
+            );
+        }
+        if remove {
+            // Highest index first so earlier removals do not shift later ones
+            dropped.sort_by_key(|(_, _, index)| std::cmp::Reverse(*index));
+            for (_, username, index) in &dropped {
+                if let Some(user_record) = store.users.get_mut(username) {
+                    user_record.credentials.remove(*index);
+                }
+            }
+        }
+        Ok((duplicates, dropped))
+    };
+    let (duplicates, dropped) = if remove {
+        update_store(storage, scan)?
+    } else {
+        scan(&mut storage.load_credentials()?)?
+    };
+
+    if remove {
+        for (credential_id, username, _) in &dropped {
+            audit::record(
+                storage,
+                actor,
+                "credential_dedupe",
+                credential_id,
+                serde_json::json!({ "username": username }),
+            )?;
+        }
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "duplicates": duplicates,
+        "removedCount": if remove { dropped.len() } else { 0 },
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
 pub fn cleanup_challenges(storage: &dyn StorageProvider) -> Result<String, AppError> {
     let count = storage.cleanup_challenges()?;
     let response = SuccessResponse::new(serde_json::json!({
@@ -90,3 +376,147 @@ pub fn cleanup_challenges(storage: &dyn StorageProvider) -> Result<String, AppEr
     }));
     Ok(serde_json::to_string(&response)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{CredentialStore, MemoryStorage, StoredAttestation, UserRecord};
+
+    #[test]
+    fn test_list_items_carry_the_aaguid() {
+        let mut recorded = crate::wal::tests::credential("a");
+        recorded.aaguid = "fbfc3007-154e-4ecc-8c0b-6e020557d7bd".to_string();
+        let mut attested = crate::wal::tests::credential("b");
+        attested.attestation = Some(StoredAttestation {
+            fmt: "none".to_string(),
+            aaguid: "ee882879-721c-4913-9775-3dfcce97072a".to_string(),
+            alg: None,
+            x5c: Vec::new(),
+            sig: None,
+        });
+        let legacy = crate::wal::tests::credential("c");
+
+        let storage = MemoryStorage::default();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![recorded, attested, legacy],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+
+        let list: serde_json::Value = serde_json::from_str(&list_credentials(&storage, "root", None, OutputFormat::Json).unwrap()).unwrap();
+        let aaguids: Vec<_> = list["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item.get("aaguid").cloned())
+            .collect();
+        assert_eq!(
+            aaguids,
+            [
+                Some("fbfc3007-154e-4ecc-8c0b-6e020557d7bd".into()),
+                Some("ee882879-721c-4913-9775-3dfcce97072a".into()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_update_designates_admin_keys() {
+        let storage = MemoryStorage::default();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        assert!(matches!(
+            update_credential(&storage, "a", None, None, None),
+            Err(AppError::InvalidInput(_))
+        ));
+
+        let updated: serde_json::Value =
+            serde_json::from_str(&update_credential(&storage, "a", None, Some(true), Some("admin")).unwrap()).unwrap();
+        assert_eq!(updated["data"]["admin"], true);
+        assert_eq!(updated["data"]["newName"], updated["data"]["oldName"]);
+        let list: serde_json::Value = serde_json::from_str(&list_credentials(&storage, "root", None, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(list["data"][0]["admin"], true);
+
+        // Renaming alone keeps the designation; only changes are audited
+        update_credential(&storage, "a", Some("Desk key"), None, None).unwrap();
+        update_credential(&storage, "a", None, Some(true), None).unwrap();
+        assert!(storage.load_credentials().unwrap().users["root"].credentials[0].admin);
+        let actions: Vec<String> = storage.load_audit_log().unwrap().into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["credential_admin", "credential_rename"]);
+    }
+
+    #[test]
+    fn test_dedupe_keeps_the_most_recently_used_copy() {
+        let storage = MemoryStorage::default();
+        let mut store = CredentialStore::default();
+        let mut stale = crate::wal::tests::credential("a");
+        stale.last_used_at = Some("2025-01-01T00:00:00Z".to_string());
+        let mut fresh = crate::wal::tests::credential("a");
+        fresh.last_used_at = Some("2025-06-01T00:00:00Z".to_string());
+        for (username, credentials) in [
+            ("root", vec![stale.clone(), crate::wal::tests::credential("b"), stale]),
+            ("alice", vec![fresh]),
+        ] {
+            store.users.insert(
+                username.to_string(),
+                UserRecord {
+                    user_id: username.to_string(),
+                    recovery_codes: vec![],
+                    roles: vec![],
+                    groups: vec![],
+                    credentials,
+                },
+            );
+        }
+        storage.save_credentials(&store).unwrap();
+
+        // Reporting leaves the store alone
+        let report: serde_json::Value = serde_json::from_str(&dedupe(&storage, false, None).unwrap()).unwrap();
+        assert_eq!(report["data"]["removedCount"], 0);
+        let copies = report["data"]["duplicates"][0]["copies"].as_array().unwrap();
+        assert_eq!(report["data"]["duplicates"][0]["credentialId"], "a");
+        let kept: Vec<(&str, bool)> = copies
+            .iter()
+            .map(|copy| (copy["username"].as_str().unwrap(), copy["kept"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(kept, [("alice", true), ("root", false), ("root", false)]);
+        assert_eq!(storage.load_credentials().unwrap().users["root"].credentials.len(), 3);
+
+        let removed: serde_json::Value = serde_json::from_str(&dedupe(&storage, true, Some("admin")).unwrap()).unwrap();
+        assert_eq!(removed["data"]["removedCount"], 2);
+        let store = storage.load_credentials().unwrap();
+        let ids = |username: &str| {
+            store.users[username]
+                .credentials
+                .iter()
+                .map(|c| c.credential_id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!((ids("root"), ids("alice")), (vec!["b".to_string()], vec!["a".to_string()]));
+        let audit = storage.load_audit_log().unwrap();
+        assert_eq!(audit.len(), 2);
+        assert!(audit
+            .iter()
+            .all(|e| e.action == "credential_dedupe" && e.details["username"] == "root"));
+
+        let again: serde_json::Value = serde_json::from_str(&dedupe(&storage, false, None).unwrap()).unwrap();
+        assert_eq!(again["data"]["duplicates"], serde_json::json!([]));
+    }
+}
@@ -1,29 +1,85 @@
+use std::io::Write;
+use std::time::Duration;
+
 use crate::errors::AppError;
-use crate::schemas::{HealthCheckData, StorageStatus, SuccessResponse};
+use crate::schemas::{ApiVersions, ChallengeStatus, HealthCheckData, LocationStatus, StorageStatus, SuccessResponse};
 use crate::storage::StorageProvider;
 
 pub fn health_check(storage: &dyn StorageProvider) -> Result<String, AppError> {
-    let cred_path = storage.credentials_path();
-    let writable = check_writable(cred_path);
+    let paths = storage.credential_locations();
+    let locations: Vec<LocationStatus> = if paths.len() > 1 {
+        paths
+            .iter()
+            .map(|path| LocationStatus {
+                path: path.to_string_lossy().to_string(),
+                readable: std::fs::File::open(path).is_ok(),
+                // A fallback's missing directory is an unmounted medium, not
+                // something to create
+                writable: path.parent().is_some_and(|p| p.exists()) && check_writable(path),
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let active = locations.iter().find(|l| l.writable);
+    let writable = if locations.is_empty() {
+        storage
+            .probe_writable()
+            .unwrap_or_else(|| check_writable(storage.credentials_path()))
+    } else {
+        active.is_some()
+    };
+    let cred_path = active.map_or_else(|| storage.credentials_path().to_string_lossy().to_string(), |l| l.path.clone());
 
     let count = match storage.load_credentials() {
         Ok(store) => store.users.values().map(|u| u.credentials.len()).sum(),
         Err(_) => 0,
     };
 
+    let challenges = storage.challenge_stats().unwrap_or_default();
+
+    // A read-only store is not expected to be writable
+    let read_only = storage.read_only();
     let data = HealthCheckData {
-        status: if writable { "ok".to_string() } else { "degraded".to_string() },
+        status: if writable || read_only { "ok".to_string() } else { "degraded".to_string() },
         version: env!("CARGO_PKG_VERSION").to_string(),
         storage: StorageStatus {
             writable,
-            path: cred_path.to_string_lossy().to_string(),
+            mode: if read_only { "read_only" } else { "read_write" }.to_string(),
+            path: cred_path,
             count,
+            locations,
         },
+        challenges: ChallengeStatus {
+            length_bytes: crate::challenge::CHALLENGE_LEN,
+            pending: challenges.pending,
+            oldest_pending_age_secs: challenges.oldest_age_secs,
+            free_bytes: challenges.free_bytes,
+        },
+        api_versions: ApiVersions::current(),
     };
     let response = SuccessResponse::new(data);
     Ok(serde_json::to_string(&response)?)
 }
 
+/// Samples health every `interval_secs` seconds as one JSON line each
+/// (NDJSON), for monitoring exec plugins, until `count` samples were taken
+/// or the reader goes away. The last sample is returned, to be printed like
+/// any other response.
+pub fn watch(storage: &dyn StorageProvider, interval_secs: u64, count: Option<usize>, out: &mut dyn Write) -> Result<String, AppError> {
+    let mut taken = 0;
+    loop {
+        let sample = health_check(storage)?;
+        taken += 1;
+        if count.is_some_and(|count| taken >= count) {
+            return Ok(sample);
+        }
+        writeln!(out, "{}", sample)?;
+        out.flush()?;
+        std::thread::sleep(Duration::from_secs(interval_secs));
+    }
+}
+
 fn check_writable(path: &std::path::Path) -> bool {
     if let Some(parent) = path.parent() {
         if !parent.exists() {
@@ -41,3 +97,23 @@ fn check_writable(path: &std::path::Path) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_watch_prints_one_line_per_sample() {
+        let storage = MemoryStorage::default();
+        let mut out = Vec::new();
+        let last = watch(&storage, 1, Some(2), &mut out).unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), 1);
+        for sample in [lines[0], &last] {
+            let sample: serde_json::Value = serde_json::from_str(sample).unwrap();
+            assert_eq!(sample["success"], true);
+            assert!(sample["data"]["status"].is_string());
+        }
+    }
+}
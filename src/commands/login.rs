@@ -1,24 +1,33 @@
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
 
 use webauthn_rp::bin::{Decode, Encode};
-use webauthn_rp::request::auth::{AllowedCredentials, AuthenticationVerificationOptions};
-use webauthn_rp::request::register::UserHandle64;
-use webauthn_rp::request::{AsciiDomain, Credentials, PublicKeyCredentialDescriptor, RpId};
+use webauthn_rp::request::auth::{AllowedCredentials, AuthenticationVerificationOptions, SignatureCounterEnforcement};
+use webauthn_rp::request::register::UserHandle;
+use webauthn_rp::request::{AsciiDomain, Credentials, PublicKeyCredentialDescriptor, RpId, UserVerificationRequirement};
 use webauthn_rp::response::register::{CompressedPubKey, DynamicState, StaticState};
-use webauthn_rp::response::{AuthTransports, Backup, CredentialId};
+use webauthn_rp::response::{AuthenticatorAttachment, Backup, CredentialId};
 use webauthn_rp::{
-    AuthenticatedCredential, NonDiscoverableAuthentication64, NonDiscoverableAuthenticationServerState,
+    AuthenticatedCredential, NonDiscoverableAuthentication, NonDiscoverableAuthenticationServerState,
     NonDiscoverableCredentialRequestOptions,
 };
 
+use crate::audit;
+use crate::challenge::{new_challenge_id, substitute_challenge};
+use crate::config::Config;
 use crate::errors::AppError;
-use crate::schemas::{LoginFinishData, SuccessResponse};
+use crate::hooks::{self, Event};
+use crate::input::{read_client_input, untrusted};
+use crate::origin::{check_origin, check_pinned_origin, pin_origin};
+use crate::policy::Policy;
+use crate::schemas::{begin_data, ClientFormat, LoginFinishData, SuccessResponse};
+use crate::secret::{client_data_digest, ct_eq, Zeroize, Zeroizing};
 use crate::storage::*;
+use crate::syslog;
 
 /// Type alias for the decoded StaticState with compressed public key types.
-type StoredStaticState = StaticState<CompressedPubKey<[u8; 32], [u8; 32], [u8; 48], Vec<u8>>>;
+pub(crate) type StoredStaticState = StaticState<CompressedPubKey<[u8; 32], [u8; 32], [u8; 48], Vec<u8>>>;
 
 fn make_rp_id(rp_id: &str) -> Result<RpId, AppError> {
     AsciiDomain::try_from(rp_id.to_owned())
@@ -26,39 +35,90 @@ fn make_rp_id(rp_id: &str) -> Result<RpId, AppError> {
         .map_err(|e| AppError::InvalidInput(format!("Invalid RP ID: {}", e)))
 }
 
-fn extract_host(origin: &str) -> Option<&str> {
-    let rest = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"))?;
-    let authority = rest.split('/').next().unwrap_or(rest);
-    Some(authority.rsplit_once(':').map_or(authority, |(h, _)| h))
+/// Decodes a credential's public key and registration extensions.
+pub(crate) fn decode_static_state(stored_cred: &StoredCredential) -> Result<StoredStaticState, AppError> {
+    let static_state_bytes = Zeroizing::new(
+        URL_SAFE_NO_PAD
+            .decode(&stored_cred.static_state)
+            .map_err(|e| AppError::Storage(format!("Failed to decode static state: {}", e)))?,
+    );
+    StaticState::decode(static_state_bytes.as_slice()).map_err(|e| AppError::Storage(format!("Failed to decode static state: {}", e)))
 }
 
-pub fn login_begin(storage: &dyn StorageProvider, username: &str, rp_id: &str) -> Result<String, AppError> {
-    let rp = make_rp_id(rp_id)?;
+/// Decodes the persisted parts of a credential; intermediate buffers are wiped on drop.
+fn decode_credential<const LEN: usize>(
+    stored_cred: &StoredCredential,
+) -> Result<(StoredStaticState, DynamicState, UserHandle<LEN>), AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let static_state = decode_static_state(stored_cred)?;
 
-    let store = storage.load_credentials()?;
-    let user_record = store
-        .users
-        .get(username)
-        .ok_or_else(|| AppError::UserNotFound(username.to_string()))?;
+    let dynamic_state_bytes = Zeroizing::new(
+        URL_SAFE_NO_PAD
+            .decode(&stored_cred.dynamic_state)
+            .map_err(|e| AppError::Storage(format!("Failed to decode dynamic state: {}", e)))?,
+    );
+    let mut ds_array: [u8; 7] = dynamic_state_bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| AppError::Storage("Invalid dynamic state length".to_string()))?;
+    let dynamic_state = DynamicState::decode(ds_array).map_err(|e| AppError::Storage(format!("Failed to decode dynamic state: {}", e)));
+    ds_array.zeroize();
+    let dynamic_state = dynamic_state?;
+
+    let user_handle_bytes = Zeroizing::new(
+        URL_SAFE_NO_PAD
+            .decode(&stored_cred.user_handle)
+            .map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)))?,
+    );
+    let mut uh_array: [u8; LEN] = user_handle_bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| AppError::Storage("Invalid user handle length".to_string()))?;
+    let user_handle = UserHandle::<LEN>::decode(uh_array).map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)));
+    uh_array.zeroize();
+    let user_handle = user_handle?;
+
+    Ok((static_state, dynamic_state, user_handle))
+}
 
-    if user_record.credentials.is_empty() {
-        return Err(AppError::UserNotFound(format!("No credentials found for user: {}", username)));
+/// Parses a `--user-verification` value for login. Second-factor logins
+/// discourage UV unless asked otherwise.
+fn parse_user_verification(value: Option<&str>) -> Result<UserVerificationRequirement, AppError> {
+    match value {
+        None | Some("discouraged") => Ok(UserVerificationRequirement::Discouraged),
+        Some("preferred") => Ok(UserVerificationRequirement::Preferred),
+        Some("required") => Ok(UserVerificationRequirement::Required),
+        Some(other) => Err(AppError::InvalidInput(format!(
+            "Unsupported user verification: {} (expected required, preferred or discouraged)",
+            other
+        ))),
     }
+}
 
+/// Starts an authentication ceremony over the given credentials and returns
+/// the encoded server state together with the options for the client. The
+/// server state records `uv`, so verification enforces a required UV.
+fn start_authentication(
+    rp: &RpId,
+    credentials: &[&StoredCredential],
+    uv: UserVerificationRequirement,
+) -> Result<(Vec<u8>, serde_json::Value), AppError> {
     // Build AllowedCredentials
-    let mut allowed_creds = AllowedCredentials::with_capacity(user_record.credentials.len());
-    for cred in &user_record.credentials {
+    let mut allowed_creds = AllowedCredentials::with_capacity(credentials.len());
+    for cred in credentials {
         let id_bytes = URL_SAFE_NO_PAD
             .decode(&cred.credential_id)
             .map_err(|e| AppError::Storage(format!("Failed to decode credential ID: {}", e)))?;
         let cred_id = CredentialId::<Vec<u8>>::decode(id_bytes).map_err(|e| AppError::Storage(format!("Invalid credential ID: {}", e)))?;
-        let transports =
-            AuthTransports::decode(cred.transports).unwrap_or_else(|_| AuthTransports::decode(0u8).expect("zero is always valid"));
+        let transports = cred.auth_transports();
         allowed_creds.push(PublicKeyCredentialDescriptor { id: cred_id, transports }.into());
     }
 
-    let options =
-        NonDiscoverableCredentialRequestOptions::second_factor(&rp, allowed_creds).map_err(|e| AppError::WebAuthn(e.to_string()))?;
+    let mut options =
+        NonDiscoverableCredentialRequestOptions::second_factor(rp, allowed_creds).map_err(|e| AppError::WebAuthn(e.to_string()))?;
+    options.options().user_verification = uv;
 
     let (server_state, client_state) = options.start_ceremony().map_err(|e| AppError::WebAuthn(e.to_string()))?;
 
@@ -66,42 +126,397 @@ pub fn login_begin(storage: &dyn StorageProvider, username: &str, rp_id: &str) -
     let state_bytes = server_state
         .encode()
         .map_err(|e| AppError::WebAuthn(format!("Failed to encode server state: {}", e)))?;
+    Ok((state_bytes, serde_json::to_value(&client_state)?))
+}
+
+/// Disabled credentials are kept for the admin to decide on, but never accepted.
+fn check_enabled(cred: &StoredCredential) -> Result<(), AppError> {
+    if cred.enabled {
+        Ok(())
+    } else {
+        Err(AppError::CredentialDisabled(cred.credential_id.clone()))
+    }
+}
+
+pub fn login_begin(
+    storage: &dyn StorageProvider,
+    username: &str,
+    rp_id: &str,
+    user_verification: Option<&str>,
+    allow_credentials: &[&str],
+    external_challenge: Option<&str>,
+    client_format: Option<&str>,
+) -> Result<String, AppError> {
+    let rp = make_rp_id(rp_id)?;
+    let uv = parse_user_verification(user_verification)?;
+    let client_format = ClientFormat::parse(client_format)?;
+
+    let user_record = storage
+        .load_user(username)?
+        .ok_or_else(|| AppError::UserNotFound(username.to_string()))?;
+
+    let rp_credentials: Vec<&StoredCredential> = user_record.credentials.iter().filter(|c| c.belongs_to(rp_id)).collect();
+    if rp_credentials.is_empty() {
+        return Err(AppError::UserNotFound(format!(
+            "No credentials found for user: {} on RP {}",
+            username, rp_id
+        )));
+    }
+    // Restricting to given keys, e.g. an admin's hardware key for step-up
+    let rp_credentials: Vec<&StoredCredential> = if allow_credentials.is_empty() {
+        rp_credentials.into_iter().filter(|c| c.enabled).collect()
+    } else {
+        allow_credentials
+            .iter()
+            .map(|id| {
+                let cred = rp_credentials
+                    .iter()
+                    .copied()
+                    .find(|c| ct_eq(&c.credential_id, id))
+                    .ok_or_else(|| AppError::CredentialNotFound(id.to_string()))?;
+                check_enabled(cred)?;
+                Ok(cred)
+            })
+            .collect::<Result<_, AppError>>()?
+    };
+    if rp_credentials.is_empty() {
+        return Err(AppError::CredentialDisabled(format!(
+            "All credentials of user {} on RP {} are disabled",
+            username, rp_id
+        )));
+    }
+
+    let (mut state_bytes, mut public_key) = start_authentication(&rp, &rp_credentials, uv)?;
+    #[cfg(feature = "deterministic")]
+    let seeded = crate::deterministic::challenge();
+    #[cfg(feature = "deterministic")]
+    let external_challenge = external_challenge.or(seeded.as_deref());
+    if let Some(external) = external_challenge {
+        substitute_challenge(&mut state_bytes, &mut public_key, external)?;
+    }
     let state_b64 = URL_SAFE_NO_PAD.encode(&state_bytes);
 
-    let challenge_id = Uuid::new_v4().to_string();
+    let challenge_id = new_challenge_id();
     let challenge_state = ChallengeState {
         challenge_type: ChallengeType::Authentication,
         username: username.to_string(),
         rp_id: rp_id.to_string(),
         state: state_b64,
-        created_at: now_iso8601(),
+        created_at: crate::clock::now_rfc3339(),
+        user_handle_len: URL_SAFE_NO_PAD.decode(&user_record.user_id).ok().map(|uh| uh.len()),
+        attestation: Conveyance::None,
+        purpose: None,
+        payload: None,
     };
     storage.save_challenge(&challenge_id, &challenge_state)?;
 
-    let public_key = serde_json::to_value(&client_state)?;
-    let data = serde_json::json!({
-        "publicKey": public_key,
-        "challengeId": challenge_id,
-    });
-    let response = SuccessResponse::new(data);
+    let response = SuccessResponse::new(begin_data(public_key, challenge_id, client_format));
     Ok(serde_json::to_string(&response)?)
 }
 
-pub fn login_finish(storage: &dyn StorageProvider, challenge_id: &str, origin_str: &str) -> Result<String, AppError> {
-    let challenge = storage.load_challenge(challenge_id)?;
+/// Verifies an assertion against a stored credential and returns the
+/// credential's updated dynamic state.
+fn verify_signature<const LEN: usize>(
+    rp: &RpId,
+    server_state: NonDiscoverableAuthenticationServerState,
+    auth_response: &NonDiscoverableAuthentication<LEN>,
+    stored_cred: &StoredCredential,
+    origin_str: &str,
+    sig_counter_enforcement: SignatureCounterEnforcement,
+) -> Result<DynamicState, AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let (static_state, dynamic_state, user_handle) = decode_credential::<LEN>(stored_cred)?;
+
+    // Build AuthenticatedCredential
+    let mut auth_cred = AuthenticatedCredential::new(auth_response.raw_id(), &user_handle, static_state, dynamic_state)
+        .map_err(|e| AppError::WebAuthn(format!("Failed to create authenticated credential: {}", e)))?;
+
+    // Verify authentication
+    let ver_opts: AuthenticationVerificationOptions<'_, '_, String, String> = AuthenticationVerificationOptions {
+        allowed_origins: &[origin_str.to_string()],
+        error_on_unsolicited_extensions: false,
+        update_uv: true,
+        sig_counter_enforcement,
+        ..Default::default()
+    };
+    server_state
+        .verify(rp, auth_response, &mut auth_cred, &ver_opts)
+        .map_err(|e| AppError::WebAuthn(e.to_string()))?;
+    Ok(auth_cred.dynamic_state())
+}
+
+/// A credential's state after a verified assertion, as [`untrusted`] hands
+/// it back; the encoded dynamic state is stored as it is.
+#[derive(Serialize, Deserialize)]
+struct AssertedState {
+    dynamic_state: String,
+    sign_count: u32,
+    user_verified: bool,
+    backup_eligible: bool,
+    backed_up: bool,
+}
+
+impl From<DynamicState> for AssertedState {
+    fn from(ds: DynamicState) -> Self {
+        let ds_bytes = Zeroizing::new(ds.encode().expect("DynamicState encode is infallible"));
+        AssertedState {
+            dynamic_state: URL_SAFE_NO_PAD.encode(ds_bytes.as_slice()),
+            sign_count: ds.sign_count,
+            user_verified: ds.user_verified,
+            backup_eligible: !matches!(ds.backup, Backup::NotEligible),
+            backed_up: matches!(ds.backup, Backup::Exists),
+        }
+    }
+}
+
+/// What [`check_login`] read from an assertion: the credential that made it,
+/// its clientDataJSON digest, the attachment it reports and, unless the
+/// signature was rejected, the credential's new state.
+#[derive(Serialize, Deserialize)]
+struct Asserted {
+    credential_id: String,
+    digest: String,
+    attachment: Option<String>,
+    state: Result<AssertedState, String>,
+}
+
+/// Parses an assertion for a user with a `LEN`-byte user handle and
+/// verifies it against the one of `credentials` it was made with.
+fn check_login<const LEN: usize>(
+    rp: &RpId,
+    server_state: NonDiscoverableAuthenticationServerState,
+    credentials: &[StoredCredential],
+    origin_str: &str,
+    input: &str,
+) -> Result<Asserted, AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let auth_response = NonDiscoverableAuthentication::<LEN>::from_json_relaxed(input.as_bytes())
+        .map_err(|e| AppError::InvalidInput(format!("Invalid client response: {}", e)))?;
+    let credential_id = URL_SAFE_NO_PAD.encode(auth_response.raw_id().as_ref());
+    let stored_cred = credentials
+        .iter()
+        .find(|c| ct_eq(&c.credential_id, &credential_id))
+        .ok_or_else(|| AppError::CredentialNotFound("No matching credential found".to_string()))?;
+
+    let state = match verify_signature(
+        rp,
+        server_state,
+        &auth_response,
+        stored_cred,
+        origin_str,
+        SignatureCounterEnforcement::Fail,
+    ) {
+        Ok(new_ds) => Ok(new_ds.into()),
+        Err(AppError::WebAuthn(message)) => Err(message),
+        Err(e) => return Err(e),
+    };
+    let attachment = match auth_response.authenticator_attachment() {
+        AuthenticatorAttachment::Platform => Some("platform"),
+        AuthenticatorAttachment::CrossPlatform => Some("cross-platform"),
+        AuthenticatorAttachment::None => None,
+    };
+    Ok(Asserted {
+        credential_id,
+        digest: client_data_digest(auth_response.response().client_data_json()),
+        attachment: attachment.map(str::to_string),
+        state,
+    })
+}
+
+/// Verifies an assertion for a user with a `LEN`-byte user handle and
+/// updates the credential's state in a single locked pass.
+#[allow(clippy::too_many_arguments)]
+fn finish_login<const LEN: usize>(
+    storage: &dyn StorageProvider,
+    challenge: &ChallengeState,
+    rp: &RpId,
+    server_state: NonDiscoverableAuthenticationServerState,
+    origin_str: &str,
+    input: &str,
+    config: &Config,
+    client: &ClientInfo,
+) -> Result<VerifiedLogin, AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let policy = Policy::for_config(config)?;
+
+    // Verify against and update the credential in a single locked load-mutate-save pass
+    let mut response_cred_id_b64 = String::new();
+    let mut failed = false;
+    let mut became_backed_up = false;
+    let mut drifted = None;
+    let result = update_user(storage, &challenge.username, |user_record| {
+        let credentials: Vec<StoredCredential> = user_record
+            .credentials
+            .iter()
+            .filter(|c| c.belongs_to(&challenge.rp_id))
+            .cloned()
+            .collect();
+        let asserted = untrusted(|| check_login::<LEN>(rp, server_state, &credentials, origin_str, input))?;
+        if storage.client_data_seen(&asserted.digest)? {
+            return Err(AppError::ReplayDetected("Assertion was already used".to_string()));
+        }
+        response_cred_id_b64 = asserted.credential_id;
+
+        let stored_cred = user_record
+            .credentials
+            .iter_mut()
+            .find(|c| c.belongs_to(&challenge.rp_id) && ct_eq(&c.credential_id, &response_cred_id_b64))
+            .ok_or_else(|| AppError::CredentialNotFound("No matching credential found".to_string()))?;
+        check_enabled(stored_cred)?;
+        let new_state = asserted.state.map_err(|message| {
+            failed = true;
+            AppError::WebAuthn(message)
+        })?;
+
+        if !storage.record_client_data(&asserted.digest)? {
+            return Err(AppError::ReplayDetected("Assertion was already used".to_string()));
+        }
+
+        // A device-bound credential turning up in a synced backup can now be used from other devices
+        became_backed_up =
+            (new_state.backup_eligible && !stored_cred.backup_eligible) || (new_state.backed_up && stored_cred.backed_up == Some(false));
+
+        // Update credential state
+        stored_cred.dynamic_state = new_state.dynamic_state;
+        stored_cred.sign_count = new_state.sign_count;
+        stored_cred.user_verified = new_state.user_verified;
+        stored_cred.backup_eligible = new_state.backup_eligible;
+        stored_cred.backed_up = Some(new_state.backed_up);
+        drifted = asserted
+            .attachment
+            .filter(|attachment| stored_cred.record_attachment(attachment));
+        let now = crate::clock::now_rfc3339();
+        if new_state.user_verified {
+            stored_cred.last_user_verified = Some(now.clone());
+        }
+        stored_cred.last_used_at = Some(now);
+        stored_cred.use_count += 1;
+        stored_cred.failed_attempts = 0;
+        client.record(stored_cred);
+        // Backfill the RP of legacy credentials on first use
+        if stored_cred.rp_id.is_empty() {
+            stored_cred.rp_id = challenge.rp_id.clone();
+        }
+        // A rejected assertion stays spent, but the credential is not updated
+        policy.check_login(stored_cred, new_state.user_verified)?;
+        Ok(VerifiedLogin {
+            credential: stored_cred.clone(),
+            roles: user_record.roles.clone(),
+            groups: user_record.groups.clone(),
+        })
+    });
+    if failed {
+        record_failure(storage, config, &challenge.username, &response_cred_id_b64);
+    }
+    if became_backed_up && result.is_ok() {
+        record_backed_up(storage, &challenge.username, &response_cred_id_b64);
+    }
+    if let (Some(attachment), Ok(verified)) = (drifted, &result) {
+        syslog::warning(&format!(
+            "credential {} of {} logged in as a {} authenticator, which its transports {} do not explain",
+            response_cred_id_b64,
+            challenge.username,
+            attachment,
+            serde_json::to_string(&verified.credential.auth_transports()).unwrap_or_default()
+        ));
+    }
+    result
+}
+
+/// Warns about and audits a credential that was device-bound until this
+/// login. Best effort, like [`record_failure`]: the login itself succeeded.
+fn record_backed_up(storage: &dyn StorageProvider, username: &str, credential_id: &str) {
+    syslog::warning(&format!(
+        "credential {} of {} is now backed up and may be used from other devices",
+        credential_id, username
+    ));
+    let recorded = audit::record(
+        storage,
+        None,
+        "credential_backed_up",
+        credential_id,
+        serde_json::json!({ "username": username }),
+    );
+    if let Err(e) = recorded {
+        syslog::warning(&format!("failed to audit backup of credential {}: {}", credential_id, e));
+    }
+}
+
+/// Counts a failed verification against a credential and disables it once
+/// `max_failed_attempts` failures in a row are reached. Best effort: the
+/// login has failed already, and that is the error the caller gets.
+fn record_failure(storage: &dyn StorageProvider, config: &Config, username: &str, credential_id: &str) {
+    let limit = config.max_failed_attempts;
+    let counted = update_user(storage, username, |user_record| {
+        let Some(cred) = user_record.credentials.iter_mut().find(|c| c.credential_id == credential_id) else {
+            return Ok(false);
+        };
+        cred.failed_attempts = cred.failed_attempts.saturating_add(1);
+        let disable = limit > 0 && cred.enabled && cred.failed_attempts >= limit;
+        if disable {
+            cred.enabled = false;
+        }
+        Ok(disable)
+    });
+    let disabled = match counted {
+        Ok(disabled) => disabled,
+        Err(e) => {
+            syslog::warning(&format!(
+                "failed to count a failed login of credential {}: {}",
+                credential_id, e
+            ));
+            return;
+        }
+    };
+    if disabled {
+        syslog::warning(&format!(
+            "credential {} of {} disabled after {} failed logins",
+            credential_id, username, limit
+        ));
+        let recorded = audit::record(
+            storage,
+            None,
+            "credential_auto_disable",
+            credential_id,
+            serde_json::json!({ "username": username, "failedAttempts": limit }),
+        );
+        if let Err(e) = recorded {
+            syslog::warning(&format!("failed to audit disabling credential {}: {}", credential_id, e));
+        }
+    }
+}
+
+/// What a successful login reports: the updated credential and the
+/// authorization hints of its user.
+struct VerifiedLogin {
+    credential: StoredCredential,
+    roles: Vec<String>,
+    groups: Vec<String>,
+}
+
+/// Verifies the assertion for a loaded challenge and updates the credential.
+fn verify_login(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    challenge: &ChallengeState,
+    origin_str: &str,
+    client: &ClientInfo,
+    client_response: &mut dyn std::io::Read,
+) -> Result<VerifiedLogin, AppError> {
     if challenge.challenge_type != ChallengeType::Authentication {
         return Err(AppError::InvalidInput(
             "Challenge is not an authentication challenge".to_string(),
         ));
     }
 
-    let origin_host = extract_host(origin_str).ok_or_else(|| AppError::InvalidOrigin("Origin has no host".to_string()))?;
-    if origin_host != challenge.rp_id {
-        return Err(AppError::InvalidOrigin(format!(
-            "Origin {} does not match RP ID {}",
-            origin_str, challenge.rp_id
-        )));
-    }
+    check_origin(origin_str, &challenge.rp_id, config)?;
+    check_pinned_origin(storage, config, origin_str, &challenge.rp_id)?;
 
     let rp = make_rp_id(&challenge.rp_id)?;
 
@@ -112,93 +527,857 @@ pub fn login_finish(storage: &dyn StorageProvider, challenge_id: &str, origin_st
     let server_state = NonDiscoverableAuthenticationServerState::decode(state_bytes.as_slice())
         .map_err(|e| AppError::Storage(format!("Failed to decode authentication state: {}", e)))?;
 
-    // Read client response from stdin
-    let mut input = String::new();
-    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)?;
-    let auth_response = NonDiscoverableAuthentication64::from_json_relaxed(input.as_bytes())
-        .map_err(|e| AppError::InvalidInput(format!("Invalid client response: {}", e)))?;
+    // Read client response (stdin for the CLI)
+    let input = read_client_input(client_response, config.max_input_bytes)?;
 
-    // Find matching credential
-    let store = storage.load_credentials()?;
-    let user_record = store
-        .users
-        .get(&challenge.username)
-        .ok_or_else(|| AppError::UserNotFound(challenge.username.clone()))?;
+    match challenge.user_handle_len.unwrap_or(64) {
+        16 => finish_login::<16>(storage, challenge, &rp, server_state, origin_str, &input, config, client),
+        32 => finish_login::<32>(storage, challenge, &rp, server_state, origin_str, &input, config, client),
+        64 => finish_login::<64>(storage, challenge, &rp, server_state, origin_str, &input, config, client),
+        n => Err(AppError::Storage(format!("Unsupported user handle length: {}", n))),
+    }
+}
 
-    let response_cred_id_b64 = URL_SAFE_NO_PAD.encode(auth_response.raw_id().as_ref());
+pub fn login_finish(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    challenge_id: &str,
+    origin_str: &str,
+    client: &ClientInfo,
+    client_response: &mut dyn std::io::Read,
+) -> Result<String, AppError> {
+    let challenge = storage.load_challenge(challenge_id)?;
+    if challenge.purpose.is_some() {
+        return Err(AppError::InvalidInput(
+            "Challenge is a step-up challenge; finish it with reauth-finish".to_string(),
+        ));
+    }
+    let event = [
+        &[
+            ("USERNAME", challenge.username.as_str()),
+            ("RP_ID", &challenge.rp_id),
+            ("ORIGIN", origin_str),
+        ][..],
+        &client.event_details(),
+    ]
+    .concat();
+    let VerifiedLogin {
+        credential: updated,
+        roles,
+        groups,
+    } = match verify_login(storage, config, &challenge, origin_str, client, client_response) {
+        Ok(verified) => verified,
+        Err(err) => {
+            let code = err.error_code();
+            hooks::fire(config, Event::LoginFailure, &[&event[..], &[("ERROR_CODE", code)]].concat());
+            return Err(err);
+        }
+    };
 
-    let stored_cred = user_record
-        .credentials
-        .iter()
-        .find(|c| c.credential_id == response_cred_id_b64)
-        .ok_or_else(|| AppError::CredentialNotFound("No matching credential found".to_string()))?;
+    storage.delete_challenge(challenge_id)?;
+    pin_origin(storage, config, origin_str, &challenge.rp_id)?;
+    let user_verified = updated.user_verified.to_string();
+    let sign_count = updated.sign_count.to_string();
+    hooks::fire(
+        config,
+        Event::LoginSuccess,
+        &[
+            &event[..],
+            &[
+                ("CREDENTIAL_ID", &updated.credential_id),
+                ("USER_VERIFIED", &user_verified),
+                ("SIGN_COUNT", &sign_count),
+            ],
+        ]
+        .concat(),
+    );
 
-    // Decode stored credential data
-    let static_state_bytes = URL_SAFE_NO_PAD
-        .decode(&stored_cred.static_state)
-        .map_err(|e| AppError::Storage(format!("Failed to decode static state: {}", e)))?;
-    let static_state: StoredStaticState = StaticState::decode(static_state_bytes.as_slice())
-        .map_err(|e| AppError::Storage(format!("Failed to decode static state: {}", e)))?;
-
-    let dynamic_state_bytes = URL_SAFE_NO_PAD
-        .decode(&stored_cred.dynamic_state)
-        .map_err(|e| AppError::Storage(format!("Failed to decode dynamic state: {}", e)))?;
-    let ds_array: [u8; 7] = dynamic_state_bytes
-        .try_into()
-        .map_err(|_| AppError::Storage("Invalid dynamic state length".to_string()))?;
-    let dynamic_state = DynamicState::decode(ds_array).map_err(|e| AppError::Storage(format!("Failed to decode dynamic state: {}", e)))?;
+    let data = LoginFinishData {
+        username: challenge.username,
+        credential_id: updated.credential_id,
+        user_verified: updated.user_verified,
+        counter: updated.sign_count,
+        admin: updated.admin,
+        roles,
+        groups,
+    };
+    let response = SuccessResponse::new(data);
+    Ok(serde_json::to_string(&response)?)
+}
 
-    let user_handle_bytes = URL_SAFE_NO_PAD
-        .decode(&stored_cred.user_handle)
-        .map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)))?;
-    let uh_array: [u8; 64] = user_handle_bytes
-        .try_into()
-        .map_err(|_| AppError::Storage("Invalid user handle length".to_string()))?;
-    let user_handle = UserHandle64::decode(uh_array).map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)))?;
+/// Purpose marking the authentication challenges of `reauth-begin`
+const REAUTH_PURPOSE: &str = "reauth";
 
-    // Build AuthenticatedCredential
-    let mut auth_cred = AuthenticatedCredential::new(auth_response.raw_id(), &user_handle, static_state, dynamic_state)
-        .map_err(|e| AppError::WebAuthn(format!("Failed to create authenticated credential: {}", e)))?;
+/// Step-up challenges are answered by a user already at the screen, so they
+/// expire well before ordinary login challenges do.
+const REAUTH_MAX_AGE_SECS: u64 = 30;
 
-    // Verify authentication
-    let ver_opts: AuthenticationVerificationOptions<'_, '_, String, String> = AuthenticationVerificationOptions {
-        allowed_origins: &[origin_str.to_string()],
-        error_on_unsolicited_extensions: false,
-        update_uv: true,
-        ..Default::default()
+/// Challenge type of the tokens `reauth-finish` issues
+const STEP_UP_TYPE: &str = "step_up";
+
+/// Challenges keep a digest of the session they were issued for, so the
+/// challenge directory holds no usable session IDs.
+fn session_digest(session: &str) -> Result<String, AppError> {
+    if session.is_empty() {
+        return Err(AppError::InvalidInput("Session must not be empty".to_string()));
+    }
+    Ok(client_data_digest(session.as_bytes()))
+}
+
+/// Starts a step-up ceremony for the frontend session `session`, restricted
+/// to the credential that session logged in with and requiring UV.
+pub fn reauth_begin(
+    storage: &dyn StorageProvider,
+    session: &str,
+    username: &str,
+    rp_id: &str,
+    credential_id: &str,
+    client_format: Option<&str>,
+) -> Result<String, AppError> {
+    let session = session_digest(session)?;
+    let rp = make_rp_id(rp_id)?;
+    let client_format = ClientFormat::parse(client_format)?;
+
+    let user_record = storage
+        .load_user(username)?
+        .ok_or_else(|| AppError::UserNotFound(username.to_string()))?;
+    let cred = user_record
+        .credentials
+        .iter()
+        .find(|c| c.belongs_to(rp_id) && ct_eq(&c.credential_id, credential_id))
+        .ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))?;
+    check_enabled(cred)?;
+
+    let (state_bytes, public_key) = start_authentication(&rp, &[cred], UserVerificationRequirement::Required)?;
+    let challenge_id = new_challenge_id();
+    let challenge_state = ChallengeState {
+        challenge_type: ChallengeType::Authentication,
+        username: username.to_string(),
+        rp_id: rp_id.to_string(),
+        state: URL_SAFE_NO_PAD.encode(&state_bytes),
+        created_at: crate::clock::now_rfc3339(),
+        user_handle_len: URL_SAFE_NO_PAD.decode(&user_record.user_id).ok().map(|uh| uh.len()),
+        attestation: Conveyance::None,
+        purpose: Some(REAUTH_PURPOSE.to_string()),
+        payload: Some(serde_json::json!({ "session": session, "credentialId": cred.credential_id })),
     };
-    server_state
-        .verify(&rp, &auth_response, &mut auth_cred, &ver_opts)
-        .map_err(|e| AppError::WebAuthn(e.to_string()))?;
+    storage.save_challenge(&challenge_id, &challenge_state)?;
 
-    let new_ds = auth_cred.dynamic_state();
-    let user_verified = new_ds.user_verified;
-    let counter = new_ds.sign_count;
-
-    // Update credential state
-    let mut store = storage.load_credentials()?;
-    if let Some(user_record) = store.users.get_mut(&challenge.username) {
-        for cred in &mut user_record.credentials {
-            if cred.credential_id == response_cred_id_b64 {
-                let ds_bytes = new_ds.encode().expect("DynamicState encode is infallible");
-                cred.dynamic_state = URL_SAFE_NO_PAD.encode(ds_bytes);
-                cred.sign_count = new_ds.sign_count;
-                cred.user_verified = new_ds.user_verified;
-                cred.backup_eligible = !matches!(new_ds.backup, Backup::NotEligible);
-                cred.last_used_at = Some(now_iso8601());
-                break;
-            }
-        }
+    let response = SuccessResponse::new(begin_data(public_key, challenge_id, client_format));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Verifies a step-up assertion from the session `reauth_begin` was called
+/// for and issues a single-use token, which the protected action redeems
+/// with `challenge consume --type step_up` within the challenge lifetime.
+pub fn reauth_finish(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    challenge_id: &str,
+    session: &str,
+    origin_str: &str,
+    client: &ClientInfo,
+    client_response: &mut dyn std::io::Read,
+) -> Result<String, AppError> {
+    let session = session_digest(session)?;
+    let challenge = storage.load_challenge(challenge_id)?;
+    if challenge.challenge_type != ChallengeType::Authentication || challenge.purpose.as_deref() != Some(REAUTH_PURPOSE) {
+        return Err(AppError::InvalidInput("Challenge is not a step-up challenge".to_string()));
+    }
+    let bound = |key: &str| {
+        challenge
+            .payload
+            .as_ref()
+            .and_then(|payload| payload.get(key))
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+    };
+    // Spent either way, so a leaked challenge cannot be retried
+    if !ct_eq(bound("session"), &session) {
+        storage.delete_challenge(challenge_id)?;
+        return Err(AppError::InvalidInput("Challenge was issued for another session".to_string()));
+    }
+    let age = crate::clock::parse_rfc3339(&challenge.created_at).map(|created| crate::clock::now_secs().saturating_sub(created));
+    if age.is_none_or(|age| age > REAUTH_MAX_AGE_SECS) {
+        storage.delete_challenge(challenge_id)?;
+        return Err(AppError::ChallengeExpired(challenge_id.to_string()));
     }
-    storage.save_credentials(&store)?;
 
+    let VerifiedLogin { credential, .. } = verify_login(storage, config, &challenge, origin_str, client, client_response)?;
     storage.delete_challenge(challenge_id)?;
+    if !ct_eq(&credential.credential_id, bound("credentialId")) {
+        return Err(AppError::InvalidInput(
+            "Assertion was made with a different credential".to_string(),
+        ));
+    }
 
-    let data = LoginFinishData {
-        username: challenge.username,
-        user_verified,
-        counter,
-    };
-    let response = SuccessResponse::new(data);
+    let (token, state) = super::challenge::store(
+        storage,
+        STEP_UP_TYPE,
+        Some(serde_json::json!({ "username": challenge.username, "credentialId": credential.credential_id })),
+    )?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "username": challenge.username,
+        "credentialId": credential.credential_id,
+        "userVerified": credential.user_verified,
+        "token": token,
+        "issuedAt": state.created_at,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Checks an assertion for one credential against a caller-supplied challenge.
+fn check_assertion<const LEN: usize>(
+    rp: &RpId,
+    stored_cred: &StoredCredential,
+    challenge_b64: &str,
+    origin_str: &str,
+    input: &str,
+) -> Result<DynamicState, AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let auth_response = NonDiscoverableAuthentication::<LEN>::from_json_relaxed(input.as_bytes())
+        .map_err(|e| AppError::InvalidInput(format!("Invalid client response: {}", e)))?;
+    if !ct_eq(
+        &URL_SAFE_NO_PAD.encode(auth_response.raw_id().as_ref()),
+        &stored_cred.credential_id,
+    ) {
+        return Err(AppError::InvalidInput(
+            "Assertion was made with a different credential".to_string(),
+        ));
+    }
+
+    let (mut state_bytes, mut public_key) = start_authentication(rp, &[stored_cred], UserVerificationRequirement::Discouraged)?;
+    substitute_challenge(&mut state_bytes, &mut public_key, challenge_b64)?;
+    let server_state = NonDiscoverableAuthenticationServerState::decode(state_bytes.as_slice())
+        .map_err(|e| AppError::WebAuthn(format!("Failed to decode authentication state: {}", e)))?;
+
+    // Replays are expected here, so a stale counter is not an error
+    verify_signature(
+        rp,
+        server_state,
+        &auth_response,
+        stored_cred,
+        origin_str,
+        SignatureCounterEnforcement::Ignore,
+    )
+}
+
+/// Verifies an assertion for a stored credential against a challenge the
+/// caller manages itself. Nothing is written: no challenge file is read or
+/// removed, and the credential's counter and replay cache are left alone.
+pub fn verify_assertion(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    credential_id: &str,
+    challenge_b64: &str,
+    origin_str: &str,
+    rp_id: Option<&str>,
+    client_response: &mut dyn std::io::Read,
+) -> Result<String, AppError> {
+    let store = storage.load_credentials()?;
+    let (username, user_record, stored_cred) = store
+        .users
+        .iter()
+        .find_map(|(username, user_record)| {
+            let cred = user_record
+                .credentials
+                .iter()
+                .find(|c| ct_eq(&c.credential_id, credential_id))?;
+            Some((username, user_record, cred))
+        })
+        .ok_or_else(|| AppError::CredentialNotFound(credential_id.to_string()))?;
+
+    let rp_id = rp_id.unwrap_or(&stored_cred.rp_id);
+    if rp_id.is_empty() {
+        return Err(AppError::InvalidInput(
+            "Credential has no stored RP ID; pass --rp-id".to_string(),
+        ));
+    }
+    if !stored_cred.belongs_to(rp_id) {
+        return Err(AppError::CredentialNotFound(format!("{} on RP {}", credential_id, rp_id)));
+    }
+    check_enabled(stored_cred)?;
+    check_origin(origin_str, rp_id, config)?;
+    check_pinned_origin(storage, config, origin_str, rp_id)?;
+    let rp = make_rp_id(rp_id)?;
+
+    let input = read_client_input(client_response, config.max_input_bytes)?;
+
+    let user_handle_len = URL_SAFE_NO_PAD.decode(&user_record.user_id).ok().map(|uh| uh.len());
+    let dynamic_state: AssertedState = untrusted(|| {
+        match user_handle_len.unwrap_or(64) {
+            16 => check_assertion::<16>(&rp, stored_cred, challenge_b64, origin_str, &input),
+            32 => check_assertion::<32>(&rp, stored_cred, challenge_b64, origin_str, &input),
+            64 => check_assertion::<64>(&rp, stored_cred, challenge_b64, origin_str, &input),
+            n => Err(AppError::Storage(format!("Unsupported user handle length: {}", n))),
+        }
+        .map(AssertedState::from)
+    })?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "credentialId": stored_cred.credential_id,
+        "username": username,
+        "verified": true,
+        "userVerified": dynamic_state.user_verified,
+        "counter": dynamic_state.sign_count,
+    }));
     Ok(serde_json::to_string(&response)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_options_contract() {
+        let rp = make_rp_id("router.lan").unwrap();
+        let cred = crate::wal::tests::credential(&URL_SAFE_NO_PAD.encode([7u8; 16]));
+        let (_, options) = start_authentication(&rp, &[&cred], UserVerificationRequirement::Discouraged).unwrap();
+
+        // PublicKeyCredentialRequestOptionsJSON, including the Level 3 members
+        let mut keys: Vec<&str> = options.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "allowCredentials",
+                "challenge",
+                "extensions",
+                "hints",
+                "rpId",
+                "timeout",
+                "userVerification"
+            ]
+        );
+        assert_eq!(options["rpId"], "router.lan");
+        let challenge = URL_SAFE_NO_PAD.decode(options["challenge"].as_str().unwrap()).unwrap();
+        assert_eq!(challenge.len(), crate::challenge::CHALLENGE_LEN);
+        assert!(options["timeout"].is_u64());
+        assert!(options["userVerification"].is_string());
+        assert!(options["hints"].as_array().unwrap().iter().all(|h| h.is_string()));
+        assert!(options["extensions"].is_object());
+
+        let allow = options["allowCredentials"].as_array().unwrap();
+        assert_eq!(allow.len(), 1);
+        assert_eq!(allow[0]["type"], "public-key");
+        assert_eq!(allow[0]["id"], cred.credential_id);
+        assert!(allow[0]["transports"].is_array());
+    }
+
+    #[test]
+    fn test_login_without_filesystem() {
+        let storage = MemoryStorage::default();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: URL_SAFE_NO_PAD.encode([5u8; 16]),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential(&URL_SAFE_NO_PAD.encode([7u8; 16]))],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+
+        let begin = login_begin(&storage, "root", "router.lan", None, &[], None, Some("webauthn-json")).unwrap();
+        let begin: serde_json::Value = serde_json::from_str(&begin).unwrap();
+        let challenge_id = begin["data"]["challengeId"].as_str().unwrap();
+        assert!(begin["data"]["options"]["publicKey"]["challenge"].is_string());
+        assert_eq!(storage.load_challenge(challenge_id).unwrap().user_handle_len, Some(16));
+
+        let result = login_finish(
+            &storage,
+            &Config::default(),
+            challenge_id,
+            "https://router.lan",
+            &ClientInfo::default(),
+            &mut b"{}".as_slice(),
+        );
+        assert!(matches!(result, Err(AppError::InvalidInput(_))));
+
+        let required = login_begin(&storage, "root", "router.lan", Some("required"), &[], None, None).unwrap();
+        let required: serde_json::Value = serde_json::from_str(&required).unwrap();
+        assert_eq!(required["data"]["publicKey"]["userVerification"], "required");
+        let invalid = login_begin(&storage, "root", "router.lan", Some("always"), &[], None, None);
+        assert!(matches!(invalid, Err(AppError::InvalidInput(_))));
+
+        let only = URL_SAFE_NO_PAD.encode([7u8; 16]);
+        let restricted = login_begin(&storage, "root", "router.lan", None, &[&only], None, None).unwrap();
+        let restricted: serde_json::Value = serde_json::from_str(&restricted).unwrap();
+        assert_eq!(restricted["data"]["publicKey"]["allowCredentials"][0]["id"], only);
+        let unknown = login_begin(&storage, "root", "router.lan", None, &["bm9wZQ"], None, None);
+        assert!(matches!(unknown, Err(AppError::CredentialNotFound(_))));
+    }
+
+    #[test]
+    fn test_verify_assertion_needs_no_challenge_file() {
+        let storage = MemoryStorage::default();
+        let cred_id = URL_SAFE_NO_PAD.encode([7u8; 16]);
+        let mut legacy = crate::wal::tests::credential(&URL_SAFE_NO_PAD.encode([8u8; 16]));
+        legacy.rp_id.clear();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: URL_SAFE_NO_PAD.encode([5u8; 16]),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential(&cred_id), legacy],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+
+        let config = Config::default();
+        let challenge = URL_SAFE_NO_PAD.encode([1u8; 16]);
+        let verify = |id: &str, rp_id: Option<&str>| {
+            verify_assertion(
+                &storage,
+                &config,
+                id,
+                &challenge,
+                "https://router.lan",
+                rp_id,
+                &mut b"{}".as_slice(),
+            )
+        };
+        assert!(matches!(verify(&cred_id, None), Err(AppError::InvalidInput(_))));
+        assert!(matches!(
+            verify(&cred_id, Some("other.lan")),
+            Err(AppError::CredentialNotFound(_))
+        ));
+        assert!(matches!(verify("missing", None), Err(AppError::CredentialNotFound(_))));
+        let legacy_id = URL_SAFE_NO_PAD.encode([8u8; 16]);
+        assert!(matches!(verify(&legacy_id, None), Err(AppError::InvalidInput(_))));
+        assert_eq!(storage.challenge_stats().unwrap().pending, 0);
+    }
+
+    /// An assertion by the key `registration_response` registers.
+    fn assertion_response(challenge: &str, origin: &str, rp_id: &str, counter: u32) -> String {
+        assertion_with_flags(challenge, origin, rp_id, counter, 0x05)
+    }
+
+    /// As [`assertion_response`], with authenticator data carrying `flags`.
+    fn assertion_with_flags(challenge: &str, origin: &str, rp_id: &str, counter: u32, flags: u8) -> String {
+        use p256::ecdsa::signature::Signer;
+        use sha2::{Digest, Sha256};
+
+        let key = p256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        auth_data.push(flags);
+        auth_data.extend_from_slice(&counter.to_be_bytes());
+        let client_data = serde_json::json!({
+            "type": "webauthn.get",
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        })
+        .to_string();
+        let signature: p256::ecdsa::Signature = key.sign(&[auth_data.as_slice(), &Sha256::digest(client_data.as_bytes())].concat());
+        serde_json::json!({
+            "id": URL_SAFE_NO_PAD.encode([9u8; 16]),
+            "rawId": URL_SAFE_NO_PAD.encode([9u8; 16]),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(&client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(&auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature.to_der().as_bytes()),
+                "userHandle": null,
+            },
+            "clientExtensionResults": {},
+        })
+        .to_string()
+    }
+
+    /// Storage where root registered the key of `registration_response` for router.lan.
+    fn registered(client: &ClientInfo) -> MemoryStorage {
+        registered_with_flags(client, 0x45)
+    }
+
+    /// As [`registered`], with the registration's authenticator data carrying `flags`.
+    fn registered_with_flags(client: &ClientInfo, flags: u8) -> MemoryStorage {
+        use crate::commands::register::tests::registration_response;
+        use crate::commands::register::{register_begin, register_finish};
+
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let begin = register_begin(
+            &storage,
+            &config,
+            "root",
+            "router.lan",
+            "preferred",
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let begin: serde_json::Value = serde_json::from_str(&begin).unwrap();
+        let response = registration_response(
+            begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+            "https://router.lan",
+            "router.lan",
+            flags,
+        );
+        register_finish(
+            &storage,
+            &config,
+            begin["data"]["challengeId"].as_str().unwrap(),
+            "https://router.lan",
+            "key",
+            false,
+            client,
+            &mut response.as_bytes(),
+        )
+        .unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_disabled_credential_cannot_log_in() {
+        use crate::commands::credential::set_enabled;
+
+        let storage = registered(&ClientInfo::default());
+        let config = Config::default();
+        let cred_id = URL_SAFE_NO_PAD.encode([9u8; 16]);
+        let spare_id = URL_SAFE_NO_PAD.encode([8u8; 16]);
+        update_user(&storage, "root", |record| {
+            record.credentials.push(crate::wal::tests::credential(&spare_id));
+            Ok(())
+        })
+        .unwrap();
+
+        let finish = |counter: u32| {
+            let begin: serde_json::Value =
+                serde_json::from_str(&login_begin(&storage, "root", "router.lan", None, &[], None, None).unwrap()).unwrap();
+            let response = assertion_response(
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+                "https://router.lan",
+                "router.lan",
+                counter,
+            );
+            (
+                begin["data"]["publicKey"]["allowCredentials"].as_array().unwrap().len(),
+                response,
+                begin,
+            )
+        };
+
+        // Disabled while the ceremony is under way
+        let (allowed, response, begin) = finish(1);
+        assert_eq!(allowed, 2);
+        set_enabled(&storage, &cred_id, false, Some("admin")).unwrap();
+        let disabled = login_finish(
+            &storage,
+            &config,
+            begin["data"]["challengeId"].as_str().unwrap(),
+            "https://router.lan",
+            &ClientInfo::default(),
+            &mut response.as_bytes(),
+        );
+        assert!(matches!(disabled, Err(AppError::CredentialDisabled(_))));
+        assert_eq!(storage.load_audit_log().unwrap().last().unwrap().action, "credential_disable");
+
+        // Left out of new ceremonies, and refused when asked for by ID
+        assert_eq!(finish(2).0, 1);
+        let restricted = login_begin(&storage, "root", "router.lan", None, &[&cred_id], None, None);
+        assert!(matches!(restricted, Err(AppError::CredentialDisabled(_))));
+        set_enabled(&storage, &spare_id, false, None).unwrap();
+        let none_left = login_begin(&storage, "root", "router.lan", None, &[], None, None);
+        assert!(matches!(none_left, Err(AppError::CredentialDisabled(_))));
+        let assertion = verify_assertion(
+            &storage,
+            &config,
+            &cred_id,
+            &URL_SAFE_NO_PAD.encode([1u8; 16]),
+            "https://router.lan",
+            None,
+            &mut b"{}".as_slice(),
+        );
+        assert!(matches!(assertion, Err(AppError::CredentialDisabled(_))));
+
+        let enabled: serde_json::Value = serde_json::from_str(&set_enabled(&storage, &cred_id, true, None).unwrap()).unwrap();
+        assert_eq!(enabled["data"]["changed"], true);
+        let (allowed, response, begin) = finish(3);
+        assert_eq!(allowed, 1);
+        login_finish(
+            &storage,
+            &config,
+            begin["data"]["challengeId"].as_str().unwrap(),
+            "https://router.lan",
+            &ClientInfo::default(),
+            &mut response.as_bytes(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_finish_records_client_details() {
+        let storage = registered(&ClientInfo::new(Some("192.168.1.50"), Some("Firefox")).unwrap());
+        let config = Config::default();
+        let cred_id = URL_SAFE_NO_PAD.encode([9u8; 16]);
+        let last_client = || {
+            let cred = storage.load_user("root").unwrap().unwrap().credentials.remove(0);
+            (cred.last_client_ip, cred.last_user_agent)
+        };
+        assert_eq!(last_client(), (Some("192.168.1.50".to_string()), Some("Firefox".to_string())));
+
+        let login = |counter: u32, client: &ClientInfo| {
+            let begin: serde_json::Value =
+                serde_json::from_str(&login_begin(&storage, "root", "router.lan", None, &[], None, None).unwrap()).unwrap();
+            let response = assertion_response(
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+                "https://router.lan",
+                "router.lan",
+                counter,
+            );
+            login_finish(
+                &storage,
+                &config,
+                begin["data"]["challengeId"].as_str().unwrap(),
+                "https://router.lan",
+                client,
+                &mut response.as_bytes(),
+            )
+        };
+        login(1, &ClientInfo::new(Some("::ffff:10.0.0.7"), Some("curl/8.5\r\n")).unwrap()).unwrap();
+        let shown: serde_json::Value =
+            serde_json::from_str(&crate::commands::credential::show_credential(&storage, &cred_id).unwrap()).unwrap();
+        assert_eq!(shown["data"]["lastClientIp"], "::ffff:10.0.0.7");
+        assert_eq!(shown["data"]["lastUserAgent"], "curl/8.5");
+
+        // A login without details does not leave stale ones behind
+        login(2, &ClientInfo::default()).unwrap();
+        assert_eq!(last_client(), (None, None));
+        assert!(matches!(
+            ClientInfo::new(Some("router.lan"), None),
+            Err(AppError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_failed_logins_disable_credential() {
+        let storage = registered(&ClientInfo::default());
+        let config = Config {
+            max_failed_attempts: 2,
+            ..Config::default()
+        };
+        let attempt = |counter: u32, forged: bool| {
+            let begin: serde_json::Value =
+                serde_json::from_str(&login_begin(&storage, "root", "router.lan", None, &[], None, None).unwrap()).unwrap();
+            let challenge = if forged {
+                URL_SAFE_NO_PAD.encode([0u8; 16])
+            } else {
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap().to_string()
+            };
+            login_finish(
+                &storage,
+                &config,
+                begin["data"]["challengeId"].as_str().unwrap(),
+                "https://router.lan",
+                &ClientInfo::default(),
+                &mut assertion_response(&challenge, "https://router.lan", "router.lan", counter).as_bytes(),
+            )
+        };
+        let failed_attempts = || storage.load_user("root").unwrap().unwrap().credentials[0].failed_attempts;
+
+        assert!(matches!(attempt(1, true), Err(AppError::WebAuthn(_))));
+        assert_eq!(failed_attempts(), 1);
+        // A success resets the count
+        attempt(2, false).unwrap();
+        assert_eq!(failed_attempts(), 0);
+
+        assert!(attempt(3, true).is_err());
+        assert!(attempt(4, true).is_err());
+        let cred = storage.load_user("root").unwrap().unwrap().credentials.remove(0);
+        assert_eq!(cred.failed_attempts, 2);
+        assert!(!cred.enabled);
+        let entry = storage.load_audit_log().unwrap().pop().unwrap();
+        assert_eq!((entry.action.as_str(), entry.actor), ("credential_auto_disable", None));
+        assert!(matches!(
+            login_begin(&storage, "root", "router.lan", None, &[], None, None),
+            Err(AppError::CredentialDisabled(_))
+        ));
+    }
+
+    #[test]
+    fn test_reauth_is_bound_to_session_and_credential() {
+        let storage = registered(&ClientInfo::default());
+        let config = Config::default();
+        let cred_id = URL_SAFE_NO_PAD.encode([9u8; 16]);
+        let start = crate::clock::now();
+        crate::clock::set(crate::clock::FixedClock(start));
+        let begin = |session: &str| {
+            let begin: serde_json::Value =
+                serde_json::from_str(&reauth_begin(&storage, session, "root", "router.lan", &cred_id, None).unwrap()).unwrap();
+            (
+                begin["data"]["challengeId"].as_str().unwrap().to_string(),
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap().to_string(),
+                begin,
+            )
+        };
+        let finish = |challenge_id: &str, session: &str, challenge: &str, counter: u32| {
+            reauth_finish(
+                &storage,
+                &config,
+                challenge_id,
+                session,
+                "https://router.lan",
+                &ClientInfo::default(),
+                &mut assertion_response(challenge, "https://router.lan", "router.lan", counter).as_bytes(),
+            )
+        };
+
+        let (challenge_id, challenge, begun) = begin("session-1");
+        assert_eq!(begun["data"]["publicKey"]["userVerification"], "required");
+        assert_eq!(begun["data"]["publicKey"]["allowCredentials"].as_array().unwrap().len(), 1);
+        // Not a login challenge, and spent when answered from another session
+        let as_login = login_finish(
+            &storage,
+            &config,
+            &challenge_id,
+            "https://router.lan",
+            &ClientInfo::default(),
+            &mut assertion_response(&challenge, "https://router.lan", "router.lan", 1).as_bytes(),
+        );
+        assert!(matches!(as_login, Err(AppError::InvalidInput(_))));
+        assert!(matches!(
+            finish(&challenge_id, "session-2", &challenge, 1),
+            Err(AppError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            finish(&challenge_id, "session-1", &challenge, 1),
+            Err(AppError::ChallengeNotFound(_))
+        ));
+
+        let (challenge_id, challenge, _) = begin("session-1");
+        let finished: serde_json::Value = serde_json::from_str(&finish(&challenge_id, "session-1", &challenge, 2).unwrap()).unwrap();
+        assert_eq!(finished["data"]["credentialId"], cred_id.as_str());
+        let token = finished["data"]["token"].as_str().unwrap();
+        let redeemed: serde_json::Value =
+            serde_json::from_str(&crate::commands::challenge::consume(&storage, token, STEP_UP_TYPE).unwrap()).unwrap();
+        assert_eq!(redeemed["data"]["payload"]["username"], "root");
+        assert!(crate::commands::challenge::consume(&storage, token, STEP_UP_TYPE).is_err());
+
+        // Step-up challenges expire long before login challenges do
+        let (challenge_id, challenge, _) = begin("session-1");
+        crate::clock::set(crate::clock::FixedClock(start + time::Duration::seconds(31)));
+        assert!(matches!(
+            finish(&challenge_id, "session-1", &challenge, 3),
+            Err(AppError::ChallengeExpired(_))
+        ));
+        assert_eq!(storage.challenge_stats().unwrap().pending, 0);
+
+        assert!(matches!(
+            reauth_begin(&storage, "session-1", "root", "router.lan", "unknown", None),
+            Err(AppError::CredentialNotFound(_))
+        ));
+        assert!(matches!(
+            reauth_begin(&storage, "", "root", "router.lan", &cred_id, None),
+            Err(AppError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_credential_becoming_backed_up_is_audited() {
+        // Backup eligible, but not backed up when registered
+        let storage = registered_with_flags(&ClientInfo::default(), 0x4d);
+        let config = Config::default();
+        let cred_id = URL_SAFE_NO_PAD.encode([9u8; 16]);
+        let login = |counter: u32, flags: u8| {
+            let begin: serde_json::Value =
+                serde_json::from_str(&login_begin(&storage, "root", "router.lan", None, &[], None, None).unwrap()).unwrap();
+            login_finish(
+                &storage,
+                &config,
+                begin["data"]["challengeId"].as_str().unwrap(),
+                "https://router.lan",
+                &ClientInfo::default(),
+                &mut assertion_with_flags(
+                    begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+                    "https://router.lan",
+                    "router.lan",
+                    counter,
+                    flags,
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        };
+        let backed_up_audits = || {
+            storage
+                .load_audit_log()
+                .unwrap()
+                .iter()
+                .filter(|entry| entry.action == "credential_backed_up")
+                .count()
+        };
+        assert_eq!(
+            storage.load_user("root").unwrap().unwrap().credentials[0].backed_up,
+            Some(false)
+        );
+
+        login(1, 0x0d);
+        assert_eq!(backed_up_audits(), 0);
+        login(2, 0x1d);
+        assert_eq!(backed_up_audits(), 1);
+        let entry = storage.load_audit_log().unwrap().pop().unwrap();
+        assert_eq!(
+            (entry.target.as_str(), &entry.details),
+            (cred_id.as_str(), &serde_json::json!({ "username": "root" }))
+        );
+        let shown: serde_json::Value =
+            serde_json::from_str(&crate::commands::credential::show_credential(&storage, &cred_id).unwrap()).unwrap();
+        assert_eq!(shown["data"]["backedUp"], true);
+
+        // Only the transition is reported
+        login(3, 0x1d);
+        assert_eq!(backed_up_audits(), 1);
+    }
+
+    #[test]
+    fn test_attachment_drift_is_shown() {
+        let storage = registered(&ClientInfo::default());
+        let config = Config::default();
+        let cred_id = URL_SAFE_NO_PAD.encode([9u8; 16]);
+        let login = |counter: u32, attachment: &str| {
+            let begin: serde_json::Value =
+                serde_json::from_str(&login_begin(&storage, "root", "router.lan", None, &[], None, None).unwrap()).unwrap();
+            let mut response: serde_json::Value = serde_json::from_str(&assertion_response(
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+                "https://router.lan",
+                "router.lan",
+                counter,
+            ))
+            .unwrap();
+            response["authenticatorAttachment"] = attachment.into();
+            login_finish(
+                &storage,
+                &config,
+                begin["data"]["challengeId"].as_str().unwrap(),
+                "https://router.lan",
+                &ClientInfo::default(),
+                &mut response.to_string().as_bytes(),
+            )
+            .unwrap();
+            let shown: serde_json::Value =
+                serde_json::from_str(&crate::commands::credential::show_credential(&storage, &cred_id).unwrap()).unwrap();
+            shown["data"].clone()
+        };
+
+        // The test key registered as a USB key
+        let shown = login(1, "cross-platform");
+        assert_eq!(shown["seenAttachments"], serde_json::json!(["cross-platform"]));
+        assert!(shown.get("transportDrift").is_none());
+        let shown = login(2, "platform");
+        assert_eq!(shown["seenAttachments"], serde_json::json!(["cross-platform", "platform"]));
+        assert!(shown["transportDrift"].is_string());
+    }
+}
@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::mds;
+use crate::schemas::SuccessResponse;
+use crate::storage::{MdsCache, StorageProvider};
+
+/// Fetches (or reads from `file`) the Metadata Service blob, verifies it and
+/// replaces the cached metadata. With `offline`, only reports on the cache.
+pub fn update(storage: &dyn StorageProvider, config: &Config, file: Option<&str>, offline: bool) -> Result<String, AppError> {
+    let now = crate::clock::now_secs();
+    // A cache torn by a crash must not stop the update that replaces it
+    let previous = storage.load_mds_cache().unwrap_or_else(|e| {
+        crate::syslog::warning(&format!("ignoring unreadable metadata cache: {}", e));
+        None
+    });
+
+    let (cache, updated) = if offline {
+        let cache = previous.ok_or_else(|| AppError::Metadata("No cached metadata; run mds update without --offline".to_string()))?;
+        (cache, false)
+    } else {
+        let blob = match file {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => mds::fetch(&config.mds_url)?,
+        };
+        let root = mds::load_root(Path::new(&config.mds_root_cert))?;
+        let payload = mds::verify_blob(&blob, &root, now)?;
+        let cache = mds::parse_payload(&payload, crate::clock::now_rfc3339())?;
+        if let Some(previous) = &previous {
+            // A lower serial number means an old blob is being replayed
+            if cache.no < previous.no {
+                return Err(AppError::Metadata(format!(
+                    "Metadata blob {} is older than the cached blob {}",
+                    cache.no, previous.no
+                )));
+            }
+        }
+        storage.save_mds_cache(&cache)?;
+        let updated = previous.is_none_or(|previous: MdsCache| previous.no != cache.no);
+        (cache, updated)
+    };
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "no": cache.no,
+        "nextUpdate": cache.next_update,
+        "fetchedAt": cache.fetched_at,
+        "entries": cache.entries.len(),
+        "updated": updated,
+        "stale": mds::is_stale(&cache, now),
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MdsEntry, MemoryStorage};
+
+    #[test]
+    fn test_offline_update_reports_the_cache() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let err = update(&storage, &config, None, true).unwrap_err();
+        assert_eq!(err.error_code(), "METADATA_ERROR");
+
+        let mut cache = MdsCache {
+            no: 7,
+            next_update: "2000-01-01".to_string(),
+            fetched_at: "1999-12-01T00:00:00Z".to_string(),
+            ..Default::default()
+        };
+        cache.entries.insert(
+            "cb69481e-8ff7-4039-93ec-0a2729a154a8".to_string(),
+            MdsEntry {
+                description: "YubiKey 5 Series".to_string(),
+                status: None,
+                attestation_roots: vec![],
+            },
+        );
+        storage.save_mds_cache(&cache).unwrap();
+
+        let output: serde_json::Value = serde_json::from_str(&update(&storage, &config, None, true).unwrap()).unwrap();
+        assert_eq!(output["data"]["no"], 7);
+        assert_eq!(output["data"]["entries"], 1);
+        assert_eq!(output["data"]["updated"], false);
+        assert_eq!(output["data"]["stale"], true);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_cache_counts_as_absent() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let storage = crate::storage::FileStorage::with_paths(dir.path().join("credentials.json"), dir.path().join("challenges"));
+        std::fs::write(dir.path().join("mds.json"), "{\"no\":").unwrap();
+        assert!(storage.load_mds_cache().is_err());
+
+        let err = update(&storage, &Config::default(), None, true).unwrap_err();
+        assert_eq!(err.error_code(), "METADATA_ERROR");
+    }
+}
@@ -1,4 +1,21 @@
+pub mod audit;
+pub mod backup;
+pub mod bench;
+pub mod challenge;
+pub mod config;
 pub mod credential;
 pub mod health;
 pub mod login;
+pub mod mds;
+pub mod nonce;
+pub mod origins;
+pub mod recovery;
 pub mod register;
+pub mod rpcd;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod selftest;
+pub mod stats;
+pub mod storage;
+pub mod user;
+pub mod wizard;
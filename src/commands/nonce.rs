@@ -0,0 +1,80 @@
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::secret::ct_eq;
+use crate::storage::StorageProvider;
+
+use super::challenge::{store, take};
+
+/// Challenge type nonces are kept under
+const NONCE_TYPE: &str = "csrf_nonce";
+
+/// Issues a single-use anti-CSRF nonce, optionally bound to a value such as
+/// the caller's session ID that `verify` must then be given as well.
+pub fn create(storage: &dyn StorageProvider, bind: Option<&str>) -> Result<String, AppError> {
+    let (nonce, state) = store(storage, NONCE_TYPE, bind.map(serde_json::Value::from))?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "nonce": nonce,
+        "createdAt": state.created_at,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Redeems a nonce from `create`. It is used up even when the binding does
+/// not match, so a leaked nonce cannot be retried.
+pub fn verify(storage: &dyn StorageProvider, nonce: &str, bind: Option<&str>) -> Result<String, AppError> {
+    let state = take(storage, nonce, NONCE_TYPE)?;
+    let bound = state.payload.as_ref().and_then(serde_json::Value::as_str);
+    let matches = match (bound, bind) {
+        (None, None) => true,
+        (Some(bound), Some(bind)) => ct_eq(bound, bind),
+        _ => false,
+    };
+    if !matches {
+        return Err(AppError::InvalidInput("Nonce was issued for another binding".to_string()));
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({ "valid": true }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn nonce(storage: &MemoryStorage, bind: Option<&str>) -> String {
+        let created: serde_json::Value = serde_json::from_str(&create(storage, bind).unwrap()).unwrap();
+        created["data"]["nonce"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_nonce_is_single_use_and_bound() {
+        let storage = MemoryStorage::default();
+
+        let unbound = nonce(&storage, None);
+        assert!(verify(&storage, &unbound, None).is_ok());
+        assert!(matches!(verify(&storage, &unbound, None), Err(AppError::ChallengeNotFound(_))));
+
+        let bound = nonce(&storage, Some("session-1"));
+        assert!(matches!(
+            verify(&storage, &bound, Some("session-2")),
+            Err(AppError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            verify(&storage, &bound, Some("session-1")),
+            Err(AppError::ChallengeNotFound(_))
+        ));
+
+        let bound = nonce(&storage, Some("session-1"));
+        assert!(matches!(verify(&storage, &bound, None), Err(AppError::InvalidInput(_))));
+        let bound = nonce(&storage, Some("session-1"));
+        assert!(verify(&storage, &bound, Some("session-1")).is_ok());
+
+        // Other generic challenges are not nonces
+        let other: serde_json::Value =
+            serde_json::from_str(&crate::commands::challenge::create(&storage, "recovery", None).unwrap()).unwrap();
+        let other = other["data"]["challengeId"].as_str().unwrap();
+        assert!(matches!(verify(&storage, other, None), Err(AppError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::audit;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::storage::StorageProvider;
+
+pub fn list_pinned(storage: &dyn StorageProvider, config: &Config) -> Result<String, AppError> {
+    let response = SuccessResponse::new(serde_json::json!({
+        "tofuEnabled": config.tofu_origins,
+        "pinned": storage.load_pinned_origins()?,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Forgets the pinned origin of one RP, or of every RP; the next successful
+/// ceremony pins a new one.
+pub fn clear_pinned(storage: &dyn StorageProvider, rp_id: Option<&str>, actor: Option<&str>) -> Result<String, AppError> {
+    let mut cleared = Vec::new();
+    storage.modify_pinned_origins(&mut |pinned| {
+        cleared = match rp_id {
+            Some(rp_id) => pinned.remove_entry(rp_id).map(|(rp, _)| rp).into_iter().collect(),
+            None => std::mem::take(pinned).into_keys().collect(),
+        };
+        Ok(())
+    })?;
+    if !cleared.is_empty() {
+        audit::record(
+            storage,
+            actor,
+            "pinned_origins_clear",
+            rp_id.unwrap_or("*"),
+            serde_json::json!({ "cleared": cleared }),
+        )?;
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({ "cleared": cleared }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_clear_pinned() {
+        let storage = MemoryStorage::default();
+        storage
+            .modify_pinned_origins(&mut |pinned| {
+                pinned.insert("a.lan".to_string(), "https://a.lan".to_string());
+                pinned.insert("b.lan".to_string(), "https://b.lan".to_string());
+                Ok(())
+            })
+            .unwrap();
+
+        let one: serde_json::Value = serde_json::from_str(&clear_pinned(&storage, Some("a.lan"), None).unwrap()).unwrap();
+        assert_eq!(one["data"]["cleared"], serde_json::json!(["a.lan"]));
+        let listed: serde_json::Value = serde_json::from_str(&list_pinned(&storage, &Config::default()).unwrap()).unwrap();
+        assert_eq!(listed["data"]["pinned"], serde_json::json!({ "b.lan": "https://b.lan" }));
+
+        let all: serde_json::Value = serde_json::from_str(&clear_pinned(&storage, None, Some("admin")).unwrap()).unwrap();
+        assert_eq!(all["data"]["cleared"], serde_json::json!(["b.lan"]));
+        assert!(storage.load_pinned_origins().unwrap().is_empty());
+
+        let audit = storage.load_audit_log().unwrap();
+        assert_eq!(audit.len(), 2);
+        assert_eq!(audit[1].actor.as_deref(), Some("admin"));
+        assert_eq!(audit[1].target, "*");
+    }
+}
@@ -0,0 +1,191 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use zeroize::Zeroizing;
+
+use crate::audit;
+use crate::commands::register::random_user_handle;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::input::read_client_input;
+use crate::schemas::SuccessResponse;
+use crate::storage::{update_user, StorageProvider};
+
+/// Codes handed out by `generate` unless told otherwise
+pub const DEFAULT_COUNT: usize = 10;
+
+/// Most codes a user can hold
+const MAX_COUNT: usize = 32;
+
+/// Crockford's base32 alphabet: no I, L, O or U to misread
+const ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// Characters per code, 5 bits each
+const CODE_LEN: usize = 10;
+
+/// A fresh code such as `7k2mq-x9d4p`.
+fn random_code() -> Zeroizing<String> {
+    // User handles come from the OS generator; 32 divides 256, so every
+    // character is uniform
+    let bytes = random_user_handle(16);
+    let mut code = Zeroizing::new(String::with_capacity(CODE_LEN + 1));
+    for (i, b) in bytes.iter().take(CODE_LEN).enumerate() {
+        if i == CODE_LEN / 2 {
+            code.push('-');
+        }
+        code.push(char::from(ALPHABET[usize::from(b & 31)]));
+    }
+    code
+}
+
+/// Lowercases a typed code and drops separators, reading I and L as 1 and O as 0.
+fn normalize(code: &str) -> Zeroizing<String> {
+    Zeroizing::new(
+        code.chars()
+            .filter(|c| !matches!(c, '-' | ' ' | '\n' | '\r' | '\t'))
+            .map(|c| match c.to_ascii_lowercase() {
+                'i' | 'l' => '1',
+                'o' => '0',
+                c => c,
+            })
+            .collect(),
+    )
+}
+
+fn hash(code: &str) -> Result<String, AppError> {
+    let salt =
+        SaltString::encode_b64(&random_user_handle(16)).map_err(|e| AppError::Storage(format!("Failed to salt a recovery code: {}", e)))?;
+    Argon2::default()
+        .hash_password(normalize(code).as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::Storage(format!("Failed to hash a recovery code: {}", e)))
+}
+
+/// Replaces `username`'s recovery codes with `count` new ones. Only their
+/// Argon2 hashes are stored, so this is the one time they can be shown.
+pub fn generate(storage: &dyn StorageProvider, username: &str, count: usize, actor: Option<&str>) -> Result<String, AppError> {
+    if count == 0 || count > MAX_COUNT {
+        return Err(AppError::InvalidInput(format!(
+            "Recovery code count must be between 1 and {}",
+            MAX_COUNT
+        )));
+    }
+    if storage.read_only() {
+        return Err(crate::storage::read_only_error());
+    }
+    let codes: Vec<Zeroizing<String>> = (0..count).map(|_| random_code()).collect();
+    let hashes = codes.iter().map(|code| hash(code)).collect::<Result<Vec<_>, _>>()?;
+    let replaced = update_user(storage, username, |record| {
+        Ok(std::mem::replace(&mut record.recovery_codes, hashes).len())
+    })?;
+    audit::record(
+        storage,
+        actor,
+        "recovery_generate",
+        username,
+        serde_json::json!({ "count": count, "replaced": replaced }),
+    )?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "username": username,
+        "codes": codes.iter().map(|code| code.as_str()).collect::<Vec<_>>(),
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Checks a recovery code read from `input` and uses it up. The check and
+/// removal happen under the store lock, so a code works at most once.
+pub fn verify(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    username: &str,
+    input: &mut dyn std::io::Read,
+    actor: Option<&str>,
+) -> Result<String, AppError> {
+    let code = Zeroizing::new(read_client_input(input, config.max_input_bytes)?);
+    let code = normalize(&code);
+    if code.len() != CODE_LEN {
+        return Err(AppError::InvalidInput("Invalid recovery code".to_string()));
+    }
+    let remaining = update_user(storage, username, |record| {
+        let used = record
+            .recovery_codes
+            .iter()
+            .position(|stored| {
+                PasswordHash::new(stored).is_ok_and(|hash| Argon2::default().verify_password(code.as_bytes(), &hash).is_ok())
+            })
+            .ok_or_else(|| AppError::InvalidInput("Invalid recovery code".to_string()))?;
+        record.recovery_codes.remove(used);
+        Ok(record.recovery_codes.len())
+    })?;
+    audit::record(
+        storage,
+        actor,
+        "recovery_use",
+        username,
+        serde_json::json!({ "remaining": remaining }),
+    )?;
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "username": username,
+        "valid": true,
+        "remaining": remaining,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{CredentialStore, MemoryStorage, UserRecord};
+
+    fn storage_with_user() -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        storage
+            .save_credentials(&CredentialStore {
+                users: [(
+                    "root".to_string(),
+                    UserRecord {
+                        user_id: "uid".to_string(),
+                        recovery_codes: vec![],
+                        roles: vec![],
+                        groups: vec![],
+                        credentials: vec![],
+                    },
+                )]
+                .into(),
+            })
+            .unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_recovery_code_is_single_use() {
+        let storage = storage_with_user();
+        let config = Config::default();
+        let generated: serde_json::Value = serde_json::from_str(&generate(&storage, "root", 2, None).unwrap()).unwrap();
+        let codes: Vec<String> = generated["data"]["codes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c.as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(codes.len(), 2);
+        assert_eq!(codes[0].len(), CODE_LEN + 1);
+        let stored = storage.load_user("root").unwrap().unwrap().recovery_codes;
+        assert!(stored[0].starts_with("$argon2id$") && !stored.iter().any(|h| h.contains(&codes[0])));
+
+        // Typed in upper case without the dash
+        let typed = codes[1].replace('-', "").to_uppercase();
+        let used: serde_json::Value =
+            serde_json::from_str(&verify(&storage, &config, "root", &mut typed.as_bytes(), None).unwrap()).unwrap();
+        assert_eq!(used["data"]["remaining"], 1);
+        assert!(matches!(
+            verify(&storage, &config, "root", &mut codes[1].as_bytes(), None),
+            Err(AppError::InvalidInput(_))
+        ));
+        assert_eq!(storage.load_audit_log().unwrap().last().unwrap().action, "recovery_use");
+
+        assert!(matches!(generate(&storage, "admin", 2, None), Err(AppError::UserNotFound(_))));
+        assert!(matches!(generate(&storage, "root", 0, None), Err(AppError::InvalidInput(_))));
+    }
+}
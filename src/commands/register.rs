@@ -1,24 +1,35 @@
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
-use uuid::Uuid;
+use serde::{Deserialize, Serialize};
 
 use webauthn_rp::bin::{Decode, Encode};
-use webauthn_rp::request::register::{PublicKeyCredentialUserEntity, RegistrationVerificationOptions, UserHandle64};
+use webauthn_rp::request::register::{
+    PublicKeyCredentialUserEntity, RegistrationVerificationOptions, UserHandle, UserHandle16, UserHandle64,
+};
 use webauthn_rp::request::{AsciiDomain, PublicKeyCredentialDescriptor, RpId, UserVerificationRequirement};
-use webauthn_rp::response::{AuthTransports, Backup, CredentialId};
+use webauthn_rp::response::register::CredentialProtectionPolicy;
+use webauthn_rp::response::{Backup, CredentialId};
 use webauthn_rp::{PublicKeyCredentialCreationOptions, Registration, RegistrationServerState};
 
+use crate::attestation::{check_trusted, parse_statement, verify_statement};
+use crate::challenge::{new_challenge_id, substitute_challenge};
+use crate::config::{Config, USER_HANDLE_LENS};
 use crate::errors::AppError;
-use crate::schemas::{RegisterFinishData, SuccessResponse};
+use crate::hooks::{self, Event};
+use crate::input::{read_client_input, untrusted};
+use crate::origin::{check_origin, check_pinned_origin, pin_origin};
+use crate::policy::Policy;
+use crate::schemas::{begin_data, ClientFormat, RegisterFinishData, SuccessResponse};
+use crate::secret::{client_data_digest, Zeroize, Zeroizing};
 use crate::storage::*;
 
-fn make_rp_id(rp_id: &str) -> Result<RpId, AppError> {
+pub(crate) fn make_rp_id(rp_id: &str) -> Result<RpId, AppError> {
     AsciiDomain::try_from(rp_id.to_owned())
         .map(RpId::Domain)
         .map_err(|e| AppError::InvalidInput(format!("Invalid RP ID: {}", e)))
 }
 
-fn format_aaguid(bytes: &[u8]) -> String {
+pub(crate) fn format_aaguid(bytes: &[u8]) -> String {
     if bytes.len() == 16 {
         let hex: String = bytes.iter().fold(String::with_capacity(32), |mut s, b| {
             use std::fmt::Write;
@@ -38,42 +49,71 @@ fn format_aaguid(bytes: &[u8]) -> String {
     }
 }
 
-fn extract_host(origin: &str) -> Option<&str> {
-    let rest = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"))?;
-    let authority = rest.split('/').next().unwrap_or(rest);
-    Some(authority.rsplit_once(':').map_or(authority, |(h, _)| h))
+/// WebAuthn spelling of a credProtect policy; `None` if the authenticator reported none.
+fn cred_protect_name(policy: CredentialProtectionPolicy) -> Option<&'static str> {
+    match policy {
+        CredentialProtectionPolicy::None => None,
+        CredentialProtectionPolicy::UserVerificationOptional => Some("userVerificationOptional"),
+        CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList => Some("userVerificationOptionalWithCredentialIDList"),
+        CredentialProtectionPolicy::UserVerificationRequired => Some("userVerificationRequired"),
+    }
 }
 
-pub fn register_begin(storage: &dyn StorageProvider, username: &str, rp_id: &str, user_verification: &str) -> Result<String, AppError> {
-    let rp = make_rp_id(rp_id)?;
-    let store = storage.load_credentials()?;
-
-    // Generate or load user handle and build exclude credentials
-    let (user_handle, exclude_creds) = if let Some(user_record) = store.users.get(username) {
-        let uh_bytes = URL_SAFE_NO_PAD
-            .decode(&user_record.user_id)
-            .map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)))?;
-        let uh_array: [u8; 64] = uh_bytes
-            .try_into()
-            .map_err(|_| AppError::Storage("Invalid user handle length".to_string()))?;
-        let uh = UserHandle64::decode(uh_array).map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)))?;
+/// Decodes a base64url user handle, checking it has a supported length.
+pub(crate) fn decode_user_handle(user_handle_b64: &str) -> Result<Zeroizing<Vec<u8>>, AppError> {
+    let bytes = Zeroizing::new(
+        URL_SAFE_NO_PAD
+            .decode(user_handle_b64)
+            .map_err(|e| AppError::InvalidInput(format!("Failed to decode user handle: {}", e)))?,
+    );
+    if !USER_HANDLE_LENS.contains(&bytes.len()) {
+        return Err(AppError::InvalidInput(format!(
+            "User handle must be 16, 32 or 64 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    Ok(bytes)
+}
 
-        let creds: Vec<PublicKeyCredentialDescriptor<Vec<u8>>> = user_record
-            .credentials
-            .iter()
-            .filter_map(|c| {
-                let id_bytes = URL_SAFE_NO_PAD.decode(&c.credential_id).ok()?;
-                let cred_id = CredentialId::<Vec<u8>>::decode(id_bytes).ok()?;
-                let transports =
-                    AuthTransports::decode(c.transports).unwrap_or_else(|_| AuthTransports::decode(0u8).expect("zero is always valid"));
-                Some(PublicKeyCredentialDescriptor { id: cred_id, transports })
-            })
-            .collect();
-        (uh, creds)
-    } else {
-        (UserHandle64::new(), vec![])
-    };
+/// A fresh random user handle of `len` bytes.
+pub(crate) fn random_user_handle(len: usize) -> Zeroizing<Vec<u8>> {
+    #[cfg(feature = "deterministic")]
+    if let Some(bytes) = crate::deterministic::bytes(len) {
+        return Zeroizing::new(bytes);
+    }
+    Zeroizing::new(match len {
+        16 => UserHandle16::new().as_ref().to_vec(),
+        32 => UserHandle::<32>::new().as_ref().to_vec(),
+        _ => UserHandle64::new().as_ref().to_vec(),
+    })
+}
 
+/// Converts raw bytes into a `UserHandle<LEN>`, wiping the intermediate array.
+fn user_handle_from_bytes<const LEN: usize>(bytes: &[u8]) -> Result<UserHandle<LEN>, AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let mut array: [u8; LEN] = bytes
+        .try_into()
+        .map_err(|_| AppError::Storage("Invalid user handle length".to_string()))?;
+    let user_handle = UserHandle::<LEN>::decode(array);
+    array.zeroize();
+    user_handle.map_err(|e| AppError::Storage(format!("Failed to decode user handle: {}", e)))
+}
+
+/// Starts a registration ceremony for a `LEN`-byte user handle and returns
+/// the encoded server state together with the options for the client.
+fn start_registration<const LEN: usize>(
+    rp: &RpId,
+    username: &str,
+    user_handle_bytes: &[u8],
+    exclude_creds: Vec<PublicKeyCredentialDescriptor<Vec<u8>>>,
+    user_verification: &str,
+) -> Result<(Vec<u8>, serde_json::Value), AppError>
+where
+    UserHandle<LEN>: Default,
+{
+    let user_handle = user_handle_from_bytes::<LEN>(user_handle_bytes)?;
     let user_entity = PublicKeyCredentialUserEntity {
         name: username
             .try_into()
@@ -86,7 +126,7 @@ pub fn register_begin(storage: &dyn StorageProvider, username: &str, rp_id: &str
         ),
     };
 
-    let mut options = PublicKeyCredentialCreationOptions::passkey(&rp, user_entity, exclude_creds);
+    let mut options = PublicKeyCredentialCreationOptions::passkey(rp, user_entity, exclude_creds);
 
     // Apply user verification policy from CLI argument
     let uv = match user_verification {
@@ -103,60 +143,173 @@ pub fn register_begin(storage: &dyn StorageProvider, username: &str, rp_id: &str
 
     let (server_state, client_state) = options.start_ceremony().map_err(|e| AppError::WebAuthn(e.to_string()))?;
 
-    // Encode server state to binary and base64
+    // Encode server state to binary
     let state_bytes = server_state
         .encode()
         .map_err(|e| AppError::WebAuthn(format!("Failed to encode server state: {}", e)))?;
+    Ok((state_bytes, serde_json::to_value(&client_state)?))
+}
+
+/// Longest `rp.name` accepted; browsers may truncate names past 64 bytes
+const MAX_RP_NAME_LEN: usize = 64;
+
+/// Validates a human-readable RP name for the browser prompt.
+fn check_rp_name(name: &str) -> Result<&str, AppError> {
+    if name.trim().is_empty() || name.len() > MAX_RP_NAME_LEN || name.chars().any(char::is_control) {
+        return Err(AppError::InvalidInput(format!(
+            "RP name must be 1-{} bytes of printable text",
+            MAX_RP_NAME_LEN
+        )));
+    }
+    Ok(name)
+}
+
+/// Validates a comma-separated list of WebAuthn `hints`, most preferred first.
+fn parse_hints(hints: &str) -> Result<Vec<&str>, AppError> {
+    let mut list = Vec::new();
+    for hint in hints.split(',').map(str::trim) {
+        if !["security-key", "client-device", "hybrid"].contains(&hint) {
+            return Err(AppError::InvalidInput(format!(
+                "Unsupported hint: {} (expected security-key, client-device or hybrid)",
+                hint
+            )));
+        }
+        if list.contains(&hint) {
+            return Err(AppError::InvalidInput(format!("Duplicate hint: {}", hint)));
+        }
+        list.push(hint);
+    }
+    Ok(list)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn register_begin(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    username: &str,
+    rp_id: &str,
+    user_verification: &str,
+    allow_reregistration: bool,
+    user_handle: Option<&str>,
+    external_challenge: Option<&str>,
+    client_format: Option<&str>,
+    hints: Option<&str>,
+    attestation: Option<&str>,
+    rp_name: Option<&str>,
+) -> Result<String, AppError> {
+    // Fail before the user touches their authenticator, not at register-finish
+    if storage.read_only() {
+        return Err(crate::storage::read_only_error());
+    }
+    let rp = make_rp_id(rp_id)?;
+    let client_format = ClientFormat::parse(client_format)?;
+    let hints = hints.map(parse_hints).transpose()?;
+    let conveyance = Conveyance::parse(attestation)?;
+    let rp_name = rp_name.or(config.rp_name.as_deref()).map(check_rp_name).transpose()?;
+    let user_record = storage.load_user(username)?;
+
+    // Use the existing, imported or a freshly generated user handle
+    let user_handle_bytes = match (&user_record, user_handle) {
+        (Some(record), Some(imported)) if record.user_id != imported => {
+            return Err(AppError::InvalidInput(format!(
+                "User {} already has a different user handle",
+                username
+            )));
+        }
+        (Some(record), _) => decode_user_handle(&record.user_id).map_err(|e| AppError::Storage(e.to_string()))?,
+        (None, Some(imported)) => decode_user_handle(imported)?,
+        (None, None) => random_user_handle(config.user_handle_len),
+    };
+
+    // Build exclude credentials
+    let exclude_creds: Vec<PublicKeyCredentialDescriptor<Vec<u8>>> = user_record
+        .iter()
+        .flat_map(|record| &record.credentials)
+        // Re-enrolling deliberately (e.g. after an authenticator reset) needs an empty exclude list
+        .filter(|c| !allow_reregistration && c.belongs_to(rp_id))
+        .filter_map(|c| {
+            let id_bytes = URL_SAFE_NO_PAD.decode(&c.credential_id).ok()?;
+            let cred_id = CredentialId::<Vec<u8>>::decode(id_bytes).ok()?;
+            let transports = c.auth_transports();
+            Some(PublicKeyCredentialDescriptor { id: cred_id, transports })
+        })
+        .collect();
+
+    let (mut state_bytes, mut public_key) = match user_handle_bytes.len() {
+        16 => start_registration::<16>(&rp, username, &user_handle_bytes, exclude_creds, user_verification)?,
+        32 => start_registration::<32>(&rp, username, &user_handle_bytes, exclude_creds, user_verification)?,
+        _ => start_registration::<64>(&rp, username, &user_handle_bytes, exclude_creds, user_verification)?,
+    };
+    #[cfg(feature = "deterministic")]
+    let seeded = crate::deterministic::challenge();
+    #[cfg(feature = "deterministic")]
+    let external_challenge = external_challenge.or(seeded.as_deref());
+    if let Some(external) = external_challenge {
+        substitute_challenge(&mut state_bytes, &mut public_key, external)?;
+    }
+    if let Some(name) = rp_name {
+        public_key["rp"]["name"] = name.into();
+    }
+    if let Some(hints) = hints {
+        public_key["hints"] = serde_json::json!(hints);
+    }
+    if !conveyance.is_none() {
+        public_key["attestation"] = conveyance.as_str().into();
+        public_key["attestationFormats"] = serde_json::json!(["packed"]);
+    }
     let state_b64 = URL_SAFE_NO_PAD.encode(&state_bytes);
 
-    let challenge_id = Uuid::new_v4().to_string();
+    let challenge_id = new_challenge_id();
     let challenge_state = ChallengeState {
         challenge_type: ChallengeType::Registration,
         username: username.to_string(),
         rp_id: rp_id.to_string(),
         state: state_b64,
-        created_at: now_iso8601(),
+        created_at: crate::clock::now_rfc3339(),
+        user_handle_len: Some(user_handle_bytes.len()),
+        attestation: conveyance,
+        purpose: None,
+        payload: None,
     };
     storage.save_challenge(&challenge_id, &challenge_state)?;
 
-    let public_key = serde_json::to_value(&client_state)?;
-    let data = serde_json::json!({
-        "publicKey": public_key,
-        "challengeId": challenge_id,
-    });
-    let response = SuccessResponse::new(data);
+    let response = SuccessResponse::new(begin_data(public_key, challenge_id, client_format));
     Ok(serde_json::to_string(&response)?)
 }
 
-pub fn register_finish(storage: &dyn StorageProvider, challenge_id: &str, origin_str: &str, device_name: &str) -> Result<String, AppError> {
-    let challenge = storage.load_challenge(challenge_id)?;
-    if challenge.challenge_type != ChallengeType::Registration {
-        return Err(AppError::InvalidInput("Challenge is not a registration challenge".to_string()));
-    }
-
-    let origin_host = extract_host(origin_str).ok_or_else(|| AppError::InvalidOrigin("Origin has no host".to_string()))?;
-    if origin_host != challenge.rp_id {
-        return Err(AppError::InvalidOrigin(format!(
-            "Origin {} does not match RP ID {}",
-            origin_str, challenge.rp_id
-        )));
-    }
-
-    let rp = make_rp_id(&challenge.rp_id)?;
+/// A registration response verified by [`verify_registration`].
+#[derive(Serialize, Deserialize)]
+struct Registered {
+    credential: StoredCredential,
+    aaguid: String,
+    /// Digest of the clientDataJSON, for the replay cache
+    digest: String,
+}
 
+/// Verifies a registration response against the server state of a
+/// `LEN`-byte user handle ceremony and returns the credential to store.
+fn finish_registration<const LEN: usize>(
+    challenge: &ChallengeState,
+    rp: &RpId,
+    origin_str: &str,
+    device_name: &str,
+    input: &str,
+) -> Result<Registered, AppError>
+where
+    UserHandle<LEN>: Default,
+{
     // Decode server state
     let state_bytes = URL_SAFE_NO_PAD
         .decode(&challenge.state)
         .map_err(|e| AppError::Storage(format!("Failed to decode server state: {}", e)))?;
-    let server_state = RegistrationServerState::<64>::decode(state_bytes.as_slice())
+    let server_state = RegistrationServerState::<LEN>::decode(state_bytes.as_slice())
         .map_err(|e| AppError::Storage(format!("Failed to decode registration state: {}", e)))?;
 
-    // Read client response from stdin
-    let mut input = String::new();
-    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)?;
     let registration =
         Registration::from_json_relaxed(input.as_bytes()).map_err(|e| AppError::InvalidInput(format!("Invalid client response: {}", e)))?;
 
+    let digest = client_data_digest(registration.response().client_data_json());
+
     // Verify registration
     let ver_opts: RegistrationVerificationOptions<'_, '_, String, String> = RegistrationVerificationOptions {
         allowed_origins: &[origin_str.to_string()],
@@ -164,57 +317,528 @@ pub fn register_finish(storage: &dyn StorageProvider, challenge_id: &str, origin
         ..Default::default()
     };
     let credential = server_state
-        .verify(&rp, &registration, &ver_opts)
+        .verify(rp, &registration, &ver_opts)
         .map_err(|e| AppError::WebAuthn(e.to_string()))?;
 
     let (cred_id, transports, user_id, static_state, dynamic_state, metadata) = credential.into_parts();
-
-    let credential_id_str = URL_SAFE_NO_PAD.encode(cred_id.as_ref());
-    let created_at = now_iso8601();
     let aaguid = format_aaguid(metadata.aaguid.data());
 
-    // Encode parts for storage (these use Infallible error types)
-    let static_state_bytes = static_state.encode().expect("StaticState encode is infallible");
-    let static_state_b64 = URL_SAFE_NO_PAD.encode(&static_state_bytes);
-
-    let dynamic_state_bytes = dynamic_state.encode().expect("DynamicState encode is infallible");
-    let dynamic_state_b64 = URL_SAFE_NO_PAD.encode(dynamic_state_bytes);
-
-    let user_handle_bytes = user_id.encode().expect("UserHandle encode is infallible");
-    let user_handle_b64 = URL_SAFE_NO_PAD.encode(user_handle_bytes);
+    // Encode parts for storage (these use Infallible error types); raw buffers are wiped on drop
+    let static_state_bytes = Zeroizing::new(static_state.encode().expect("StaticState encode is infallible"));
+    let dynamic_state_bytes = Zeroizing::new(dynamic_state.encode().expect("DynamicState encode is infallible"));
+    let user_handle_bytes = Zeroizing::new(user_id.encode().expect("UserHandle encode is infallible"));
 
-    let transports_u8 = transports.encode().expect("AuthTransports encode is infallible");
-    let backup_eligible = !matches!(dynamic_state.backup, Backup::NotEligible);
-
-    // Save credential
-    let mut store = storage.load_credentials()?;
-    let user_record = store.users.entry(challenge.username.clone()).or_insert_with(|| UserRecord {
-        user_id: user_handle_b64.clone(),
-        credentials: vec![],
-    });
-
-    user_record.credentials.push(StoredCredential {
-        credential_id: credential_id_str.clone(),
+    let stored = StoredCredential {
+        credential_id: URL_SAFE_NO_PAD.encode(cred_id.as_ref()),
+        rp_id: challenge.rp_id.clone(),
         device_name: device_name.to_string(),
-        static_state: static_state_b64,
-        dynamic_state: dynamic_state_b64,
-        user_handle: user_handle_b64,
-        transports: transports_u8,
-        created_at: created_at.clone(),
+        static_state: URL_SAFE_NO_PAD.encode(static_state_bytes.as_slice()),
+        dynamic_state: URL_SAFE_NO_PAD.encode(dynamic_state_bytes.as_slice()),
+        user_handle: URL_SAFE_NO_PAD.encode(user_handle_bytes.as_slice()),
+        transports: transports.encode().expect("AuthTransports encode is infallible"),
+        created_at: crate::clock::now_rfc3339(),
         last_used_at: None,
-        backup_eligible,
+        backup_eligible: !matches!(dynamic_state.backup, Backup::NotEligible),
+        backed_up: Some(matches!(dynamic_state.backup, Backup::Exists)),
         user_verified: dynamic_state.user_verified,
         sign_count: dynamic_state.sign_count,
-    });
-    storage.save_credentials(&store)?;
+        use_count: 0,
+        failed_attempts: 0,
+        last_user_verified: None,
+        uv_initialized: Some(dynamic_state.user_verified),
+        cred_protect: cred_protect_name(static_state.extensions.cred_protect).map(str::to_string),
+        aaguid: aaguid.clone(),
+        attestation: None,
+        seen_attachments: vec![],
+        transport_drift_at: None,
+        last_client_ip: None,
+        last_user_agent: None,
+        enabled: true,
+        admin: false,
+    };
+    Ok(Registered {
+        credential: stored,
+        aaguid,
+        digest,
+    })
+}
+
+/// Everything register-finish reads from the client response: the
+/// attestation checks, the ceremony itself and the statement kept with the
+/// credential. `mds` is the metadata cache when a trusted attestation is
+/// required.
+fn verify_registration(
+    challenge: &ChallengeState,
+    rp: &RpId,
+    origin_str: &str,
+    device_name: &str,
+    input: &str,
+    mds: Option<Option<&MdsCache>>,
+    keep_statement: bool,
+) -> Result<Registered, AppError> {
+    let verified = verify_statement(input, challenge.attestation, crate::clock::now_secs())?;
+    if let Some(cache) = mds {
+        check_trusted(input, cache, crate::clock::now_secs())?;
+    }
+    let mut registered = match challenge.user_handle_len.unwrap_or(64) {
+        16 => finish_registration::<16>(challenge, rp, origin_str, device_name, &verified)?,
+        32 => finish_registration::<32>(challenge, rp, origin_str, device_name, &verified)?,
+        64 => finish_registration::<64>(challenge, rp, origin_str, device_name, &verified)?,
+        n => return Err(AppError::Storage(format!("Unsupported user handle length: {}", n))),
+    };
+    if keep_statement {
+        registered.credential.attestation = parse_statement(input, &registered.aaguid);
+    }
+    Ok(registered)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn register_finish(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    challenge_id: &str,
+    origin_str: &str,
+    device_name: &str,
+    require_attestation: bool,
+    client: &ClientInfo,
+    client_response: &mut dyn std::io::Read,
+) -> Result<String, AppError> {
+    let challenge = storage.load_challenge(challenge_id)?;
+    if challenge.challenge_type != ChallengeType::Registration {
+        return Err(AppError::InvalidInput("Challenge is not a registration challenge".to_string()));
+    }
+
+    check_origin(origin_str, &challenge.rp_id, config)?;
+    check_pinned_origin(storage, config, origin_str, &challenge.rp_id)?;
+    let policy = Policy::for_config(config)?;
+
+    let rp = make_rp_id(&challenge.rp_id)?;
+
+    // Read client response (stdin for the CLI)
+    let input = read_client_input(client_response, config.max_input_bytes)?;
+    let mds = match require_attestation || policy.require_attestation {
+        true => Some(storage.load_mds_cache()?),
+        false => None,
+    };
+    // Attestation that was asked for is kept, whatever the global setting
+    let keep_statement = config.store_attestation || !challenge.attestation.is_none();
+    let Registered {
+        credential: mut new_cred,
+        aaguid,
+        digest,
+    } = untrusted(|| {
+        verify_registration(
+            &challenge,
+            &rp,
+            origin_str,
+            device_name,
+            &input,
+            mds.as_ref().map(Option::as_ref),
+            keep_statement,
+        )
+    })?;
+    if !storage.record_client_data(&digest)? {
+        return Err(AppError::ReplayDetected("Registration response was already used".to_string()));
+    }
+    client.record(&mut new_cred);
+    let credential_id = new_cred.credential_id.clone();
+    let created_at = new_cred.created_at.clone();
+
+    // Save credential
+    update_store(storage, |store| {
+        let user_record = store.users.entry(challenge.username.clone()).or_insert_with(|| UserRecord {
+            user_id: new_cred.user_handle.clone(),
+            recovery_codes: vec![],
+            roles: vec![],
+            groups: vec![],
+            credentials: vec![],
+        });
+        // A re-enrolled authenticator may report a known credential ID; the new record replaces the old one
+        user_record.credentials.retain(|c| c.credential_id != new_cred.credential_id);
+        let existing = user_record
+            .credentials
+            .iter()
+            .filter(|c| c.belongs_to(&challenge.rp_id))
+            .count();
+        policy.check_registration(&new_cred, existing)?;
+        user_record.credentials.push(new_cred);
+        Ok(())
+    })?;
 
     storage.delete_challenge(challenge_id)?;
+    pin_origin(storage, config, origin_str, &challenge.rp_id)?;
+    hooks::fire(
+        config,
+        Event::Register,
+        &[
+            &[
+                ("USERNAME", challenge.username.as_str()),
+                ("RP_ID", &challenge.rp_id),
+                ("ORIGIN", origin_str),
+                ("CREDENTIAL_ID", &credential_id),
+                ("DEVICE_NAME", device_name),
+                ("AAGUID", &aaguid),
+            ][..],
+            &client.event_details(),
+        ]
+        .concat(),
+    );
 
     let data = RegisterFinishData {
-        credential_id: credential_id_str,
+        credential_id,
         aaguid,
         created_at,
     };
     let response = SuccessResponse::new(data);
     Ok(serde_json::to_string(&response)?)
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ciborium::Value;
+    use sha2::{Digest, Sha256};
+
+    fn cbor(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// A "none" attestation registration response from a P-256 key whose
+    /// authenticator data carries `flags`.
+    pub(crate) fn registration_response(challenge: &str, origin: &str, rp_id: &str, flags: u8) -> String {
+        let key = p256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer((-7).into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (Value::Integer((-2).into()), Value::Bytes(point.x().unwrap().to_vec())),
+            (Value::Integer((-3).into()), Value::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let credential_id = [9u8; 16];
+        let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        auth_data.push(flags);
+        auth_data.extend_from_slice(&[0; 4]);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&credential_id);
+        auth_data.extend_from_slice(&cbor(&cose_key));
+        let attestation_object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (Value::Text("authData".into()), Value::Bytes(auth_data)),
+        ]);
+        let client_data = serde_json::json!({
+            "type": "webauthn.create",
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        });
+        serde_json::json!({
+            "id": URL_SAFE_NO_PAD.encode(credential_id),
+            "rawId": URL_SAFE_NO_PAD.encode(credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data.to_string()),
+                "attestationObject": URL_SAFE_NO_PAD.encode(cbor(&attestation_object)),
+                "transports": ["usb"],
+            },
+            "clientExtensionResults": {},
+        })
+        .to_string()
+    }
+
+    /// Decodes a binary member the way `parseCreationOptionsFromJSON` does:
+    /// unpadded base64url only.
+    fn b64url(value: &serde_json::Value) -> Vec<u8> {
+        URL_SAFE_NO_PAD
+            .decode(value.as_str().expect("binary members are strings"))
+            .unwrap()
+    }
+
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn test_creation_options_contract() {
+        let rp = make_rp_id("router.lan").unwrap();
+        let excluded = || PublicKeyCredentialDescriptor {
+            id: CredentialId::<Vec<u8>>::decode(vec![7u8; 16]).unwrap(),
+            transports: webauthn_rp::response::AuthTransports::decode(0u8).unwrap(),
+        };
+        for uv in ["required", "preferred", "discouraged"] {
+            let (_, options) = start_registration::<16>(&rp, "root", &[5u8; 16], vec![excluded()], uv).unwrap();
+
+            // PublicKeyCredentialCreationOptionsJSON, including the Level 3 members
+            assert_eq!(
+                keys(&options),
+                [
+                    "attestation",
+                    "attestationFormats",
+                    "authenticatorSelection",
+                    "challenge",
+                    "excludeCredentials",
+                    "extensions",
+                    "hints",
+                    "pubKeyCredParams",
+                    "rp",
+                    "timeout",
+                    "user"
+                ]
+            );
+            assert_eq!(options["rp"], serde_json::json!({ "id": "router.lan", "name": "router.lan" }));
+            assert_eq!(keys(&options["user"]), ["displayName", "id", "name"]);
+            assert_eq!(b64url(&options["user"]["id"]), [5u8; 16]);
+            assert_eq!(options["user"]["name"], "root");
+            assert_eq!(b64url(&options["challenge"]).len(), crate::challenge::CHALLENGE_LEN);
+            assert!(options["timeout"].is_u64());
+
+            for param in options["pubKeyCredParams"].as_array().unwrap() {
+                assert_eq!(keys(param), ["alg", "type"]);
+                assert_eq!(param["type"], "public-key");
+                assert!(param["alg"].is_i64());
+            }
+
+            let exclude = options["excludeCredentials"].as_array().unwrap();
+            assert_eq!(exclude.len(), 1);
+            assert_eq!(keys(&exclude[0]), ["id", "transports", "type"]);
+            assert_eq!(exclude[0]["type"], "public-key");
+            assert_eq!(b64url(&exclude[0]["id"]), [7u8; 16]);
+            assert!(exclude[0]["transports"].is_array());
+
+            let selection = &options["authenticatorSelection"];
+            assert_eq!(selection["residentKey"], "required");
+            assert_eq!(selection["requireResidentKey"], true);
+            assert_eq!(selection["userVerification"], uv);
+
+            assert!(options["hints"].as_array().unwrap().iter().all(|h| h.is_string()));
+            assert_eq!(options["attestation"], "none");
+            assert_eq!(options["attestationFormats"], serde_json::json!(["none"]));
+            assert!(options["extensions"].is_object());
+        }
+    }
+
+    #[test]
+    fn test_registration_without_filesystem() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let begin = register_begin(
+            &storage,
+            &config,
+            "root",
+            "router.lan",
+            "preferred",
+            false,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let begin: serde_json::Value = serde_json::from_str(&begin).unwrap();
+        let challenge_id = begin["data"]["challengeId"].as_str().unwrap();
+        assert_eq!(storage.load_challenge(challenge_id).unwrap().username, "root");
+
+        let result = register_finish(
+            &storage,
+            &config,
+            challenge_id,
+            "https://router.lan",
+            "key",
+            false,
+            &ClientInfo::default(),
+            &mut b"{}".as_slice(),
+        );
+        assert!(matches!(result, Err(AppError::InvalidInput(_))));
+        assert!(storage.load_user("root").unwrap().is_none());
+
+        let direct = register_begin(
+            &storage,
+            &config,
+            "root",
+            "router.lan",
+            "preferred",
+            false,
+            None,
+            None,
+            None,
+            None,
+            Some("direct"),
+            None,
+        )
+        .unwrap();
+        let direct: serde_json::Value = serde_json::from_str(&direct).unwrap();
+        assert_eq!(direct["data"]["publicKey"]["attestation"], "direct");
+        let challenge = storage.load_challenge(direct["data"]["challengeId"].as_str().unwrap()).unwrap();
+        assert_eq!(challenge.attestation, Conveyance::Direct);
+    }
+
+    #[test]
+    fn test_rp_name_from_option_or_config() {
+        let storage = MemoryStorage::default();
+        let config = Config {
+            rp_name: Some("Home Router".to_string()),
+            ..Config::default()
+        };
+        let rp = |rp_name: Option<&str>| {
+            let begin = register_begin(
+                &storage,
+                &config,
+                "root",
+                "192.168.1.1",
+                "preferred",
+                false,
+                None,
+                None,
+                None,
+                None,
+                None,
+                rp_name,
+            )?;
+            let begin: serde_json::Value = serde_json::from_str(&begin)?;
+            Ok::<_, AppError>(begin["data"]["publicKey"]["rp"].clone())
+        };
+        assert_eq!(
+            rp(None).unwrap(),
+            serde_json::json!({ "id": "192.168.1.1", "name": "Home Router" })
+        );
+        assert_eq!(rp(Some("My Router")).unwrap()["name"], "My Router");
+        assert!(matches!(rp(Some(" ")), Err(AppError::InvalidInput(_))));
+        assert!(matches!(rp(Some("a\nb")), Err(AppError::InvalidInput(_))));
+        assert!(matches!(rp(Some(&"x".repeat(65))), Err(AppError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_device_bound_only_rejects_synced_passkeys() {
+        let storage = MemoryStorage::default();
+        let config = Config {
+            device_bound_only: true,
+            ..Config::default()
+        };
+        for (flags, allowed) in [(0x45, true), (0x4d, false)] {
+            let begin = register_begin(
+                &storage,
+                &config,
+                "root",
+                "router.lan",
+                "preferred",
+                true,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let begin: serde_json::Value = serde_json::from_str(&begin).unwrap();
+            let response = registration_response(
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+                "https://router.lan",
+                "router.lan",
+                flags,
+            );
+            let result = register_finish(
+                &storage,
+                &config,
+                begin["data"]["challengeId"].as_str().unwrap(),
+                "https://router.lan",
+                "key",
+                false,
+                &ClientInfo::default(),
+                &mut response.as_bytes(),
+            );
+            assert_eq!(result.is_ok(), allowed, "{:?}", result);
+            if !allowed {
+                assert!(matches!(result, Err(AppError::PolicyViolation { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn test_policy_file_is_enforced() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy_file = dir.path().join("policy.json");
+        let storage = MemoryStorage::default();
+        let config = Config {
+            policy_file: policy_file.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let register = || {
+            let begin = register_begin(
+                &storage,
+                &config,
+                "root",
+                "router.lan",
+                "preferred",
+                true,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let begin: serde_json::Value = serde_json::from_str(&begin).unwrap();
+            let response = registration_response(
+                begin["data"]["publicKey"]["challenge"].as_str().unwrap(),
+                "https://router.lan",
+                "router.lan",
+                0x45,
+            );
+            register_finish(
+                &storage,
+                &config,
+                begin["data"]["challengeId"].as_str().unwrap(),
+                "https://router.lan",
+                "key",
+                false,
+                &ClientInfo::default(),
+                &mut response.as_bytes(),
+            )
+            .map_err(|e| e.rule().map_or_else(|| e.error_code().to_string(), str::to_string))
+        };
+
+        std::fs::write(&policy_file, r#"{"allowed_algorithms":["EdDSA"]}"#).unwrap();
+        assert_eq!(register().unwrap_err(), "allowed_algorithms");
+
+        // Re-registering the same authenticator does not count against the limit
+        std::fs::write(&policy_file, r#"{"allowed_algorithms":["ES256"],"max_credentials":1}"#).unwrap();
+        assert!(register().is_ok());
+        assert!(register().is_ok());
+        update_store(&storage, |store| {
+            store
+                .users
+                .get_mut("root")
+                .unwrap()
+                .credentials
+                .push(crate::wal::tests::credential("other"));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(register().unwrap_err(), "max_credentials");
+
+        // The test key sends "none" attestation
+        std::fs::write(&policy_file, r#"{"require_attestation":true}"#).unwrap();
+        assert_eq!(register().unwrap_err(), "ATTESTATION_UNTRUSTED");
+    }
+
+    #[test]
+    fn test_creation_options_extensions_follow_uv() {
+        let rp = make_rp_id("router.lan").unwrap();
+        let (_, required) = start_registration::<64>(&rp, "root", &[5u8; 64], Vec::new(), "required").unwrap();
+        assert_eq!(required["extensions"]["credentialProtectionPolicy"], "userVerificationRequired");
+        assert_eq!(required["extensions"]["enforceCredentialProtectionPolicy"], true);
+
+        let (_, preferred) = start_registration::<64>(&rp, "root", &[5u8; 64], Vec::new(), "preferred").unwrap();
+        assert_eq!(preferred["extensions"], serde_json::json!({}));
+    }
+}
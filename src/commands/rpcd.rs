@@ -0,0 +1,477 @@
+use serde_json::{json, Map, Value};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::input::read_client_input;
+use crate::schemas::OutputFormat;
+use crate::storage::{ClientInfo, StorageProvider};
+
+/// Which ACL section grants a method.
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    /// Needed by the login page before a session exists
+    Login,
+    Read,
+    Write,
+}
+
+struct Method {
+    name: &'static str,
+    /// Example argument values; rpcd derives the ubus argument types from them
+    signature: fn() -> Value,
+    access: Access,
+}
+
+const METHODS: [Method; 29] = [
+    Method {
+        name: "register_begin",
+        signature: || {
+            json!({
+                "username": "str",
+                "rpId": "str",
+                "userVerification": "str",
+                "allowReregistration": true,
+                "userHandle": "str",
+                "challenge": "str",
+                "clientFormat": "str",
+                "hints": "str",
+                "attestation": "str",
+                "rpName": "str",
+            })
+        },
+        access: Access::Write,
+    },
+    Method {
+        name: "register_finish",
+        signature: || {
+            json!({
+                "challengeId": "str",
+                "origin": "str",
+                "deviceName": "str",
+                "requireAttestation": "bool",
+                "clientIp": "str",
+                "userAgent": "str",
+                "response": {},
+            })
+        },
+        access: Access::Write,
+    },
+    Method {
+        name: "login_begin",
+        signature: || {
+            json!({
+                "username": "str",
+                "rpId": "str",
+                "userVerification": "str",
+                "allowCredentials": ["str"],
+                "challenge": "str",
+                "clientFormat": "str",
+            })
+        },
+        access: Access::Login,
+    },
+    Method {
+        name: "login_finish",
+        signature: || json!({ "challengeId": "str", "origin": "str", "clientIp": "str", "userAgent": "str", "response": {} }),
+        access: Access::Login,
+    },
+    // Keyed to the session rpcd adds as ubus_rpc_session, which callers cannot choose
+    Method {
+        name: "reauth_begin",
+        signature: || json!({ "username": "str", "rpId": "str", "credentialId": "str", "clientFormat": "str" }),
+        access: Access::Read,
+    },
+    Method {
+        name: "reauth_finish",
+        signature: || json!({ "challengeId": "str", "origin": "str", "clientIp": "str", "userAgent": "str", "response": {} }),
+        access: Access::Read,
+    },
+    Method {
+        name: "verify_assertion",
+        signature: || json!({ "credentialId": "str", "challenge": "str", "origin": "str", "rpId": "str", "response": {} }),
+        access: Access::Read,
+    },
+    Method {
+        name: "credential_list",
+        signature: || json!({ "username": "str", "rpId": "str" }),
+        access: Access::Read,
+    },
+    Method {
+        name: "credential_show",
+        signature: || json!({ "id": "str" }),
+        access: Access::Read,
+    },
+    Method {
+        name: "credential_export_key",
+        signature: || json!({ "id": "str", "format": "str" }),
+        access: Access::Read,
+    },
+    Method {
+        name: "credential_delete",
+        signature: || json!({ "id": "str", "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "credential_update",
+        signature: || json!({ "id": "str", "name": "str", "admin": "bool", "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "credential_disable",
+        signature: || json!({ "id": "str", "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "credential_enable",
+        signature: || json!({ "id": "str", "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "credential_cleanup",
+        signature: || json!({}),
+        access: Access::Write,
+    },
+    Method {
+        name: "credential_dedupe",
+        signature: || json!({ "remove": "bool", "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "user_list",
+        signature: || json!({}),
+        access: Access::Read,
+    },
+    Method {
+        name: "user_set_roles",
+        signature: || json!({ "username": "str", "roles": ["str"], "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "user_set_groups",
+        signature: || json!({ "username": "str", "groups": ["str"], "actor": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "health_check",
+        signature: || json!({}),
+        access: Access::Read,
+    },
+    Method {
+        name: "stats",
+        signature: || json!({}),
+        access: Access::Read,
+    },
+    Method {
+        name: "audit_list",
+        signature: || json!({ "action": "str", "limit": 0 }),
+        access: Access::Read,
+    },
+    Method {
+        name: "audit_verify",
+        signature: || json!({}),
+        access: Access::Read,
+    },
+    Method {
+        name: "challenge_create",
+        signature: || json!({ "type": "str", "payload": {} }),
+        access: Access::Write,
+    },
+    Method {
+        name: "challenge_consume",
+        signature: || json!({ "challengeId": "str", "type": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "nonce_create",
+        signature: || json!({ "bind": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "nonce_verify",
+        signature: || json!({ "nonce": "str", "bind": "str" }),
+        access: Access::Write,
+    },
+    Method {
+        name: "pinned_origins_list",
+        signature: || json!({}),
+        access: Access::Read,
+    },
+    Method {
+        name: "pinned_origins_clear",
+        signature: || json!({ "rpId": "str", "actor": "str" }),
+        access: Access::Write,
+    },
+];
+
+/// Answers rpcd's `list` probe with every method and its argument signature.
+pub fn list() -> Result<String, AppError> {
+    let methods: Map<String, Value> = METHODS.iter().map(|m| (m.name.to_string(), (m.signature)())).collect();
+    Ok(serde_json::to_string(&methods)?)
+}
+
+fn methods_with(access: Access) -> Vec<&'static str> {
+    METHODS.iter().filter(|m| m.access == access).map(|m| m.name).collect()
+}
+
+/// rpcd ACL granting the login methods to unauthenticated sessions and the
+/// rest to the `webauthn-helper` group, for /usr/share/rpcd/acl.d/.
+pub fn acl(object: &str) -> Result<String, AppError> {
+    let acl = json!({
+        "unauthenticated": {
+            "description": "WebAuthn login",
+            "read": { "ubus": { object: methods_with(Access::Login) } },
+        },
+        "webauthn-helper": {
+            "description": "WebAuthn credential management",
+            "read": { "ubus": { object: methods_with(Access::Read) } },
+            "write": { "ubus": { object: methods_with(Access::Write) } },
+        },
+    });
+    Ok(serde_json::to_string_pretty(&acl)?)
+}
+
+fn required<'a>(args: &'a Value, name: &str) -> Result<&'a str, AppError> {
+    optional(args, name)?.ok_or_else(|| AppError::InvalidInput(format!("Missing argument: {}", name)))
+}
+
+fn optional<'a>(args: &'a Value, name: &str) -> Result<Option<&'a str>, AppError> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(AppError::InvalidInput(format!("Argument {} must be a string", name))),
+    }
+}
+
+fn strings<'a>(args: &'a Value, name: &str) -> Result<Vec<&'a str>, AppError> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .ok_or_else(|| AppError::InvalidInput(format!("Argument {} must be an array of strings", name)))
+            })
+            .collect(),
+        Some(_) => Err(AppError::InvalidInput(format!("Argument {} must be an array of strings", name))),
+    }
+}
+
+/// The authenticator response of a finish call, as the JSON the CLI reads
+/// from stdin. LuCI may pass it either as an object or pre-serialized.
+fn client_response(args: &Value) -> Result<String, AppError> {
+    match args.get("response") {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(response @ Value::Object(_)) => Ok(response.to_string()),
+        _ => Err(AppError::InvalidInput("Missing argument: response".to_string())),
+    }
+}
+
+fn client_info(args: &Value) -> Result<ClientInfo, AppError> {
+    ClientInfo::new(optional(args, "clientIp")?, optional(args, "userAgent")?)
+}
+
+/// Runs one method with the JSON arguments rpcd passes on stdin.
+pub fn call(storage: &dyn StorageProvider, config: &Config, method: &str, input: &mut dyn std::io::Read) -> Result<String, AppError> {
+    let data = read_client_input(input, config.max_input_bytes)?;
+    let args: Value = if data.trim().is_empty() {
+        json!({})
+    } else {
+        serde_json::from_str(&data).map_err(|e| AppError::InvalidInput(format!("Invalid call arguments: {}", e)))?
+    };
+    // The frontend's own request ID, so its logs line up with ours
+    if let Some(trace_id) = optional(&args, "traceId")? {
+        crate::trace::select(Some(trace_id))?;
+    }
+    // rpcd has no command line to pass --api-version on
+    match args.get("apiVersion") {
+        None | Some(Value::Null) => {}
+        Some(Value::Number(version)) => crate::schemas::select_api_version(Some(&version.to_string()))?,
+        Some(Value::String(version)) => crate::schemas::select_api_version(Some(version))?,
+        Some(_) => return Err(AppError::InvalidInput("apiVersion must be a number".to_string())),
+    }
+
+    let output = match method {
+        "register_begin" => super::register::register_begin(
+            storage,
+            config,
+            required(&args, "username")?,
+            required(&args, "rpId")?,
+            optional(&args, "userVerification")?.unwrap_or("preferred"),
+            args.get("allowReregistration").and_then(Value::as_bool).unwrap_or(false),
+            optional(&args, "userHandle")?,
+            optional(&args, "challenge")?,
+            optional(&args, "clientFormat")?,
+            optional(&args, "hints")?,
+            optional(&args, "attestation")?,
+            optional(&args, "rpName")?,
+        ),
+        "register_finish" => super::register::register_finish(
+            storage,
+            config,
+            required(&args, "challengeId")?,
+            required(&args, "origin")?,
+            required(&args, "deviceName")?,
+            args.get("requireAttestation").and_then(Value::as_bool).unwrap_or(false),
+            &client_info(&args)?,
+            &mut client_response(&args)?.as_bytes(),
+        ),
+        "login_begin" => super::login::login_begin(
+            storage,
+            required(&args, "username")?,
+            required(&args, "rpId")?,
+            optional(&args, "userVerification")?,
+            &strings(&args, "allowCredentials")?,
+            optional(&args, "challenge")?,
+            optional(&args, "clientFormat")?,
+        ),
+        "login_finish" => super::login::login_finish(
+            storage,
+            config,
+            required(&args, "challengeId")?,
+            required(&args, "origin")?,
+            &client_info(&args)?,
+            &mut client_response(&args)?.as_bytes(),
+        ),
+        "reauth_begin" => super::login::reauth_begin(
+            storage,
+            required(&args, "ubus_rpc_session")?,
+            required(&args, "username")?,
+            required(&args, "rpId")?,
+            required(&args, "credentialId")?,
+            optional(&args, "clientFormat")?,
+        ),
+        "reauth_finish" => super::login::reauth_finish(
+            storage,
+            config,
+            required(&args, "challengeId")?,
+            required(&args, "ubus_rpc_session")?,
+            required(&args, "origin")?,
+            &client_info(&args)?,
+            &mut client_response(&args)?.as_bytes(),
+        ),
+        "verify_assertion" => super::login::verify_assertion(
+            storage,
+            config,
+            required(&args, "credentialId")?,
+            required(&args, "challenge")?,
+            required(&args, "origin")?,
+            optional(&args, "rpId")?,
+            &mut client_response(&args)?.as_bytes(),
+        ),
+        "credential_list" => super::credential::list_credentials(
+            storage,
+            required(&args, "username")?,
+            optional(&args, "rpId")?,
+            OutputFormat::Json,
+        ),
+        "credential_show" => super::credential::show_credential(storage, required(&args, "id")?),
+        "credential_export_key" => {
+            super::credential::export_key(storage, required(&args, "id")?, optional(&args, "format")?.unwrap_or("pem"))
+        }
+        "credential_delete" => super::credential::delete_credential(storage, required(&args, "id")?, optional(&args, "actor")?),
+        "credential_update" => super::credential::update_credential(
+            storage,
+            required(&args, "id")?,
+            optional(&args, "name")?,
+            args.get("admin").and_then(Value::as_bool),
+            optional(&args, "actor")?,
+        ),
+        "credential_disable" => super::credential::set_enabled(storage, required(&args, "id")?, false, optional(&args, "actor")?),
+        "credential_enable" => super::credential::set_enabled(storage, required(&args, "id")?, true, optional(&args, "actor")?),
+        "credential_cleanup" => super::credential::cleanup_challenges(storage),
+        "credential_dedupe" => super::credential::dedupe(
+            storage,
+            args.get("remove").and_then(Value::as_bool).unwrap_or(false),
+            optional(&args, "actor")?,
+        ),
+        "user_list" => super::user::list(storage),
+        "user_set_roles" => super::user::set_roles(
+            storage,
+            required(&args, "username")?,
+            &strings(&args, "roles")?,
+            optional(&args, "actor")?,
+        ),
+        "user_set_groups" => super::user::set_groups(
+            storage,
+            required(&args, "username")?,
+            &strings(&args, "groups")?,
+            optional(&args, "actor")?,
+        ),
+        "health_check" => super::health::health_check(storage),
+        "stats" => super::stats::stats(storage),
+        "audit_list" => super::audit::list(
+            storage,
+            optional(&args, "action")?,
+            args.get("limit").and_then(Value::as_u64).map(|limit| limit as usize),
+        ),
+        "audit_verify" => super::audit::verify(storage),
+        "challenge_create" => {
+            let payload = args.get("payload").filter(|p| !p.is_null()).map(Value::to_string);
+            super::challenge::create(storage, required(&args, "type")?, payload.as_deref())
+        }
+        "challenge_consume" => super::challenge::consume(storage, required(&args, "challengeId")?, required(&args, "type")?),
+        "nonce_create" => super::nonce::create(storage, optional(&args, "bind")?),
+        "nonce_verify" => super::nonce::verify(storage, required(&args, "nonce")?, optional(&args, "bind")?),
+        "pinned_origins_list" => super::origins::list_pinned(storage, config),
+        "pinned_origins_clear" => super::origins::clear_pinned(storage, optional(&args, "rpId")?, optional(&args, "actor")?),
+        _ => Err(AppError::InvalidInput(format!("Unknown method: {}", method))),
+    }?;
+    if changes_store(method) {
+        super::backup::note_change(storage, config);
+    }
+    Ok(output)
+}
+
+/// Whether a successful call of `method` changed the credential store,
+/// which counts towards the next automatic backup.
+fn changes_store(method: &str) -> bool {
+    matches!(
+        method,
+        "register_finish"
+            | "credential_delete"
+            | "credential_update"
+            | "credential_disable"
+            | "credential_enable"
+            | "credential_dedupe"
+            | "user_set_roles"
+            | "user_set_groups"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_list_covers_every_method() {
+        let listed: Value = serde_json::from_str(&list().unwrap()).unwrap();
+        assert_eq!(listed.as_object().unwrap().len(), METHODS.len());
+        assert_eq!(listed["login_finish"]["response"], json!({}));
+
+        let acl: Value = serde_json::from_str(&acl("webauthn").unwrap()).unwrap();
+        assert_eq!(
+            acl["unauthenticated"]["read"]["ubus"]["webauthn"],
+            json!(["login_begin", "login_finish"])
+        );
+        let granted = ["read", "write"]
+            .iter()
+            .map(|section| acl["webauthn-helper"][section]["ubus"]["webauthn"].as_array().unwrap().len())
+            .sum::<usize>();
+        assert_eq!(granted + 2, METHODS.len());
+    }
+
+    #[test]
+    fn test_call_dispatches_with_json_args() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let args = br#"{"username":"root","rpId":"router.lan"}"#;
+        let begin: Value = serde_json::from_str(&call(&storage, &config, "register_begin", &mut args.as_slice()).unwrap()).unwrap();
+        assert!(begin["data"]["challengeId"].is_string());
+
+        let missing = call(&storage, &config, "credential_list", &mut b"{}".as_slice());
+        assert!(matches!(missing, Err(AppError::InvalidInput(_))));
+        let unknown = call(&storage, &config, "nope", &mut b"".as_slice());
+        assert!(matches!(unknown, Err(AppError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,48 @@
+use schemars::{schema_for, JsonSchema};
+
+use crate::errors::AppError;
+use crate::schemas::{
+    CredentialDetail, CredentialListItem, CredentialUpdateData, ErrorResponse, HealthCheckData, LoginFinishData, MaintenanceData,
+    RegisterFinishData, SuccessResponse,
+};
+
+fn schema<T: JsonSchema>() -> Result<serde_json::Value, AppError> {
+    Ok(serde_json::to_value(schema_for!(T))?)
+}
+
+/// JSON Schema (draft 2020-12) documents generated from the response types,
+/// keyed by type name. `SuccessResponse` leaves `data` open; the `*Data` and
+/// list item documents describe what it holds for the commands returning
+/// them.
+pub fn dump() -> Result<String, AppError> {
+    let schemas = serde_json::json!({
+        "SuccessResponse": schema::<SuccessResponse<serde_json::Value>>()?,
+        "ErrorResponse": schema::<ErrorResponse>()?,
+        "RegisterFinishData": schema::<RegisterFinishData>()?,
+        "LoginFinishData": schema::<LoginFinishData>()?,
+        "CredentialListItem": schema::<CredentialListItem>()?,
+        "CredentialDetail": schema::<CredentialDetail>()?,
+        "CredentialUpdateData": schema::<CredentialUpdateData>()?,
+        "HealthCheckData": schema::<HealthCheckData>()?,
+        "MaintenanceData": schema::<MaintenanceData>()?,
+    });
+    let response = SuccessResponse::new(serde_json::json!({ "schemas": schemas }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_describes_the_envelopes() {
+        let output: serde_json::Value = serde_json::from_str(&dump().unwrap()).unwrap();
+        let schemas = &output["data"]["schemas"];
+        let error = &schemas["ErrorResponse"];
+        assert!(error["properties"]["apiVersion"].is_object(), "{}", error);
+        assert!(error["$defs"]["ErrorDetail"]["properties"]["code"].is_object(), "{}", error);
+        // Renamed fields appear under their JSON names
+        assert!(schemas["CredentialListItem"]["properties"]["credentialId"].is_object());
+        assert_eq!(schemas["CredentialListItem"]["properties"]["transports"]["type"], "array");
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Instant;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::Value;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use sha2::{Digest, Sha256};
+
+use crate::commands::login::{login_begin, login_finish};
+use crate::commands::register::{random_user_handle, register_begin, register_finish};
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::storage::{ClientInfo, StorageProvider};
+
+/// RP the self-test registers with; `.invalid` can never be a real site
+pub(crate) const RP_ID: &str = "self-test.invalid";
+pub(crate) const ORIGIN: &str = "https://self-test.invalid";
+const USERNAME: &str = "self-test";
+
+/// authenticatorData flags: UP and UV, plus AT when a credential is attached
+const FLAGS_ASSERTION: u8 = 0x05;
+const FLAGS_ATTESTED: u8 = 0x45;
+
+fn cbor(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes).expect("writing CBOR to a Vec cannot fail");
+    bytes
+}
+
+/// A P-256 authenticator in software with a fresh key, answering challenges
+/// the way a browser passes a security key's responses on.
+pub struct SoftAuthenticator {
+    key: SigningKey,
+    credential_id: [u8; 16],
+    counter: u32,
+}
+
+impl SoftAuthenticator {
+    pub fn new() -> Self {
+        // 32 random bytes are a valid scalar all but ~2^-32 of the time
+        let key = loop {
+            if let Ok(key) = SigningKey::from_slice(&random_user_handle(32)) {
+                break key;
+            }
+        };
+        let mut credential_id = [0u8; 16];
+        credential_id.copy_from_slice(&random_user_handle(16));
+        Self {
+            key,
+            credential_id,
+            counter: 0,
+        }
+    }
+
+    fn client_data(ceremony: &str, challenge: &str, origin: &str) -> String {
+        serde_json::json!({
+            "type": ceremony,
+            "challenge": challenge,
+            "origin": origin,
+            "crossOrigin": false,
+        })
+        .to_string()
+    }
+
+    /// A registration response with "none" attestation.
+    pub fn register(&self, challenge: &str, origin: &str, rp_id: &str) -> String {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer((-7).into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (
+                Value::Integer((-2).into()),
+                Value::Bytes(point.x().expect("uncompressed").to_vec()),
+            ),
+            (
+                Value::Integer((-3).into()),
+                Value::Bytes(point.y().expect("uncompressed").to_vec()),
+            ),
+        ]);
+        let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        auth_data.push(FLAGS_ATTESTED);
+        auth_data.extend_from_slice(&self.counter.to_be_bytes());
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.credential_id);
+        auth_data.extend_from_slice(&cbor(&cose_key));
+        let attestation_object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (Value::Text("authData".into()), Value::Bytes(auth_data)),
+        ]);
+        serde_json::json!({
+            "id": URL_SAFE_NO_PAD.encode(self.credential_id),
+            "rawId": URL_SAFE_NO_PAD.encode(self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(Self::client_data("webauthn.create", challenge, origin)),
+                "attestationObject": URL_SAFE_NO_PAD.encode(cbor(&attestation_object)),
+                "transports": ["internal"],
+            },
+            "clientExtensionResults": {},
+        })
+        .to_string()
+    }
+
+    /// An assertion over `challenge`, advancing the signature counter.
+    pub fn sign(&mut self, challenge: &str, origin: &str, rp_id: &str) -> String {
+        self.counter += 1;
+        let client_data = Self::client_data("webauthn.get", challenge, origin);
+        let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        auth_data.push(FLAGS_ASSERTION);
+        auth_data.extend_from_slice(&self.counter.to_be_bytes());
+        let signature: Signature = self
+            .key
+            .sign(&[auth_data.as_slice(), &Sha256::digest(client_data.as_bytes())].concat());
+        serde_json::json!({
+            "id": URL_SAFE_NO_PAD.encode(self.credential_id),
+            "rawId": URL_SAFE_NO_PAD.encode(self.credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(&client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(&auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature.to_der().as_bytes()),
+                "userHandle": null,
+            },
+            "clientExtensionResults": {},
+        })
+        .to_string()
+    }
+}
+
+/// Runs one stage, returning its `data` on success and recording how it went.
+fn stage(stages: &mut Vec<serde_json::Value>, name: &str, run: impl FnOnce() -> Result<String, AppError>) -> Option<serde_json::Value> {
+    let started = Instant::now();
+    let result = run().and_then(|output| Ok(serde_json::from_str::<serde_json::Value>(&output)?));
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(mut output) => {
+            stages.push(serde_json::json!({ "stage": name, "status": "pass", "elapsedMs": elapsed_ms }));
+            Some(output["data"].take())
+        }
+        Err(e) => {
+            stages.push(serde_json::json!({
+                "stage": name,
+                "status": "fail",
+                "elapsedMs": elapsed_ms,
+                "error": { "code": e.error_code(), "message": e.to_string() },
+            }));
+            None
+        }
+    }
+}
+
+/// Registers and logs in with a software authenticator against `storage`,
+/// which should be empty and throwaway. Stages after a failed one are
+/// reported as skipped.
+pub fn run(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    // Defaults only: the installed policy and hooks are not what is tested
+    let config = Config {
+        policy_file: String::new(),
+        ..Config::default()
+    };
+    let client = ClientInfo::default();
+    let mut authenticator = SoftAuthenticator::new();
+    let mut stages = Vec::new();
+
+    let passed = (|| {
+        let begin = stage(&mut stages, "register-begin", || {
+            register_begin(
+                storage,
+                &config,
+                USERNAME,
+                RP_ID,
+                "preferred",
+                false,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        })?;
+        let response = authenticator.register(begin["publicKey"]["challenge"].as_str()?, ORIGIN, RP_ID);
+        stage(&mut stages, "register-finish", || {
+            register_finish(
+                storage,
+                &config,
+                begin["challengeId"].as_str().unwrap_or_default(),
+                ORIGIN,
+                "Self-test",
+                false,
+                &client,
+                &mut response.as_bytes(),
+            )
+        })?;
+        let begin = stage(&mut stages, "login-begin", || {
+            login_begin(storage, USERNAME, RP_ID, None, &[], None, None)
+        })?;
+        let response = authenticator.sign(begin["publicKey"]["challenge"].as_str()?, ORIGIN, RP_ID);
+        stage(&mut stages, "login-finish", || {
+            login_finish(
+                storage,
+                &config,
+                begin["challengeId"].as_str().unwrap_or_default(),
+                ORIGIN,
+                &client,
+                &mut response.as_bytes(),
+            )
+        })
+    })()
+    .is_some();
+
+    for name in ["register-begin", "register-finish", "login-begin", "login-finish"]
+        .iter()
+        .skip(stages.len())
+    {
+        stages.push(serde_json::json!({ "stage": name, "status": "skipped" }));
+    }
+    let response = SuccessResponse::new(serde_json::json!({
+        "passed": passed,
+        "stages": stages,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Calls `run` with a fresh file store in the temporary directory, laid out
+/// and encoded as `config` says and removed again afterwards, so the real
+/// credentials are never touched.
+#[cfg(unix)]
+pub fn in_temp_store(config: &Config, run: impl FnOnce(&dyn StorageProvider) -> Result<String, AppError>) -> Result<String, AppError> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let dir = std::env::temp_dir().join(format!("webauthn-helper-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let storage = crate::storage::FileStorage::with_paths(dir.join("credentials.json"), dir.join("challenges"))
+        .with_layout(config.storage_layout)
+        .with_format(config.storage_format);
+    let result = run(&storage);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_self_test_passes_every_stage() {
+        let output: serde_json::Value = serde_json::from_str(&run(&MemoryStorage::default()).unwrap()).unwrap();
+        let stages = output["data"]["stages"].as_array().unwrap();
+        assert_eq!(stages.len(), 4, "{}", output);
+        assert!(stages.iter().all(|s| s["status"] == "pass"), "{}", output);
+        assert_eq!(output["data"]["passed"], true);
+    }
+}
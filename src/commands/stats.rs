@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::storage::StorageProvider;
+
+/// Window of the recent activity counts (24 hours)
+const RECENT_SECS: u64 = 24 * 60 * 60;
+
+fn count(counts: &mut BTreeMap<String, usize>, key: &str) {
+    *counts.entry(key.to_string()).or_default() += 1;
+}
+
+/// Counts users and credentials by transport, authenticator model, key
+/// algorithm and backup eligibility, and the activity of the last 24 hours,
+/// for fleet dashboards. A credential counts once for every transport it
+/// reported.
+pub fn stats(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let store = storage.load_credentials()?;
+    let since = crate::clock::now_secs().saturating_sub(RECENT_SECS);
+    let recent = |time: &str| crate::clock::parse_rfc3339(time).is_some_and(|time| time >= since);
+
+    let mut by_transport = BTreeMap::new();
+    let mut by_aaguid = BTreeMap::new();
+    let mut by_algorithm = BTreeMap::new();
+    let (mut synced, mut disabled, mut registered, mut used) = (0, 0, 0, 0);
+    let credentials: Vec<_> = store.users.values().flat_map(|u| &u.credentials).collect();
+    for credential in &credentials {
+        let transports: Vec<String> = serde_json::from_value(serde_json::to_value(credential.auth_transports())?)?;
+        if transports.is_empty() {
+            count(&mut by_transport, "unknown");
+        }
+        for transport in &transports {
+            count(&mut by_transport, transport);
+        }
+        count(
+            &mut by_aaguid,
+            if credential.aaguid.is_empty() { "unknown" } else { &credential.aaguid },
+        );
+        let algorithm = crate::commands::login::decode_static_state(credential)
+            .map(|state| crate::public_key::algorithm(&state.credential_public_key))
+            .unwrap_or("unknown");
+        count(&mut by_algorithm, algorithm);
+        synced += usize::from(credential.backup_eligible);
+        disabled += usize::from(!credential.enabled);
+        registered += usize::from(recent(&credential.created_at));
+        used += usize::from(credential.last_used_at.as_deref().is_some_and(recent));
+    }
+
+    let mut audited = BTreeMap::new();
+    for entry in storage.load_audit_log()?.iter().filter(|entry| recent(&entry.time)) {
+        count(&mut audited, &entry.action);
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "users": store.users.len(),
+        "credentials": credentials.len(),
+        "disabledCredentials": disabled,
+        "byTransport": by_transport,
+        "byAaguid": by_aaguid,
+        "byAlgorithm": by_algorithm,
+        "byBackupEligibility": {
+            "eligible": synced,
+            "notEligible": credentials.len() - synced,
+        },
+        "last24h": {
+            "registrations": registered,
+            "credentialsUsed": used,
+            "auditActions": audited,
+        },
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::public_key::PublicKey;
+    use crate::storage::{CredentialStore, MemoryStorage, StoredCredential, UserRecord};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    #[test]
+    fn test_stats_breaks_credentials_down() {
+        let storage = MemoryStorage::default();
+        let now = crate::clock::now_rfc3339();
+        let mut record = serde_json::to_value(crate::wal::tests::credential("fresh")).unwrap();
+        record["transports"] = serde_json::json!(["usb", "nfc"]);
+        let mut fresh: StoredCredential = serde_json::from_value(record).unwrap();
+        let point = p256::ecdsa::SigningKey::from_slice(&[7u8; 32])
+            .unwrap()
+            .verifying_key()
+            .to_encoded_point(false);
+        let key = PublicKey::ec2(1, point.x().unwrap(), point.y().unwrap()).unwrap();
+        fresh.static_state = URL_SAFE_NO_PAD.encode(key.static_state().unwrap());
+        fresh.created_at = now.clone();
+        fresh.last_used_at = Some(now);
+        fresh.aaguid = "cb69481e-8ff7-4039-93ec-0a2729a154a8".to_string();
+        fresh.backup_eligible = true;
+        let mut old = crate::wal::tests::credential("old");
+        old.created_at = "2020-01-01T00:00:00Z".to_string();
+        old.enabled = false;
+        storage
+            .save_credentials(&CredentialStore {
+                users: [(
+                    "root".to_string(),
+                    UserRecord {
+                        user_id: "dXNlcg".to_string(),
+                        recovery_codes: vec![],
+                        roles: vec![],
+                        groups: vec![],
+                        credentials: vec![fresh, old],
+                    },
+                )]
+                .into(),
+            })
+            .unwrap();
+        crate::audit::record(&storage, Some("admin"), "credential_rename", "fresh", serde_json::json!({})).unwrap();
+
+        let stats: serde_json::Value = serde_json::from_str(&stats(&storage).unwrap()).unwrap();
+        let data = &stats["data"];
+        assert_eq!((data["users"].as_u64(), data["credentials"].as_u64()), (Some(1), Some(2)));
+        assert_eq!(data["disabledCredentials"], 1);
+        assert_eq!(data["byTransport"], serde_json::json!({ "nfc": 1, "usb": 1, "unknown": 1 }));
+        assert_eq!(data["byAaguid"]["unknown"], 1);
+        assert_eq!(data["byAlgorithm"], serde_json::json!({ "ES256": 1, "unknown": 1 }));
+        assert_eq!(data["byAaguid"]["cb69481e-8ff7-4039-93ec-0a2729a154a8"], 1);
+        assert_eq!(
+            data["byBackupEligibility"],
+            serde_json::json!({ "eligible": 1, "notEligible": 1 })
+        );
+        let recent = &data["last24h"];
+        assert_eq!(
+            (recent["registrations"].as_u64(), recent["credentialsUsed"].as_u64()),
+            (Some(1), Some(1))
+        );
+        assert_eq!(recent["auditActions"], serde_json::json!({ "credential_rename": 1 }));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::errors::AppError;
+use crate::schemas::{MaintenanceData, SuccessResponse};
+use crate::storage::StorageProvider;
+
+pub fn fix_permissions(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let fixed = storage.fix_permissions()?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "path": storage.credentials_path().to_string_lossy(),
+        "fixed": fixed
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Reports the size of the store and how much `compact` could reclaim.
+pub fn stats(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let stats = storage.store_stats()?;
+    let store = storage.load_credentials()?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "path": storage.credentials_path().to_string_lossy(),
+        "readOnly": storage.read_only(),
+        "storeBytes": stats.store_bytes,
+        "users": store.users.len(),
+        "credentials": store.users.values().map(|u| u.credentials.len()).sum::<usize>(),
+        "wal": {
+            "entries": stats.wal_entries,
+            "bytes": stats.wal_bytes,
+            "supersededEntries": stats.superseded_entries,
+        },
+        "needsRewrite": stats.needs_rewrite,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Folds the write-ahead log, and rewrites the store only if it is not in
+/// the configured layout or encoding yet, so flash is not written needlessly.
+pub fn compact(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let before = storage.store_stats()?;
+    let folded = storage.compact()?;
+    let rewritten = folded == 0 && before.needs_rewrite;
+    if rewritten {
+        storage.modify_credentials(&mut |_| Ok(()))?;
+    }
+    let after = storage.store_stats()?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "foldedEntries": folded,
+        "rewritten": folded > 0 || rewritten,
+        "bytesBefore": before.store_bytes + before.wal_bytes,
+        "bytesAfter": after.store_bytes + after.wal_bytes,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Runs every periodic housekeeping task once: expired challenge and replay
+/// cache cleanup, write-ahead log compaction and audit log rotation.
+pub fn run_maintenance(storage: &dyn StorageProvider) -> Result<MaintenanceData, AppError> {
+    crate::audit::rotate(storage)?;
+    Ok(MaintenanceData {
+        removed_challenges: storage.cleanup_challenges()?,
+        folded_entries: storage.compact()?,
+    })
+}
+
+pub fn maintain(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let response = SuccessResponse::new(run_maintenance(storage)?);
+    Ok(serde_json::to_string(&response)?)
+}
@@ -0,0 +1,219 @@
+use crate::audit;
+use crate::errors::AppError;
+use crate::schemas::SuccessResponse;
+use crate::storage::{update_user, StorageProvider, UserRecord};
+
+/// Most roles or groups a user can hold
+const MAX_NAMES: usize = 32;
+
+/// Longest role or group name
+const MAX_NAME_LEN: usize = 64;
+
+/// A list of names kept per user and returned on login.
+struct NameList {
+    /// Singular, for messages
+    noun: &'static str,
+    /// Output key, and audit detail of the new list
+    key: &'static str,
+    /// Audit detail of the replaced list
+    old_key: &'static str,
+    action: &'static str,
+    field: fn(&mut UserRecord) -> &mut Vec<String>,
+}
+
+const ROLES: NameList = NameList {
+    noun: "role",
+    key: "roles",
+    old_key: "oldRoles",
+    action: "user_roles",
+    field: |record| &mut record.roles,
+};
+
+const GROUPS: NameList = NameList {
+    noun: "group",
+    key: "groups",
+    old_key: "oldGroups",
+    action: "user_groups",
+    field: |record| &mut record.groups,
+};
+
+/// Names are short words such as `admin` or `luci:network`, so they can go
+/// into session data and ACL names unquoted.
+fn check_name(list: &NameList, name: &str) -> Result<(), AppError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'));
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::InvalidInput(format!(
+            "Invalid {} {:?}: use up to {} letters, digits, '-', '_', '.' or ':'",
+            list.noun, name, MAX_NAME_LEN
+        )))
+    }
+}
+
+/// Lists every user with the number of credentials and the roles and
+/// groups they hold, sorted by name.
+pub fn list(storage: &dyn StorageProvider) -> Result<String, AppError> {
+    let store = storage.load_credentials()?;
+    let mut users: Vec<_> = store.users.iter().collect();
+    users.sort_by_key(|(username, _)| *username);
+    let items: Vec<serde_json::Value> = users
+        .into_iter()
+        .map(|(username, record)| {
+            serde_json::json!({
+                "username": username,
+                "credentials": record.credentials.len(),
+                "roles": record.roles,
+                "groups": record.groups,
+            })
+        })
+        .collect();
+
+    let response = SuccessResponse::new(items);
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Replaces one of `username`'s name lists; no names clears it.
+fn replace_names(
+    storage: &dyn StorageProvider,
+    list: &NameList,
+    username: &str,
+    names: &[&str],
+    actor: Option<&str>,
+) -> Result<String, AppError> {
+    let mut new_names: Vec<String> = Vec::new();
+    for name in names {
+        check_name(list, name)?;
+        if !new_names.iter().any(|n| n == name) {
+            new_names.push(name.to_string());
+        }
+    }
+    if new_names.len() > MAX_NAMES {
+        return Err(AppError::InvalidInput(format!(
+            "A user can hold at most {} {}",
+            MAX_NAMES, list.key
+        )));
+    }
+    if storage.read_only() {
+        return Err(crate::storage::read_only_error());
+    }
+    let old_names = update_user(storage, username, |record| {
+        Ok(std::mem::replace((list.field)(record), new_names.clone()))
+    })?;
+    let changed = old_names != new_names;
+    if changed {
+        audit::record(
+            storage,
+            actor,
+            list.action,
+            username,
+            serde_json::json!({ list.old_key: old_names, list.key: new_names }),
+        )?;
+    }
+
+    let response = SuccessResponse::new(serde_json::json!({
+        "username": username,
+        list.key: new_names,
+        "changed": changed,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// Replaces `username`'s roles, which `login-finish` returns as a hint for
+/// the frontend's authorization.
+pub fn set_roles(storage: &dyn StorageProvider, username: &str, roles: &[&str], actor: Option<&str>) -> Result<String, AppError> {
+    replace_names(storage, &ROLES, username, roles, actor)
+}
+
+/// Replaces `username`'s group memberships, which `login-finish` returns so
+/// rpcd session ACLs can be derived from them.
+pub fn set_groups(storage: &dyn StorageProvider, username: &str, groups: &[&str], actor: Option<&str>) -> Result<String, AppError> {
+    replace_names(storage, &GROUPS, username, groups, actor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{CredentialStore, MemoryStorage, UserRecord};
+
+    #[test]
+    fn test_set_roles() {
+        let storage = MemoryStorage::default();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                credentials: vec![crate::wal::tests::credential("a")],
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+
+        let set: serde_json::Value =
+            serde_json::from_str(&set_roles(&storage, "root", &["admin", "luci:network", "admin"], Some("admin")).unwrap()).unwrap();
+        assert_eq!(set["data"]["roles"], serde_json::json!(["admin", "luci:network"]));
+        assert_eq!(set["data"]["changed"], true);
+        let listed: serde_json::Value = serde_json::from_str(&list(&storage).unwrap()).unwrap();
+        assert_eq!(
+            listed["data"],
+            serde_json::json!([{ "username": "root", "credentials": 1, "roles": ["admin", "luci:network"], "groups": [] }])
+        );
+
+        // Setting the same roles again is not audited
+        set_roles(&storage, "root", &["admin", "luci:network"], None).unwrap();
+        assert_eq!(storage.load_audit_log().unwrap().len(), 1);
+
+        assert!(matches!(
+            set_roles(&storage, "root", &["has space"], None),
+            Err(AppError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            set_roles(&storage, "nobody", &["admin"], None),
+            Err(AppError::UserNotFound(_))
+        ));
+        set_roles(&storage, "root", &[], None).unwrap();
+        assert!(storage.load_user("root").unwrap().unwrap().roles.is_empty());
+    }
+
+    #[test]
+    fn test_set_groups_leaves_roles_alone() {
+        let storage = MemoryStorage::default();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                credentials: vec![],
+                recovery_codes: vec![],
+                roles: vec!["admin".to_string()],
+                groups: vec![],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+
+        let set: serde_json::Value = serde_json::from_str(&set_groups(&storage, "root", &["network", "system"], None).unwrap()).unwrap();
+        assert_eq!(set["data"]["groups"], serde_json::json!(["network", "system"]));
+        let record = storage.load_user("root").unwrap().unwrap();
+        assert_eq!(
+            (record.roles, record.groups),
+            (vec!["admin".to_string()], vec!["network".to_string(), "system".to_string()])
+        );
+        let entry = storage.load_audit_log().unwrap().pop().unwrap();
+        assert_eq!(entry.action, "user_groups");
+        assert_eq!(
+            entry.details,
+            serde_json::json!({ "oldGroups": [], "groups": ["network", "system"] })
+        );
+        assert!(matches!(
+            set_groups(&storage, "root", &["a/b"], None),
+            Err(AppError::InvalidInput(_))
+        ));
+    }
+}
@@ -0,0 +1,335 @@
+use std::io::{BufRead, Write};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::mds;
+use crate::origin::check_origin;
+use crate::storage::{ClientInfo, StorageProvider};
+
+/// authenticatorData flag bits (WebAuthn §6.1)
+const FLAG_UP: u8 = 0x01;
+const FLAG_UV: u8 = 0x04;
+const FLAG_BE: u8 = 0x08;
+const FLAG_BS: u8 = 0x10;
+const FLAG_AT: u8 = 0x40;
+
+/// One line of the verification walkthrough: passed, failed, or just a note.
+enum Step {
+    Pass(String),
+    Fail(String),
+    Info(String),
+}
+
+fn b64url(value: Option<&serde_json::Value>) -> Option<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(value?.as_str()?.trim_end_matches('=')).ok()
+}
+
+/// Checks the pasted response the way register-finish will, one property at a
+/// time, so the first thing that is wrong can be named.
+fn explain(response: &serde_json::Value, challenge: &str, origin: &str, rp_id: &str, config: &Config) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let mut check = |ok: bool, pass: String, fail: String| steps.push(if ok { Step::Pass(pass) } else { Step::Fail(fail) });
+
+    let credential_type = response.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+    check(
+        credential_type == "public-key",
+        "credential type is public-key".to_string(),
+        format!("credential type is {:?}, expected public-key", credential_type),
+    );
+
+    let inner = response.get("response");
+    let client_data: Option<serde_json::Value> =
+        b64url(inner.and_then(|r| r.get("clientDataJSON"))).and_then(|bytes| serde_json::from_slice(&bytes).ok());
+    match &client_data {
+        None => check(
+            false,
+            String::new(),
+            "response.clientDataJSON is missing or not base64url JSON".to_string(),
+        ),
+        Some(client_data) => {
+            let field = |key: &str| client_data.get(key).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            let ceremony = field("type");
+            check(
+                ceremony == "webauthn.create",
+                "clientData.type is webauthn.create".to_string(),
+                format!("clientData.type is {:?}; a login response was pasted?", ceremony),
+            );
+            check(
+                field("challenge") == challenge,
+                "clientData.challenge matches the issued challenge".to_string(),
+                "clientData.challenge does not match; the response belongs to another ceremony".to_string(),
+            );
+            let client_origin = field("origin");
+            check(
+                client_origin == origin,
+                format!("clientData.origin is {}", client_origin),
+                format!("clientData.origin is {}, but --origin is {}", client_origin, origin),
+            );
+            let allowed = check_origin(origin, rp_id, config);
+            check(
+                allowed.is_ok(),
+                format!("origin {} is allowed for RP {}", origin, rp_id),
+                format!("origin rules reject {} for RP {}", origin, rp_id),
+            );
+        }
+    }
+
+    let object =
+        b64url(inner.and_then(|r| r.get("attestationObject"))).and_then(|bytes| ciborium::from_reader::<Value, _>(bytes.as_slice()).ok());
+    let Some(Value::Map(object)) = object else {
+        check(
+            false,
+            String::new(),
+            "response.attestationObject is missing or not base64url CBOR".to_string(),
+        );
+        return steps;
+    };
+    let get = |key: &str| object.iter().find(|(k, _)| k.as_text() == Some(key)).map(|(_, v)| v);
+    let fmt = get("fmt").and_then(Value::as_text).unwrap_or("?");
+    steps.push(Step::Info(format!("attestation format is {}", fmt)));
+
+    let auth_data = get("authData").and_then(Value::as_bytes).map(Vec::as_slice).unwrap_or_default();
+    if auth_data.len() < 37 {
+        steps.push(Step::Fail(format!(
+            "authenticatorData is {} bytes, expected at least 37",
+            auth_data.len()
+        )));
+        return steps;
+    }
+    let rp_id_hash_ok = auth_data[..32] == Sha256::digest(rp_id.as_bytes())[..];
+    let flags = auth_data[32];
+    let mut check = |ok: bool, pass: String, fail: String| steps.push(if ok { Step::Pass(pass) } else { Step::Fail(fail) });
+    check(
+        rp_id_hash_ok,
+        format!("rpIdHash is SHA-256 of {}", rp_id),
+        format!("rpIdHash is not SHA-256 of {}; the browser used another RP ID", rp_id),
+    );
+    check(
+        flags & FLAG_UP != 0,
+        "user presence (UP) flag is set".to_string(),
+        "user presence (UP) flag is not set".to_string(),
+    );
+    check(
+        flags & FLAG_AT != 0,
+        "attested credential data (AT) flag is set".to_string(),
+        "attested credential data (AT) flag is not set".to_string(),
+    );
+    steps.push(Step::Info(format!(
+        "user verified (UV): {}, backup eligible (BE): {}, backed up (BS): {}",
+        flags & FLAG_UV != 0,
+        flags & FLAG_BE != 0,
+        flags & FLAG_BS != 0
+    )));
+
+    if flags & FLAG_AT != 0 && auth_data.len() >= 53 {
+        let aaguid = uuid::Uuid::from_slice(&auth_data[37..53])
+            .map(|u| u.to_string())
+            .unwrap_or_default();
+        let name = mds::describe(None, &aaguid).unwrap_or_else(|| "unknown model".to_string());
+        steps.push(Step::Info(format!("authenticator AAGUID is {} ({})", aaguid, name)));
+    }
+    steps
+}
+
+/// Reads lines until they form one JSON value; the response is usually pasted
+/// as a single line but may be pretty-printed.
+fn read_pasted_json(input: &mut dyn BufRead, max_bytes: usize) -> Result<(String, serde_json::Value), AppError> {
+    let mut pasted = String::new();
+    loop {
+        if input.read_line(&mut pasted)? == 0 {
+            return Err(AppError::InvalidInput("No client response was pasted".to_string()));
+        }
+        if pasted.len() > max_bytes {
+            return Err(AppError::InvalidInput(format!("Input exceeds the {} byte limit", max_bytes)));
+        }
+        if pasted.trim().is_empty() {
+            pasted.clear();
+            continue;
+        }
+        if let Ok(value) = serde_json::from_str(&pasted) {
+            return Ok((pasted, value));
+        }
+    }
+}
+
+/// A registration started by the wizard, waiting for the browser's response.
+struct Pending {
+    challenge_id: String,
+    challenge: String,
+}
+
+fn start(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    username: &str,
+    rp_id: &str,
+    origin: &str,
+    out: &mut dyn Write,
+) -> Result<Pending, AppError> {
+    let begin = super::register::register_begin(
+        storage,
+        config,
+        username,
+        rp_id,
+        "preferred",
+        false,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    let begin: serde_json::Value = serde_json::from_str(&begin)?;
+    let options = &begin["data"]["publicKey"];
+    let pending = Pending {
+        challenge_id: begin["data"]["challengeId"].as_str().unwrap_or_default().to_string(),
+        challenge: options["challenge"].as_str().unwrap_or_default().to_string(),
+    };
+
+    writeln!(out, "Step 1/3: register-begin")?;
+    writeln!(
+        out,
+        "  Created challenge {} for user {} on RP {}.",
+        pending.challenge_id, username, rp_id
+    )?;
+    writeln!(
+        out,
+        "  In the browser console on {}, run:\n\n    \
+         const cred = await navigator.credentials.create({{ publicKey: PublicKeyCredential.parseCreationOptionsFromJSON(OPTIONS) }});\n    \
+         console.log(JSON.stringify(cred.toJSON()));\n\n  \
+         with OPTIONS set to:\n",
+        origin
+    )?;
+    writeln!(out, "{}\n", serde_json::to_string_pretty(options)?)?;
+    writeln!(out, "Step 2/3: paste the JSON the browser printed, then press Enter:")?;
+    out.flush()?;
+    Ok(pending)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn finish(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    pending: &Pending,
+    rp_id: &str,
+    origin: &str,
+    device_name: &str,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<String, AppError> {
+    let (pasted, response) = read_pasted_json(input, config.max_input_bytes)?;
+
+    writeln!(out, "\nStep 3/3: register-finish")?;
+    for step in explain(&response, &pending.challenge, origin, rp_id, config) {
+        match step {
+            Step::Pass(text) => writeln!(out, "  ok    {}", text)?,
+            Step::Fail(text) => writeln!(out, "  FAIL  {}", text)?,
+            Step::Info(text) => writeln!(out, "  info  {}", text)?,
+        }
+    }
+    match super::register::register_finish(
+        storage,
+        config,
+        &pending.challenge_id,
+        origin,
+        device_name,
+        false,
+        &ClientInfo::default(),
+        &mut pasted.as_bytes(),
+    ) {
+        Ok(result) => {
+            writeln!(out, "  ok    signature, attestation and credential verified; credential stored")?;
+            Ok(result)
+        }
+        Err(err) => {
+            writeln!(out, "  FAIL  {}", err)?;
+            Err(err)
+        }
+    }
+}
+
+/// Walks through one registration by hand: begin, paste the browser's
+/// response, then finish with each verification step explained on `out`.
+/// Returns the register-finish output.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    storage: &dyn StorageProvider,
+    config: &Config,
+    username: &str,
+    rp_id: &str,
+    origin: &str,
+    device_name: &str,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<String, AppError> {
+    let pending = start(storage, config, username, rp_id, origin, out)?;
+    finish(storage, config, &pending, rp_id, origin, device_name, input, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn registration_response(challenge: &str, origin: &str, rp_id: &str) -> String {
+        crate::commands::register::tests::registration_response(challenge, origin, rp_id, FLAG_UP | FLAG_UV | FLAG_AT)
+    }
+
+    #[test]
+    fn test_explain_names_the_mismatch() {
+        let config = Config::default();
+        let response: serde_json::Value =
+            serde_json::from_str(&registration_response("c2FtZQ", "https://router.lan", "router.lan")).unwrap();
+
+        let steps = explain(&response, "c2FtZQ", "https://router.lan", "router.lan", &config);
+        assert!(steps.iter().all(|step| !matches!(step, Step::Fail(_))));
+
+        let steps = explain(&response, "b3RoZXI", "https://router.lan", "other.lan", &config);
+        let failures: Vec<&str> = steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Fail(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failures.len(), 3, "{:?}", failures);
+        assert!(failures[0].contains("challenge does not match"));
+        assert!(failures[1].contains("origin rules reject"));
+        assert!(failures[2].contains("rpIdHash"));
+    }
+
+    #[test]
+    fn test_wizard_registers_a_pasted_response() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+        let mut transcript = Vec::new();
+
+        let pending = start(&storage, &config, "root", "router.lan", "https://router.lan", &mut transcript).unwrap();
+        let pasted = registration_response(&pending.challenge, "https://router.lan", "router.lan") + "\n";
+        let result = finish(
+            &storage,
+            &config,
+            &pending,
+            "router.lan",
+            "https://router.lan",
+            "Test key",
+            &mut std::io::Cursor::new(pasted),
+            &mut transcript,
+        )
+        .unwrap();
+
+        let result: serde_json::Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(result["success"], true);
+        assert_eq!(storage.load_user("root").unwrap().unwrap().credentials.len(), 1);
+        let transcript = String::from_utf8(transcript).unwrap();
+        assert!(transcript.contains(&pending.challenge));
+        assert!(!transcript.contains("FAIL"), "{}", transcript);
+        assert!(transcript.contains("credential stored"));
+    }
+}
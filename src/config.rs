@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::AppError;
+use crate::origin::OriginRules;
+
+/// Default location of the helper's own configuration file
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/webauthn/config.json";
+
+/// Socket `serve` listens on unless `--socket` is given
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/webauthn-helper.sock";
+
+/// User handle sizes (in bytes) the helper can register and authenticate
+pub const USER_HANDLE_LENS: [usize; 3] = [16, 32, 64];
+
+// ─── Configuration File (snake_case) ───
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Mode of the challenge directory, as an octal string
+    pub challenge_dir_mode: String,
+    /// Mode of individual challenge files, as an octal string
+    pub challenge_file_mode: String,
+    /// Unprivileged user to switch to after startup when started as root
+    pub run_as_user: Option<String>,
+    /// Group to switch to (defaults to the primary group of `run_as_user`)
+    pub run_as_group: Option<String>,
+    /// Confine file access to the store, challenge and log directories with
+    /// Landlock where the kernel supports it
+    pub landlock: bool,
+    /// Origin matching rules applied to every RP without an override
+    pub origin: OriginRules,
+    /// Per-RP origin rules, keyed by RP ID; replaces `origin` for that RP
+    pub rp_origins: HashMap<String, OriginRules>,
+    /// On-disk layout of the credential store
+    pub storage_layout: StorageLayout,
+    /// Encoding used when writing the credential store
+    pub storage_format: StorageFormat,
+    /// Locations of the single-file credential store in order of preference;
+    /// empty means /etc/webauthn/data/credentials.json
+    pub credentials_paths: Vec<String>,
+    /// Append login usage updates to a write-ahead log instead of rewriting
+    /// the credential store on every login
+    pub usage_wal: bool,
+    /// Compact the log into the store once it holds this many entries
+    /// (0 = only on `storage compact`)
+    pub wal_max_entries: usize,
+    /// The credential store is on a read-only filesystem: logins record
+    /// usage state in `usage_side_file`, anything else that would change the
+    /// store fails with READ_ONLY
+    pub read_only_store: bool,
+    /// Writable log of login usage updates while `read_only_store` is set
+    pub usage_side_file: String,
+    /// Size in bytes of user handles generated for new users
+    pub user_handle_len: usize,
+    /// Name shown for the RP in the browser's registration prompt (`rp.name`);
+    /// unset shows the RP ID
+    pub rp_name: Option<String>,
+    /// Keep each registration's attestation statement with the credential
+    pub store_attestation: bool,
+    /// Largest request body accepted on stdin, in bytes
+    pub max_input_bytes: usize,
+    /// Seconds to wait for the request body on stdin (0 = wait indefinitely)
+    pub input_timeout_secs: u64,
+    /// Seconds between housekeeping runs in `serve` mode (0 = only at shutdown)
+    pub maintenance_interval_secs: u64,
+    /// Pin the first verified origin of each RP and reject any other afterwards
+    pub tofu_origins: bool,
+    /// Where `mds update` downloads the FIDO Metadata Service blob
+    pub mds_url: String,
+    /// Trust anchor the blob's certificate chain must lead to (PEM or DER)
+    pub mds_root_cert: String,
+    /// Reject registrations of backup-eligible (synced) credentials
+    pub device_bound_only: bool,
+    /// Consecutive failed logins after which a credential is disabled (0 = never)
+    pub max_failed_attempts: u32,
+    /// Registration and login policy file; a missing file imposes no policy
+    pub policy_file: String,
+    /// Executable run after each successful registration
+    pub on_register: Option<String>,
+    /// Executable run after each successful login
+    pub on_login_success: Option<String>,
+    /// Executable run after each failed login
+    pub on_login_failure: Option<String>,
+    /// Remote syslog collector, as `udp://host:port` or `tcp://host:port`
+    pub syslog_server: Option<String>,
+    /// Facility of the messages sent to `syslog_server`
+    pub syslog_facility: String,
+    /// Rotate the audit log once it holds this many bytes (0 = never)
+    pub audit_rotate_bytes: u64,
+    /// Rotate the audit log once its first entry is this many days old (0 = never)
+    pub audit_rotate_days: u64,
+    /// Compressed rotated audit logs kept; older ones are deleted
+    pub audit_max_files: usize,
+    /// Delete rotated audit logs this many days old, except the newest one
+    /// (0 = keep them until `audit_max_files` is reached)
+    pub audit_max_days: u64,
+    /// Where `backup push` sends the credential store, as an http(s) URL
+    pub backup_url: Option<String>,
+    /// File holding the bearer token sent with each backup
+    pub backup_token_file: Option<String>,
+    /// File holding the passphrase backups are encrypted with; unset sends
+    /// them in the clear
+    pub backup_passphrase_file: Option<String>,
+    /// Push a backup after this many changes to the credential store
+    /// (0 = only on `backup push`)
+    pub backup_push_every: u64,
+    /// Origins from `--origins-file`, accepted for every RP
+    #[serde(skip)]
+    pub extra_origins: Vec<String>,
+}
+
+/// How credentials are laid out on disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageLayout {
+    /// Every user in a single credentials.json
+    #[default]
+    Single,
+    /// One file per user under `users/`, so commands that concern a single
+    /// user only read that user's records
+    PerUser,
+}
+
+/// Encoding of credential files. Either encoding is read regardless of this
+/// setting; it only decides what is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageFormat {
+    /// Pretty-printed JSON
+    #[default]
+    Json,
+    /// Compact CBOR, smaller and cheaper to (de)serialize
+    Cbor,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            challenge_dir_mode: "0700".to_string(),
+            challenge_file_mode: "0600".to_string(),
+            run_as_user: None,
+            run_as_group: None,
+            landlock: true,
+            origin: OriginRules::default(),
+            rp_origins: HashMap::new(),
+            storage_layout: StorageLayout::default(),
+            storage_format: StorageFormat::default(),
+            credentials_paths: Vec::new(),
+            usage_wal: false,
+            wal_max_entries: 256,
+            read_only_store: false,
+            usage_side_file: "/tmp/webauthn/usage.wal".to_string(),
+            user_handle_len: 64,
+            rp_name: None,
+            store_attestation: false,
+            max_input_bytes: 1024 * 1024,
+            input_timeout_secs: 10,
+            maintenance_interval_secs: 60,
+            tofu_origins: false,
+            mds_url: "https://mds3.fidoalliance.org/".to_string(),
+            mds_root_cert: "/etc/ssl/certs/GlobalSign_Root_CA_-_R3.crt".to_string(),
+            device_bound_only: false,
+            max_failed_attempts: 0,
+            policy_file: "/etc/webauthn/policy.json".to_string(),
+            on_register: None,
+            on_login_success: None,
+            on_login_failure: None,
+            syslog_server: None,
+            syslog_facility: "authpriv".to_string(),
+            audit_rotate_bytes: 0,
+            audit_rotate_days: 0,
+            audit_max_files: 5,
+            audit_max_days: 0,
+            backup_url: None,
+            backup_token_file: None,
+            backup_passphrase_file: None,
+            backup_push_every: 0,
+            extra_origins: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path`. A missing file yields the defaults.
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(path)?;
+        let config: Config =
+            serde_json::from_str(&data).map_err(|e| AppError::Config(format!("Failed to parse {}: {}", path.display(), e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), AppError> {
+        let dir_mode = parse_mode("challenge_dir_mode", &self.challenge_dir_mode)?;
+        if dir_mode & 0o700 != 0o700 || dir_mode & 0o077 != 0 {
+            return Err(AppError::Config(
+                "challenge_dir_mode must grant the owner rwx and nothing to group or others".to_string(),
+            ));
+        }
+        let file_mode = parse_mode("challenge_file_mode", &self.challenge_file_mode)?;
+        if file_mode & 0o600 != 0o600 || file_mode & 0o077 != 0 {
+            return Err(AppError::Config(
+                "challenge_file_mode must grant the owner rw and nothing to group or others".to_string(),
+            ));
+        }
+        if !USER_HANDLE_LENS.contains(&self.user_handle_len) {
+            return Err(AppError::Config("user_handle_len must be 16, 32 or 64".to_string()));
+        }
+        if self.max_input_bytes == 0 {
+            return Err(AppError::Config("max_input_bytes must be greater than 0".to_string()));
+        }
+        if self.run_as_group.is_some() && self.run_as_user.is_none() {
+            return Err(AppError::Config("run_as_group requires run_as_user".to_string()));
+        }
+        if let Some(path) = self.credentials_paths.iter().find(|p| !Path::new(p).is_absolute()) {
+            return Err(AppError::Config(format!("credentials_paths must be absolute, got {}", path)));
+        }
+        if self.credentials_paths.len() > 1 && self.storage_layout == StorageLayout::PerUser {
+            return Err(AppError::Config(
+                "Several credentials_paths require the single storage_layout".to_string(),
+            ));
+        }
+        if self.read_only_store && !Path::new(&self.usage_side_file).is_absolute() {
+            return Err(AppError::Config("usage_side_file must be an absolute path".to_string()));
+        }
+        if self.audit_max_files == 0 {
+            return Err(AppError::Config(
+                "audit_max_files must be at least 1, so the audit chain can continue".to_string(),
+            ));
+        }
+        if let Some(url) = self
+            .backup_url
+            .as_deref()
+            .filter(|url| !url.starts_with("https://") && !url.starts_with("http://"))
+        {
+            return Err(AppError::Config(format!("backup_url must be an http(s) URL, got {}", url)));
+        }
+        if self.backup_push_every > 0 && self.backup_url.is_none() {
+            return Err(AppError::Config("backup_push_every requires backup_url".to_string()));
+        }
+        crate::syslog::validate(self)?;
+        self.origin.validate()?;
+        for rules in self.rp_origins.values() {
+            rules.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn origin_rules_for(&self, rp_id: &str) -> &OriginRules {
+        self.rp_origins.get(rp_id).unwrap_or(&self.origin)
+    }
+
+    pub fn challenge_dir_mode(&self) -> u32 {
+        parse_mode("challenge_dir_mode", &self.challenge_dir_mode).unwrap_or(0o700)
+    }
+
+    pub fn challenge_file_mode(&self) -> u32 {
+        parse_mode("challenge_file_mode", &self.challenge_file_mode).unwrap_or(0o600)
+    }
+}
+
+/// Parses an octal permission string such as "0600".
+fn parse_mode(key: &str, value: &str) -> Result<u32, AppError> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(AppError::Config(format!(
+            "{} must be an octal mode like \"0600\", got \"{}\"",
+            key, value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_missing_config_uses_defaults() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::load(&dir.path().join("config.json")).unwrap();
+        assert_eq!(config.challenge_dir_mode(), 0o700);
+        assert_eq!(config.challenge_file_mode(), 0o600);
+    }
+
+    #[test]
+    fn test_mode_override() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, r#"{"challenge_file_mode":"0700"}"#).unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.challenge_file_mode(), 0o700);
+        assert_eq!(config.challenge_dir_mode(), 0o700);
+
+        // Challenges are secrets: nothing for group or others
+        for mode in [r#"{"challenge_file_mode":"0640"}"#, r#"{"challenge_dir_mode":"0750"}"#] {
+            fs::write(&path, mode).unwrap();
+            assert!(Config::load(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_invalid_config_rejected() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.json");
+
+        fs::write(&path, r#"{"challenge_file_mode":"rw-------"}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+
+        fs::write(&path, r#"{"challenge_file_mode":"0400"}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+
+        fs::write(&path, r#"{"user_handle_len":20}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+
+        fs::write(&path, r#"{"storage_layout":"sharded"}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+
+        fs::write(&path, r#"{"no_such_key":true}"#).unwrap();
+        assert!(Config::load(&path).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::errors::AppError;
+
+/// Seed of the byte stream replacing the system RNG
+pub const SEED_ENV: &str = "WEBAUTHN_HELPER_SEED";
+/// Fixed current time: Unix seconds or `YYYY-MM-DDTHH:MM:SSZ`
+pub const NOW_ENV: &str = "WEBAUTHN_HELPER_NOW";
+
+/// Blocks of the byte stream handed out so far
+static BLOCKS: AtomicU64 = AtomicU64::new(0);
+
+fn seed() -> Option<String> {
+    std::env::var(SEED_ENV).ok().filter(|seed| !seed.is_empty())
+}
+
+fn parse_now(value: &str) -> Option<u64> {
+    value.parse().ok().or_else(|| crate::clock::parse_rfc3339(value))
+}
+
+/// Checks the injected time and warns that the output is predictable; call
+/// once at startup.
+pub fn init() -> Result<(), AppError> {
+    let now = std::env::var(NOW_ENV).ok();
+    if let Some(now) = &now {
+        if parse_now(now).is_none() {
+            return Err(AppError::Config(format!(
+                "{} must be Unix seconds or YYYY-MM-DDTHH:MM:SSZ, got {}",
+                NOW_ENV, now
+            )));
+        }
+    }
+    if seed().is_some() || now.is_some() {
+        crate::syslog::warning(&format!(
+            "{} or {} is set: challenges, IDs and times are predictable; for tests only",
+            SEED_ENV, NOW_ENV
+        ));
+    }
+    Ok(())
+}
+
+/// `len` bytes of SHA-256(seed ‖ block number) blocks, continuing where the
+/// previous call stopped, or `None` without a seed.
+pub fn bytes(len: usize) -> Option<Vec<u8>> {
+    let seed = seed()?;
+    let mut bytes = Vec::with_capacity(len);
+    while bytes.len() < len {
+        let block = BLOCKS.fetch_add(1, Ordering::SeqCst);
+        let mut hasher = Sha256::new();
+        hasher.update(seed.as_bytes());
+        hasher.update(block.to_be_bytes());
+        bytes.extend_from_slice(&hasher.finalize());
+    }
+    bytes.truncate(len);
+    Some(bytes)
+}
+
+/// A seeded WebAuthn challenge (base64url) to swap in for the library's own.
+pub fn challenge() -> Option<String> {
+    bytes(crate::challenge::CHALLENGE_LEN).map(|bytes| URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// The injected current time in Unix seconds.
+pub fn now() -> Option<u64> {
+    std::env::var(NOW_ENV).ok().as_deref().and_then(parse_now)
+}
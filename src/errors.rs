@@ -5,12 +5,18 @@ pub enum AppError {
     #[error("Challenge not found: {0}")]
     ChallengeNotFound(String),
 
+    #[error("Challenge expired: {0}")]
+    ChallengeExpired(String),
+
     #[error("User not found: {0}")]
     UserNotFound(String),
 
     #[error("Credential not found: {0}")]
     CredentialNotFound(String),
 
+    #[error("Credential disabled: {0}")]
+    CredentialDisabled(String),
+
     #[error("Invalid origin: {0}")]
     InvalidOrigin(String),
 
@@ -28,20 +34,144 @@ pub enum AppError {
 
     #[error("Invalid input: {0}")]
     InvalidInput(String),
+
+    #[error("Insecure permissions: {0}")]
+    InsecurePermissions(String),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    #[error("Privilege error: {0}")]
+    Privilege(String),
+
+    #[error("Replay detected: {0}")]
+    ReplayDetected(String),
+
+    #[error("Metadata error: {0}")]
+    Metadata(String),
+
+    #[error("Policy violation: {reason}")]
+    PolicyViolation { rule: &'static str, reason: String },
+
+    #[error("Read-only: {0}")]
+    ReadOnly(String),
+
+    #[error("Backup error: {0}")]
+    Backup(String),
+
+    #[error("Clock skew: {reason}")]
+    ClockSkew { skew_secs: i64, reason: String },
+
+    #[error("Untrusted attestation: {0}")]
+    AttestationUntrusted(String),
 }
 
 impl AppError {
     pub fn error_code(&self) -> &str {
         match self {
             AppError::ChallengeNotFound(_) => "CHALLENGE_NOT_FOUND",
+            AppError::ChallengeExpired(_) => "CHALLENGE_EXPIRED",
             AppError::UserNotFound(_) => "USER_NOT_FOUND",
             AppError::CredentialNotFound(_) => "CREDENTIAL_NOT_FOUND",
+            AppError::CredentialDisabled(_) => "CREDENTIAL_DISABLED",
             AppError::InvalidOrigin(_) => "INVALID_ORIGIN",
             AppError::WebAuthn(_) => "WEBAUTHN_ERROR",
             AppError::Storage(_) => "STORAGE_ERROR",
             AppError::Json(_) => "JSON_ERROR",
             AppError::Io(_) => "IO_ERROR",
             AppError::InvalidInput(_) => "INVALID_INPUT",
+            AppError::InsecurePermissions(_) => "INSECURE_PERMISSIONS",
+            AppError::Config(_) => "CONFIG_ERROR",
+            AppError::Privilege(_) => "PRIVILEGE_ERROR",
+            AppError::ReplayDetected(_) => "REPLAY_DETECTED",
+            AppError::Metadata(_) => "METADATA_ERROR",
+            AppError::PolicyViolation { .. } => "POLICY_VIOLATION",
+            AppError::ReadOnly(_) => "READ_ONLY",
+            AppError::Backup(_) => "BACKUP_ERROR",
+            AppError::ClockSkew { .. } => "CLOCK_SKEW",
+            AppError::AttestationUntrusted(_) => "ATTESTATION_UNTRUSTED",
+        }
+    }
+
+    /// The message without the prefix naming the kind of error.
+    pub fn detail(&self) -> String {
+        match self {
+            AppError::ChallengeNotFound(detail)
+            | AppError::ChallengeExpired(detail)
+            | AppError::UserNotFound(detail)
+            | AppError::CredentialNotFound(detail)
+            | AppError::CredentialDisabled(detail)
+            | AppError::InvalidOrigin(detail)
+            | AppError::WebAuthn(detail)
+            | AppError::Storage(detail)
+            | AppError::InvalidInput(detail)
+            | AppError::InsecurePermissions(detail)
+            | AppError::Config(detail)
+            | AppError::Privilege(detail)
+            | AppError::ReplayDetected(detail)
+            | AppError::Metadata(detail)
+            | AppError::ReadOnly(detail)
+            | AppError::Backup(detail)
+            | AppError::AttestationUntrusted(detail)
+            | AppError::PolicyViolation { reason: detail, .. }
+            | AppError::ClockSkew { reason: detail, .. } => detail.clone(),
+            AppError::Json(e) => e.to_string(),
+            AppError::Io(e) => e.to_string(),
+        }
+    }
+
+    /// Who has to act: `client` for a malformed or stale request, `auth` for
+    /// an authenticator response that failed verification, `server` for the
+    /// helper's own setup and storage.
+    pub fn category(&self) -> &'static str {
+        match self {
+            AppError::ChallengeNotFound(_)
+            | AppError::ChallengeExpired(_)
+            | AppError::UserNotFound(_)
+            | AppError::CredentialNotFound(_)
+            | AppError::InvalidOrigin(_)
+            | AppError::Json(_)
+            | AppError::InvalidInput(_) => "client",
+            AppError::CredentialDisabled(_)
+            | AppError::WebAuthn(_)
+            | AppError::ReplayDetected(_)
+            | AppError::PolicyViolation { .. }
+            | AppError::AttestationUntrusted(_) => "auth",
+            AppError::Storage(_)
+            | AppError::Io(_)
+            | AppError::InsecurePermissions(_)
+            | AppError::Config(_)
+            | AppError::Privilege(_)
+            | AppError::Metadata(_)
+            | AppError::ReadOnly(_)
+            | AppError::Backup(_)
+            | AppError::ClockSkew { .. } => "server",
+        }
+    }
+
+    /// Whether the same request may succeed when the ceremony is started
+    /// over: stale challenges, and storage that was busy or briefly failing.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            AppError::ChallengeNotFound(_) | AppError::ChallengeExpired(_) | AppError::Storage(_) | AppError::Io(_)
+        )
+    }
+
+    /// Policy rule that was broken, for `POLICY_VIOLATION` errors.
+    pub fn rule(&self) -> Option<&'static str> {
+        match self {
+            AppError::PolicyViolation { rule, .. } => Some(rule),
+            _ => None,
+        }
+    }
+
+    /// Seconds a challenge's creation time lies ahead of the clock
+    /// (negative: behind), for `CLOCK_SKEW` errors.
+    pub fn skew_secs(&self) -> Option<i64> {
+        match self {
+            AppError::ClockSkew { skew_secs, .. } => Some(*skew_secs),
+            _ => None,
         }
     }
 }
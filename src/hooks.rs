@@ -0,0 +1,111 @@
+use std::process::{Child, Command, Stdio};
+
+use crate::config::Config;
+use crate::syslog::{self, Severity};
+
+/// Authentication events a hook script can be attached to.
+#[derive(Clone, Copy)]
+pub enum Event {
+    Register,
+    LoginSuccess,
+    LoginFailure,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Register => "register",
+            Event::LoginSuccess => "login_success",
+            Event::LoginFailure => "login_failure",
+        }
+    }
+
+    fn severity(self) -> Severity {
+        match self {
+            Event::LoginFailure => Severity::Warning,
+            _ => Severity::Info,
+        }
+    }
+
+    fn script(self, config: &Config) -> Option<&str> {
+        match self {
+            Event::Register => config.on_register.as_deref(),
+            Event::LoginSuccess => config.on_login_success.as_deref(),
+            Event::LoginFailure => config.on_login_failure.as_deref(),
+        }
+    }
+}
+
+/// Starts `script` with the event in `WEBAUTHN_EVENT`, the trace ID in
+/// `WEBAUTHN_TRACE_ID` and each detail in `WEBAUTHN_<NAME>`. It gets no
+/// stdin or stdout, so it cannot disturb the JSON output; its stderr is
+/// ours.
+fn spawn(script: &str, event: Event, details: &[(&str, &str)]) -> std::io::Result<Child> {
+    let mut command = Command::new(script);
+    command
+        .env("WEBAUTHN_EVENT", event.name())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    if let Some(trace_id) = crate::trace::current() {
+        command.env("WEBAUTHN_TRACE_ID", trace_id);
+    }
+    for (name, value) in details {
+        command.env(format!("WEBAUTHN_{}", name), value);
+    }
+    command.spawn()
+}
+
+/// Forwards `event` to the remote syslog collector and runs the hook
+/// configured for it, if any, without waiting for it. A hook that cannot be
+/// started only produces a warning.
+pub fn fire(config: &Config, event: Event, details: &[(&str, &str)]) {
+    syslog::event(event.name(), event.severity(), details);
+    let Some(script) = event.script(config) else {
+        return;
+    };
+    match spawn(script, event, details) {
+        // Reaped in the background so `serve` does not collect zombies
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => syslog::warning(&format!("failed to run {} hook {}: {}", event.name(), script, e)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_hook_receives_event_details() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("hook.sh");
+        let out = dir.path().join("env");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$WEBAUTHN_EVENT $WEBAUTHN_USERNAME $WEBAUTHN_ERROR_CODE\" > {}\n",
+                out.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut child = spawn(
+            script.to_str().unwrap(),
+            Event::LoginFailure,
+            &[("USERNAME", "root"), ("ERROR_CODE", "WEBAUTHN_ERROR")],
+        )
+        .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "login_failure root WEBAUTHN_ERROR\n");
+
+        // A missing script is not an error for the ceremony
+        let config = Config {
+            on_register: Some(dir.path().join("missing").to_string_lossy().into_owned()),
+            ..Config::default()
+        };
+        fire(&config, Event::Register, &[]);
+    }
+}
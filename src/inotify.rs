@@ -0,0 +1,121 @@
+use std::ffi::{CString, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::AppError;
+
+/// Events that mean a file in a watched directory was written, replaced or
+/// removed
+const CHANGE_EVENTS: u32 =
+    libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+
+/// Size of the fixed part of an event; the file name follows it
+const EVENT_HEADER_LEN: usize = std::mem::size_of::<libc::inotify_event>();
+
+/// Reports changes other processes make to a set of files, so a
+/// long-running server can drop what it keeps of them in memory. The
+/// directories holding the files are watched, which also sees a file being
+/// replaced by a rename or created after the watch started.
+pub struct Watcher {
+    fd: OwnedFd,
+    names: Vec<OsString>,
+}
+
+impl Watcher {
+    /// Watches `files` whose directory exists. Returns `None` when none does.
+    pub fn new(files: &[PathBuf]) -> Result<Option<Self>, AppError> {
+        // SAFETY: plain syscall without pointers
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(AppError::Config(format!(
+                "inotify_init1 failed: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        // SAFETY: fd was just created and is owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut watched = false;
+        for dir in files.iter().filter_map(|file| file.parent()).filter(|dir| dir.is_dir()) {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            let cdir =
+                CString::new(dir.as_os_str().as_bytes()).map_err(|_| AppError::Config(format!("Invalid path: {}", dir.display())))?;
+            // SAFETY: cdir is a valid NUL-terminated string; watching a directory twice reuses its watch
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), cdir.as_ptr(), CHANGE_EVENTS) } < 0 {
+                return Err(AppError::Config(format!(
+                    "Watching {} failed: {}",
+                    dir.display(),
+                    io::Error::last_os_error()
+                )));
+            }
+            watched = true;
+        }
+        let names = files.iter().filter_map(|file| file.file_name()).map(OsString::from).collect();
+        Ok(watched.then_some(Self { fd, names }))
+    }
+
+    /// Descriptor that becomes readable when events are pending.
+    pub fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// Consumes the pending events; true when one concerned a watched file
+    /// or events were lost because the queue overflowed.
+    pub fn changed(&self) -> bool {
+        let mut changed = false;
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: buf is valid for writes of its length
+            let read = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if read <= 0 {
+                return changed;
+            }
+            let events = &buf[..read as usize];
+            let mut offset = 0;
+            while offset + EVENT_HEADER_LEN <= events.len() {
+                // SAFETY: the kernel wrote a whole event header at offset; it may be unaligned in buf
+                let event = unsafe { std::ptr::read_unaligned(events[offset..].as_ptr() as *const libc::inotify_event) };
+                let name_start = offset + EVENT_HEADER_LEN;
+                let name_end = (name_start + event.len as usize).min(events.len());
+                let name = &events[name_start..name_end];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                if event.mask & libc::IN_Q_OVERFLOW != 0 || self.names.iter().any(|watched| watched.as_bytes() == name) {
+                    changed = true;
+                }
+                offset = name_end;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reports_changes_to_watched_files_only() {
+        let dir = TempDir::new().unwrap();
+        let store = dir.path().join("credentials.json");
+        let watcher = Watcher::new(&[store.clone(), dir.path().join("missing/copy.json")])
+            .unwrap()
+            .unwrap();
+        assert!(!watcher.changed());
+
+        fs::write(dir.path().join("audit.log"), "x").unwrap();
+        assert!(!watcher.changed());
+        fs::write(&store, "{}").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        // Replaced by a rename
+        fs::write(dir.path().join(".tmp"), "{}").unwrap();
+        fs::rename(dir.path().join(".tmp"), &store).unwrap();
+        assert!(watcher.changed());
+
+        assert!(Watcher::new(&[dir.path().join("missing/copy.json")]).unwrap().is_none());
+    }
+}
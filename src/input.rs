@@ -0,0 +1,153 @@
+use std::io::Read;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+/// The request body's descriptor with an overall deadline, so a caller that
+/// never sends a body fails the command instead of blocking its CGI worker
+/// forever.
+#[cfg(unix)]
+pub struct DeadlineReader {
+    /// Stdin, or the descriptor given with `--fd`
+    fd: libc::c_int,
+    /// None when `timeout_secs` is 0, i.e. wait indefinitely
+    deadline: Option<Instant>,
+    timeout_secs: u64,
+}
+
+#[cfg(unix)]
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let mut fds = libc::pollfd {
+                    fd: self.fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let timeout_ms = remaining.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+                // SAFETY: fds is a single valid pollfd for the duration of the call.
+                match unsafe { libc::poll(&mut fds, 1, timeout_ms) } {
+                    0 => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            match self.fd {
+                                libc::STDIN_FILENO => format!("No request body received on stdin within {} seconds", self.timeout_secs),
+                                fd => format!("No request body received on fd {} within {} seconds", fd, self.timeout_secs),
+                            },
+                        ))
+                    }
+                    n if n > 0 => break,
+                    _ => {
+                        let err = std::io::Error::last_os_error();
+                        if err.kind() != std::io::ErrorKind::Interrupted {
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+        }
+        // Read the descriptor directly: std's buffered Stdin could hold data
+        // that poll() no longer reports as readable.
+        // SAFETY: buf is valid for writes of buf.len() bytes.
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+/// Where to read a request body from: stdin, or descriptor `fd` (from
+/// `--fd`), giving up after `timeout_secs` (0 = no deadline).
+#[cfg(unix)]
+pub fn body(fd: Option<i32>, timeout_secs: u64) -> Result<DeadlineReader, AppError> {
+    let fd = fd.unwrap_or(libc::STDIN_FILENO);
+    // SAFETY: F_GETFD only inspects the descriptor table
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(AppError::InvalidInput(format!(
+            "Cannot read the request body from fd {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(DeadlineReader {
+        fd,
+        deadline: (timeout_secs > 0).then(|| Instant::now() + Duration::from_secs(timeout_secs)),
+        timeout_secs,
+    })
+}
+
+/// Stdin for reading a request body; there is no deadline or `--fd` on this
+/// platform.
+#[cfg(not(unix))]
+pub fn body(fd: Option<i32>, _timeout_secs: u64) -> Result<std::io::Stdin, AppError> {
+    if fd.is_some() {
+        return Err(AppError::Config("--fd requires a Unix platform".to_string()));
+    }
+    Ok(std::io::stdin())
+}
+
+/// Reads a client request body, refusing anything larger than `max_bytes`
+/// so an oversized CGI body cannot exhaust memory.
+pub fn read_client_input(reader: &mut dyn Read, max_bytes: usize) -> Result<String, AppError> {
+    let mut data = String::new();
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_string(&mut data)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::TimedOut => AppError::InvalidInput(e.to_string()),
+            _ => e.into(),
+        })?;
+    if data.len() > max_bytes {
+        return Err(AppError::InvalidInput(format!("Input exceeds the {} byte limit", max_bytes)));
+    }
+    Ok(data)
+}
+
+/// Runs `work`, which parses and verifies a client response, in a child
+/// confined by seccomp when built with the `seccomp` feature on Linux, and
+/// in this process otherwise. Callers act only on what it returns and never
+/// parse the response themselves.
+pub fn untrusted<T: Serialize + DeserializeOwned>(work: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+    #[cfg(all(feature = "seccomp", target_os = "linux"))]
+    return crate::sandbox::confined(work);
+    #[cfg(not(all(feature = "seccomp", target_os = "linux")))]
+    work()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_client_input_limit() {
+        assert_eq!(read_client_input(&mut b"{}".as_slice(), 2).unwrap(), "{}");
+        let result = read_client_input(&mut b"{ }".as_slice(), 2);
+        assert!(matches!(result, Err(AppError::InvalidInput(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_body_reads_the_given_descriptor() {
+        use std::io::Write;
+        use std::os::fd::FromRawFd;
+
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for both ends of the pipe
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: the write end was just created and is owned here
+        let mut writer = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+        writer.write_all(b"{}").unwrap();
+        drop(writer);
+        assert_eq!(read_client_input(&mut body(Some(fds[0]), 5).unwrap(), 16).unwrap(), "{}");
+        // SAFETY: the read end is owned here and no longer used
+        drop(unsafe { std::fs::File::from_raw_fd(fds[0]) });
+        assert!(matches!(body(Some(i32::MAX), 5), Err(AppError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,192 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::errors::AppError;
+
+/// Filesystem access rights (linux/landlock.h), by the ABI that added them
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Every right of ABI 1, up to `LANDLOCK_ACCESS_FS_MAKE_SYM`
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// Rights that can be granted on a file rather than a directory
+const FILE_RIGHTS: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: u32 = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+fn os_error(what: &str) -> AppError {
+    AppError::Privilege(format!("{} failed: {}", what, io::Error::last_os_error()))
+}
+
+/// The Landlock ABI version the kernel speaks, or `None` without Landlock.
+fn abi_version() -> Option<i64> {
+    // SAFETY: the version query takes no attribute
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    (version > 0).then_some(version as i64)
+}
+
+fn add_rule(ruleset: i32, path: &Path, access: u64) -> Result<(), AppError> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|_| AppError::Config(format!("Invalid path: {}", path.display())))?;
+    // SAFETY: cpath is a valid NUL-terminated string
+    let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(os_error(&format!("Opening {} for Landlock", path.display())));
+    }
+    let access = if path.is_dir() { access } else { access & FILE_RIGHTS };
+    let rule = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: fd,
+    };
+    // SAFETY: rule outlives the call; fd is closed right after
+    let added = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset,
+            RULE_PATH_BENEATH,
+            &rule as *const PathBeneathAttr,
+            0u32,
+        )
+    };
+    // SAFETY: fd was opened above
+    unsafe { libc::close(fd) };
+    if added != 0 {
+        return Err(os_error(&format!("Adding a Landlock rule for {}", path.display())));
+    }
+    Ok(())
+}
+
+/// Confines the calling thread, and every thread and process it starts
+/// later, to `writable` (the storage's directories and the like), plus
+/// read access to `readable` and the files the configuration names. With
+/// `exec`, the rest of the filesystem stays readable and executable (but
+/// not writable) for hooks and downloads. Returns false when the kernel
+/// has no Landlock.
+pub fn confine(config: &Config, readable: &[PathBuf], writable: &[PathBuf], exec: bool) -> Result<bool, AppError> {
+    let Some(abi) = abi_version() else {
+        return Ok(false);
+    };
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: attr outlives the call and its size is passed along
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    if ruleset < 0 {
+        return Err(os_error("Creating the Landlock ruleset"));
+    }
+    let ruleset = ruleset as i32;
+
+    let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    let mut rules: Vec<(PathBuf, u64)> = writable.iter().map(|dir| (dir.clone(), handled)).collect();
+    for path in [&config.policy_file, &config.mds_root_cert] {
+        if !path.is_empty() {
+            rules.push((PathBuf::from(path), read));
+        }
+    }
+    rules.extend(readable.iter().map(|path| (path.clone(), read)));
+    rules.push((PathBuf::from("/proc/sys/kernel/hostname"), read));
+    rules.push((PathBuf::from("/dev/null"), read | ACCESS_FS_WRITE_FILE));
+    if exec {
+        rules.push((PathBuf::from("/"), read | ACCESS_FS_EXECUTE));
+    }
+
+    // Rules attach to open files, so a path that is missing gets no access
+    let result = rules
+        .iter()
+        .filter(|(path, _)| path.exists())
+        .try_for_each(|(path, access)| add_rule(ruleset, path, *access));
+    let result = result.and_then(|()| {
+        // SAFETY: plain prctl and Landlock calls on the ruleset created above
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(os_error("prctl(PR_SET_NO_NEW_PRIVS)"));
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32) != 0 {
+                return Err(os_error("Enforcing the Landlock ruleset"));
+            }
+        }
+        Ok(())
+    });
+    // SAFETY: ruleset was created above and is no longer needed
+    unsafe { libc::close(ruleset) };
+    result.map(|()| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_confined_thread_only_reaches_the_store() {
+        let dir = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        std::fs::write(outside.path().join("secret"), "x").unwrap();
+        let storage = FileStorage::with_paths(dir.path().join("store/credentials.json"), dir.path().join("tmp/challenges"));
+        let writable = storage.writable_dirs(&dir.path().join("config.json")).unwrap();
+        std::fs::write(dir.path().join("config.json"), "{}").unwrap();
+        let config = Config {
+            policy_file: String::new(),
+            mds_root_cert: String::new(),
+            ..Config::default()
+        };
+        let outside = outside.path().to_path_buf();
+        let store = dir.path().to_path_buf();
+        // Landlock binds the calling thread only, so the test harness stays free
+        std::thread::spawn(move || {
+            if !confine(&config, &storage.readable_files(), &writable, false).unwrap() {
+                return;
+            }
+            std::fs::write(store.join("store/credentials.json"), "{}").unwrap();
+            std::fs::write(store.join("tmp/challenges/id.json"), "{}").unwrap();
+            // Only the directories themselves, not what holds them
+            assert!(std::fs::write(store.join("tmp/other"), "x").is_err());
+            assert!(std::fs::write(store.join("config.json"), "x").is_err());
+            assert!(std::fs::read(outside.join("secret")).is_err());
+            assert!(std::fs::write(outside.join("new"), "x").is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
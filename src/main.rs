@@ -1,70 +1,543 @@
+// Without Unix file storage only the verification logic (and its tests) is built
+#![cfg_attr(not(unix), allow(dead_code))]
+
+#[cfg(feature = "aaguid-names")]
+mod aaguid_names;
+mod attestation;
+mod audit;
+mod challenge;
 mod cli;
+mod clock;
 mod commands;
+mod config;
+#[cfg(feature = "deterministic")]
+mod deterministic;
 mod errors;
+mod hooks;
+#[cfg(target_os = "linux")]
+mod inotify;
+mod input;
+#[cfg(target_os = "linux")]
+mod landlock;
+mod mds;
+mod origin;
+mod output;
+mod policy;
+#[cfg(unix)]
+mod privileges;
+mod public_key;
+#[cfg(feature = "qr")]
+mod qr;
+#[cfg(all(feature = "seccomp", target_os = "linux"))]
+mod sandbox;
 mod schemas;
+mod secret;
+#[cfg(unix)]
+mod serve;
 mod storage;
+mod syslog;
+mod trace;
+mod wal;
 
-use cli::{Cli, Commands, CredentialAction};
+use cli::{
+    AuditAction, BackupAction, ChallengeAction, Cli, Commands, ConfigAction, CredentialAction, MdsAction, NonceAction, PinnedOriginsAction,
+    RecoveryAction, StorageAction, UserAction,
+};
+use config::Config;
 use errors::AppError;
 use schemas::ErrorResponse;
-use storage::FileStorage;
-
-fn run() -> Result<String, AppError> {
-    let cli = Cli::parse();
-    let storage = FileStorage::new();
+use storage::{ClientInfo, StorageProvider};
 
+fn run(cli: Cli) -> Result<String, AppError> {
+    schemas::select_api_version(cli.api_version.as_deref())?;
+    #[cfg(feature = "deterministic")]
+    deterministic::init()?;
+    trace::select(cli.trace_id.as_deref())?;
+    let config_path = cli.config_path.as_deref().unwrap_or(config::DEFAULT_CONFIG_PATH);
+    // Before loading the file or dropping privileges, so a bad value in it
+    // can still be corrected
+    if let Commands::Config { action } = &cli.command {
+        let path = std::path::Path::new(config_path);
+        return match action {
+            ConfigAction::List => commands::config::list(path),
+            ConfigAction::Get { key } => commands::config::get(path, key),
+            ConfigAction::Set { key, value } => commands::config::set(path, key, value),
+        };
+    }
+    let mut config = Config::load(std::path::Path::new(config_path))?;
+    if let Some(origins_file) = &cli.origins_file {
+        config.extra_origins = origin::load_origins_file(std::path::Path::new(origins_file))?;
+    }
+    syslog::init(&config)?;
+    // Scratch-store commands need neither the real store nor privileges
+    #[cfg(unix)]
     match cli.command {
+        Commands::SelfTest => return commands::selftest::in_temp_store(&config, commands::selftest::run),
+        Commands::Bench { iterations } => {
+            return commands::selftest::in_temp_store(&config, |storage| commands::bench::run(storage, iterations))
+        }
+        _ => {}
+    }
+    #[cfg(not(unix))]
+    if let Commands::SelfTest | Commands::Bench { .. } = cli.command {
+        return Err(AppError::Config("A scratch store requires a Unix platform".to_string()));
+    }
+    // Bound while still root, before open_storage drops privileges
+    #[cfg(unix)]
+    let listener = match &cli.command {
+        Commands::Serve { socket, .. } => match serve::activated_listener()? {
+            Some(listener) => Some(listener),
+            None => Some(serve::bind(std::path::Path::new(socket))?),
+        },
+        _ => None,
+    };
+    let storage = open_storage(&config, &cli)?;
+    let storage = storage.as_ref();
+    let changes_store = cli.command.changes_store();
+
+    let output = match cli.command {
         Commands::RegisterBegin {
             username,
             rp_id,
             user_verification,
-        } => commands::register::register_begin(&storage, &username, &rp_id, &user_verification),
+            allow_reregistration,
+            user_handle,
+            challenge,
+            client_format,
+            hints,
+            qr,
+            enroll_url,
+            attestation,
+            rp_name,
+        } => {
+            #[cfg(feature = "qr")]
+            let qr = qr.as_deref().map(|mode| qr::mode(mode, hints.as_deref())).transpose()?;
+            #[cfg(not(feature = "qr"))]
+            if qr.is_some() || enroll_url.is_some() {
+                return Err(AppError::Config("Built without QR code support (the qr feature)".to_string()));
+            }
+            let output = commands::register::register_begin(
+                storage,
+                &config,
+                &username,
+                &rp_id,
+                &user_verification,
+                allow_reregistration,
+                user_handle.as_deref(),
+                challenge.as_deref(),
+                client_format.as_deref(),
+                hints.as_deref(),
+                attestation.as_deref(),
+                rp_name.as_deref(),
+            )?;
+            #[cfg(feature = "qr")]
+            if let Some(mode) = qr {
+                return qr::attach(&output, mode, enroll_url.as_deref(), &mut std::io::stderr());
+            }
+            Ok(output)
+        }
 
         Commands::RegisterFinish {
             challenge_id,
             origin,
             device_name,
-        } => commands::register::register_finish(&storage, &challenge_id, &origin, &device_name),
+            require_attestation,
+            client_ip,
+            user_agent,
+        } => ClientInfo::new(client_ip.as_deref(), user_agent.as_deref()).and_then(|client| {
+            commands::register::register_finish(
+                storage,
+                &config,
+                &challenge_id,
+                &origin,
+                &device_name,
+                require_attestation,
+                &client,
+                &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+            )
+        }),
+
+        Commands::LoginBegin {
+            username,
+            rp_id,
+            user_verification,
+            allow_credentials,
+            challenge,
+            client_format,
+        } => commands::login::login_begin(
+            storage,
+            &username,
+            &rp_id,
+            user_verification.as_deref(),
+            &allow_credentials.iter().map(String::as_str).collect::<Vec<_>>(),
+            challenge.as_deref(),
+            client_format.as_deref(),
+        ),
+
+        Commands::LoginFinish {
+            challenge_id,
+            origin,
+            client_ip,
+            user_agent,
+        } => ClientInfo::new(client_ip.as_deref(), user_agent.as_deref()).and_then(|client| {
+            commands::login::login_finish(
+                storage,
+                &config,
+                &challenge_id,
+                &origin,
+                &client,
+                &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+            )
+        }),
 
-        Commands::LoginBegin { username, rp_id } => commands::login::login_begin(&storage, &username, &rp_id),
+        Commands::ReauthBegin {
+            session,
+            username,
+            rp_id,
+            credential_id,
+            client_format,
+        } => commands::login::reauth_begin(storage, &session, &username, &rp_id, &credential_id, client_format.as_deref()),
 
-        Commands::LoginFinish { challenge_id, origin } => commands::login::login_finish(&storage, &challenge_id, &origin),
+        Commands::ReauthFinish {
+            challenge_id,
+            session,
+            origin,
+            client_ip,
+            user_agent,
+        } => ClientInfo::new(client_ip.as_deref(), user_agent.as_deref()).and_then(|client| {
+            commands::login::reauth_finish(
+                storage,
+                &config,
+                &challenge_id,
+                &session,
+                &origin,
+                &client,
+                &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+            )
+        }),
+
+        Commands::VerifyAssertion {
+            credential_id,
+            challenge,
+            origin,
+            rp_id,
+        } => commands::login::verify_assertion(
+            storage,
+            &config,
+            &credential_id,
+            &challenge,
+            &origin,
+            rp_id.as_deref(),
+            &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+        ),
 
         Commands::CredentialManage { action } => match action {
-            CredentialAction::List { username } => commands::credential::list_credentials(&storage, &username),
-            CredentialAction::Delete { id } => commands::credential::delete_credential(&storage, &id),
-            CredentialAction::Update { id, name } => commands::credential::update_credential(&storage, &id, &name),
-            CredentialAction::Cleanup => commands::credential::cleanup_challenges(&storage),
+            CredentialAction::List { username, rp_id, format } => schemas::OutputFormat::parse(format.as_deref())
+                .and_then(|format| commands::credential::list_credentials(storage, &username, rp_id.as_deref(), format)),
+            CredentialAction::Delete { id } => commands::credential::delete_credential(storage, &id, cli.actor.as_deref()),
+            CredentialAction::Show { id } => commands::credential::show_credential(storage, &id),
+            CredentialAction::ExportKey { id, format } => commands::credential::export_key(storage, &id, &format),
+            CredentialAction::Update { id, name, admin } => {
+                commands::credential::update_credential(storage, &id, name.as_deref(), admin, cli.actor.as_deref())
+            }
+            CredentialAction::Disable { id } => commands::credential::set_enabled(storage, &id, false, cli.actor.as_deref()),
+            CredentialAction::Enable { id } => commands::credential::set_enabled(storage, &id, true, cli.actor.as_deref()),
+            CredentialAction::Cleanup => commands::credential::cleanup_challenges(storage),
+            CredentialAction::Dedupe { remove } => commands::credential::dedupe(storage, remove, cli.actor.as_deref()),
+        },
+
+        Commands::Storage { action } => match action {
+            StorageAction::FixPerms => commands::storage::fix_permissions(storage),
+            StorageAction::Compact => commands::storage::compact(storage),
+            StorageAction::Maintain => commands::storage::maintain(storage),
+            StorageAction::Stats => commands::storage::stats(storage),
+        },
+
+        Commands::HealthCheck { watch: None, .. } => commands::health::health_check(storage),
+        Commands::HealthCheck {
+            watch: Some(interval),
+            count,
+        } => commands::health::watch(storage, interval, count, &mut std::io::stdout()),
+        Commands::Stats => commands::stats::stats(storage),
+
+        Commands::Audit { action } => match action {
+            AuditAction::List { action, limit } => commands::audit::list(storage, action.as_deref(), limit),
+            AuditAction::Verify => commands::audit::verify(storage),
+        },
+
+        Commands::Backup { action } => match action {
+            BackupAction::Import {
+                format,
+                username,
+                rp_id,
+                user_handle,
+                device_name,
+            } => commands::backup::import(
+                storage,
+                &config,
+                &format,
+                &username,
+                &rp_id,
+                user_handle.as_deref(),
+                &device_name,
+                &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+                cli.actor.as_deref(),
+            ),
+            BackupAction::Export { format, username, rp_id } => {
+                commands::backup::export(storage, &format, username.as_deref(), rp_id.as_deref())
+            }
+            BackupAction::Push {
+                url,
+                token_file,
+                passphrase_file,
+            } => commands::backup::push(
+                storage,
+                &config,
+                url.as_deref(),
+                token_file.as_deref(),
+                passphrase_file.as_deref(),
+            ),
+        },
+
+        Commands::Challenge { action } => match action {
+            ChallengeAction::Create { kind, payload } => commands::challenge::create(storage, &kind, payload.as_deref()),
+            ChallengeAction::Consume { challenge_id, kind } => commands::challenge::consume(storage, &challenge_id, &kind),
+        },
+
+        Commands::Nonce { action } => match action {
+            NonceAction::Create { bind } => commands::nonce::create(storage, bind.as_deref()),
+            NonceAction::Verify { nonce, bind } => commands::nonce::verify(storage, &nonce, bind.as_deref()),
         },
 
-        Commands::HealthCheck => commands::health::health_check(&storage),
+        Commands::Recovery { action } => match action {
+            RecoveryAction::Generate { username, count } => commands::recovery::generate(storage, &username, count, cli.actor.as_deref()),
+            RecoveryAction::Verify { username } => commands::recovery::verify(
+                storage,
+                &config,
+                &username,
+                &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+                cli.actor.as_deref(),
+            ),
+        },
+        Commands::UserManage { action } => match action {
+            UserAction::List => commands::user::list(storage),
+            UserAction::SetRoles { username, roles } => commands::user::set_roles(
+                storage,
+                &username,
+                &roles.iter().map(String::as_str).collect::<Vec<_>>(),
+                cli.actor.as_deref(),
+            ),
+            UserAction::SetGroups { username, groups } => commands::user::set_groups(
+                storage,
+                &username,
+                &groups.iter().map(String::as_str).collect::<Vec<_>>(),
+                cli.actor.as_deref(),
+            ),
+        },
+        Commands::Config { .. } => unreachable!("handled before the config is loaded"),
+        Commands::SelfTest | Commands::Bench { .. } => unreachable!("handled before the store is opened"),
+
+        Commands::Wizard {
+            username,
+            rp_id,
+            origin,
+            device_name,
+        } => commands::wizard::run(
+            storage,
+            &config,
+            &username,
+            &rp_id,
+            &origin,
+            &device_name,
+            &mut std::io::BufReader::new(input::body(cli.input_fd, 0)?),
+            &mut std::io::stderr(),
+        ),
+
+        Commands::Mds { action } => match action {
+            MdsAction::Update { file, offline } => commands::mds::update(storage, &config, file.as_deref(), offline),
+        },
+
+        Commands::PinnedOrigins { action } => match action {
+            PinnedOriginsAction::List => commands::origins::list_pinned(storage, &config),
+            PinnedOriginsAction::Clear { rp_id } => commands::origins::clear_pinned(storage, rp_id.as_deref(), cli.actor.as_deref()),
+        },
+
+        #[cfg(unix)]
+        Commands::Serve { foreground, idle_exit, .. } => {
+            if !foreground {
+                serve::daemonize()?;
+            }
+            serve::serve(storage, &config, listener.expect("bound above"), idle_exit)
+        }
+        #[cfg(not(unix))]
+        Commands::Serve { .. } => Err(AppError::Config("serve requires a Unix platform".to_string())),
+        #[cfg(unix)]
+        Commands::InitScript { binary, socket } => serve::init_script(&binary, &socket),
+        #[cfg(not(unix))]
+        Commands::InitScript { .. } => Err(AppError::Config("init-script requires a Unix platform".to_string())),
+
+        Commands::RpcdList => commands::rpcd::list(),
+        Commands::RpcdCall { method } => commands::rpcd::call(
+            storage,
+            &config,
+            &method,
+            &mut input::body(cli.input_fd, config.input_timeout_secs)?,
+        ),
+        Commands::RpcdAcl { object } => commands::rpcd::acl(&object),
+        #[cfg(feature = "schema")]
+        Commands::Schema {
+            action: cli::SchemaAction::Dump,
+        } => commands::schema::dump(),
+        #[cfg(not(feature = "schema"))]
+        Commands::Schema {
+            action: cli::SchemaAction::Dump,
+        } => Err(AppError::Config(
+            "Built without JSON Schema support (the schema feature)".to_string(),
+        )),
+    }?;
+    if changes_store {
+        commands::backup::note_change(storage, &config);
+    }
+    Ok(output)
+}
+
+#[cfg(unix)]
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn open_storage(config: &Config, cli: &Cli) -> Result<Box<dyn StorageProvider>, AppError> {
+    // A remote store is reached before privileges are dropped and the process confined
+    let remote = cli.storage.as_deref().map(|url| open_remote_storage(config, url)).transpose()?;
+    // Move a store out of the configuration's directory before anything opens it
+    if remote.is_none() && config.credentials_paths.is_empty() && !config.read_only_store {
+        let legacy = std::path::Path::new(storage::LEGACY_DATA_DIR);
+        if let Err(e) = storage::migrate_data_dir(legacy, std::path::Path::new(storage::DATA_DIR)) {
+            syslog::warning(&format!("cannot move the credential store to {}: {}", storage::DATA_DIR, e));
+        }
     }
+    let local = remote.is_none().then(|| {
+        let storage = storage::FileStorage::new(config).with_sync(!cli.no_sync);
+        // A long-running server reads the store far more often than it changes
+        if matches!(cli.command, Commands::Serve { .. }) {
+            storage.with_cache()
+        } else {
+            storage
+        }
+    });
+    if let Some(user) = &config.run_as_user {
+        let config_path = cli.config_path.as_deref().unwrap_or(config::DEFAULT_CONFIG_PATH);
+        privileges::drop_privileges(
+            local.as_ref(),
+            user,
+            config.run_as_group.as_deref(),
+            std::path::Path::new(config_path),
+        )?;
+    }
+    #[cfg(target_os = "linux")]
+    if config.landlock {
+        // Hooks and uclient-fetch need the rest of the filesystem to run
+        let hooks = config.on_register.is_some() || config.on_login_success.is_some() || config.on_login_failure.is_some();
+        let (mut readable, mut exec) = match &cli.command {
+            Commands::Mds {
+                action: MdsAction::Update { file, .. },
+            } => (file.iter().map(std::path::PathBuf::from).collect(), hooks || file.is_none()),
+            _ => (Vec::new(), hooks),
+        };
+        // So do backup pushes through curl, which also read the token and passphrase
+        let backup_files = match &cli.command {
+            Commands::Backup {
+                action:
+                    BackupAction::Push {
+                        token_file,
+                        passphrase_file,
+                        ..
+                    },
+            } => Some([
+                token_file.as_ref().or(config.backup_token_file.as_ref()),
+                passphrase_file.as_ref().or(config.backup_passphrase_file.as_ref()),
+            ]),
+            _ if config.backup_push_every > 0 => Some([config.backup_token_file.as_ref(), config.backup_passphrase_file.as_ref()]),
+            _ => None,
+        };
+        if let Some(files) = backup_files {
+            exec = true;
+            readable.extend(files.into_iter().flatten().map(std::path::PathBuf::from));
+        }
+        let config_path = std::path::Path::new(cli.config_path.as_deref().unwrap_or(config::DEFAULT_CONFIG_PATH));
+        let mut writable = Vec::new();
+        if let Some(local) = &local {
+            writable.extend(local.writable_dirs(config_path)?);
+            readable.extend(local.readable_files());
+        }
+        // --out replaces the file through a temporary one beside it
+        writable.extend(cli.out.iter().filter_map(|out| std::path::Path::new(out).parent()).map(|dir| {
+            if dir.as_os_str().is_empty() {
+                ".".into()
+            } else {
+                dir.to_path_buf()
+            }
+        }));
+        landlock::confine(config, &readable, &writable, exec)?;
+    }
+    Ok(match local {
+        Some(local) => Box::new(local),
+        None => remote.expect("opened when there is no local store"),
+    })
+}
+
+#[cfg(feature = "redis")]
+fn open_remote_storage(config: &Config, url: &str) -> Result<Box<dyn StorageProvider>, AppError> {
+    if config.read_only_store {
+        return Err(AppError::Config("read_only_store only applies to the file store".to_string()));
+    }
+    Ok(Box::new(storage::RedisStorage::connect(url)?))
+}
+
+#[cfg(not(feature = "redis"))]
+fn open_remote_storage(_config: &Config, _url: &str) -> Result<Box<dyn StorageProvider>, AppError> {
+    Err(AppError::Config(
+        "Built without remote storage support (the redis feature)".to_string(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn open_storage(_config: &Config, _cli: &Cli) -> Result<Box<dyn StorageProvider>, AppError> {
+    Err(AppError::Config("Credential storage requires a Unix platform".to_string()))
 }
 
 fn main() {
+    let cli = Cli::parse();
+    let out = cli.out.clone().map(std::path::PathBuf::from);
     // Catch panics and convert to JSON error output
-    let result = std::panic::catch_unwind(run);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run(cli)));
 
-    match result {
-        Ok(Ok(json)) => {
-            println!("{}", json);
-        }
+    let (json, code) = match result {
+        Ok(Ok(json)) => (json, 0),
         Ok(Err(err)) => {
-            let response = ErrorResponse::new(err.error_code(), &err.to_string());
+            let response = ErrorResponse::from_error(&err);
             let json = serde_json::to_string(&response).unwrap_or_else(|_| {
-                r#"{"success":false,"error":{"code":"INTERNAL_ERROR","message":"Failed to serialize error response"}}"#.to_string()
+                r#"{"success":false,"apiVersion":1,"error":{"code":"INTERNAL_ERROR","message":"Failed to serialize error response"}}"#
+                    .to_string()
             });
-            eprintln!("{}", err);
-            println!("{}", json);
-            std::process::exit(1);
+            syslog::error(&err);
+            (json, 1)
         }
         Err(_panic) => {
             let response = ErrorResponse::new("INTERNAL_ERROR", "An unexpected internal error occurred");
             let json = serde_json::to_string(&response).unwrap_or_else(|_| {
-                r#"{"success":false,"error":{"code":"INTERNAL_ERROR","message":"An unexpected internal error occurred"}}"#.to_string()
+                r#"{"success":false,"apiVersion":1,"error":{"code":"INTERNAL_ERROR","message":"An unexpected internal error occurred"}}"#.to_string()
             });
+            (json, 2)
+        }
+    };
+    if let Err(e) = output::emit(&json, out.as_deref()) {
+        // The response is lost; say why where the caller can still see it
+        let err = AppError::Io(e);
+        syslog::error(&err);
+        if let Ok(json) = serde_json::to_string(&ErrorResponse::from_error(&err)) {
             println!("{}", json);
-            std::process::exit(2);
         }
+        std::process::exit(1);
+    }
+    if code != 0 {
+        std::process::exit(code);
     }
 }
@@ -0,0 +1,413 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::signature::Verifier;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::spki::{ObjectIdentifier, SubjectPublicKeyInfoOwned};
+use x509_cert::Certificate;
+
+use crate::errors::AppError;
+use crate::storage::{MdsCache, MdsEntry};
+
+const SHA256_WITH_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+/// Signature schemes accepted for the blob and its certificate chain.
+#[derive(Clone, Copy)]
+pub(crate) enum Scheme {
+    RsaSha256,
+    EcdsaSha256,
+    EcdsaSha384,
+}
+
+impl Scheme {
+    fn from_oid(oid: ObjectIdentifier) -> Result<Self, AppError> {
+        match oid {
+            SHA256_WITH_RSA => Ok(Self::RsaSha256),
+            ECDSA_WITH_SHA256 => Ok(Self::EcdsaSha256),
+            ECDSA_WITH_SHA384 => Ok(Self::EcdsaSha384),
+            other => Err(AppError::Metadata(format!(
+                "Unsupported certificate signature algorithm {}",
+                other
+            ))),
+        }
+    }
+
+    fn from_jws(alg: &str) -> Result<Self, AppError> {
+        match alg {
+            "RS256" => Ok(Self::RsaSha256),
+            "ES256" => Ok(Self::EcdsaSha256),
+            "ES384" => Ok(Self::EcdsaSha384),
+            other => Err(AppError::Metadata(format!("Unsupported blob signature algorithm {}", other))),
+        }
+    }
+}
+
+/// Checks `signature` over `message` with the key in `spki`. ECDSA signatures
+/// are DER in certificates but fixed-size r || s in a JWS, hence `der_ecdsa`.
+pub(crate) fn verify_signature(
+    spki: &SubjectPublicKeyInfoOwned,
+    scheme: Scheme,
+    message: &[u8],
+    signature: &[u8],
+    der_ecdsa: bool,
+) -> bool {
+    let key = spki.subject_public_key.raw_bytes();
+    match scheme {
+        Scheme::RsaSha256 => {
+            let Ok(der) = spki.to_der() else { return false };
+            let Ok(key) = rsa::RsaPublicKey::from_public_key_der(&der) else {
+                return false;
+            };
+            let Ok(signature) = rsa::pkcs1v15::Signature::try_from(signature) else {
+                return false;
+            };
+            rsa::pkcs1v15::VerifyingKey::<sha2::Sha256>::new(key)
+                .verify(message, &signature)
+                .is_ok()
+        }
+        Scheme::EcdsaSha256 => {
+            let Ok(key) = p256::ecdsa::VerifyingKey::from_sec1_bytes(key) else {
+                return false;
+            };
+            let signature = if der_ecdsa {
+                p256::ecdsa::Signature::from_der(signature)
+            } else {
+                p256::ecdsa::Signature::from_slice(signature)
+            };
+            signature.is_ok_and(|signature| key.verify(message, &signature).is_ok())
+        }
+        Scheme::EcdsaSha384 => {
+            let Ok(key) = p384::ecdsa::VerifyingKey::from_sec1_bytes(key) else {
+                return false;
+            };
+            let signature = if der_ecdsa {
+                p384::ecdsa::Signature::from_der(signature)
+            } else {
+                p384::ecdsa::Signature::from_slice(signature)
+            };
+            signature.is_ok_and(|signature| key.verify(message, &signature).is_ok())
+        }
+    }
+}
+
+pub(crate) fn check_validity(cert: &Certificate, now: u64) -> Result<(), AppError> {
+    let validity = &cert.tbs_certificate.validity;
+    let not_before = validity.not_before.to_unix_duration().as_secs();
+    let not_after = validity.not_after.to_unix_duration().as_secs();
+    if now < not_before || now > not_after {
+        return Err(AppError::Metadata(format!(
+            "Certificate {} is not valid at this time",
+            cert.tbs_certificate.subject
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `cert` was issued and signed by `issuer`.
+pub(crate) fn check_issued_by(cert: &Certificate, issuer: &Certificate) -> Result<(), AppError> {
+    let tbs = &cert.tbs_certificate;
+    let scheme = Scheme::from_oid(cert.signature_algorithm.oid)?;
+    let tbs_der = tbs
+        .to_der()
+        .map_err(|e| AppError::Metadata(format!("Invalid certificate: {}", e)))?;
+    if tbs.issuer != issuer.tbs_certificate.subject
+        || !verify_signature(
+            &issuer.tbs_certificate.subject_public_key_info,
+            scheme,
+            &tbs_der,
+            cert.signature.raw_bytes(),
+            true,
+        )
+    {
+        return Err(AppError::Metadata(format!(
+            "Certificate {} is not signed by {}",
+            tbs.subject, issuer.tbs_certificate.subject
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the trust anchor for the blob's certificate chain, PEM or DER.
+pub fn load_root(path: &Path) -> Result<Certificate, AppError> {
+    let data = std::fs::read(path).map_err(|e| AppError::Metadata(format!("Failed to read root certificate {}: {}", path.display(), e)))?;
+    let der = match std::str::from_utf8(&data) {
+        Ok(text) if text.contains("-----BEGIN CERTIFICATE-----") => {
+            let body: String = text
+                .lines()
+                .skip_while(|line| !line.starts_with("-----BEGIN CERTIFICATE-----"))
+                .skip(1)
+                .take_while(|line| !line.starts_with("-----END"))
+                .collect();
+            STANDARD
+                .decode(body.trim())
+                .map_err(|e| AppError::Metadata(format!("Invalid root certificate PEM: {}", e)))?
+        }
+        _ => data,
+    };
+    Certificate::from_der(&der).map_err(|e| AppError::Metadata(format!("Invalid root certificate: {}", e)))
+}
+
+/// Verifies a Metadata Service blob (a JWS in compact form whose `x5c` header
+/// chains to `root`) at Unix time `now` and returns its payload.
+pub fn verify_blob(blob: &str, root: &Certificate, now: u64) -> Result<serde_json::Value, AppError> {
+    let invalid = |what: &str| AppError::Metadata(format!("Invalid metadata blob: {}", what));
+    let mut parts = blob.trim().split('.');
+    let (Some(header_b64), Some(payload_b64), Some(signature_b64), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid("not a compact JWS"));
+    };
+
+    let header: serde_json::Value = URL_SAFE_NO_PAD
+        .decode(header_b64)
+        .ok()
+        .and_then(|h| serde_json::from_slice(&h).ok())
+        .ok_or_else(|| invalid("malformed header"))?;
+    let scheme = Scheme::from_jws(header.get("alg").and_then(|a| a.as_str()).unwrap_or_default())?;
+    let chain = header
+        .get("x5c")
+        .and_then(|x| x.as_array())
+        .filter(|x| !x.is_empty())
+        .ok_or_else(|| invalid("missing x5c certificate chain"))?
+        .iter()
+        .map(|cert| {
+            let der = cert
+                .as_str()
+                .and_then(|c| STANDARD.decode(c).ok())
+                .ok_or_else(|| invalid("malformed x5c entry"))?;
+            Certificate::from_der(&der).map_err(|e| AppError::Metadata(format!("Invalid x5c certificate: {}", e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    check_validity(root, now)?;
+    for (i, cert) in chain.iter().enumerate() {
+        check_validity(cert, now)?;
+        // The chain may end with the root itself
+        let issuer = chain.get(i + 1).unwrap_or(root);
+        if cert != root {
+            check_issued_by(cert, issuer)?;
+        }
+    }
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature_b64)
+        .map_err(|_| invalid("malformed signature"))?;
+    let signed = format!("{}.{}", header_b64, payload_b64);
+    if !verify_signature(
+        &chain[0].tbs_certificate.subject_public_key_info,
+        scheme,
+        signed.as_bytes(),
+        &signature,
+        false,
+    ) {
+        return Err(AppError::Metadata("Metadata blob signature does not verify".to_string()));
+    }
+
+    URL_SAFE_NO_PAD
+        .decode(payload_b64)
+        .ok()
+        .and_then(|p| serde_json::from_slice(&p).ok())
+        .ok_or_else(|| invalid("malformed payload"))
+}
+
+/// Extracts the entries with an AAGUID (FIDO2 authenticators) from a verified payload.
+pub fn parse_payload(payload: &serde_json::Value, fetched_at: String) -> Result<MdsCache, AppError> {
+    let no = payload
+        .get("no")
+        .and_then(|n| n.as_u64())
+        .ok_or_else(|| AppError::Metadata("Metadata payload has no serial number".to_string()))?;
+    let next_update = payload
+        .get("nextUpdate")
+        .and_then(|n| n.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut entries = BTreeMap::new();
+    for entry in payload.get("entries").and_then(|e| e.as_array()).into_iter().flatten() {
+        let Some(aaguid) = entry.get("aaguid").and_then(|a| a.as_str()) else {
+            continue;
+        };
+        let description = entry
+            .pointer("/metadataStatement/description")
+            .and_then(|d| d.as_str())
+            .unwrap_or_default()
+            .to_string();
+        // Reports carry YYYY-MM-DD dates, which sort lexicographically
+        let status = entry
+            .get("statusReports")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+            .max_by_key(|r| r.get("effectiveDate").and_then(|d| d.as_str()).unwrap_or_default())
+            .and_then(|r| r.get("status").and_then(|s| s.as_str()))
+            .map(str::to_string);
+        let attestation_roots = entry
+            .pointer("/metadataStatement/attestationRootCertificates")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|r| r.as_str())
+            .map(str::to_string)
+            .collect();
+        entries.insert(
+            aaguid.to_ascii_lowercase(),
+            MdsEntry {
+                description,
+                status,
+                attestation_roots,
+            },
+        );
+    }
+
+    Ok(MdsCache {
+        no,
+        next_update,
+        fetched_at,
+        entries,
+    })
+}
+
+/// Downloads the blob with OpenWrt's `uclient-fetch`, which brings its own TLS stack.
+pub fn fetch(url: &str) -> Result<String, AppError> {
+    let output = std::process::Command::new("uclient-fetch")
+        .args(["-q", "-T", "30", "-O", "-", url])
+        .output()
+        .map_err(|e| AppError::Metadata(format!("Failed to run uclient-fetch: {}", e)))?;
+    if !output.status.success() {
+        return Err(AppError::Metadata(format!(
+            "Failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    String::from_utf8(output.stdout).map_err(|_| AppError::Metadata("Metadata blob is not text".to_string()))
+}
+
+/// Whether the cache is past the `nextUpdate` date the blob announced.
+pub fn is_stale(cache: &MdsCache, now: u64) -> bool {
+    crate::clock::parse_rfc3339(&format!("{}T00:00:00Z", cache.next_update)).is_none_or(|next| now >= next)
+}
+
+/// Product name of an authenticator model, from the cached metadata or, for
+/// models it does not name (or with no cache at all), the built-in table.
+pub fn describe(cache: Option<&MdsCache>, aaguid: &str) -> Option<String> {
+    let aaguid = aaguid.to_ascii_lowercase();
+    cache
+        .and_then(|cache| cache.entries.get(&aaguid))
+        .map(|entry| entry.description.clone())
+        .filter(|description| !description.is_empty())
+        .or_else(|| builtin_name(&aaguid).map(str::to_string))
+}
+
+#[cfg(feature = "aaguid-names")]
+fn builtin_name(aaguid: &str) -> Option<&'static str> {
+    crate::aaguid_names::lookup(aaguid)
+}
+
+#[cfg(not(feature = "aaguid-names"))]
+fn builtin_name(_aaguid: &str) -> Option<&'static str> {
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+
+    // P-256 test root and leaf, valid 2026-10-17 to 2126-09-23
+    pub(crate) const ROOT: &str = "MIIBdDCCARqgAwIBAgIBATAKBggqhkjOPQQDAjAYMRYwFAYDVQQDDA1UZXN0IE1EUyBSb290MCAXDTI2MTAxNzAzMTAxMloYDzIxMjYwOTIzMDMxMDEyWjAYMRYwFAYDVQQDDA1UZXN0IE1EUyBSb290MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEJw5zlwArcsT7BbEqF3926peJUm7JZRTO3njUgdc9YrSvAAVEHS6BJekoAQDrCUq+J1dMGEfRI7ber5pp/DWK3aNTMFEwHQYDVR0OBBYEFKdhyiw2i+3GpkqV5x3EPGHANq25MB8GA1UdIwQYMBaAFKdhyiw2i+3GpkqV5x3EPGHANq25MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIhAJiR/PmsFA9XDH80ANvnfVsFYbYcvJO1Ag3a2G8YKIwhAiATSVmnPdu36YrlmysnwY7kKZ3WiKt+KUOFywXwNLwBqQ==";
+    pub(crate) const LEAF: &str = "MIIBZjCCAQygAwIBAgIBAjAKBggqhkjOPQQDAjAYMRYwFAYDVQQDDA1UZXN0IE1EUyBSb290MCAXDTI2MTAxNzAzMTAxMloYDzIxMjYwOTIzMDMxMDEyWjAbMRkwFwYDVQQDDBBtZHMuZXhhbXBsZS50ZXN0MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEZNtsKEcq92MB+MIVy7ePvy/O1902wMrcfk/oSlzizljbKFKEIRmmETiJ6An0xKitucfDqad661kNZ8caqEow3qNCMEAwHQYDVR0OBBYEFH6vv0qOLnEuSrr3+PJu1y98jDqHMB8GA1UdIwQYMBaAFKdhyiw2i+3GpkqV5x3EPGHANq25MAoGCCqGSM49BAMCA0gAMEUCIQDcbz2nfcIcFMRJtWiL1D9FubIcU5ZDToF+4FGrR3cJbQIgYn7wBrp8nV3Cw9NrMXlfn3zX3FaYGmqqSwf+FfezcTQ=";
+    const LEAF_KEY: &str = "3ff520667481e003fff2cad3964495d614c40197c10b9c89afcc32b7e4555490";
+    pub(crate) const NOW: u64 = 4_102_444_800; // 2100-01-01
+
+    fn cert(b64: &str) -> Certificate {
+        Certificate::from_der(&STANDARD.decode(b64).unwrap()).unwrap()
+    }
+
+    /// Private key of `LEAF`
+    pub(crate) fn leaf_key() -> p256::ecdsa::SigningKey {
+        let key_bytes: Vec<u8> = (0..LEAF_KEY.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&LEAF_KEY[i..i + 2], 16).unwrap())
+            .collect();
+        p256::ecdsa::SigningKey::from_slice(&key_bytes).unwrap()
+    }
+
+    fn signed_blob(payload: &serde_json::Value, x5c: &[&str]) -> String {
+        let header = serde_json::json!({ "alg": "ES256", "typ": "JWT", "x5c": x5c });
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(payload.to_string())
+        );
+        let signature: p256::ecdsa::Signature = leaf_key().sign(signed.as_bytes());
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+
+    fn payload() -> serde_json::Value {
+        serde_json::json!({
+            "no": 42,
+            "nextUpdate": "2100-02-01",
+            "entries": [
+                {
+                    "aaguid": "CB69481E-8FF7-4039-93EC-0A2729A154A8",
+                    "metadataStatement": { "description": "YubiKey 5 Series", "attestationRootCertificates": ["MIIB"] },
+                    "statusReports": [
+                        { "status": "FIDO_CERTIFIED", "effectiveDate": "2021-01-01" },
+                        { "status": "FIDO_CERTIFIED_L1", "effectiveDate": "2023-05-01" }
+                    ]
+                },
+                { "aaid": "4e4e#4005", "metadataStatement": { "description": "UAF only" } }
+            ]
+        })
+    }
+
+    #[test]
+    fn test_verified_blob_is_parsed() {
+        let blob = signed_blob(&payload(), &[LEAF]);
+        let verified = verify_blob(&blob, &cert(ROOT), NOW).unwrap();
+        let cache = parse_payload(&verified, "2100-01-01T00:00:00Z".to_string()).unwrap();
+        assert_eq!(cache.no, 42);
+        assert_eq!(cache.entries.len(), 1);
+        let entry = &cache.entries["cb69481e-8ff7-4039-93ec-0a2729a154a8"];
+        assert_eq!(entry.description, "YubiKey 5 Series");
+        assert_eq!(entry.status.as_deref(), Some("FIDO_CERTIFIED_L1"));
+        assert_eq!(entry.attestation_roots, ["MIIB"]);
+        assert_eq!(
+            describe(Some(&cache), "CB69481E-8FF7-4039-93EC-0A2729A154A8").as_deref(),
+            Some("YubiKey 5 Series")
+        );
+        assert!(!is_stale(&cache, NOW));
+        assert!(is_stale(&cache, NOW + 40 * 86400));
+
+        // A chain that repeats the root is accepted as well
+        assert!(verify_blob(&signed_blob(&payload(), &[LEAF, ROOT]), &cert(ROOT), NOW).is_ok());
+    }
+
+    #[cfg(feature = "aaguid-names")]
+    #[test]
+    fn test_builtin_names_fill_in_without_metadata() {
+        let icloud = "FBFC3007-154E-4ECC-8C0B-6E020557D7BD";
+        assert_eq!(describe(None, icloud).as_deref(), Some("iCloud Keychain"));
+        assert_eq!(describe(Some(&MdsCache::default()), icloud).as_deref(), Some("iCloud Keychain"));
+        assert_eq!(describe(None, "00000000-0000-0000-0000-000000000000"), None);
+    }
+
+    #[test]
+    fn test_tampered_or_untrusted_blob_is_rejected() {
+        let blob = signed_blob(&payload(), &[LEAF]);
+        let (signed, signature) = blob.rsplit_once('.').unwrap();
+        let (header, _) = signed.split_once('.').unwrap();
+        let forged = format!("{}.{}.{}", header, URL_SAFE_NO_PAD.encode(b"{\"no\":43}"), signature);
+        assert!(matches!(verify_blob(&forged, &cert(ROOT), NOW), Err(AppError::Metadata(_))));
+
+        // The leaf did not issue itself, so the chain does not reach the root
+        assert!(verify_blob(&signed_blob(&payload(), &[LEAF, LEAF]), &cert(ROOT), NOW).is_err());
+        // Outside the certificates' validity period
+        assert!(verify_blob(&blob, &cert(ROOT), 1_000_000_000).is_err());
+        assert!(verify_blob(&signed_blob(&payload(), &[]), &cert(ROOT), NOW).is_err());
+    }
+}
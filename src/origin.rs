@@ -0,0 +1,293 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::storage::StorageProvider;
+
+/// Rules deciding which client origins are acceptable for an RP ID.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OriginRules {
+    /// Accept origins whose host is a subdomain of the RP ID
+    pub allow_subdomains: bool,
+    /// Ports an origin may use (explicit or scheme default); empty allows any
+    pub allowed_ports: Vec<u16>,
+    /// Reject plain `http://` origins
+    pub require_https: bool,
+    /// Additional origins accepted verbatim, e.g. a companion web app on
+    /// another host or an Android app (`android:apk-key-hash:<b64url>`)
+    pub related_origins: Vec<String>,
+}
+
+/// Prefix of origins reported by Android apps using the platform FIDO2 API
+const ANDROID_APK_PREFIX: &str = "android:apk-key-hash:";
+
+impl OriginRules {
+    pub fn validate(&self) -> Result<(), AppError> {
+        for origin in &self.related_origins {
+            if !is_valid_verbatim_origin(origin) {
+                return Err(AppError::Config(format!("Invalid related origin: {}", origin)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `origin` can be matched verbatim: an http(s) origin or an
+/// Android APK key hash.
+fn is_valid_verbatim_origin(origin: &str) -> bool {
+    match origin.strip_prefix(ANDROID_APK_PREFIX) {
+        Some(hash) => !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'),
+        None => parse_origin(origin).is_some(),
+    }
+}
+
+/// Reads an origin allowlist: one origin per line, blank lines and lines
+/// starting with `#` ignored.
+pub fn load_origins_file(path: &Path) -> Result<Vec<String>, AppError> {
+    let data = fs::read_to_string(path).map_err(|e| AppError::Config(format!("Failed to read {}: {}", path.display(), e)))?;
+    parse_origins_list(&data)
+        .map_err(|(line, origin)| AppError::Config(format!("Invalid origin on line {} of {}: {}", line, path.display(), origin)))
+}
+
+fn parse_origins_list(data: &str) -> Result<Vec<String>, (usize, &str)> {
+    let mut origins = Vec::new();
+    for (index, line) in data.lines().enumerate() {
+        let origin = line.trim();
+        if origin.is_empty() || origin.starts_with('#') {
+            continue;
+        }
+        if !is_valid_verbatim_origin(origin) {
+            return Err((index + 1, origin));
+        }
+        origins.push(origin.to_string());
+    }
+    Ok(origins)
+}
+
+/// The parts of an origin relevant to matching.
+#[derive(Debug, PartialEq)]
+pub struct ParsedOrigin<'a> {
+    pub scheme: &'a str,
+    pub host: &'a str,
+    pub port: u16,
+}
+
+/// Splits `scheme://host[:port][/path]` into its parts. Only http(s) origins
+/// are understood; IPv6 hosts must be bracketed (`[::1]:8443`).
+pub fn parse_origin(origin: &str) -> Option<ParsedOrigin<'_>> {
+    let (scheme, rest) = origin.split_once("://")?;
+    let default_port = match scheme {
+        "https" => 443,
+        "http" => 80,
+        _ => return None,
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, after) = bracketed.split_once(']')?;
+        (host, after.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(p) => p.parse().ok()?,
+        None => default_port,
+    };
+    Some(ParsedOrigin { scheme, host, port })
+}
+
+/// Verifies `origin` against `rp_id` using the rules configured for that RP.
+pub fn check_origin(origin: &str, rp_id: &str, config: &Config) -> Result<(), AppError> {
+    let rules = config.origin_rules_for(rp_id);
+    if rules.related_origins.iter().chain(&config.extra_origins).any(|o| o == origin) {
+        return Ok(());
+    }
+
+    let parsed = parse_origin(origin).ok_or_else(|| AppError::InvalidOrigin("Origin has no host".to_string()))?;
+
+    if rules.require_https && parsed.scheme != "https" {
+        return Err(AppError::InvalidOrigin(format!("Origin {} must use https", origin)));
+    }
+
+    let host_matches =
+        parsed.host == rp_id || (rules.allow_subdomains && parsed.host.strip_suffix(rp_id).is_some_and(|prefix| prefix.ends_with('.')));
+    if !host_matches {
+        return Err(AppError::InvalidOrigin(format!(
+            "Origin {} does not match RP ID {}",
+            origin, rp_id
+        )));
+    }
+
+    if !rules.allowed_ports.is_empty() && !rules.allowed_ports.contains(&parsed.port) {
+        return Err(AppError::InvalidOrigin(format!(
+            "Origin {} uses port {}, which is not allowed for RP ID {}",
+            origin, parsed.port, rp_id
+        )));
+    }
+    Ok(())
+}
+
+/// In trust-on-first-use mode, rejects an origin other than the one pinned
+/// for the RP.
+pub fn check_pinned_origin(storage: &dyn StorageProvider, config: &Config, origin: &str, rp_id: &str) -> Result<(), AppError> {
+    if !config.tofu_origins {
+        return Ok(());
+    }
+    match storage.load_pinned_origins()?.get(rp_id) {
+        Some(pinned) if pinned != origin => Err(AppError::InvalidOrigin(format!(
+            "Origin {} does not match the origin {} pinned for RP ID {}",
+            origin, pinned, rp_id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// In trust-on-first-use mode, pins `origin` for the RP after a successful
+/// ceremony unless an origin is pinned already.
+pub fn pin_origin(storage: &dyn StorageProvider, config: &Config, origin: &str, rp_id: &str) -> Result<(), AppError> {
+    if !config.tofu_origins || storage.load_pinned_origins()?.contains_key(rp_id) {
+        return Ok(());
+    }
+    storage.modify_pinned_origins(&mut |pinned| {
+        pinned.entry(rp_id.to_string()).or_insert_with(|| origin.to_string());
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with(rules: OriginRules) -> Config {
+        Config {
+            origin: rules,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_origin() {
+        let parsed = parse_origin("https://192.168.1.1:8443/cgi-bin/luci").unwrap();
+        assert_eq!(
+            parsed,
+            ParsedOrigin {
+                scheme: "https",
+                host: "192.168.1.1",
+                port: 8443
+            }
+        );
+        assert_eq!(parse_origin("http://router.lan").unwrap().port, 80);
+        assert_eq!(parse_origin("https://[fd00::1]:444").unwrap().host, "fd00::1");
+        assert!(parse_origin("ftp://router.lan").is_none());
+        assert!(parse_origin("https://").is_none());
+        assert!(parse_origin("router.lan").is_none());
+    }
+
+    #[test]
+    fn test_default_rules_require_exact_host() {
+        let config = Config::default();
+        assert!(check_origin("https://router.lan", "router.lan", &config).is_ok());
+        assert!(check_origin("http://router.lan:8080", "router.lan", &config).is_ok());
+        assert!(check_origin("https://luci.router.lan", "router.lan", &config).is_err());
+        assert!(check_origin("https://evilrouter.lan", "router.lan", &config).is_err());
+    }
+
+    #[test]
+    fn test_subdomain_port_and_scheme_rules() {
+        let config = config_with(OriginRules {
+            allow_subdomains: true,
+            allowed_ports: vec![443],
+            require_https: true,
+            ..OriginRules::default()
+        });
+        assert!(check_origin("https://luci.router.lan", "router.lan", &config).is_ok());
+        assert!(check_origin("https://evilrouter.lan", "router.lan", &config).is_err());
+        assert!(check_origin("https://router.lan:8443", "router.lan", &config).is_err());
+        assert!(check_origin("http://router.lan:443", "router.lan", &config).is_err());
+    }
+
+    #[test]
+    fn test_related_origins() {
+        let apk = "android:apk-key-hash:Tmhlc2VBcmVOb3RUaGVCeXRlc1lvdUFyZUxvb2tpbmdGb3I";
+        let rules = OriginRules {
+            related_origins: vec!["https://app.example.com".to_string(), apk.to_string()],
+            ..OriginRules::default()
+        };
+        assert!(rules.validate().is_ok());
+
+        let config = config_with(rules);
+        assert!(check_origin(apk, "router.lan", &config).is_ok());
+        assert!(check_origin("https://app.example.com", "router.lan", &config).is_ok());
+        assert!(check_origin("https://app.example.com:444", "router.lan", &config).is_err());
+        assert!(check_origin("android:apk-key-hash:other", "router.lan", &config).is_err());
+    }
+
+    #[test]
+    fn test_invalid_related_origins_rejected() {
+        for bad in ["android:apk-key-hash:", "android:apk-key-hash:a+b/c=", "app.example.com"] {
+            let rules = OriginRules {
+                related_origins: vec![bad.to_string()],
+                ..OriginRules::default()
+            };
+            assert!(rules.validate().is_err(), "{bad} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_per_rp_override() {
+        let mut config = Config::default();
+        config.rp_origins.insert(
+            "router.lan".to_string(),
+            OriginRules {
+                allow_subdomains: true,
+                ..OriginRules::default()
+            },
+        );
+        assert!(check_origin("https://luci.router.lan", "router.lan", &config).is_ok());
+        assert!(check_origin("https://luci.other.lan", "other.lan", &config).is_err());
+    }
+
+    #[test]
+    fn test_origins_file_entries_apply_to_every_rp() {
+        let list = "# DDNS names\nhttps://home.example.net:8443\n\n  android:apk-key-hash:abc_-1  \n";
+        let origins = parse_origins_list(list).unwrap();
+        assert_eq!(origins, ["https://home.example.net:8443", "android:apk-key-hash:abc_-1"]);
+        assert_eq!(parse_origins_list("https://ok.lan\nnot an origin\n"), Err((2, "not an origin")));
+
+        let config = Config {
+            extra_origins: origins,
+            ..Config::default()
+        };
+        assert!(check_origin("https://home.example.net:8443", "router.lan", &config).is_ok());
+        assert!(check_origin("https://home.example.net", "router.lan", &config).is_err());
+    }
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let storage = crate::storage::MemoryStorage::default();
+        let mut config = Config::default();
+        pin_origin(&storage, &config, "https://router.lan", "router.lan").unwrap();
+        assert!(storage.load_pinned_origins().unwrap().is_empty());
+
+        config.tofu_origins = true;
+        check_pinned_origin(&storage, &config, "https://router.lan", "router.lan").unwrap();
+        pin_origin(&storage, &config, "https://router.lan", "router.lan").unwrap();
+        pin_origin(&storage, &config, "https://router.lan:8443", "router.lan").unwrap();
+        assert_eq!(storage.load_pinned_origins().unwrap()["router.lan"], "https://router.lan");
+
+        assert!(check_pinned_origin(&storage, &config, "https://router.lan", "router.lan").is_ok());
+        let moved = check_pinned_origin(&storage, &config, "https://router.lan:8443", "router.lan");
+        assert!(matches!(moved, Err(AppError::InvalidOrigin(_))));
+        assert!(check_pinned_origin(&storage, &config, "https://other.lan", "other.lan").is_ok());
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Replaces `path` with `data` through a temporary file beside it, keeping
+/// the old file's mode, so a reader sees either the old or the new content
+/// and never half of it. A new file is private (0600): responses can carry
+/// secrets such as recovery codes.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display())))?;
+    // Unpredictable, and created exclusively: a name planted in a shared
+    // directory such as /tmp (e.g. a symlink) makes the write fail instead
+    // of redirecting it
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    let written = (|| {
+        // Only a regular file passes its mode on, never a symlink's target
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_file() => file.set_permissions(meta.permissions())?,
+            _ => {}
+        }
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Hands a response to the caller: on stdout, or written to `out` as a whole.
+pub fn emit(json: &str, out: Option<&Path>) -> io::Result<()> {
+    match out {
+        Some(path) => write_atomic(path, format!("{}\n", json).as_bytes()),
+        None => {
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_atomic_replaces_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+        emit(r#"{"success":true}"#, Some(&path)).unwrap();
+        emit(r#"{"success":false}"#, Some(&path)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"success\":false}\n");
+        // No temporary file is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(emit("{}", Some(&dir.path().join("missing/result.json"))).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_is_private_and_ignores_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("codes.json");
+        write_atomic(&path, b"secret").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // An existing file keeps its mode
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, b"again").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // A symlink is replaced, not followed, and lends no mode
+        let victim = dir.path().join("victim");
+        fs::write(&victim, "keep").unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o644)).unwrap();
+        let link = dir.path().join("result.json");
+        std::os::unix::fs::symlink(&victim, &link).unwrap();
+        write_atomic(&link, b"out").unwrap();
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "keep");
+        assert_eq!(fs::read_to_string(&link).unwrap(), "out");
+        assert_eq!(fs::symlink_metadata(&link).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
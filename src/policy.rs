@@ -0,0 +1,205 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::storage::StoredCredential;
+
+/// COSE algorithm names a policy may list
+const ALGORITHMS: [&str; 4] = ["ES256", "ES384", "EdDSA", "RS256"];
+
+/// Which credentials a policy accepts with respect to backups.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupEligibility {
+    #[default]
+    Any,
+    /// Hardware-bound credentials only; synced passkeys are rejected
+    DeviceBound,
+    /// Synced passkeys only, e.g. so users cannot lose their only key
+    Synced,
+}
+
+/// Declarative rules from the policy file, checked by `register-finish` and
+/// `login-finish`. Every rule defaults to allowing everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Authenticator models (AAGUIDs) that may be used; empty allows any
+    pub allowed_aaguids: Vec<String>,
+    /// Reject ceremonies in which the authenticator did not verify the user
+    pub require_user_verification: bool,
+    /// COSE algorithms credentials may use; empty allows any
+    pub allowed_algorithms: Vec<String>,
+    /// Credentials a user may hold per RP (0 = unlimited)
+    pub max_credentials: usize,
+    pub backup_eligibility: BackupEligibility,
+    /// Reject registrations whose attestation does not vouch for the
+    /// authenticator model, as `register-finish --require-attestation`
+    pub require_attestation: bool,
+}
+
+fn violation(rule: &'static str, reason: String) -> AppError {
+    AppError::PolicyViolation { rule, reason }
+}
+
+impl Policy {
+    /// Loads the policy from `path`. A missing file yields the empty policy.
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(path)?;
+        let mut policy: Policy =
+            serde_json::from_str(&data).map_err(|e| AppError::Config(format!("Failed to parse {}: {}", path.display(), e)))?;
+        policy.validate()?;
+        for aaguid in &mut policy.allowed_aaguids {
+            *aaguid = aaguid.to_ascii_lowercase();
+        }
+        Ok(policy)
+    }
+
+    /// The policy in force: `policy_file`, with `device_bound_only` folded in.
+    pub fn for_config(config: &Config) -> Result<Self, AppError> {
+        let mut policy = Self::load(Path::new(&config.policy_file))?;
+        if config.device_bound_only {
+            if policy.backup_eligibility == BackupEligibility::Synced {
+                return Err(AppError::Config(
+                    "device_bound_only conflicts with the policy's backup_eligibility".to_string(),
+                ));
+            }
+            policy.backup_eligibility = BackupEligibility::DeviceBound;
+        }
+        Ok(policy)
+    }
+
+    fn validate(&self) -> Result<(), AppError> {
+        if let Some(alg) = self.allowed_algorithms.iter().find(|alg| !ALGORITHMS.contains(&alg.as_str())) {
+            return Err(AppError::Config(format!(
+                "Unsupported algorithm in policy: {} (expected {})",
+                alg,
+                ALGORITHMS.join(", ")
+            )));
+        }
+        if let Some(aaguid) = self
+            .allowed_aaguids
+            .iter()
+            .find(|aaguid| uuid::Uuid::parse_str(aaguid).is_err())
+        {
+            return Err(AppError::Config(format!("Invalid AAGUID in policy: {}", aaguid)));
+        }
+        Ok(())
+    }
+
+    /// Checks a credential that is about to be stored. `existing` is how many
+    /// other credentials the user already holds for the RP.
+    pub fn check_registration(&self, cred: &StoredCredential, existing: usize) -> Result<(), AppError> {
+        if self.max_credentials > 0 && existing >= self.max_credentials {
+            return Err(violation(
+                "max_credentials",
+                format!("User already has {} credentials, the most allowed", existing),
+            ));
+        }
+        self.check_credential(cred, cred.user_verified)
+    }
+
+    /// Checks a credential that has just produced a valid assertion.
+    pub fn check_login(&self, cred: &StoredCredential, user_verified: bool) -> Result<(), AppError> {
+        self.check_credential(cred, user_verified)
+    }
+
+    fn check_credential(&self, cred: &StoredCredential, user_verified: bool) -> Result<(), AppError> {
+        if self.require_user_verification && !user_verified {
+            return Err(violation(
+                "require_user_verification",
+                "The authenticator did not verify the user".to_string(),
+            ));
+        }
+        match self.backup_eligibility {
+            BackupEligibility::DeviceBound if cred.backup_eligible => {
+                return Err(violation(
+                    "backup_eligibility",
+                    "Credential is backup eligible (a synced passkey); only device-bound credentials are allowed".to_string(),
+                ));
+            }
+            BackupEligibility::Synced if !cred.backup_eligible => {
+                return Err(violation(
+                    "backup_eligibility",
+                    "Credential is device-bound; only synced passkeys are allowed".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        // Credentials registered before the AAGUID was kept cannot be checked
+        if !self.allowed_aaguids.is_empty() && !cred.aaguid.is_empty() && !self.allowed_aaguids.contains(&cred.aaguid) {
+            return Err(violation(
+                "allowed_aaguids",
+                format!("Authenticator model {} is not allowed", cred.aaguid),
+            ));
+        }
+        if !self.allowed_algorithms.is_empty() {
+            let static_state = crate::commands::login::decode_static_state(cred)?;
+            let alg = crate::public_key::algorithm(&static_state.credential_public_key);
+            if !self.allowed_algorithms.iter().any(|allowed| allowed == alg) {
+                return Err(violation("allowed_algorithms", format!("Algorithm {} is not allowed", alg)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::tests::credential;
+
+    fn policy(json: &str) -> Result<Policy, AppError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.json");
+        fs::write(&path, json).unwrap();
+        Policy::load(&path)
+    }
+
+    #[test]
+    fn test_load_validates_rules() {
+        assert!(Policy::load(Path::new("/nonexistent/policy.json")).unwrap().max_credentials == 0);
+        let loaded = policy(r#"{"allowed_aaguids":["CB69481E-8FF7-4039-93EC-0A2729A154A8"],"backup_eligibility":"device_bound"}"#).unwrap();
+        assert_eq!(loaded.allowed_aaguids, ["cb69481e-8ff7-4039-93ec-0a2729a154a8"]);
+        assert_eq!(loaded.backup_eligibility, BackupEligibility::DeviceBound);
+
+        assert!(matches!(
+            policy(r#"{"allowed_algorithms":["ES512"]}"#),
+            Err(AppError::Config(_))
+        ));
+        assert!(matches!(policy(r#"{"allowed_aaguids":["yubikey"]}"#), Err(AppError::Config(_))));
+        assert!(matches!(policy(r#"{"max_credential":1}"#), Err(AppError::Config(_))));
+    }
+
+    #[test]
+    fn test_checks_name_the_broken_rule() {
+        let rule = |result: Result<(), AppError>| result.err().and_then(|e| e.rule());
+        let mut cred = credential("a");
+        cred.aaguid = "ea9b8d66-4d01-1d21-3ce4-b6b48cb575d4".to_string();
+        cred.backup_eligible = true;
+
+        let policy = Policy {
+            allowed_aaguids: vec!["cb69481e-8ff7-4039-93ec-0a2729a154a8".to_string()],
+            ..Policy::default()
+        };
+        assert_eq!(rule(policy.check_login(&cred, true)), Some("allowed_aaguids"));
+
+        let policy = Policy {
+            require_user_verification: true,
+            max_credentials: 2,
+            backup_eligibility: BackupEligibility::Synced,
+            ..Policy::default()
+        };
+        assert_eq!(rule(policy.check_login(&cred, false)), Some("require_user_verification"));
+        assert_eq!(rule(policy.check_login(&cred, true)), None);
+        assert_eq!(rule(policy.check_registration(&cred, 2)), Some("max_credentials"));
+        cred.backup_eligible = false;
+        assert_eq!(rule(policy.check_login(&cred, true)), Some("backup_eligibility"));
+    }
+}
@@ -0,0 +1,94 @@
+use std::ffi::CString;
+use std::io;
+use std::path::Path;
+
+use crate::errors::AppError;
+use crate::storage::FileStorage;
+
+/// Resolves a user name to its (uid, primary gid).
+fn lookup_user(name: &str) -> Result<(u32, u32), AppError> {
+    let cname = CString::new(name).map_err(|_| AppError::Config(format!("Invalid user name: {}", name)))?;
+    // SAFETY: cname is a valid NUL-terminated string; the returned record is
+    // only read before any other passwd lookup can overwrite it.
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(AppError::Config(format!("Unknown user: {}", name)));
+    }
+    // SAFETY: pw was checked to be non-null above.
+    unsafe { Ok(((*pw).pw_uid, (*pw).pw_gid)) }
+}
+
+/// Resolves a group name to its gid.
+fn lookup_group(name: &str) -> Result<u32, AppError> {
+    let cname = CString::new(name).map_err(|_| AppError::Config(format!("Invalid group name: {}", name)))?;
+    // SAFETY: see lookup_user.
+    let gr = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        return Err(AppError::Config(format!("Unknown group: {}", name)));
+    }
+    // SAFETY: gr was checked to be non-null above.
+    unsafe { Ok((*gr).gr_gid) }
+}
+
+fn os_error(what: &str) -> AppError {
+    AppError::Privilege(format!("{} failed: {}", what, io::Error::last_os_error()))
+}
+
+/// Hands the storage over to `user` and permanently drops root privileges.
+///
+/// Only acts when running as root; an unprivileged process has nothing to drop.
+/// The storage directories are created and chowned while still root, so the
+/// unprivileged user can read and write them afterwards; the directory of
+/// `config_path` stays root's.
+pub fn drop_privileges(storage: Option<&FileStorage>, user: &str, group: Option<&str>, config_path: &Path) -> Result<(), AppError> {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(name) => lookup_group(name)?,
+        None => primary_gid,
+    };
+
+    if let Some(storage) = storage {
+        storage.hand_over(uid, gid, config_path)?;
+    }
+
+    // SAFETY: plain syscalls without pointer arguments (setgroups with a
+    // zero-length list accepts a null pointer).
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) != 0 {
+            return Err(os_error("setgroups"));
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(os_error("setgid"));
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(os_error("setuid"));
+        }
+        // Make sure root cannot be regained
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(AppError::Privilege("Privileges could be regained after setuid".to_string()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_root() {
+        assert_eq!(lookup_user("root").unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_lookup_unknown_user() {
+        let err = lookup_user("no-such-user-webauthn-test").unwrap_err();
+        assert_eq!(err.error_code(), "CONFIG_ERROR");
+        assert!(lookup_group("no-such-group-webauthn-test").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ciborium::Value;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use webauthn_rp::bin::Encode;
+use webauthn_rp::response::register::{
+    AuthenticatorExtensionOutputStaticState, CompressedPubKey, CredentialProtectionPolicy, Ed25519PubKey, RsaPubKey, StaticState,
+    UncompressedP256PubKey, UncompressedP384PubKey, UncompressedPubKey,
+};
+
+use crate::errors::AppError;
+
+type StoredPubKey = CompressedPubKey<[u8; 32], [u8; 32], [u8; 48], Vec<u8>>;
+
+/// Output encoding of `credential-manage export-key`.
+#[derive(Clone, Copy)]
+pub enum KeyFormat {
+    /// COSE_Key (RFC 9052) as base64url CBOR, as the authenticator reported it
+    Cose,
+    /// SubjectPublicKeyInfo in a PEM "PUBLIC KEY" block
+    Pem,
+}
+
+impl KeyFormat {
+    pub fn parse(value: &str) -> Result<Self, AppError> {
+        match value {
+            "cose" => Ok(Self::Cose),
+            "pem" => Ok(Self::Pem),
+            other => Err(AppError::InvalidInput(format!(
+                "Unsupported key format: {} (expected cose or pem)",
+                other
+            ))),
+        }
+    }
+}
+
+/// A stored public key in uncompressed form.
+pub(crate) enum PublicKey {
+    Ed25519([u8; 32]),
+    /// SEC1 uncompressed point (0x04 || x || y)
+    P256(Vec<u8>),
+    P384(Vec<u8>),
+    Rsa {
+        n: Vec<u8>,
+        e: u32,
+    },
+}
+
+fn invalid_key(reason: impl std::fmt::Display) -> AppError {
+    AppError::InvalidInput(format!("Invalid public key: {}", reason))
+}
+
+impl PublicKey {
+    pub(crate) fn ed25519(x: &[u8]) -> Result<Self, AppError> {
+        Ok(Self::Ed25519(
+            x.try_into().map_err(|_| invalid_key("Ed25519 keys are 32 bytes"))?,
+        ))
+    }
+
+    /// An EC2 key on COSE curve `crv` (1 = P-256, 2 = P-384).
+    pub(crate) fn ec2(crv: i128, x: &[u8], y: &[u8]) -> Result<Self, AppError> {
+        let len = match crv {
+            1 => 32,
+            2 => 48,
+            other => return Err(invalid_key(format!("unsupported curve {}", other))),
+        };
+        if x.len() != len || y.len() != len {
+            return Err(invalid_key(format!("coordinates must be {} bytes", len)));
+        }
+        let point = [&[0x04][..], x, y].concat();
+        Ok(if crv == 1 { Self::P256(point) } else { Self::P384(point) })
+    }
+
+    /// An RSA key from its big-endian modulus and exponent.
+    pub(crate) fn rsa(n: &[u8], e: &[u8]) -> Result<Self, AppError> {
+        let e = trim_leading_zeros(e);
+        if e.len() > 4 {
+            return Err(invalid_key("RSA exponent is too large"));
+        }
+        Ok(Self::Rsa {
+            n: trim_leading_zeros(n).to_vec(),
+            e: e.iter().fold(0, |acc, &b| (acc << 8) | u32::from(b)),
+        })
+    }
+
+    /// Parses a COSE_Key (RFC 9052) of one of the supported algorithms.
+    pub(crate) fn from_cose(data: &[u8]) -> Result<Self, AppError> {
+        let value: Value = ciborium::from_reader(data).map_err(|e| invalid_key(format!("not CBOR: {}", e)))?;
+        let map = value.as_map().ok_or_else(|| invalid_key("COSE key is not a map"))?;
+        let get = |label: i64| map.iter().find(|(k, _)| k.as_integer() == Some(label.into())).map(|(_, v)| v);
+        let int = |label: i64| get(label).and_then(Value::as_integer).map(i128::from);
+        let bytes = |label: i64| {
+            get(label)
+                .and_then(Value::as_bytes)
+                .map(Vec::as_slice)
+                .ok_or_else(|| invalid_key(format!("COSE key lacks parameter {}", label)))
+        };
+        let (key, alg) = match (int(1), int(-1)) {
+            (Some(1), Some(6)) => (Self::ed25519(bytes(-2)?)?, -8),
+            (Some(2), Some(crv)) => (Self::ec2(crv, bytes(-2)?, bytes(-3)?)?, if crv == 1 { -7 } else { -35 }),
+            (Some(3), _) => (Self::rsa(bytes(-1)?, bytes(-2)?)?, -257),
+            _ => return Err(invalid_key("unsupported COSE key type or curve")),
+        };
+        match int(3) {
+            Some(found) if found != alg => Err(invalid_key(format!("algorithm {} does not match the key", found))),
+            _ => Ok(key),
+        }
+    }
+
+    /// Encodes the key as the static state a registration would have stored
+    /// for it, after checking it is a valid key.
+    pub(crate) fn static_state(&self) -> Result<Vec<u8>, AppError> {
+        let credential_public_key = match self {
+            Self::Ed25519(x) => UncompressedPubKey::Ed25519(Ed25519PubKey::try_from(x.as_slice()).map_err(invalid_key)?),
+            Self::P256(point) => {
+                UncompressedPubKey::P256(UncompressedP256PubKey::try_from((&point[1..33], &point[33..])).map_err(invalid_key)?)
+            }
+            Self::P384(point) => {
+                UncompressedPubKey::P384(UncompressedP384PubKey::try_from((&point[1..49], &point[49..])).map_err(invalid_key)?)
+            }
+            Self::Rsa { n, e } => UncompressedPubKey::Rsa(RsaPubKey::try_from((n.as_slice(), *e)).map_err(invalid_key)?),
+        };
+        credential_public_key.validate().map_err(invalid_key)?;
+        let state = StaticState {
+            credential_public_key,
+            extensions: AuthenticatorExtensionOutputStaticState {
+                cred_protect: CredentialProtectionPolicy::None,
+                hmac_secret: None,
+            },
+        };
+        Ok(state.encode().expect("StaticState encode is infallible"))
+    }
+
+    /// EC keys are stored compressed, so their y-coordinate is recomputed here.
+    fn from_stored(key: &StoredPubKey) -> Result<Self, AppError> {
+        let invalid = |e: p256::elliptic_curve::Error| AppError::Storage(format!("Invalid stored public key: {}", e));
+        Ok(match key {
+            CompressedPubKey::Ed25519(k) => Self::Ed25519(*k.inner()),
+            CompressedPubKey::P256(k) => {
+                let point = [&[0x02 | u8::from(k.y_is_odd())][..], k.x()].concat();
+                let key = p256::PublicKey::from_sec1_bytes(&point).map_err(invalid)?;
+                Self::P256(key.to_encoded_point(false).as_bytes().to_vec())
+            }
+            CompressedPubKey::P384(k) => {
+                let point = [&[0x02 | u8::from(k.y_is_odd())][..], k.x()].concat();
+                let key = p384::PublicKey::from_sec1_bytes(&point).map_err(invalid)?;
+                Self::P384(key.to_encoded_point(false).as_bytes().to_vec())
+            }
+            CompressedPubKey::Rsa(k) => Self::Rsa {
+                n: k.n().clone(),
+                e: k.e(),
+            },
+        })
+    }
+
+    fn to_cose(&self) -> Result<Vec<u8>, AppError> {
+        let int = |i: i64| Value::Integer(i.into());
+        let bytes = |b: &[u8]| Value::Bytes(b.to_vec());
+        // kty, alg, then the key-type specific parameters
+        let entries = match self {
+            Self::Ed25519(x) => vec![(1, int(1)), (3, int(-8)), (-1, int(6)), (-2, bytes(x))],
+            Self::P256(point) => vec![
+                (1, int(2)),
+                (3, int(-7)),
+                (-1, int(1)),
+                (-2, bytes(&point[1..33])),
+                (-3, bytes(&point[33..])),
+            ],
+            Self::P384(point) => vec![
+                (1, int(2)),
+                (3, int(-35)),
+                (-1, int(2)),
+                (-2, bytes(&point[1..49])),
+                (-3, bytes(&point[49..])),
+            ],
+            Self::Rsa { n, e } => vec![
+                (1, int(3)),
+                (3, int(-257)),
+                (-1, bytes(n)),
+                (-2, bytes(trim_leading_zeros(&e.to_be_bytes()))),
+            ],
+        };
+        let map = Value::Map(entries.into_iter().map(|(label, value)| (int(label), value)).collect());
+        let mut out = Vec::new();
+        ciborium::into_writer(&map, &mut out).map_err(|e| AppError::Storage(format!("Failed to encode COSE key: {}", e)))?;
+        Ok(out)
+    }
+
+    fn to_spki(&self) -> Vec<u8> {
+        const ED25519: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+        const EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+        const P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+        const P384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+        const RSA_ENCRYPTION: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00];
+
+        let (algorithm, key) = match self {
+            Self::Ed25519(x) => (ED25519.to_vec(), x.to_vec()),
+            Self::P256(point) => ([EC_PUBLIC_KEY, P256].concat(), point.clone()),
+            Self::P384(point) => ([EC_PUBLIC_KEY, P384].concat(), point.clone()),
+            Self::Rsa { n, e } => {
+                let rsa_key = [der_integer(n), der_integer(&e.to_be_bytes())].concat();
+                (RSA_ENCRYPTION.to_vec(), der(0x30, &rsa_key))
+            }
+        };
+        let bit_string = der(0x03, &[&[0][..], &key].concat());
+        der(0x30, &[der(0x30, &algorithm), bit_string].concat())
+    }
+}
+
+fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len().saturating_sub(1));
+    &bytes[start..]
+}
+
+/// DER TLV with a definite length.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len => {
+            let len_bytes = trim_leading_zeros(&len.to_be_bytes()).to_vec();
+            out.push(0x80 | len_bytes.len() as u8);
+            out.extend(len_bytes);
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// DER INTEGER for an unsigned big-endian value.
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let value = trim_leading_zeros(value);
+    if value[0] & 0x80 != 0 {
+        der(0x02, &[&[0][..], value].concat())
+    } else {
+        der(0x02, value)
+    }
+}
+
+fn pem(spki: &[u8]) -> String {
+    let body = STANDARD.encode(spki);
+    let lines: Vec<&str> = body
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+        .collect();
+    format!("-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n", lines.join("\n"))
+}
+
+/// COSE algorithm name a stored key signs with.
+pub fn algorithm(key: &StoredPubKey) -> &'static str {
+    match key {
+        CompressedPubKey::Ed25519(_) => "EdDSA",
+        CompressedPubKey::P256(_) => "ES256",
+        CompressedPubKey::P384(_) => "ES384",
+        CompressedPubKey::Rsa(_) => "RS256",
+    }
+}
+
+/// Encodes a stored public key, returning it with its COSE algorithm name.
+pub fn export(stored: &StoredPubKey, format: KeyFormat) -> Result<(String, &'static str), AppError> {
+    let key = PublicKey::from_stored(stored)?;
+    let encoded = match format {
+        KeyFormat::Cose => URL_SAFE_NO_PAD.encode(key.to_cose()?),
+        KeyFormat::Pem => pem(&key.to_spki()),
+    };
+    Ok((encoded, algorithm(stored)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_der_lengths_and_integers() {
+        assert_eq!(der(0x04, &[1, 2]), [0x04, 0x02, 1, 2]);
+        assert_eq!(&der(0x04, &[0; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(der_integer(&65537u32.to_be_bytes()), [0x02, 0x03, 0x01, 0x00, 0x01]);
+        assert_eq!(der_integer(&[0x00, 0x80]), [0x02, 0x02, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_p256_key_round_trips_through_compression() {
+        // Generator point of P-256
+        let x = hex("6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296");
+        let y = hex("4fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5");
+        let key = PublicKey::P256([&[0x04][..], &x, &y].concat());
+
+        let cose: Value = ciborium::from_reader(key.to_cose().unwrap().as_slice()).unwrap();
+        let cose = cose.as_map().unwrap();
+        assert_eq!(cose[3].1.as_bytes().unwrap(), &x);
+        assert_eq!(cose[4].1.as_bytes().unwrap(), &y);
+
+        let spki = key.to_spki();
+        assert_eq!(&spki[..2], [0x30, 0x59]);
+        assert_eq!(&spki[spki.len() - 64..], [&x[..], &y[..]].concat());
+        assert!(pem(&spki).starts_with("-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE"));
+
+        let compressed = p256::PublicKey::from_sec1_bytes(&[&[0x03][..], &x].concat()).unwrap();
+        assert_eq!(compressed.to_encoded_point(false).y().unwrap()[..], y[..]);
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
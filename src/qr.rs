@@ -0,0 +1,194 @@
+use std::io::Write;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::{Color, QrCode};
+
+use crate::errors::AppError;
+
+/// Pixels per QR module in the PNG
+const PNG_SCALE: usize = 4;
+/// Light border around the code, in modules, that scanners need
+const QUIET_ZONE: usize = 4;
+
+/// Where `register-begin --qr` puts the code.
+#[derive(Clone, Copy)]
+pub enum QrMode {
+    /// Drawn with Unicode half blocks on stderr, for an SSH session
+    Terminal,
+    /// A `data:image/png;base64,` URI in the output's `qrPng` member
+    Png,
+}
+
+/// Parses `--qr`, which only makes sense when the hints steer the browser
+/// towards a phone.
+pub fn mode(value: &str, hints: Option<&str>) -> Result<QrMode, AppError> {
+    if !hints.is_some_and(|hints| hints.split(',').any(|hint| hint.trim() == "hybrid")) {
+        return Err(AppError::InvalidInput("--qr needs --hints to include hybrid".to_string()));
+    }
+    match value {
+        "terminal" => Ok(QrMode::Terminal),
+        "png" => Ok(QrMode::Png),
+        other => Err(AppError::InvalidInput(format!(
+            "Unsupported QR output: {} (expected terminal or png)",
+            other
+        ))),
+    }
+}
+
+/// Adds a QR code to `register-begin` output so a phone can act as the
+/// authenticator. It encodes `enroll_url`, with `{challengeId}` replaced, or
+/// else the begin output's `data` itself.
+pub fn attach(begin_output: &str, mode: QrMode, enroll_url: Option<&str>, out: &mut dyn Write) -> Result<String, AppError> {
+    let mut output: serde_json::Value = serde_json::from_str(begin_output)?;
+    let data = &output["data"];
+    let payload = match enroll_url {
+        Some(url) => url.replace("{challengeId}", data["challengeId"].as_str().unwrap_or_default()),
+        None => data.to_string(),
+    };
+    let code = QrCode::new(payload.as_bytes()).map_err(|e| AppError::InvalidInput(format!("Cannot encode a QR code: {}", e)))?;
+
+    match mode {
+        QrMode::Terminal => {
+            // Inverted so the code reads as dark-on-light on a dark terminal
+            let art = code
+                .render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build();
+            writeln!(out, "{}", art)?;
+        }
+        QrMode::Png => {
+            output["data"]["qrPng"] = format!("data:image/png;base64,{}", STANDARD.encode(png(&code))).into();
+        }
+    }
+    Ok(serde_json::to_string(&output)?)
+}
+
+/// Encodes the code as a 1-bit grayscale PNG with stored (uncompressed)
+/// deflate blocks, which keeps this free of an image library.
+fn png(code: &QrCode) -> Vec<u8> {
+    let modules = code.width() + 2 * QUIET_ZONE;
+    let size = modules * PNG_SCALE;
+    let colors = code.to_colors();
+    let dark = |x: usize, y: usize| {
+        let (x, y) = (x / PNG_SCALE, y / PNG_SCALE);
+        (QUIET_ZONE..QUIET_ZONE + code.width()).contains(&x)
+            && (QUIET_ZONE..QUIET_ZONE + code.width()).contains(&y)
+            && colors[(y - QUIET_ZONE) * code.width() + x - QUIET_ZONE] == Color::Dark
+    };
+
+    // Scanlines: filter type 0, then one bit per pixel with 1 = white
+    let row_bytes = size.div_ceil(8);
+    let mut raw = Vec::with_capacity(size * (row_bytes + 1));
+    for y in 0..size {
+        raw.push(0);
+        let mut row = vec![0u8; row_bytes];
+        for x in (0..size).filter(|&x| !dark(x, y)) {
+            row[x / 8] |= 0x80 >> (x % 8);
+        }
+        raw.extend_from_slice(&row);
+    }
+
+    // zlib stream of stored blocks
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(u16::MAX as usize).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push(u8::from(i == blocks.len() - 1));
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&[1, 0, 0, 0, 0]); // bit depth 1, grayscale, no interlace
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, body) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(body);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_mode_requires_hybrid_hint() {
+        assert!(matches!(mode("png", Some("security-key, hybrid")), Ok(QrMode::Png)));
+        assert!(matches!(mode("png", Some("security-key")), Err(AppError::InvalidInput(_))));
+        assert!(matches!(mode("png", None), Err(AppError::InvalidInput(_))));
+        assert!(matches!(mode("svg", Some("hybrid")), Err(AppError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_attach_png_and_terminal() {
+        let begin = r#"{"success":true,"data":{"publicKey":{"hints":["hybrid"]},"challengeId":"abc"}}"#;
+
+        let output = attach(
+            begin,
+            QrMode::Png,
+            Some("https://router.lan/enroll?c={challengeId}"),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        let output: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let png = STANDARD
+            .decode(
+                output["data"]["qrPng"]
+                    .as_str()
+                    .unwrap()
+                    .strip_prefix("data:image/png;base64,")
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        let modules = QrCode::new("https://router.lan/enroll?c=abc").unwrap().width();
+        let size = (modules + 2 * QUIET_ZONE) * PNG_SCALE;
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()) as usize, size);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        let mut art = Vec::new();
+        let output = attach(begin, QrMode::Terminal, None, &mut art).unwrap();
+        assert_eq!(output, serde_json::from_str::<serde_json::Value>(begin).unwrap().to_string());
+        assert!(String::from_utf8(art).unwrap().contains('▀'));
+    }
+}
@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "x86")]
+const AUDIT_ARCH: u32 = 0x4000_0003;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(all(target_arch = "arm", target_endian = "little"))]
+const AUDIT_ARCH: u32 = 0x4000_0028;
+#[cfg(all(target_arch = "mips", target_endian = "big"))]
+const AUDIT_ARCH: u32 = 0x0000_0008;
+#[cfg(all(target_arch = "mips", target_endian = "little"))]
+const AUDIT_ARCH: u32 = 0x4000_0008;
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: u32 = 0xc000_00f3;
+#[cfg(target_arch = "powerpc")]
+const AUDIT_ARCH: u32 = 0x0000_0014;
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    all(target_arch = "arm", target_endian = "little"),
+    target_arch = "mips",
+    target_arch = "riscv64",
+    target_arch = "powerpc",
+)))]
+compile_error!("the seccomp feature supports x86, x86_64, aarch64, arm, mips, riscv64 and powerpc");
+
+/// Offsets into `struct seccomp_data`; the low half of the first argument
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+#[cfg(target_endian = "little")]
+const ARG0_OFFSET: u32 = 16;
+#[cfg(target_endian = "big")]
+const ARG0_OFFSET: u32 = 20;
+
+/// More than any verification result the child sends back
+const MAX_RESULT_BYTES: u64 = 1 << 20;
+
+/// What the child may do besides writing its result: manage memory, read
+/// the clock, handle signals and exit.
+fn allowed_syscalls() -> Vec<u32> {
+    let mut allowed = vec![
+        libc::SYS_brk,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_futex,
+        libc::SYS_getrandom,
+        libc::SYS_clock_gettime,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+    #[cfg(not(target_arch = "arm"))]
+    allowed.push(libc::SYS_mmap);
+    #[cfg(target_pointer_width = "32")]
+    allowed.push(libc::SYS_mmap2);
+    allowed.into_iter().map(|nr| nr as u32).collect()
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump_if_equal(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Installs the filter on the calling process; anything not allowed kills
+/// it, as does a write to any descriptor but `out`.
+fn confine(out: libc::c_int) -> io::Result<()> {
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    let mut program = vec![
+        statement(load, ARCH_OFFSET),
+        jump_if_equal(AUDIT_ARCH, 1, 0),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, NR_OFFSET),
+        jump_if_equal(libc::SYS_write as u32, 0, 4),
+        statement(load, ARG0_OFFSET),
+        jump_if_equal(out as u32, 0, 1),
+        statement(ret, libc::SECCOMP_RET_ALLOW),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+    ];
+    for nr in allowed_syscalls() {
+        program.push(jump_if_equal(nr, 0, 1));
+        program.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    }
+    program.push(statement(ret, libc::SECCOMP_RET_KILL_PROCESS));
+
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: plain prctl calls; `fprog` points at `program`, alive until
+    // the kernel has copied it
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &fprog as *const libc::sock_fprog,
+            ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Runs `work` in a forked child confined by seccomp-bpf and returns its
+/// result, which the child sends back serialized over a pipe. The child may
+/// only use memory, read the clock, write to that pipe and exit: opening a
+/// file, spawning or exec'ing kills it. `work` must parse and verify the
+/// client response itself, since the caller only ever sees what it returns;
+/// input that crashes the parsers fails the command, and an exploit in it
+/// is left holding a process that can reach nothing but the pipe.
+pub fn confined<T: Serialize + DeserializeOwned>(work: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe2 stores
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: both descriptors were just created and are owned here alone
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    // SAFETY: the child only runs `work` under the filter and leaves with
+    // _exit, never returning into the caller's stack
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if pid == 0 {
+        let code = match confine(writer.as_raw_fd()) {
+            Ok(()) => match catch_unwind(AssertUnwindSafe(work)) {
+                Ok(result) => send(&writer, result),
+                Err(_) => 2,
+            },
+            Err(_) => 1,
+        };
+        // SAFETY: ends the child without running the parent's destructors
+        unsafe { libc::_exit(code) };
+    }
+    drop(writer);
+
+    // Read before waiting, so a result larger than the pipe buffer cannot
+    // block the child; a child that sends more than any result is cut off
+    let mut sent = Vec::new();
+    let read = reader.take(MAX_RESULT_BYTES).read_to_end(&mut sent);
+    let mut status = 0;
+    // SAFETY: waits for the child forked above
+    while unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err.into());
+        }
+    }
+    match (libc::WIFEXITED(status), libc::WEXITSTATUS(status)) {
+        (true, 0) => {}
+        (true, 1) => return Err(AppError::Config("Failed to install the seccomp filter".to_string())),
+        _ => {
+            crate::syslog::warning("client response crashed the sandboxed parser");
+            return Err(AppError::InvalidInput(
+                "Client response was rejected by the parser sandbox".to_string(),
+            ));
+        }
+    }
+    read?;
+    match serde_json::from_slice::<Result<T, (String, String)>>(&sent) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err((code, detail))) => Err(rebuild(&code, detail)),
+        Err(_) => Err(AppError::InvalidInput(
+            "Client response was rejected by the parser sandbox".to_string(),
+        )),
+    }
+}
+
+/// Writes the child's result to the pipe and returns its exit code.
+fn send<T: Serialize>(writer: &OwnedFd, result: Result<T, AppError>) -> libc::c_int {
+    let result = result.map_err(|e| (e.error_code().to_string(), e.detail()));
+    let Ok(bytes) = serde_json::to_vec(&result) else {
+        return 3;
+    };
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        // SAFETY: rest is valid for reads of rest.len() bytes
+        match unsafe { libc::write(writer.as_raw_fd(), rest.as_ptr().cast(), rest.len()) } {
+            n if n > 0 => rest = &rest[n as usize..],
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+            _ => return 3,
+        }
+    }
+    0
+}
+
+/// Turns an error the child reported back into the error it was; the
+/// verification steps only raise errors that carry a plain message.
+fn rebuild(code: &str, detail: String) -> AppError {
+    match code {
+        "CHALLENGE_EXPIRED" => AppError::ChallengeExpired(detail),
+        "CREDENTIAL_NOT_FOUND" => AppError::CredentialNotFound(detail),
+        "CREDENTIAL_DISABLED" => AppError::CredentialDisabled(detail),
+        "INVALID_ORIGIN" => AppError::InvalidOrigin(detail),
+        "WEBAUTHN_ERROR" => AppError::WebAuthn(detail),
+        "STORAGE_ERROR" => AppError::Storage(detail),
+        "METADATA_ERROR" => AppError::Metadata(detail),
+        "ATTESTATION_UNTRUSTED" => AppError::AttestationUntrusted(detail),
+        _ => AppError::InvalidInput(detail),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_may_parse_but_not_open_files() {
+        let parsed = confined(|| Ok(serde_json::from_str::<serde_json::Value>(r#"{"a": [1, 2, "three"]}"#)?)).unwrap();
+        assert_eq!(parsed["a"][2], "three");
+        assert!(matches!(
+            confined(|| Ok(std::fs::File::open("/etc/passwd").is_ok())),
+            Err(AppError::InvalidInput(_))
+        ));
+        // Writing anywhere but the result pipe is fatal too
+        assert!(matches!(
+            confined(|| {
+                // SAFETY: writes one byte from a static buffer
+                Ok(unsafe { libc::write(libc::STDERR_FILENO, b"x".as_ptr().cast(), 1) })
+            }),
+            Err(AppError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            confined::<()>(|| Err(AppError::WebAuthn("bad signature".to_string()))),
+            Err(AppError::WebAuthn(detail)) if detail == "bad signature"
+        ));
+    }
+}
@@ -1,26 +1,107 @@
+use std::cell::Cell;
+
 use serde::Serialize;
 
+use crate::errors::AppError;
+
+/// Latest version of the JSON output contract. Bump it whenever a response
+/// shape changes incompatibly and keep the old shape selectable.
+pub const API_VERSION: u32 = 2;
+
+/// Contract of callers that do not ask for one, so LuCI packages written
+/// before `--api-version` keep getting the shapes they parse.
+pub const DEFAULT_API_VERSION: u32 = 1;
+
+/// Output contract versions this build can still produce.
+pub const SUPPORTED_API_VERSIONS: [u32; 2] = [1, 2];
+
+thread_local! {
+    static SELECTED_API_VERSION: Cell<u32> = const { Cell::new(DEFAULT_API_VERSION) };
+}
+
+/// Pins the output contract for this invocation (`--api-version`, or
+/// `apiVersion` in a call); without one, the default contract is used.
+pub fn select_api_version(requested: Option<&str>) -> Result<(), AppError> {
+    let Some(requested) = requested else {
+        pin_api_version(DEFAULT_API_VERSION);
+        return Ok(());
+    };
+    let version = requested
+        .parse::<u32>()
+        .ok()
+        .filter(|v| SUPPORTED_API_VERSIONS.contains(v))
+        .ok_or_else(|| {
+            AppError::InvalidInput(format!(
+                "Unsupported API version: {} (supported: {:?})",
+                requested, SUPPORTED_API_VERSIONS
+            ))
+        })?;
+    pin_api_version(version);
+    Ok(())
+}
+
+/// Renders the following responses in `version`, which must be supported.
+pub fn pin_api_version(version: u32) {
+    SELECTED_API_VERSION.with(|selected| selected.set(version));
+}
+
+/// The output contract version responses are rendered in.
+pub fn api_version() -> u32 {
+    SELECTED_API_VERSION.with(Cell::get)
+}
+
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SuccessResponse<T: Serialize> {
     pub success: bool,
+    #[serde(rename = "apiVersion")]
+    pub api_version: u32,
+    #[serde(rename = "traceId", skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
     pub data: T,
 }
 
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ErrorResponse {
     pub success: bool,
+    #[serde(rename = "apiVersion")]
+    pub api_version: u32,
+    #[serde(rename = "traceId", skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
     pub error: ErrorDetail,
 }
 
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ErrorDetail {
     pub code: String,
+    /// v1: the error's display text, e.g. `Invalid input: ...`; v2: only
+    /// what follows the prefix
     pub message: String,
+    /// v2: `client`, `auth` or `server`, who has to act on the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// v2: whether starting over can succeed without changing anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retryable: Option<bool>,
+    /// Policy rule behind a `POLICY_VIOLATION`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// Seconds the challenge's creation time lies ahead of the clock
+    /// (negative: behind), behind a `CLOCK_SKEW`
+    #[serde(rename = "skewSecs", skip_serializing_if = "Option::is_none")]
+    pub skew_secs: Option<i64>,
 }
 
 impl<T: Serialize> SuccessResponse<T> {
     pub fn new(data: T) -> Self {
-        Self { success: true, data }
+        Self {
+            success: true,
+            api_version: api_version(),
+            trace_id: crate::trace::current(),
+            data,
+        }
     }
 }
 
@@ -28,16 +109,119 @@ impl ErrorResponse {
     pub fn new(code: &str, message: &str) -> Self {
         Self {
             success: false,
+            api_version: api_version(),
+            trace_id: crate::trace::current(),
             error: ErrorDetail {
                 code: code.to_string(),
                 message: message.to_string(),
+                category: None,
+                retryable: None,
+                rule: None,
+                skew_secs: None,
             },
         }
     }
+
+    pub fn from_error(err: &AppError) -> Self {
+        let mut response = if api_version() >= 2 {
+            let mut response = Self::new(err.error_code(), &err.detail());
+            response.error.category = Some(err.category().to_string());
+            response.error.retryable = Some(err.retryable());
+            response
+        } else {
+            Self::new(err.error_code(), &err.to_string())
+        };
+        response.error.rule = err.rule().map(str::to_string);
+        response.error.skew_secs = err.skew_secs();
+        response
+    }
+}
+
+/// Shape of the options returned by `register-begin` and `login-begin`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ClientFormat {
+    /// `{ "publicKey": <options JSON>, "challengeId": ... }`
+    #[default]
+    Native,
+    /// `{ "options": { "publicKey": <options JSON> }, "challengeId": ... }`, where
+    /// `options` can be handed unchanged to webauthn-json's `create()`/`get()`
+    WebauthnJson,
+}
+
+impl ClientFormat {
+    pub fn parse(format: Option<&str>) -> Result<Self, AppError> {
+        match format {
+            None | Some("native") => Ok(Self::Native),
+            Some("webauthn-json") => Ok(Self::WebauthnJson),
+            Some(other) => Err(AppError::InvalidInput(format!(
+                "Unsupported client format: {} (expected native or webauthn-json)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Output of list commands: JSON for scripts, or aligned columns for people.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Table,
+}
+
+impl OutputFormat {
+    pub fn parse(format: Option<&str>) -> Result<Self, AppError> {
+        match format {
+            None | Some("json") => Ok(Self::Json),
+            Some("table") => Ok(Self::Table),
+            Some(other) => Err(AppError::InvalidInput(format!(
+                "Unsupported output format: {} (expected json or table)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Lays out `rows` under `headers` in columns two spaces apart.
+pub fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let header_row: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    std::iter::once(&header_row)
+        .chain(rows)
+        .map(|row| {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            line.join("  ").trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Schema A/C: Register/Login Begin Output
+pub fn begin_data(public_key: serde_json::Value, challenge_id: String, format: ClientFormat) -> serde_json::Value {
+    match format {
+        ClientFormat::Native => serde_json::json!({
+            "publicKey": public_key,
+            "challengeId": challenge_id,
+        }),
+        ClientFormat::WebauthnJson => serde_json::json!({
+            "options": { "publicKey": public_key },
+            "challengeId": challenge_id,
+        }),
+    }
 }
 
 /// Schema B: Register Finish Output
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct RegisterFinishData {
     pub credential_id: String,
@@ -47,49 +231,177 @@ pub struct RegisterFinishData {
 
 /// Schema D: Login Finish Output
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct LoginFinishData {
     pub username: String,
+    /// The key that logged in, which `reauth-begin` asks for again
+    pub credential_id: String,
     pub user_verified: bool,
     pub counter: u32,
+    /// Whether the key that logged in is designated for configuration changes
+    pub admin: bool,
+    /// The user's roles, as set with `user-manage set-roles`
+    pub roles: Vec<String>,
+    /// The user's groups, as set with `user-manage set-groups`
+    pub groups: Vec<String>,
 }
 
 /// Schema E: Credential List Item
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct CredentialListItem {
     pub credential_id: String,
     pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rp_id: Option<String>,
     pub device_name: String,
     pub created_at: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_used_at: Option<String>,
+    /// Client address and user agent of the last registration or login, as
+    /// the web frontend reported them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_user_agent: Option<String>,
     pub backup_eligible: bool,
+    /// Whether the authenticator last reported the credential as backed up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backed_up: Option<bool>,
     pub user_verified: bool,
+    pub sign_count: u32,
+    pub use_count: u64,
+    /// Failed verifications since the last successful login
+    pub failed_attempts: u32,
+    /// Last login in which the user was verified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_user_verified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uv_initialized: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<String>,
+    pub uv_capable: bool,
+    pub enabled: bool,
+    /// Designated for configuration changes; view-only otherwise
+    pub admin: bool,
+    /// WebAuthn transport names, e.g. `["nfc", "usb"]`
+    #[cfg_attr(feature = "schema", schemars(with = "Vec<String>"))]
+    pub transports: webauthn_rp::response::AuthTransports,
+    /// Attachments browsers reported at logins
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub seen_attachments: Vec<String>,
+    /// Last login through an attachment `transports` does not explain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport_drift: Option<String>,
+    /// Authenticator model, for UIs that show icons; absent for credentials
+    /// registered before it was recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aaguid: Option<String>,
+    /// Authenticator model name from the FIDO metadata, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authenticator: Option<String>,
+}
+
+/// Credential Show Output: a list item plus the stored attestation statement
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialDetail {
+    #[serde(flatten)]
+    pub item: CredentialListItem,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<crate::storage::StoredAttestation>,
 }
 
 /// Schema F: Credential Update Output
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct CredentialUpdateData {
     pub credential_id: String,
     pub old_name: String,
     pub new_name: String,
+    pub admin: bool,
 }
 
 /// Schema G: Health Check Output
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct HealthCheckData {
     pub status: String,
     pub version: String,
     pub storage: StorageStatus,
+    pub challenges: ChallengeStatus,
+    pub api_versions: ApiVersions,
 }
 
+/// Output contracts on offer, so a caller can pick the newest it understands
 #[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ApiVersions {
+    /// The contract this response is rendered in
+    pub selected: u32,
+    /// The contract used without `--api-version`
+    pub default: u32,
+    pub latest: u32,
+    pub supported: Vec<u32>,
+}
+
+impl ApiVersions {
+    pub fn current() -> Self {
+        Self {
+            selected: api_version(),
+            default: DEFAULT_API_VERSION,
+            latest: API_VERSION,
+            supported: SUPPORTED_API_VERSIONS.to_vec(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct StorageStatus {
     pub writable: bool,
+    /// `read_write`, or `read_only` with `read_only_store`
+    pub mode: String,
+    /// Location writes currently go to
     pub path: String,
     pub count: usize,
+    /// Every configured location, when `credentials_paths` lists several
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<LocationStatus>,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct LocationStatus {
+    pub path: String,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeStatus {
+    /// Bytes of entropy in each WebAuthn challenge
+    pub length_bytes: usize,
+    pub pending: usize,
+    pub oldest_pending_age_secs: Option<u64>,
+    pub free_bytes: Option<u64>,
+}
+
+/// Storage Maintenance Output
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceData {
+    pub removed_challenges: usize,
+    pub folded_entries: usize,
 }
@@ -0,0 +1,40 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+pub use zeroize::{Zeroize, Zeroizing};
+
+/// Compares two identifiers (credential IDs, challenge IDs) in constant time
+/// with respect to their contents, so the position of the first mismatching
+/// byte is not observable through timing.
+pub fn ct_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// SHA-256 of a client's clientDataJSON, base64url-encoded so it can be used
+/// as a file name in the replay cache.
+pub fn client_data_digest(client_data_json: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(client_data_json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq("abc", "abc"));
+        assert!(!ct_eq("abc", "abd"));
+        assert!(!ct_eq("abc", "abcd"));
+        assert!(ct_eq("", ""));
+    }
+
+    #[test]
+    fn test_client_data_digest() {
+        let digest = client_data_digest(b"{}");
+        assert_eq!(digest.len(), 43);
+        assert_eq!(digest, client_data_digest(b"{}"));
+        assert_ne!(digest, client_data_digest(b"{ }"));
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::commands::rpcd;
+use crate::commands::storage::run_maintenance;
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::schemas::{ErrorResponse, SuccessResponse};
+use crate::storage::StorageProvider;
+
+/// How often the accept loop wakes up to check for SIGTERM and run maintenance
+const POLL_INTERVAL_MS: libc::c_int = 1000;
+
+/// Set by SIGTERM/SIGINT; the accept loop exits once the current request is done
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_termination(_signal: libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() -> Result<(), AppError> {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        let previous = unsafe { libc::signal(signal, request_termination as *const () as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            return Err(AppError::Config(format!(
+                "Failed to install signal handler: {}",
+                io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Binds the service socket, replacing one left behind by an unclean exit.
+/// Called while still root, so the socket may live in a root-owned directory.
+pub fn bind(path: &Path) -> Result<UnixListener, AppError> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(AppError::Config(format!(
+                "{} is already served by another process",
+                path.display()
+            )));
+        }
+        fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+    Ok(listener)
+}
+
+/// First descriptor passed by a socket-activating supervisor (`SD_LISTEN_FDS_START`)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening socket handed over by a supervisor that started the
+/// service on demand, following the `LISTEN_FDS`/`LISTEN_PID` convention.
+/// Returns `None` when the variables are absent or meant for another process.
+pub fn activated_listener() -> Result<Option<UnixListener>, AppError> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<u32>().ok());
+    // Not inherited by anything spawned later
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let count = match count {
+        Some(n) if for_us && n > 0 => n,
+        _ => return Ok(None),
+    };
+    if count != 1 {
+        return Err(AppError::Config(format!("Expected one activated socket, got {}", count)));
+    }
+
+    let fd = LISTEN_FDS_START;
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: listening and len are valid for writes of the sizes passed.
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 || listening == 0 {
+        return Err(AppError::Config(format!(
+            "Activated descriptor {} is not a listening socket",
+            fd
+        )));
+    }
+    let mut domain: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: as above.
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut domain as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 || domain != libc::AF_UNIX {
+        return Err(AppError::Config(format!("Activated descriptor {} is not a Unix socket", fd)));
+    }
+    // SAFETY: fd is open; F_SETFD only changes its descriptor flags.
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    // SAFETY: fd was checked to be a listening Unix socket and nothing else in this process owns it.
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    Ok(Some(listener))
+}
+
+/// Detaches from the terminal for use without a supervisor.
+pub fn daemonize() -> Result<(), AppError> {
+    // SAFETY: the process is single-threaded at this point, so fork is sound.
+    match unsafe { libc::fork() } {
+        -1 => return Err(AppError::Config(format!("fork failed: {}", io::Error::last_os_error()))),
+        0 => {}
+        // SAFETY: _exit has no preconditions; the parent must not run destructors.
+        _ => unsafe { libc::_exit(0) },
+    }
+    // SAFETY: setsid has no preconditions.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(AppError::Config(format!("setsid failed: {}", io::Error::last_os_error())));
+    }
+    let null = fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both descriptors are open; dup2 atomically replaces fd.
+        unsafe { libc::dup2(null.as_raw_fd(), fd) };
+    }
+    Ok(())
+}
+
+/// Reads one request line: `{"method": "...", "args": {...}}`. The whole
+/// line must arrive within `input_timeout_secs`, so a client trickling it in
+/// cannot hold the only worker.
+fn read_request(stream: &UnixStream, config: &Config) -> Result<(String, String), AppError> {
+    let reader = crate::input::body(Some(stream.as_raw_fd()), config.input_timeout_secs)?;
+    let mut line = String::new();
+    BufReader::new(reader.take(config.max_input_bytes as u64 + 1))
+        .read_line(&mut line)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => AppError::InvalidInput("Timed out reading the request".to_string()),
+            _ => e.into(),
+        })?;
+    if line.len() > config.max_input_bytes {
+        return Err(AppError::InvalidInput(format!(
+            "Input exceeds the {} byte limit",
+            config.max_input_bytes
+        )));
+    }
+
+    let request: serde_json::Value = serde_json::from_str(&line).map_err(|e| AppError::InvalidInput(format!("Invalid request: {}", e)))?;
+    let method = request
+        .get("method")
+        .and_then(|m| m.as_str())
+        .ok_or_else(|| AppError::InvalidInput("Missing request method".to_string()))?;
+    let args = request.get("args").map(|a| a.to_string()).unwrap_or_default();
+    Ok((method.to_string(), args))
+}
+
+fn error_json(code: &str, message: &str) -> String {
+    serde_json::to_string(&ErrorResponse::new(code, message)).unwrap_or_default()
+}
+
+/// Answers one connection with the same JSON `call <method>` would print.
+fn handle(storage: &dyn StorageProvider, config: &Config, mut stream: UnixStream) {
+    let timeout = (config.input_timeout_secs > 0).then(|| Duration::from_secs(config.input_timeout_secs));
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_write_timeout(timeout);
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        // A fresh trace ID per request unless the call brings its own
+        crate::trace::select(None)?;
+        let (method, args) = read_request(&stream, config)?;
+        rpcd::call(storage, config, &method, &mut args.as_bytes())
+    }));
+    let reply = match result {
+        Ok(Ok(json)) => json,
+        Ok(Err(err)) => serde_json::to_string(&ErrorResponse::from_error(&err)).unwrap_or_default(),
+        Err(_panic) => error_json("INTERNAL_ERROR", "An unexpected internal error occurred"),
+    };
+    let _ = writeln!(stream, "{}", reply);
+}
+
+/// Serves requests until SIGTERM/SIGINT. Requests are handled one at a time,
+/// so the one in flight when the signal arrives completes before shutdown;
+/// pending housekeeping is then flushed and the process exits 0. A non-zero
+/// `idle_exit_secs` also stops after that long without a connection, for
+/// supervisors that start the service on demand.
+pub fn serve(storage: &dyn StorageProvider, config: &Config, listener: UnixListener, idle_exit_secs: u64) -> Result<String, AppError> {
+    install_signal_handlers()?;
+    listener.set_nonblocking(true)?;
+    let trace_id = crate::trace::current();
+    let api_version = crate::schemas::api_version();
+
+    let interval = Duration::from_secs(config.maintenance_interval_secs);
+    let mut next_maintenance = Instant::now() + interval;
+    let mut served: u64 = 0;
+    let mut last_request = Instant::now();
+
+    // The one-shot CLI may change the store behind the cached copy
+    #[cfg(target_os = "linux")]
+    let watcher = crate::inotify::Watcher::new(&storage.cached_files())?;
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut fds = vec![libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    }];
+    #[cfg(target_os = "linux")]
+    fds.extend(watcher.iter().map(|watcher| libc::pollfd {
+        fd: watcher.fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    }));
+
+    while !TERMINATE.load(Ordering::SeqCst) {
+        // SAFETY: fds holds valid pollfds for the duration of the call.
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_INTERVAL_MS) };
+        // Changes that happened before the connection are seen before it is served
+        #[cfg(target_os = "linux")]
+        if watcher.as_ref().is_some_and(|watcher| watcher.changed()) {
+            storage.reload();
+        }
+        if ready > 0 && fds[0].revents & libc::POLLIN != 0 {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    handle(storage, config, stream);
+                    // A call's apiVersion only applies to its own reply
+                    crate::schemas::pin_api_version(api_version);
+                    served += 1;
+                    last_request = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => crate::syslog::warning(&format!("accept failed: {}", e)),
+            }
+        }
+
+        if config.maintenance_interval_secs > 0 && Instant::now() >= next_maintenance {
+            if let Err(e) = run_maintenance(storage) {
+                crate::syslog::warning(&format!("maintenance failed: {}", e));
+            }
+            next_maintenance = Instant::now() + interval;
+        }
+
+        if idle_exit_secs > 0 && last_request.elapsed() >= Duration::from_secs(idle_exit_secs) {
+            break;
+        }
+    }
+
+    // Fold the usage log and drop expired challenges before exiting
+    crate::trace::select(trace_id.as_deref())?;
+    let maintenance = run_maintenance(storage)?;
+    let response = SuccessResponse::new(serde_json::json!({
+        "served": served,
+        "removedChallenges": maintenance.removed_challenges,
+        "foldedEntries": maintenance.folded_entries,
+    }));
+    Ok(serde_json::to_string(&response)?)
+}
+
+/// procd init script running `serve --foreground` under supervision.
+pub fn init_script(binary: &str, socket: &str) -> Result<String, AppError> {
+    Ok(format!(
+        "#!/bin/sh /etc/rc.common\n\
+         # Installed as /etc/init.d/webauthn-helper\n\
+         \n\
+         START=95\n\
+         STOP=10\n\
+         USE_PROCD=1\n\
+         \n\
+         start_service() {{\n\
+         \tprocd_open_instance\n\
+         \tprocd_set_param command {binary} serve --foreground --socket {socket}\n\
+         \tprocd_set_param file {config}\n\
+         \tprocd_set_param respawn\n\
+         \tprocd_set_param term_timeout 10\n\
+         \tprocd_set_param stdout 1\n\
+         \tprocd_set_param stderr 1\n\
+         \tprocd_close_instance\n\
+         }}",
+        config = crate::config::DEFAULT_CONFIG_PATH,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_handle_answers_one_request_per_connection() {
+        let storage = MemoryStorage::default();
+        let config = Config::default();
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"{\"method\":\"health_check\"}\n").unwrap();
+        handle(&storage, &config, server);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["success"], true);
+        assert_eq!(reply["traceId"].as_str().unwrap().len(), 32);
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client
+            .write_all(b"{\"method\":\"health_check\",\"args\":{\"traceId\":\"ui-7\"}}\n")
+            .unwrap();
+        handle(&storage, &config, server);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["traceId"], "ui-7");
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"{\"args\":{}}\n").unwrap();
+        handle(&storage, &config, server);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["error"]["code"], "INVALID_INPUT");
+    }
+
+    #[test]
+    fn test_trickled_request_times_out_as_a_whole() {
+        let storage = MemoryStorage::default();
+        let config = Config {
+            input_timeout_secs: 1,
+            ..Config::default()
+        };
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut writer = client.try_clone().unwrap();
+        // Each byte well within the timeout, the line far beyond it
+        std::thread::spawn(move || {
+            for byte in b"{\"method\":\"health_check\"}\n" {
+                std::thread::sleep(Duration::from_millis(300));
+                if writer.write_all(&[*byte]).is_err() {
+                    break;
+                }
+            }
+        });
+        let started = Instant::now();
+        handle(&storage, &config, server);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["error"]["code"], "INVALID_INPUT");
+    }
+
+    #[test]
+    fn test_bind_replaces_stale_socket() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("helper.sock");
+        drop(bind(&path).unwrap());
+        let _listener = bind(&path).unwrap();
+        assert!(bind(&path).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+    }
+}
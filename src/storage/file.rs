@@ -0,0 +1,2593 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fs2::FileExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::*;
+use crate::config::{Config, StorageFormat, StorageLayout};
+use crate::errors::AppError;
+use crate::wal;
+
+/// Required mode for credentials.json (owner read/write only)
+const SECRET_FILE_MODE: u32 = 0o600;
+
+/// CBOR self-describe tag (RFC 8949 §3.4.6) prefixed to CBOR credential
+/// files; it can never start a JSON document.
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Directory of the credential store, its logs and lock files: the part of
+/// `/etc/webauthn` that `run_as_user` is given. The configuration and the
+/// audit key stay in the parent, which remains root's.
+pub const DATA_DIR: &str = "/etc/webauthn/data";
+
+/// Where releases before [`DATA_DIR`] kept the store
+pub const LEGACY_DATA_DIR: &str = "/etc/webauthn";
+
+/// What [`migrate_data_dir`] moves, besides rotated audit logs
+const DATA_ENTRIES: [&str; 10] = [
+    "credentials.json",
+    "credentials.json.lock",
+    "credentials.wal",
+    "users",
+    "pinned_origins.json",
+    "audit.log",
+    "audit.head",
+    "audit.anchor",
+    "mds.json",
+    "backup_state.json",
+];
+
+// ─── FileStorage Implementation ───
+
+pub struct FileStorage {
+    credentials_path: PathBuf,
+    /// Further locations of the single-file store, after `credentials_path`
+    fallback_paths: Vec<PathBuf>,
+    users_dir: PathBuf,
+    layout: StorageLayout,
+    format: StorageFormat,
+    wal_path: PathBuf,
+    usage_wal: bool,
+    wal_max_entries: usize,
+    read_only: bool,
+    pinned_origins_path: PathBuf,
+    audit_log_path: PathBuf,
+    audit_head_path: PathBuf,
+    audit_key_path: PathBuf,
+    audit_anchor_path: PathBuf,
+    audit_retention: AuditRetention,
+    mds_cache_path: PathBuf,
+    backup_state_path: PathBuf,
+    challenge_dir: PathBuf,
+    replay_dir: PathBuf,
+    challenge_dir_mode: u32,
+    challenge_file_mode: u32,
+    /// Whether writes are flushed to disk, file and directory, before returning
+    sync: bool,
+    /// Decoded single-file store kept between requests, when enabled
+    cache: Option<RefCell<Option<CachedStore>>>,
+}
+
+/// What `stat` tells about a file's content: rewriting it in place changes
+/// its times and replacing it its inode.
+#[derive(Debug, Clone, PartialEq)]
+struct Fingerprint {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+    len: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl Fingerprint {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        Some(Self {
+            path: path.to_path_buf(),
+            dev: meta.dev(),
+            ino: meta.ino(),
+            len: meta.len(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+        })
+    }
+}
+
+struct CachedStore {
+    fingerprint: Fingerprint,
+    store: CredentialStore,
+}
+
+/// When the audit log is rotated and how long rotated logs are kept.
+#[derive(Debug, Clone, Copy)]
+struct AuditRetention {
+    /// Rotate once the log holds this many bytes (0 = never)
+    rotate_bytes: u64,
+    /// Rotate once the log's first entry is this old (0 = never)
+    rotate_secs: u64,
+    max_files: usize,
+    /// Delete rotated logs this old, except the newest (0 = never)
+    max_age_secs: u64,
+}
+
+impl AuditRetention {
+    fn new(config: &Config) -> Self {
+        const DAY_SECS: u64 = 24 * 60 * 60;
+        Self {
+            rotate_bytes: config.audit_rotate_bytes,
+            rotate_secs: config.audit_rotate_days.saturating_mul(DAY_SECS),
+            max_files: config.audit_max_files.max(1),
+            max_age_secs: config.audit_max_days.saturating_mul(DAY_SECS),
+        }
+    }
+}
+
+impl FileStorage {
+    pub fn new(config: &Config) -> Self {
+        // A store not moved yet (e.g. a read-only one) is used where it is
+        let legacy = Path::new(LEGACY_DATA_DIR);
+        let dir = if !Path::new(DATA_DIR).exists() && DATA_ENTRIES.iter().any(|name| legacy.join(name).exists()) {
+            legacy
+        } else {
+            Path::new(DATA_DIR)
+        };
+        let mut locations = config.credentials_paths.iter().map(PathBuf::from);
+        Self {
+            credentials_path: locations.next().unwrap_or_else(|| dir.join("credentials.json")),
+            fallback_paths: locations.collect(),
+            users_dir: dir.join("users"),
+            layout: config.storage_layout,
+            format: config.storage_format,
+            // A read-only store keeps its usage log on a writable side file
+            // and never folds it
+            wal_path: if config.read_only_store {
+                PathBuf::from(&config.usage_side_file)
+            } else {
+                dir.join("credentials.wal")
+            },
+            usage_wal: config.usage_wal || config.read_only_store,
+            wal_max_entries: if config.read_only_store { 0 } else { config.wal_max_entries },
+            read_only: config.read_only_store,
+            pinned_origins_path: dir.join("pinned_origins.json"),
+            audit_log_path: dir.join("audit.log"),
+            audit_head_path: dir.join("audit.head"),
+            audit_key_path: legacy.join("audit.key"),
+            audit_anchor_path: dir.join("audit.anchor"),
+            audit_retention: AuditRetention::new(config),
+            mds_cache_path: dir.join("mds.json"),
+            backup_state_path: dir.join("backup_state.json"),
+            challenge_dir: PathBuf::from("/tmp/webauthn/challenges"),
+            replay_dir: PathBuf::from("/tmp/webauthn/replay"),
+            challenge_dir_mode: config.challenge_dir_mode(),
+            challenge_file_mode: config.challenge_file_mode(),
+            sync: true,
+            cache: None,
+        }
+    }
+
+    /// Storage kept beside `credentials_path`, for tests and `self-test`.
+    pub fn with_paths(credentials_path: PathBuf, challenge_dir: PathBuf) -> Self {
+        let config = Config::default();
+        let replay_dir = challenge_dir.with_file_name("replay");
+        let users_dir = credentials_path.with_file_name("users");
+        let wal_path = credentials_path.with_extension("wal");
+        let pinned_origins_path = credentials_path.with_file_name("pinned_origins.json");
+        let audit_log_path = credentials_path.with_file_name("audit.log");
+        let audit_head_path = credentials_path.with_file_name("audit.head");
+        let audit_key_path = credentials_path.with_file_name("audit.key");
+        let audit_anchor_path = credentials_path.with_file_name("audit.anchor");
+        let mds_cache_path = credentials_path.with_file_name("mds.json");
+        let backup_state_path = credentials_path.with_file_name("backup_state.json");
+        Self {
+            credentials_path,
+            fallback_paths: Vec::new(),
+            users_dir,
+            layout: config.storage_layout,
+            format: config.storage_format,
+            wal_path,
+            usage_wal: config.usage_wal,
+            wal_max_entries: config.wal_max_entries,
+            read_only: false,
+            pinned_origins_path,
+            audit_log_path,
+            audit_head_path,
+            audit_key_path,
+            audit_anchor_path,
+            audit_retention: AuditRetention::new(&config),
+            mds_cache_path,
+            backup_state_path,
+            challenge_dir,
+            replay_dir,
+            challenge_dir_mode: config.challenge_dir_mode(),
+            challenge_file_mode: config.challenge_file_mode(),
+            sync: true,
+            cache: None,
+        }
+    }
+
+    /// Keeps the decoded single-file store in memory for a long-running
+    /// process, decoding it again only once the file changes.
+    pub fn with_cache(mut self) -> Self {
+        self.cache = Some(RefCell::new(None));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_fallbacks(mut self, fallback_paths: Vec<PathBuf>) -> Self {
+        self.fallback_paths = fallback_paths;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_read_only(mut self, wal_path: PathBuf) -> Self {
+        self.read_only = true;
+        self.usage_wal = true;
+        self.wal_max_entries = 0;
+        self.wal_path = wal_path;
+        self
+    }
+
+    /// Turns off flushing writes to disk, for benchmarks. A power loss may
+    /// then lose changes the caller was told succeeded.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: StorageLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn with_format(mut self, format: StorageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_usage_wal(mut self, usage_wal: bool, wal_max_entries: usize) -> Self {
+        self.usage_wal = usage_wal;
+        self.wal_max_entries = wal_max_entries;
+        self
+    }
+
+    /// Flushes a written file's data and size to disk.
+    fn sync_file(&self, file: &fs::File) -> Result<(), AppError> {
+        if self.sync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes a directory, so files created, renamed or removed in it
+    /// survive a power loss.
+    fn sync_dir(&self, dir: &Path) -> Result<(), AppError> {
+        if self.sync {
+            fs::File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the directory holding `path`.
+    fn sync_parent(&self, path: &Path) -> Result<(), AppError> {
+        let parent = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        self.sync_dir(parent.unwrap_or(Path::new(".")))
+    }
+
+    /// Replaces a secret (0600) file through a temporary file and a rename, so
+    /// it is never seen half written. Where the kernel and filesystem allow,
+    /// the temporary file gets its name only once it is complete.
+    fn write_private(&self, path: &Path, data: &[u8]) -> Result<(), AppError> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        #[cfg(target_os = "linux")]
+        let linked = self.link_tmpfile(&temp, data)?;
+        #[cfg(not(target_os = "linux"))]
+        let linked = false;
+        if !linked {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(SECRET_FILE_MODE)
+                .open(&temp)?;
+            file.write_all(data)?;
+            self.sync_file(&file)?;
+        }
+        fs::rename(&temp, path)?;
+        self.sync_parent(path)
+    }
+
+    /// Writes `data` to an unnamed O_TMPFILE in `temp`'s directory and links
+    /// it there as `temp`. Returns false, dropping the unnamed file, when the
+    /// kernel, the filesystem or the sandbox do not support it: linking the
+    /// descriptor itself needs CAP_DAC_READ_SEARCH, linking through
+    /// `/proc/self/fd` a mounted procfs.
+    #[cfg(target_os = "linux")]
+    fn link_tmpfile(&self, temp: &Path, data: &[u8]) -> Result<bool, AppError> {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::AsRawFd;
+
+        let dir = temp
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let Ok(mut file) = fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .mode(SECRET_FILE_MODE)
+            .open(dir)
+        else {
+            return Ok(false);
+        };
+        file.write_all(data)?;
+        self.sync_file(&file)?;
+
+        let target = std::ffi::CString::new(temp.as_os_str().as_bytes())
+            .map_err(|_| AppError::Storage(format!("Invalid path {}", temp.display())))?;
+        // Left over from an interrupted write; linkat never replaces a name
+        match fs::remove_file(temp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let fd = file.as_raw_fd();
+        // SAFETY: fd is the open O_TMPFILE descriptor held by `file`, and both
+        // paths are NUL-terminated C strings that outlive the call.
+        if unsafe { libc::linkat(fd, c"".as_ptr(), libc::AT_FDCWD, target.as_ptr(), libc::AT_EMPTY_PATH) } == 0 {
+            return Ok(true);
+        }
+        let source = std::ffi::CString::new(format!("/proc/self/fd/{}", fd)).expect("no NUL in a number");
+        // SAFETY: source and target are NUL-terminated CStrings that outlive the
+        // call; source names fd, which `file` keeps open.
+        let linked = unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                source.as_ptr(),
+                libc::AT_FDCWD,
+                target.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        };
+        Ok(linked == 0)
+    }
+
+    /// Creates a challenge or replay directory with `challenge_dir_mode`, or
+    /// checks one that exists: anyone may have created it first in a shared
+    /// location such as /tmp, so it must be a real directory owned by one of
+    /// `owners`, and a lax mode is tightened.
+    fn private_dir(&self, dir: &Path, owners: &[u32]) -> Result<(), AppError> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.challenge_dir_mode)
+            .create(dir)?;
+        let metadata = fs::symlink_metadata(dir)?;
+        if !metadata.is_dir() || !owners.contains(&metadata.uid()) {
+            return Err(AppError::InsecurePermissions(format!(
+                "{} is not a directory owned by uid {:?}",
+                dir.display(),
+                owners
+            )));
+        }
+        if metadata.permissions().mode() & 0o7777 != self.challenge_dir_mode {
+            fs::set_permissions(dir, fs::Permissions::from_mode(self.challenge_dir_mode))?;
+        }
+        Ok(())
+    }
+
+    fn warn_permissions(&self, path: &Path) -> Result<(), AppError> {
+        for violation in permission_violations(path, SECRET_FILE_MODE)? {
+            crate::syslog::warning(&violation);
+        }
+        Ok(())
+    }
+
+    /// Whether the per-user layout is configured and has been written at
+    /// least once. Until then, an existing single-file store is still read.
+    fn per_user_active(&self) -> bool {
+        self.layout == StorageLayout::PerUser && self.users_dir.exists()
+    }
+
+    /// File holding a single user's record; the name is base64url-encoded so
+    /// any username maps to a safe file name.
+    fn user_file(&self, username: &str) -> PathBuf {
+        self.users_dir.join(format!("{}.json", URL_SAFE_NO_PAD.encode(username)))
+    }
+
+    /// Lock guarding the per-user directory as a whole: whole-store operations
+    /// take it exclusively, single-user operations share it.
+    fn lock_users_dir(&self, exclusive: bool) -> Result<Option<fs::File>, AppError> {
+        // Nothing writes a read-only store, and its lock file cannot be opened
+        if self.read_only {
+            return Ok(None);
+        }
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&self.users_dir)?;
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(SECRET_FILE_MODE)
+            .open(self.users_dir.join(".lock"))?;
+        let result = if exclusive { lock.lock_exclusive() } else { lock.lock_shared() };
+        result.map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        Ok(Some(lock))
+    }
+
+    /// Every location of the single-file store, in order of preference.
+    fn locations(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.credentials_path).chain(&self.fallback_paths)
+    }
+
+    /// Location reads are served from: the first one holding a readable copy.
+    fn read_location(&self) -> &Path {
+        self.locations()
+            .find(|path| fs::File::open(path).is_ok())
+            .unwrap_or(&self.credentials_path)
+    }
+
+    /// Lock file of the single-file store, beside the first location whose
+    /// directory exists, e.g. `credentials.json.lock`.
+    fn store_lock_path(&self) -> PathBuf {
+        let location = self
+            .locations()
+            .find(|path| path.parent().is_some_and(Path::exists))
+            .unwrap_or(&self.credentials_path);
+        let mut name = location.as_os_str().to_owned();
+        name.push(".lock");
+        PathBuf::from(name)
+    }
+
+    /// Takes the lock every access to the single-file store holds: shared to
+    /// read, exclusive to write. New generations of the store replace it
+    /// whole, so the lock keeps writers from losing each other's changes and
+    /// readers from seeing a store an older, in-place release is still
+    /// writing. Readers that cannot
+    /// create the lock file (a read-only store, or before anything was
+    /// written) use it only if it exists.
+    fn lock_store(&self, exclusive: bool) -> Result<Option<fs::File>, AppError> {
+        let path = self.store_lock_path();
+        if exclusive && self.fallback_paths.is_empty() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let opened = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(SECRET_FILE_MODE)
+            .open(&path);
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) if exclusive => return Err(e.into()),
+            Err(_) => match fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let result = if exclusive { file.lock_exclusive() } else { file.lock_shared() };
+        result.map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        Ok(Some(file))
+    }
+
+    /// Opens and exclusively locks the store at the first location that can
+    /// be written. With fallbacks configured, a location whose directory does
+    /// not exist (e.g. an unmounted USB stick) is skipped rather than created.
+    fn lock_write_location(&self) -> Result<(&Path, fs::File), AppError> {
+        if self.fallback_paths.is_empty() {
+            if let Some(parent) = self.credentials_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        for path in self.locations() {
+            let opened = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(SECRET_FILE_MODE)
+                .open(path);
+            match opened {
+                Ok(file) => {
+                    file.lock_exclusive()
+                        .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+                    return Ok((path, file));
+                }
+                Err(e) if self.fallback_paths.is_empty() => return Err(e.into()),
+                Err(e) => crate::syslog::warning(&format!("cannot write credential store at {}: {}", path.display(), e)),
+            }
+        }
+        Err(AppError::Storage("No credential store location is writable".to_string()))
+    }
+
+    /// Copies freshly written store data to every other location whose
+    /// directory exists. A copy that cannot be written only produces a warning.
+    fn mirror(&self, written: &Path, data: &[u8]) {
+        for path in self.locations().filter(|path| path.as_path() != written) {
+            if !path.parent().is_some_and(Path::exists) {
+                continue;
+            }
+            if let Err(e) = self.write_private(path, data) {
+                crate::syslog::warning(&format!("failed to mirror credential store to {}: {}", path.display(), e));
+            }
+        }
+    }
+
+    fn load_single(&self) -> Result<CredentialStore, AppError> {
+        self.with_single(CredentialStore::clone)
+    }
+
+    /// Runs `f` on the single-file store. With the cache enabled, a store
+    /// whose file has not changed since it was last decoded is reused.
+    fn with_single<T>(&self, f: impl FnOnce(&CredentialStore) -> T) -> Result<T, AppError> {
+        let _lock = self.lock_store(false)?;
+        let path = self.read_location();
+        let Some(cache) = &self.cache else {
+            if !path.exists() {
+                return Ok(f(&CredentialStore::default()));
+            }
+            self.warn_permissions(path)?;
+            return Ok(f(&decode_record(&fs::read(path)?)?));
+        };
+        let Some(fingerprint) = Fingerprint::of(path) else {
+            *cache.borrow_mut() = None;
+            return Ok(f(&CredentialStore::default()));
+        };
+        let mut cached = cache.borrow_mut();
+        match cached.as_ref() {
+            Some(hit) if hit.fingerprint == fingerprint => {}
+            _ => {
+                self.warn_permissions(path)?;
+                let store = decode_record(&fs::read(path)?)?;
+                *cached = Some(CachedStore { fingerprint, store });
+            }
+        }
+        Ok(f(&cached.as_ref().expect("filled above").store))
+    }
+
+    /// Drops the cached store after a write, in case the write left the
+    /// file's size and times (at their granularity) as they were.
+    fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            *cache.borrow_mut() = None;
+        }
+    }
+
+    /// Reads every user file. The caller holds the directory lock.
+    fn load_per_user(&self) -> Result<CredentialStore, AppError> {
+        let mut store = CredentialStore::default();
+        for entry in fs::read_dir(&self.users_dir)? {
+            let path = entry?.path();
+            let Some(username) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .filter(|_| path.extension().and_then(|e| e.to_str()) == Some("json"))
+                .and_then(|s| URL_SAFE_NO_PAD.decode(s).ok())
+                .and_then(|b| String::from_utf8(b).ok())
+            else {
+                continue;
+            };
+            self.warn_permissions(&path)?;
+            let record: UserRecord = decode_record(&read_shared(&path)?)?;
+            store.users.insert(username, record);
+        }
+        Ok(store)
+    }
+
+    /// Writes one file per user and removes files of users no longer in the
+    /// store, as well as a single-file store left over from before the
+    /// layout switch. The caller holds the directory lock exclusively.
+    fn save_per_user(&self, store: &CredentialStore) -> Result<(), AppError> {
+        for (username, record) in &store.users {
+            self.write_private(&self.user_file(username), &encode_record(record, self.format)?)?;
+        }
+        let keep: Vec<PathBuf> = store.users.keys().map(|u| self.user_file(u)).collect();
+        for entry in fs::read_dir(&self.users_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("json") && !keep.contains(&path) {
+                fs::remove_file(&path)?;
+            }
+        }
+        self.sync_dir(&self.users_dir)?;
+        if self.credentials_path.exists() {
+            fs::remove_file(&self.credentials_path)?;
+            self.sync_parent(&self.credentials_path)?;
+        }
+        Ok(())
+    }
+
+    /// The `n`th newest rotated audit log, `audit.log.<n>.gz`.
+    fn rotated_audit_log(&self, n: usize) -> PathBuf {
+        let mut path = self.audit_log_path.clone().into_os_string();
+        path.push(format!(".{}.gz", n));
+        path.into()
+    }
+
+    /// The rotated audit logs, newest first.
+    fn rotated_audit_logs(&self) -> Vec<PathBuf> {
+        (1..)
+            .map(|n| self.rotated_audit_log(n))
+            .take_while(|path| path.exists())
+            .collect()
+    }
+
+    /// Every persistent file that must stay private: the credentials, the
+    /// pinned origins, the audit logs and the backup state.
+    fn secret_files(&self) -> Result<Vec<PathBuf>, AppError> {
+        let mut files: Vec<PathBuf> = self.locations().filter(|path| path.exists()).cloned().collect();
+        for path in [
+            &self.wal_path,
+            &self.pinned_origins_path,
+            &self.audit_log_path,
+            &self.audit_head_path,
+            &self.audit_key_path,
+            &self.audit_anchor_path,
+            &self.backup_state_path,
+        ] {
+            if path.exists() {
+                files.push(path.clone());
+            }
+        }
+        files.extend(self.rotated_audit_logs());
+        if self.users_dir.exists() {
+            for entry in fs::read_dir(&self.users_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                    files.push(path);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Creates the directories the store, its logs and the challenges live in
+    /// and returns them: everything this storage may create, change or
+    /// delete, for Landlock to grant exactly. The audit key is created as
+    /// well, so it only ever needs to be read. A directory holding or above
+    /// `config_path` is refused, as the configuration must stay out of reach.
+    pub fn writable_dirs(&self, config_path: &Path) -> Result<Vec<PathBuf>, AppError> {
+        let mut dirs: Vec<PathBuf> = self
+            .locations()
+            .chain([
+                &self.wal_path,
+                &self.pinned_origins_path,
+                &self.audit_log_path,
+                &self.audit_head_path,
+                &self.audit_anchor_path,
+                &self.mds_cache_path,
+                &self.backup_state_path,
+                &self.users_dir,
+            ])
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        let config_dir = config_path.parent().unwrap_or(Path::new("/"));
+        for dir in &dirs {
+            if config_dir.starts_with(dir) {
+                return Err(AppError::Config(format!(
+                    "Landlock cannot confine a store in {}, which holds the configuration; move it to a directory of its own such as {} or set landlock to false",
+                    dir.display(),
+                    DATA_DIR
+                )));
+            }
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        for dir in [&self.challenge_dir, &self.replay_dir] {
+            self.private_dir(dir, &[expected_owner().0])?;
+            dirs.push(dir.clone());
+        }
+        if !self.read_only {
+            // A key that cannot be created now could not be created confined either
+            let _ = self.audit_key(true);
+        }
+        Ok(dirs)
+    }
+
+    /// Files outside [`writable_dirs`](Self::writable_dirs) the storage reads.
+    pub fn readable_files(&self) -> Vec<PathBuf> {
+        vec![self.audit_key_path.clone()]
+    }
+
+    /// Creates the storage directories and transfers ownership of them (and
+    /// any existing store or challenge files) to `uid:gid`, ahead of dropping
+    /// root privileges. The directory holding `config_path` or the audit key
+    /// is never handed over: its owner could replace them, and root would
+    /// run the hooks of the replaced configuration.
+    pub fn hand_over(&self, uid: u32, gid: u32, config_path: &Path) -> Result<(), AppError> {
+        let protected: Vec<&Path> = [config_path.parent(), self.audit_key_path.parent()]
+            .into_iter()
+            .flatten()
+            .collect();
+        let mut paths = Vec::new();
+        if self.read_only {
+            // Only the side file; the store itself cannot change owner
+            if self.wal_path.exists() {
+                paths.push(self.wal_path.clone());
+            }
+        } else {
+            // The service reads the key but cannot create it in root's directory
+            self.audit_key(true)?;
+            paths.push(self.audit_key_path.clone());
+            let mut dirs: Vec<&Path> = self
+                .locations()
+                .chain([
+                    &self.wal_path,
+                    &self.pinned_origins_path,
+                    &self.audit_log_path,
+                    &self.audit_head_path,
+                    &self.audit_anchor_path,
+                    &self.mds_cache_path,
+                    &self.backup_state_path,
+                ])
+                .filter_map(|path| path.parent())
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+            for dir in dirs {
+                if protected.contains(&dir) {
+                    return Err(AppError::Privilege(format!(
+                        "Refusing to give {} to run_as_user: it holds the configuration or the audit key; keep the store in a directory of its own such as {}",
+                        dir.display(),
+                        DATA_DIR
+                    )));
+                }
+                if let Some(parent) = dir.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+                paths.push(dir.to_path_buf());
+            }
+            if self.users_dir.exists() {
+                paths.push(self.users_dir.clone());
+                paths.push(self.users_dir.join(".lock"));
+            }
+            let store_lock = self.store_lock_path();
+            if store_lock.exists() {
+                paths.push(store_lock);
+            }
+            paths.extend(self.secret_files()?);
+        }
+
+        for dir in [&self.challenge_dir, &self.replay_dir] {
+            self.private_dir(dir, &[expected_owner().0, uid])?;
+            paths.push(dir.clone());
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                // Only challenge and replay files; anything else may have been planted
+                if entry.file_type()?.is_file() {
+                    paths.push(entry.path());
+                }
+            }
+        }
+
+        for path in paths {
+            // Symlinks and special files are never followed or given away
+            let Ok(metadata) = fs::symlink_metadata(&path) else {
+                continue;
+            };
+            if !metadata.is_file() && !metadata.is_dir() {
+                continue;
+            }
+            std::os::unix::fs::lchown(&path, Some(uid), Some(gid))
+                .map_err(|e| AppError::Privilege(format!("Failed to change owner of {}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+}
+
+// Credential store access through the write-ahead log
+impl FileStorage {
+    /// Opens and locks the write-ahead log. Returns `None` when there is no
+    /// log to honour: it is disabled and no entries are left over from when
+    /// it was enabled. Every credential store access takes this lock before
+    /// any lock of the store itself.
+    fn lock_wal(&self, exclusive: bool) -> Result<Option<fs::File>, AppError> {
+        if !self.usage_wal && !self.wal_path.exists() {
+            return Ok(None);
+        }
+        let file = if exclusive {
+            if let Some(parent) = self.wal_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(SECRET_FILE_MODE)
+                .open(&self.wal_path)?
+        } else {
+            match fs::File::open(&self.wal_path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let result = if exclusive { file.lock_exclusive() } else { file.lock_shared() };
+        result.map_err(|e| AppError::Storage(format!("Failed to acquire write-ahead log lock: {}", e)))?;
+        Ok(Some(file))
+    }
+
+    /// Rewrites the store with the log folded in and `mutate` applied, then
+    /// empties the log. The caller holds the log's exclusive lock.
+    fn fold_wal(
+        &self,
+        wal: &fs::File,
+        entries: &[wal::UsageEntry],
+        mutate: &mut dyn FnMut(&mut CredentialStore) -> Result<(), AppError>,
+    ) -> Result<(), AppError> {
+        self.rewrite_store(&mut |store| {
+            wal::replay(store, entries);
+            mutate(store)
+        })?;
+        wal.set_len(0)?;
+        self.sync_file(wal)
+    }
+}
+
+// Credential store access without the write-ahead log
+impl FileStorage {
+    fn read_store(&self) -> Result<CredentialStore, AppError> {
+        if !self.per_user_active() {
+            return self.load_single();
+        }
+        let _lock = self.lock_users_dir(false)?;
+        self.load_per_user()
+    }
+
+    fn write_store(&self, store: &CredentialStore) -> Result<(), AppError> {
+        self.invalidate_cache();
+        if self.layout == StorageLayout::PerUser {
+            let _lock = self.lock_users_dir(true)?;
+            return self.save_per_user(store);
+        }
+        let _lock = self.lock_store(true)?;
+        self.write_single(store)
+    }
+
+    /// Replaces the single-file store. The caller holds the store lock.
+    fn write_single(&self, store: &CredentialStore) -> Result<(), AppError> {
+        // Also locked for releases that only locked the store file itself
+        let (target, _file) = self.lock_write_location()?;
+        let data = encode_record(store, self.format)?;
+        self.write_private(target, &data)?;
+        self.mirror(target, &data);
+
+        // Lock is released when file is dropped
+        Ok(())
+    }
+
+    fn rewrite_store(&self, mutate: &mut dyn FnMut(&mut CredentialStore) -> Result<(), AppError>) -> Result<(), AppError> {
+        self.invalidate_cache();
+        if self.layout == StorageLayout::PerUser {
+            let migrating = !self.users_dir.exists();
+            let _lock = self.lock_users_dir(true)?;
+            let mut store = if migrating { self.load_single()? } else { self.load_per_user()? };
+            mutate(&mut store)?;
+            return self.save_per_user(&store);
+        }
+        let _lock = self.lock_store(true)?;
+        let source = self.read_location().to_path_buf();
+        if !source.exists() {
+            let mut store = CredentialStore::default();
+            mutate(&mut store)?;
+            return self.write_single(&store);
+        }
+        self.warn_permissions(&source)?;
+
+        let (target, mut file) = self.lock_write_location()?;
+        let data = if target == source {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            data
+        } else {
+            // Failing over: start from the copy reads are served from
+            fs::read(&source)?
+        };
+        let mut store: CredentialStore = decode_record(&data)?;
+        mutate(&mut store)?;
+
+        let data = encode_record(&store, self.format)?;
+        self.write_private(target, &data)?;
+        self.mirror(target, &data);
+        Ok(())
+    }
+
+    fn read_user(&self, username: &str) -> Result<Option<UserRecord>, AppError> {
+        if !self.per_user_active() {
+            return self.with_single(|store| store.users.get(username).cloned());
+        }
+        let _lock = self.lock_users_dir(false)?;
+        let path = self.user_file(username);
+        let data = match read_shared(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        self.warn_permissions(&path)?;
+        Ok(Some(decode_record(&data)?))
+    }
+
+    fn rewrite_user(&self, username: &str, mutate: &mut dyn FnMut(&mut UserRecord) -> Result<(), AppError>) -> Result<(), AppError> {
+        if !self.per_user_active() {
+            return self.rewrite_store(&mut |store| {
+                let record = store
+                    .users
+                    .get_mut(username)
+                    .ok_or_else(|| AppError::UserNotFound(username.to_string()))?;
+                mutate(record)
+            });
+        }
+        let _lock = self.lock_users_dir(false)?;
+        let path = self.user_file(username);
+        let mut file = loop {
+            let file = match fs::OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(AppError::UserNotFound(username.to_string())),
+                Err(e) => return Err(e.into()),
+            };
+            file.lock_exclusive()
+                .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+            // The writer holding the lock before may have replaced the file
+            if fs::metadata(&path)?.ino() == file.metadata()?.ino() {
+                break file;
+            }
+        };
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut record: UserRecord = decode_record(&data)?;
+        mutate(&mut record)?;
+
+        // Still holding the old file's lock until the new one is in place
+        self.write_private(&path, &encode_record(&record, self.format)?)
+    }
+}
+
+impl StorageProvider for FileStorage {
+    fn load_credentials(&self) -> Result<CredentialStore, AppError> {
+        let wal = self.lock_wal(false)?;
+        let mut store = self.read_store()?;
+        if let Some(mut wal) = wal {
+            wal::replay(&mut store, &wal::read_entries(&mut wal)?);
+        }
+        Ok(store)
+    }
+
+    fn save_credentials(&self, store: &CredentialStore) -> Result<(), AppError> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        let wal = self.lock_wal(true)?;
+        self.write_store(store)?;
+        if let Some(wal) = wal {
+            wal.set_len(0)?;
+            self.sync_file(&wal)?;
+        }
+        Ok(())
+    }
+
+    fn modify_credentials(&self, mutate: &mut dyn FnMut(&mut CredentialStore) -> Result<(), AppError>) -> Result<(), AppError> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        match self.lock_wal(true)? {
+            Some(mut wal) => {
+                let entries = wal::read_entries(&mut wal)?;
+                self.fold_wal(&wal, &entries, mutate)
+            }
+            None => self.rewrite_store(mutate),
+        }
+    }
+
+    fn load_user(&self, username: &str) -> Result<Option<UserRecord>, AppError> {
+        let wal = self.lock_wal(false)?;
+        let mut record = self.read_user(username)?;
+        if let (Some(mut wal), Some(record)) = (wal, record.as_mut()) {
+            for entry in wal::read_entries(&mut wal)?.iter().filter(|e| e.username == username) {
+                entry.apply(record);
+            }
+        }
+        Ok(record)
+    }
+
+    fn modify_user(&self, username: &str, mutate: &mut dyn FnMut(&mut UserRecord) -> Result<(), AppError>) -> Result<(), AppError> {
+        let Some(mut wal) = self.lock_wal(true)? else {
+            return self.rewrite_user(username, mutate);
+        };
+        let entries = wal::read_entries(&mut wal)?;
+
+        let mut record = self
+            .read_user(username)?
+            .ok_or_else(|| AppError::UserNotFound(username.to_string()))?;
+        for entry in entries.iter().filter(|e| e.username == username) {
+            entry.apply(&mut record);
+        }
+        let before = record.clone();
+        mutate(&mut record)?;
+
+        // Usage updates are appended; anything else goes to the store itself
+        let changes = if self.usage_wal {
+            wal::usage_changes(username, &before, &record)
+        } else {
+            None
+        };
+        match changes {
+            Some(changes) if self.wal_max_entries == 0 || entries.len() + changes.len() < self.wal_max_entries => {
+                wal::append(&mut wal, &changes)?;
+                self.sync_file(&wal)
+            }
+            _ if self.read_only => Err(read_only_error()),
+            _ => self.fold_wal(&wal, &entries, &mut |store| {
+                store.users.insert(username.to_string(), record.clone());
+                Ok(())
+            }),
+        }
+    }
+
+    fn compact(&self) -> Result<usize, AppError> {
+        // The side file of a read-only store has nowhere to be folded into
+        if self.read_only {
+            return Ok(0);
+        }
+        let Some(mut wal) = self.lock_wal(true)? else {
+            return Ok(0);
+        };
+        let entries = wal::read_entries(&mut wal)?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        self.fold_wal(&wal, &entries, &mut |_| Ok(()))?;
+        Ok(entries.len())
+    }
+
+    fn store_stats(&self) -> Result<StoreStats, AppError> {
+        let wal = self.lock_wal(false)?;
+        let mut stats = StoreStats::default();
+        let mut files = Vec::new();
+        if self.per_user_active() {
+            let _lock = self.lock_users_dir(false)?;
+            for entry in fs::read_dir(&self.users_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                    files.push(read_shared(&path)?);
+                }
+            }
+        } else if self.read_location().exists() {
+            let _lock = self.lock_store(false)?;
+            files.push(fs::read(self.read_location())?);
+            // Not migrated to the per-user layout yet
+            stats.needs_rewrite = self.layout == StorageLayout::PerUser;
+        }
+        for data in &files {
+            stats.store_bytes += data.len() as u64;
+            if data.starts_with(&CBOR_MAGIC) != (self.format == StorageFormat::Cbor) {
+                stats.needs_rewrite = true;
+            }
+        }
+        stats.needs_rewrite &= !self.read_only;
+
+        if let Some(mut wal) = wal {
+            let entries = wal::read_entries(&mut wal)?;
+            let distinct: std::collections::HashSet<(&str, &str)> = entries
+                .iter()
+                .map(|e| (e.username.as_str(), e.credential_id.as_str()))
+                .collect();
+            stats.wal_entries = entries.len();
+            stats.wal_bytes = wal.metadata()?.len();
+            stats.superseded_entries = entries.len() - distinct.len();
+        }
+        Ok(stats)
+    }
+
+    fn load_challenge(&self, challenge_id: &str) -> Result<ChallengeState, AppError> {
+        let path = self.challenge_dir.join(format!("{}.json", challenge_id));
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(AppError::ChallengeNotFound(challenge_id.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Shared lock: never observe a challenge that is still being written
+        file.lock_shared()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire challenge lock: {}", e)))?;
+        let mut data = String::new();
+        (&file).read_to_string(&mut data)?;
+        let state: ChallengeState = serde_json::from_str(&data)?;
+
+        // Cleanup may not have run since; the recorded creation time is authoritative
+        check_challenge_age(challenge_id, &state)?;
+        Ok(state)
+    }
+
+    fn save_challenge(&self, challenge_id: &str, state: &ChallengeState) -> Result<(), AppError> {
+        self.private_dir(&self.challenge_dir, &[expected_owner().0])?;
+        let path = self.challenge_dir.join(format!("{}.json", challenge_id));
+        let data = serde_json::to_string_pretty(state)?;
+
+        // Truncate only once the exclusive lock is held, so readers never see a partial file
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .mode(self.challenge_file_mode)
+            .open(&path)?;
+        file.lock_exclusive()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire challenge lock: {}", e)))?;
+        file.set_len(0)?;
+        (&file).write_all(data.as_bytes())?;
+        self.sync_file(&file)?;
+        self.sync_parent(&path)
+    }
+
+    fn delete_challenge(&self, challenge_id: &str) -> Result<(), AppError> {
+        let path = self.challenge_dir.join(format!("{}.json", challenge_id));
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        file.lock_exclusive()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire challenge lock: {}", e)))?;
+        match fs::remove_file(&path) {
+            // Cleanup may have won the race; the challenge is gone either way
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => self.sync_parent(&path),
+        }
+    }
+
+    fn cleanup_challenges(&self) -> Result<usize, AppError> {
+        // Replay entries outlive their challenge by at most the same TTL
+        if self.replay_dir.exists() {
+            for entry in fs::read_dir(&self.replay_dir)? {
+                let path = entry?.path();
+                if is_expired(&path) {
+                    fs::remove_file(&path)?;
+                }
+            }
+            self.sync_dir(&self.replay_dir)?;
+        }
+
+        if !self.challenge_dir.exists() {
+            return Ok(0);
+        }
+        let mut count = 0;
+        let now = SystemTime::now();
+        let max_age = std::time::Duration::from_secs(CHALLENGE_MAX_AGE_SECS);
+
+        for entry in fs::read_dir(&self.challenge_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                if let Ok(metadata) = entry.metadata() {
+                    if let Ok(modified) = metadata.modified() {
+                        if let Ok(age) = now.duration_since(modified) {
+                            if age > max_age && remove_if_unlocked(&path)? {
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if count > 0 {
+            self.sync_dir(&self.challenge_dir)?;
+        }
+        Ok(count)
+    }
+
+    fn challenge_stats(&self) -> Result<ChallengeStats, AppError> {
+        let mut stats = ChallengeStats {
+            free_bytes: free_space(&self.challenge_dir),
+            ..Default::default()
+        };
+        if !self.challenge_dir.exists() {
+            return Ok(stats);
+        }
+
+        let now = SystemTime::now();
+        for entry in fs::read_dir(&self.challenge_dir)? {
+            let entry = entry?;
+            if entry.path().extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            stats.pending += 1;
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map(|age| age.as_secs());
+            if let Some(age) = age {
+                stats.oldest_age_secs = Some(stats.oldest_age_secs.map_or(age, |oldest| oldest.max(age)));
+            }
+        }
+        Ok(stats)
+    }
+
+    fn client_data_seen(&self, digest: &str) -> Result<bool, AppError> {
+        let path = self.replay_dir.join(digest);
+        Ok(path.exists() && !is_expired(&path))
+    }
+
+    fn record_client_data(&self, digest: &str) -> Result<bool, AppError> {
+        self.private_dir(&self.replay_dir, &[expected_owner().0])?;
+        let path = self.replay_dir.join(digest);
+        if path.exists() && is_expired(&path) {
+            fs::remove_file(&path)?;
+        }
+
+        // create_new makes recording atomic: only one of two racing finishes wins
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(self.challenge_file_mode)
+            .open(&path)
+        {
+            Ok(_) => {
+                self.sync_parent(&path)?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn load_pinned_origins(&self) -> Result<PinnedOrigins, AppError> {
+        if !self.pinned_origins_path.exists() {
+            return Ok(PinnedOrigins::new());
+        }
+        let file = fs::File::open(&self.pinned_origins_path)?;
+        file.lock_shared()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        let mut data = Vec::new();
+        (&file).read_to_end(&mut data)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    fn modify_pinned_origins(&self, mutate: &mut dyn FnMut(&mut PinnedOrigins) -> Result<(), AppError>) -> Result<(), AppError> {
+        if let Some(parent) = self.pinned_origins_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(SECRET_FILE_MODE)
+            .open(&self.pinned_origins_path)?;
+        file.lock_exclusive()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut pinned: PinnedOrigins = if data.is_empty() {
+            PinnedOrigins::new()
+        } else {
+            serde_json::from_slice(&data)?
+        };
+        mutate(&mut pinned)?;
+
+        let data = serde_json::to_vec_pretty(&pinned)?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&data)?;
+        self.sync_file(&file)?;
+        self.sync_parent(&self.pinned_origins_path)
+    }
+
+    fn load_audit_log(&self) -> Result<Vec<AuditEntry>, AppError> {
+        if !self.audit_log_path.exists() {
+            return Ok(Vec::new());
+        }
+        // Rotation happens under the active log's lock
+        let file = fs::File::open(&self.audit_log_path)?;
+        file.lock_shared()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        let mut entries = Vec::new();
+        for path in self.rotated_audit_logs().iter().rev() {
+            entries.extend(parse_audit_lines(&read_gzip(path)?)?);
+        }
+        let mut data = String::new();
+        (&file).read_to_string(&mut data)?;
+        entries.extend(parse_audit_lines(&data)?);
+
+        // A rotation interrupted before the log was emptied leaves its
+        // entries in both files
+        let mut seen = std::collections::HashSet::new();
+        entries.retain(|entry| seen.insert((entry.seq, entry.hash.clone())));
+        Ok(entries)
+    }
+
+    fn append_audit_entry(&self, build: &mut dyn FnMut(Option<&AuditEntry>) -> (AuditEntry, AuditHead)) -> Result<(), AppError> {
+        if let Some(parent) = self.audit_log_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(SECRET_FILE_MODE)
+            .open(&self.audit_log_path)?;
+        file.lock_exclusive()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+        let mut last: Option<AuditEntry> = parse_audit_lines(&data)?.pop();
+        // A freshly rotated log continues the chain of the newest rotated one
+        let newest = self.rotated_audit_log(1);
+        if last.is_none() && newest.exists() {
+            last = parse_audit_lines(&read_gzip(&newest)?)?.pop();
+        }
+
+        let (entry, head) = build(last.as_ref());
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        self.sync_file(&file)?;
+
+        // Still under the log's lock, so the head always names the last entry;
+        // replaced whole, so a crash never leaves it unreadable
+        self.write_private(&self.audit_head_path, &serde_json::to_vec(&head)?)
+    }
+
+    fn load_audit_head(&self) -> Result<Option<AuditHead>, AppError> {
+        if !self.audit_head_path.exists() {
+            return Ok(None);
+        }
+        // The head is rewritten under the log's lock
+        let log = fs::File::open(&self.audit_log_path).ok();
+        if let Some(log) = &log {
+            log.lock_shared()
+                .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(&self.audit_head_path)?)?))
+    }
+
+    fn rotate_audit_log(&self, anchor: &mut dyn FnMut(&AuditEntry) -> AuditAnchor) -> Result<(), AppError> {
+        if !self.audit_log_path.exists() {
+            return Ok(());
+        }
+        let file = fs::OpenOptions::new().read(true).write(true).open(&self.audit_log_path)?;
+        file.lock_exclusive()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        let retention = self.audit_retention;
+        let now = crate::clock::now_secs();
+
+        let mut data = String::new();
+        (&file).read_to_string(&mut data)?;
+        let first = parse_audit_lines(&data)?.into_iter().next();
+        let too_big = retention.rotate_bytes > 0 && data.len() as u64 >= retention.rotate_bytes;
+        let too_old = retention.rotate_secs > 0
+            && first
+                .as_ref()
+                .and_then(|entry| crate::clock::parse_rfc3339(&entry.time))
+                .is_some_and(|time| now.saturating_sub(time) >= retention.rotate_secs);
+        if first.is_some() && (too_big || too_old) {
+            let rotated = self.rotated_audit_logs();
+            for n in (1..=rotated.len()).rev() {
+                fs::rename(self.rotated_audit_log(n), self.rotated_audit_log(n + 1))?;
+            }
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(data.as_bytes())?;
+            self.write_private(&self.rotated_audit_log(1), &encoder.finish()?)?;
+            file.set_len(0)?;
+            self.sync_file(&file)?;
+        }
+
+        // The newest rotated log always stays, so the chain can continue
+        let rotated = self.rotated_audit_logs();
+        let mut keep = rotated.len().min(retention.max_files);
+        // A rotated log is as old as its last entry
+        while keep > 1 && retention.max_age_secs > 0 {
+            let last = parse_audit_lines(&read_gzip(&rotated[keep - 1])?)?.pop();
+            match last.and_then(|entry| crate::clock::parse_rfc3339(&entry.time)) {
+                Some(time) if now.saturating_sub(time) >= retention.max_age_secs => keep -= 1,
+                _ => break,
+            }
+        }
+        if keep == rotated.len() {
+            return Ok(());
+        }
+        // The anchor goes first: until the older logs are gone, their
+        // entries are merely skipped
+        let oldest_kept = parse_audit_lines(&read_gzip(&rotated[keep - 1])?)?;
+        if let Some(first) = oldest_kept.first() {
+            self.write_private(&self.audit_anchor_path, &serde_json::to_vec(&anchor(first))?)?;
+        }
+        for path in &rotated[keep..] {
+            fs::remove_file(path)?;
+        }
+        self.sync_parent(&self.audit_log_path)
+    }
+
+    fn load_audit_anchor(&self) -> Result<Option<AuditAnchor>, AppError> {
+        if !self.audit_anchor_path.exists() {
+            return Ok(None);
+        }
+        // The anchor is rewritten under the log's lock
+        let log = fs::File::open(&self.audit_log_path).ok();
+        if let Some(log) = &log {
+            log.lock_shared()
+                .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(&self.audit_anchor_path)?)?))
+    }
+
+    fn audit_key(&self, create: bool) -> Result<Option<Zeroizing<Vec<u8>>>, AppError> {
+        if self.audit_key_path.exists() || !create {
+            return match fs::read(&self.audit_key_path) {
+                Ok(key) => Ok(Some(Zeroizing::new(key))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            };
+        }
+        if let Some(parent) = self.audit_key_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut key = Zeroizing::new(vec![0u8; 32]);
+        getrandom::fill(&mut key).map_err(|e| AppError::Storage(format!("Failed to generate the audit key: {}", e)))?;
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(SECRET_FILE_MODE)
+            .open(&self.audit_key_path)
+        {
+            Ok(mut file) => {
+                file.write_all(&key)?;
+                self.sync_file(&file)?;
+                self.sync_parent(&self.audit_key_path)?;
+            }
+            // Another process created it first
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return self.audit_key(false),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Some(key))
+    }
+
+    fn load_mds_cache(&self) -> Result<Option<MdsCache>, AppError> {
+        if !self.mds_cache_path.exists() {
+            return Ok(None);
+        }
+        let file = fs::File::open(&self.mds_cache_path)?;
+        file.lock_shared()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+        let mut data = Vec::new();
+        (&file).read_to_end(&mut data)?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    fn save_mds_cache(&self, cache: &MdsCache) -> Result<(), AppError> {
+        if let Some(parent) = self.mds_cache_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.write_private(&self.mds_cache_path, &serde_json::to_vec(cache)?)
+    }
+
+    fn modify_backup_state(&self, mutate: &mut dyn FnMut(&mut BackupState) -> Result<(), AppError>) -> Result<(), AppError> {
+        if let Some(parent) = self.backup_state_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(SECRET_FILE_MODE)
+            .open(&self.backup_state_path)?;
+        file.lock_exclusive()
+            .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut state: BackupState = if data.is_empty() {
+            BackupState::default()
+        } else {
+            serde_json::from_slice(&data)?
+        };
+        mutate(&mut state)?;
+
+        let data = serde_json::to_vec_pretty(&state)?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&data)?;
+        self.sync_file(&file)?;
+        self.sync_parent(&self.backup_state_path)
+    }
+
+    fn fix_permissions(&self) -> Result<Vec<String>, AppError> {
+        let mut fixed = Vec::new();
+
+        let secret_files = self.secret_files()?;
+        for path in &secret_files {
+            repair_permissions(path, SECRET_FILE_MODE, &mut fixed)?;
+        }
+        if self.challenge_dir.exists() {
+            repair_permissions(&self.challenge_dir, self.challenge_dir_mode, &mut fixed)?;
+            for entry in fs::read_dir(&self.challenge_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                    repair_permissions(&path, self.challenge_file_mode, &mut fixed)?;
+                }
+            }
+        }
+
+        // Anything still wrong (e.g. chown without root) is reported as an error
+        let mut remaining = Vec::new();
+        for path in &secret_files {
+            remaining.extend(permission_violations(path, SECRET_FILE_MODE)?);
+        }
+        if !remaining.is_empty() {
+            return Err(AppError::InsecurePermissions(remaining.join("; ")));
+        }
+        Ok(fixed)
+    }
+
+    fn credentials_path(&self) -> &Path {
+        &self.credentials_path
+    }
+
+    fn credential_locations(&self) -> Vec<PathBuf> {
+        self.locations().cloned().collect()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn cached_files(&self) -> Vec<PathBuf> {
+        match self.cache {
+            Some(_) => self.locations().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn reload(&self) {
+        self.invalidate_cache();
+    }
+}
+
+/// Moves a store kept directly in `legacy` by earlier releases into `data`,
+/// so the directory holding the configuration and the audit key never has to
+/// be given to `run_as_user`. Entries are gathered in `<data>.new` first,
+/// which an interrupted move resumes, and `data` appears only once complete.
+pub fn migrate_data_dir(legacy: &Path, data: &Path) -> Result<(), AppError> {
+    if data.exists() || !legacy.is_dir() {
+        return Ok(());
+    }
+    let mut staging = data.as_os_str().to_owned();
+    staging.push(".new");
+    let staging = PathBuf::from(staging);
+    if !staging.exists() && !DATA_ENTRIES.iter().any(|name| legacy.join(name).exists()) {
+        return Ok(());
+    }
+
+    let lock = fs::File::open(legacy)?;
+    lock.lock_exclusive()
+        .map_err(|e| AppError::Storage(format!("Failed to acquire file lock: {}", e)))?;
+    // Another process may have moved it while this one waited
+    if data.exists() {
+        return Ok(());
+    }
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&staging)?;
+    fs::set_permissions(&staging, fs::Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(legacy)? {
+        let name = entry?.file_name();
+        let rotated_log = name
+            .to_str()
+            .is_some_and(|name| name.starts_with("audit.log.") && name.ends_with(".gz"));
+        if DATA_ENTRIES.iter().any(|entry| name == *entry) || rotated_log {
+            fs::rename(legacy.join(&name), staging.join(&name))?;
+        }
+    }
+    fs::rename(&staging, data)?;
+    fs::File::open(legacy)?.sync_all()?;
+    Ok(())
+}
+
+/// Serializes a credential file in the configured encoding.
+fn encode_record<T: Serialize>(value: &T, format: StorageFormat) -> Result<Vec<u8>, AppError> {
+    match format {
+        StorageFormat::Json => Ok(serde_json::to_vec_pretty(value)?),
+        StorageFormat::Cbor => {
+            let mut data = CBOR_MAGIC.to_vec();
+            ciborium::into_writer(value, &mut data).map_err(|e| AppError::Storage(format!("Failed to encode CBOR: {}", e)))?;
+            Ok(data)
+        }
+    }
+}
+
+/// Deserializes a credential file, detecting its encoding from the content.
+fn decode_record<T: DeserializeOwned>(data: &[u8]) -> Result<T, AppError> {
+    match data.strip_prefix(&CBOR_MAGIC) {
+        Some(cbor) => ciborium::from_reader(cbor).map_err(|e| AppError::Storage(format!("Failed to decode CBOR: {}", e))),
+        None => Ok(serde_json::from_slice(data)?),
+    }
+}
+
+/// Reads a file under a shared lock, which releases that rewrote files in
+/// place take exclusively while writing.
+fn read_shared(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    file.lock_shared()?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Parses audit log lines, skipping blank ones.
+fn parse_audit_lines(data: &str) -> Result<Vec<AuditEntry>, AppError> {
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(AppError::from))
+        .collect()
+}
+
+/// Reads a gzip-compressed text file.
+fn read_gzip(path: &Path) -> Result<String, AppError> {
+    let mut data = String::new();
+    flate2::read::GzDecoder::new(fs::File::open(path)?).read_to_string(&mut data)?;
+    Ok(data)
+}
+
+/// Removes an expired challenge file unless a ceremony currently holds its
+/// lock. Returns whether the file was removed.
+fn remove_if_unlocked(path: &Path) -> Result<bool, AppError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if file.try_lock_exclusive().is_err() {
+        return Ok(false);
+    }
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether a file's mtime is older than the challenge TTL.
+fn is_expired(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > std::time::Duration::from_secs(CHALLENGE_MAX_AGE_SECS))
+}
+
+/// Bytes available to unprivileged writers on the filesystem holding `path`,
+/// or its closest existing ancestor.
+fn free_space(path: &Path) -> Option<u64> {
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let cpath = std::ffi::CString::new(existing.as_os_str().as_encoded_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: cpath is NUL-terminated and stat points to writable memory of the right size.
+    if unsafe { libc::statvfs(cpath.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: statvfs succeeded and initialised the struct.
+    let stat = unsafe { stat.assume_init() };
+    // The field widths differ between targets (32-bit on most OpenWrt routers)
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// ─── Permission Checks ───
+
+/// Effective uid/gid the storage files are expected to be owned by.
+fn expected_owner() -> (u32, u32) {
+    // SAFETY: geteuid/getegid have no preconditions and cannot fail.
+    unsafe { (libc::geteuid(), libc::getegid()) }
+}
+
+/// Lists every way `path` deviates from the expected mode and owner.
+pub fn permission_violations(path: &Path, expected_mode: u32) -> Result<Vec<String>, AppError> {
+    let metadata = fs::metadata(path)?;
+    let mode = metadata.permissions().mode() & 0o777;
+    let (uid, _) = expected_owner();
+    let mut violations = Vec::new();
+
+    if mode != expected_mode {
+        violations.push(format!(
+            "{} has mode {:04o}, expected {:04o}",
+            path.display(),
+            mode,
+            expected_mode
+        ));
+    }
+    if metadata.uid() != uid {
+        violations.push(format!(
+            "{} is owned by uid {}, expected {}",
+            path.display(),
+            metadata.uid(),
+            uid
+        ));
+    }
+    Ok(violations)
+}
+
+fn repair_permissions(path: &Path, expected_mode: u32, fixed: &mut Vec<String>) -> Result<(), AppError> {
+    let metadata = fs::metadata(path)?;
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode != expected_mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(expected_mode))?;
+        fixed.push(format!("{}: mode {:04o} -> {:04o}", path.display(), mode, expected_mode));
+    }
+
+    let (uid, gid) = expected_owner();
+    if metadata.uid() != uid || metadata.gid() != gid {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid))
+            .map_err(|e| AppError::InsecurePermissions(format!("Failed to change owner of {}: {}", path.display(), e)))?;
+        fixed.push(format!(
+            "{}: owner {}:{} -> {}:{}",
+            path.display(),
+            metadata.uid(),
+            metadata.gid(),
+            uid,
+            gid
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn test_storage() -> (FileStorage, TempDir) {
+        let dir = TempDir::new().unwrap();
+        let cred_path = dir.path().join("credentials.json");
+        let challenge_dir = dir.path().join("challenges");
+        let storage = FileStorage::with_paths(cred_path, challenge_dir);
+        (storage, dir)
+    }
+
+    #[test]
+    fn test_load_empty_credentials() {
+        let (storage, _dir) = test_storage();
+        let store = storage.load_credentials().unwrap();
+        assert!(store.users.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load_credentials() {
+        let (storage, _dir) = test_storage();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "test_user_id".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        let loaded = storage.load_credentials().unwrap();
+        assert!(loaded.users.contains_key("root"));
+    }
+
+    #[test]
+    fn test_failover_locations() {
+        let dir = TempDir::new().unwrap();
+        let usb = dir.path().join("usb/credentials.json");
+        let overlay = dir.path().join("overlay/credentials.json");
+        fs::create_dir(dir.path().join("overlay")).unwrap();
+        let storage = FileStorage::with_paths(usb.clone(), dir.path().join("challenges")).with_fallbacks(vec![overlay.clone()]);
+        let user = |user_id: &str| UserRecord {
+            user_id: user_id.to_string(),
+            recovery_codes: vec![],
+            roles: vec![],
+            groups: vec![],
+            credentials: vec![],
+        };
+
+        // The USB stick is not mounted: its directory is not created
+        let mut store = CredentialStore::default();
+        store.users.insert("root".to_string(), user("a"));
+        storage.save_credentials(&store).unwrap();
+        assert!(!usb.exists());
+        assert!(storage.load_credentials().unwrap().users.contains_key("root"));
+
+        // Once it is, writes start from the overlay copy and are mirrored
+        fs::create_dir(dir.path().join("usb")).unwrap();
+        update_store(&storage, |store| {
+            store.users.insert("admin".to_string(), user("b"));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs::read(&usb).unwrap(), fs::read(&overlay).unwrap());
+        assert_eq!(storage.load_credentials().unwrap().users.len(), 2);
+
+        fs::remove_dir_all(dir.path().join("usb")).unwrap();
+        assert_eq!(storage.load_credentials().unwrap().users.len(), 2);
+    }
+
+    #[test]
+    fn test_update_store() {
+        let (storage, _dir) = test_storage();
+        let user_count = update_store(&storage, |store| {
+            store.users.insert(
+                "root".to_string(),
+                UserRecord {
+                    user_id: "uid".to_string(),
+                    recovery_codes: vec![],
+                    roles: vec![],
+                    groups: vec![],
+                    credentials: vec![],
+                },
+            );
+            Ok(store.users.len())
+        })
+        .unwrap();
+        assert_eq!(user_count, 1);
+
+        // A failing mutation leaves the store untouched
+        let result: Result<(), AppError> = update_store(&storage, |store| {
+            store.users.clear();
+            Err(AppError::InvalidInput("abort".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(storage.load_credentials().unwrap().users.contains_key("root"));
+    }
+
+    #[test]
+    fn test_per_user_layout() {
+        let (storage, _dir) = test_storage();
+        let record = |id: &str| UserRecord {
+            user_id: id.to_string(),
+            recovery_codes: vec![],
+            roles: vec![],
+            groups: vec![],
+            credentials: vec![],
+        };
+
+        // An existing single-file store is migrated on the first write
+        let mut store = CredentialStore::default();
+        store.users.insert("root".to_string(), record("r"));
+        storage.save_credentials(&store).unwrap();
+
+        let storage = storage.with_layout(StorageLayout::PerUser);
+        assert_eq!(storage.load_user("root").unwrap().unwrap().user_id, "r");
+        update_store(&storage, |store| {
+            store.users.insert("admin/../x".to_string(), record("a"));
+            Ok(())
+        })
+        .unwrap();
+        assert!(!storage.credentials_path().exists());
+        assert!(storage.user_file("admin/../x").starts_with(&storage.users_dir));
+        assert_eq!(storage.load_credentials().unwrap().users.len(), 2);
+
+        update_user(&storage, "root", |user| {
+            user.user_id = "r2".to_string();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(storage.load_user("root").unwrap().unwrap().user_id, "r2");
+        assert!(storage.load_user("nobody").unwrap().is_none());
+        assert!(matches!(
+            update_user(&storage, "nobody", |_| Ok(())),
+            Err(AppError::UserNotFound(_))
+        ));
+
+        // A user file being rewritten in place is not read until it is done
+        let writing = fs::OpenOptions::new().write(true).open(storage.user_file("root")).unwrap();
+        writing.lock_exclusive().unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        let (path, challenges) = (storage.credentials_path.clone(), storage.challenge_dir.clone());
+        let reader = std::thread::spawn(move || {
+            let storage = FileStorage::with_paths(path, challenges).with_layout(StorageLayout::PerUser);
+            let loaded = storage.load_user("root").map(|user| user.map(|user| user.user_id));
+            done.send(()).unwrap();
+            loaded
+        });
+        assert!(finished.recv_timeout(std::time::Duration::from_millis(200)).is_err());
+        drop(writing);
+        assert_eq!(reader.join().unwrap().unwrap().as_deref(), Some("r2"));
+
+        // Users dropped from the store lose their file
+        update_store(&storage, |store| {
+            store.users.remove("root");
+            Ok(())
+        })
+        .unwrap();
+        assert!(!storage.user_file("root").exists());
+        assert!(storage.fix_permissions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cbor_format_roundtrip_and_detection() {
+        let (storage, _dir) = test_storage();
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        let json_len = fs::metadata(storage.credentials_path()).unwrap().len();
+
+        // A JSON store is still read after switching, and rewritten as CBOR
+        let storage = storage.with_format(StorageFormat::Cbor);
+        update_store(&storage, |_| Ok(())).unwrap();
+        let data = fs::read(storage.credentials_path()).unwrap();
+        assert!(data.starts_with(&CBOR_MAGIC));
+        assert!((data.len() as u64) < json_len);
+        assert_eq!(storage.load_credentials().unwrap().users["root"].user_id, "uid");
+
+        // ...and the other way round
+        let storage = storage.with_format(StorageFormat::Json);
+        assert!(storage.load_user("root").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_store_lock_holds_off_readers_and_writers() {
+        let (storage, dir) = test_storage();
+        storage.save_credentials(&CredentialStore::default()).unwrap();
+        assert!(dir.path().join("credentials.json.lock").exists());
+
+        let writer = storage.lock_store(true).unwrap().unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        let (path, challenges) = (storage.credentials_path().to_path_buf(), dir.path().join("challenges"));
+        let reader = std::thread::spawn(move || {
+            let storage = FileStorage::with_paths(path, challenges);
+            let loaded = storage.load_credentials().map(|store| store.users.len());
+            done.send(()).unwrap();
+            loaded
+        });
+        assert!(finished.recv_timeout(std::time::Duration::from_millis(200)).is_err());
+        drop(writer);
+        finished.recv().unwrap();
+        assert_eq!(reader.join().unwrap().unwrap(), 0);
+
+        // Several readers at once
+        let _shared = storage.lock_store(false).unwrap().unwrap();
+        assert!(storage.load_credentials().unwrap().users.is_empty());
+    }
+
+    #[test]
+    fn test_cached_store_follows_changes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("credentials.json");
+        let cached = FileStorage::with_paths(path.clone(), dir.path().join("challenges")).with_cache();
+        let other = FileStorage::with_paths(path, dir.path().join("challenges"));
+        let user = |user_id: &str| UserRecord {
+            user_id: user_id.to_string(),
+            recovery_codes: vec![],
+            roles: vec![],
+            groups: vec![],
+            credentials: vec![],
+        };
+        assert!(cached.load_user("root").unwrap().is_none());
+
+        // Written by another process
+        let mut store = CredentialStore::default();
+        store.users.insert("root".to_string(), user("a"));
+        other.save_credentials(&store).unwrap();
+        assert_eq!(cached.load_user("root").unwrap().unwrap().user_id, "a");
+        assert_eq!(cached.load_credentials().unwrap().users.len(), 1);
+
+        // Written by this one, possibly within the same mtime tick
+        update_store(&cached, |store| {
+            store.users.insert("root".to_string(), user("b"));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(cached.load_user("root").unwrap().unwrap().user_id, "b");
+
+        update_store(&other, |store| {
+            store.users.insert("admin".to_string(), user("c"));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(cached.load_credentials().unwrap().users.len(), 2);
+    }
+
+    #[test]
+    fn test_usage_wal() {
+        let (storage, _dir) = test_storage();
+        let storage = storage.with_usage_wal(true, 3);
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        let base = fs::read(storage.credentials_path()).unwrap();
+
+        // Usage updates only append to the log
+        for count in 1..=2 {
+            update_user(&storage, "root", |user| {
+                user.credentials[0].sign_count = count;
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(fs::read(storage.credentials_path()).unwrap(), base);
+        assert_eq!(storage.load_user("root").unwrap().unwrap().credentials[0].sign_count, 2);
+        assert_eq!(storage.load_credentials().unwrap().users["root"].credentials[0].sign_count, 2);
+
+        assert_eq!(storage.compact().unwrap(), 2);
+        assert_eq!(storage.compact().unwrap(), 0);
+        assert_eq!(storage.read_store().unwrap().users["root"].credentials[0].sign_count, 2);
+
+        // Other changes, and reaching the entry limit, rewrite the store
+        update_user(&storage, "root", |user| {
+            user.credentials[0].device_name = "renamed".to_string();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            storage.read_store().unwrap().users["root"].credentials[0].device_name,
+            "renamed"
+        );
+        for count in 3..=5 {
+            update_user(&storage, "root", |user| {
+                user.credentials[0].sign_count = count;
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(storage.read_store().unwrap().users["root"].credentials[0].sign_count, 5);
+        assert_eq!(fs::metadata(&storage.wal_path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_store_stats() {
+        let (storage, dir) = test_storage();
+        let storage = storage.with_usage_wal(true, 0);
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        for count in 1..=3 {
+            update_user(&storage, "root", |user| {
+                user.credentials[0].sign_count = count;
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        let stats = storage.store_stats().unwrap();
+        assert_eq!(stats.store_bytes, fs::metadata(storage.credentials_path()).unwrap().len());
+        assert_eq!((stats.wal_entries, stats.superseded_entries), (3, 2));
+        assert!(!stats.needs_rewrite);
+
+        // Switching the encoding leaves the store to be rewritten
+        let cbor =
+            FileStorage::with_paths(dir.path().join("credentials.json"), dir.path().join("challenges")).with_format(StorageFormat::Cbor);
+        assert!(cbor.store_stats().unwrap().needs_rewrite);
+    }
+
+    #[test]
+    fn test_read_only_store() {
+        let dir = TempDir::new().unwrap();
+        let cred_path = dir.path().join("credentials.json");
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        FileStorage::with_paths(cred_path.clone(), dir.path().join("challenges"))
+            .save_credentials(&store)
+            .unwrap();
+        let base = fs::read(&cred_path).unwrap();
+
+        let side_file = dir.path().join("tmp/usage.wal");
+        let storage = FileStorage::with_paths(cred_path.clone(), dir.path().join("challenges")).with_read_only(side_file.clone());
+        update_user(&storage, "root", |user| {
+            user.credentials[0].sign_count = 7;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(storage.load_user("root").unwrap().unwrap().credentials[0].sign_count, 7);
+        assert!(side_file.exists());
+        assert_eq!(storage.compact().unwrap(), 0);
+
+        let rename = update_user(&storage, "root", |user| {
+            user.credentials[0].device_name = "renamed".to_string();
+            Ok(())
+        });
+        assert!(matches!(rename, Err(AppError::ReadOnly(_))));
+        assert!(matches!(storage.save_credentials(&store), Err(AppError::ReadOnly(_))));
+        assert!(matches!(update_store(&storage, |_| Ok(())), Err(AppError::ReadOnly(_))));
+        assert_eq!(fs::read(&cred_path).unwrap(), base);
+    }
+
+    #[test]
+    fn test_challenge_lifecycle() {
+        let (storage, _dir) = test_storage();
+        let challenge_id = uuid::Uuid::new_v4().to_string();
+        let state = ChallengeState {
+            challenge_type: ChallengeType::Registration,
+            username: "root".to_string(),
+            rp_id: "192.168.1.1".to_string(),
+            state: "test_state_data".to_string(),
+            created_at: crate::clock::now_rfc3339(),
+            user_handle_len: None,
+            attestation: Conveyance::None,
+            purpose: None,
+            payload: None,
+        };
+
+        storage.save_challenge(&challenge_id, &state).unwrap();
+        let loaded = storage.load_challenge(&challenge_id).unwrap();
+        assert_eq!(loaded.username, "root");
+        assert_eq!(loaded.challenge_type, ChallengeType::Registration);
+
+        storage.delete_challenge(&challenge_id).unwrap();
+        assert!(storage.load_challenge(&challenge_id).is_err());
+    }
+
+    #[test]
+    fn test_store_is_replaced_not_rewritten() {
+        let (storage, dir) = test_storage();
+        let path = dir.path().join("credentials.json");
+        let mut store = CredentialStore::default();
+        storage.save_credentials(&store).unwrap();
+        let before = fs::metadata(&path).unwrap().ino();
+        // A reader holding the old generation keeps seeing it whole
+        let old = fs::File::open(&path).unwrap();
+
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        assert_ne!(fs::metadata(&path).unwrap().ino(), before);
+        assert_eq!(
+            old.metadata().unwrap().len(),
+            serde_json::to_vec_pretty(&CredentialStore::default()).unwrap().len() as u64
+        );
+        assert!(!dir.path().join("credentials.json.tmp").exists());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, SECRET_FILE_MODE);
+        assert_eq!(storage.load_credentials().unwrap().users["root"].credentials.len(), 1);
+
+        // An interrupted write's leftover does not get in the way
+        fs::write(dir.path().join("credentials.json.tmp"), "partial").unwrap();
+        storage
+            .modify_credentials(&mut |store| {
+                store.users.clear();
+                Ok(())
+            })
+            .unwrap();
+        assert!(storage.load_credentials().unwrap().users.is_empty());
+        assert!(!dir.path().join("credentials.json.tmp").exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tmpfile_is_linked_complete() {
+        let (storage, dir) = test_storage();
+        let temp = dir.path().join("file.tmp");
+        // Filesystems without O_TMPFILE take the fallback instead
+        if storage.link_tmpfile(&temp, b"data").unwrap() {
+            assert_eq!(fs::read(&temp).unwrap(), b"data");
+            assert_eq!(fs::metadata(&temp).unwrap().permissions().mode() & 0o777, SECRET_FILE_MODE);
+        } else {
+            assert!(!temp.exists());
+        }
+    }
+
+    #[test]
+    fn test_migrate_data_dir_moves_the_store_only() {
+        let dir = TempDir::new().unwrap();
+        let legacy = dir.path();
+        let data = legacy.join("data");
+        for name in ["credentials.json", "audit.log", "audit.log.1.gz", "config.json", "audit.key"] {
+            fs::write(legacy.join(name), name).unwrap();
+        }
+        fs::create_dir(legacy.join("users")).unwrap();
+
+        // An interrupted move is resumed
+        fs::create_dir(legacy.join("data.new")).unwrap();
+        fs::rename(legacy.join("audit.log"), legacy.join("data.new/audit.log")).unwrap();
+
+        migrate_data_dir(legacy, &data).unwrap();
+        for name in ["credentials.json", "audit.log", "audit.log.1.gz", "users"] {
+            assert!(data.join(name).exists(), "{name} moved");
+            assert!(!legacy.join(name).exists());
+        }
+        for name in ["config.json", "audit.key"] {
+            assert_eq!(fs::read_to_string(legacy.join(name)).unwrap(), name);
+        }
+        assert!(!legacy.join("data.new").exists());
+        assert_eq!(fs::metadata(&data).unwrap().permissions().mode() & 0o777, 0o700);
+
+        // Once moved, a store reappearing beside the configuration is left alone
+        fs::write(legacy.join("credentials.json"), "{}").unwrap();
+        migrate_data_dir(legacy, &data).unwrap();
+        assert_eq!(fs::read_to_string(data.join("credentials.json")).unwrap(), "credentials.json");
+
+        // Nothing to move creates nothing
+        let empty = TempDir::new().unwrap();
+        migrate_data_dir(empty.path(), &empty.path().join("data")).unwrap();
+        assert!(!empty.path().join("data").exists());
+    }
+
+    #[test]
+    fn test_hand_over_keeps_the_configuration_directory() {
+        let (storage, dir) = test_storage();
+        // The store sits beside the audit key, and here the configuration
+        let err = storage.hand_over(65534, 65534, &dir.path().join("config.json")).unwrap_err();
+        assert!(matches!(err, AppError::Privilege(_)));
+        assert_ne!(fs::metadata(dir.path()).unwrap().uid(), 65534);
+    }
+
+    #[test]
+    fn test_writable_dirs_are_created_and_exclude_the_configuration() {
+        let (storage, dir) = test_storage();
+        assert!(matches!(
+            storage.writable_dirs(&dir.path().join("config.json")),
+            Err(AppError::Config(_))
+        ));
+        assert!(matches!(
+            storage.writable_dirs(&dir.path().join("etc/config.json")),
+            Err(AppError::Config(_))
+        ));
+
+        let storage = FileStorage::with_paths(dir.path().join("data/credentials.json"), dir.path().join("run/challenges"));
+        let dirs = storage.writable_dirs(&dir.path().join("config.json")).unwrap();
+        assert_eq!(
+            dirs,
+            [
+                dir.path().join("data"),
+                dir.path().join("run/challenges"),
+                dir.path().join("run/replay")
+            ]
+        );
+        assert!(dirs.iter().all(|dir| dir.is_dir()));
+        assert!(storage.readable_files()[0].exists());
+    }
+
+    #[test]
+    fn test_sync_covers_bare_paths_and_can_be_skipped() {
+        let (storage, dir) = test_storage();
+        // A relative store path without a directory syncs the working directory
+        storage.sync_parent(Path::new("credentials.json")).unwrap();
+        assert!(storage.sync_dir(&dir.path().join("missing")).is_err());
+
+        let storage = storage.with_sync(false);
+        assert!(storage.sync_dir(&dir.path().join("missing")).is_ok());
+        let mut store = CredentialStore::default();
+        store.users.insert(
+            "root".to_string(),
+            UserRecord {
+                user_id: "uid".to_string(),
+                recovery_codes: vec![],
+                roles: vec![],
+                groups: vec![],
+                credentials: vec![crate::wal::tests::credential("a")],
+            },
+        );
+        storage.save_credentials(&store).unwrap();
+        assert_eq!(storage.load_credentials().unwrap().users["root"], store.users["root"]);
+    }
+
+    #[test]
+    fn test_pinned_origins_roundtrip() {
+        let (storage, _dir) = test_storage();
+        assert!(storage.load_pinned_origins().unwrap().is_empty());
+
+        storage
+            .modify_pinned_origins(&mut |pinned| {
+                pinned.insert("router.lan".to_string(), "https://router.lan".to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(storage.load_pinned_origins().unwrap()["router.lan"], "https://router.lan");
+        let mode = fs::metadata(&storage.pinned_origins_path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+
+        storage
+            .modify_pinned_origins(&mut |pinned| {
+                pinned.clear();
+                Ok(())
+            })
+            .unwrap();
+        assert!(storage.load_pinned_origins().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_audit_log_appends_chained_entries() {
+        let (storage, _dir) = test_storage();
+        assert!(storage.load_audit_log().unwrap().is_empty());
+
+        crate::audit::record(&storage, Some("admin"), "credential_delete", "a", serde_json::json!({})).unwrap();
+        crate::audit::record(&storage, None, "credential_rename", "b", serde_json::json!({})).unwrap();
+        let entries = storage.load_audit_log().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].seq, 2);
+        assert_eq!(entries[1].prev_hash, entries[0].hash);
+        assert_eq!(crate::audit::verify_chain(&entries, None), Ok(()));
+        let key = storage.audit_key(false).unwrap().unwrap();
+        assert_eq!(key.len(), 32);
+        assert_eq!(crate::audit::verify_macs(&entries, Some(&key)), Ok(()));
+        let head = storage.load_audit_head().unwrap();
+        assert!(!crate::audit::is_truncated(&entries, head.as_ref(), Some(&key)));
+
+        for path in [&storage.audit_log_path, &storage.audit_head_path, &storage.audit_key_path] {
+            let mode = fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode, 0o600);
+        }
+
+        // The head is replaced whole: an interrupted write's leftover is never read
+        fs::write(storage.audit_head_path.with_extension("head.tmp"), "{\"seq\":").unwrap();
+        crate::audit::record(&storage, None, "credential_enable", "c", serde_json::json!({})).unwrap();
+        assert_eq!(storage.load_audit_head().unwrap().unwrap().seq, 3);
+        assert!(!storage.audit_head_path.with_extension("head.tmp").exists());
+    }
+
+    #[test]
+    fn test_audit_log_rotation_and_retention() {
+        let (mut storage, _dir) = test_storage();
+        storage.audit_retention = AuditRetention {
+            rotate_bytes: 1,
+            rotate_secs: 0,
+            max_files: 2,
+            max_age_secs: 0,
+        };
+        for target in ["a", "b", "c", "d", "e"] {
+            crate::audit::record(&storage, None, "credential_delete", target, serde_json::Value::Null).unwrap();
+        }
+        assert_eq!(storage.rotated_audit_logs().len(), 2);
+        assert_eq!(fs::metadata(&storage.audit_log_path).unwrap().len(), 0);
+        let entries = storage.load_audit_log().unwrap();
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [4, 5]);
+        assert_eq!(storage.load_audit_anchor().unwrap().unwrap().seq, 4);
+        for path in storage.secret_files().unwrap() {
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // The chain continues from the newest rotated log and verifies from the anchor
+        crate::audit::record(&storage, None, "credential_delete", "f", serde_json::Value::Null).unwrap();
+        let verified: serde_json::Value = serde_json::from_str(&crate::commands::audit::verify(&storage).unwrap()).unwrap();
+        assert_eq!(verified["data"]["valid"], true);
+        assert_eq!(verified["data"]["firstSeq"], 5);
+        assert_eq!(verified["data"]["entries"], 2);
+
+        // Rotated logs past the age limit go, except the newest
+        storage.audit_retention.max_files = 10;
+        storage.audit_retention.max_age_secs = 24 * 60 * 60;
+        crate::clock::set(crate::clock::FixedClock(crate::clock::now() + time::Duration::days(2)));
+        crate::audit::rotate(&storage).unwrap();
+        assert_eq!(storage.rotated_audit_logs().len(), 1);
+        let verified: serde_json::Value = serde_json::from_str(&crate::commands::audit::verify(&storage).unwrap()).unwrap();
+        assert_eq!(verified["data"]["valid"], true);
+        assert_eq!(verified["data"]["firstSeq"], 6);
+
+        // A forged anchor cannot hide pruned entries
+        let mut anchor = storage.load_audit_anchor().unwrap().unwrap();
+        anchor.seq = 1;
+        fs::write(&storage.audit_anchor_path, serde_json::to_vec(&anchor).unwrap()).unwrap();
+        let verified: serde_json::Value = serde_json::from_str(&crate::commands::audit::verify(&storage).unwrap()).unwrap();
+        assert_eq!(verified["data"]["valid"], false);
+        assert_eq!(verified["data"]["brokenAt"], 1);
+    }
+
+    #[test]
+    fn test_challenge_files_are_private() {
+        let (storage, _dir) = test_storage();
+        let state = ChallengeState {
+            challenge_type: ChallengeType::Authentication,
+            username: "root".to_string(),
+            rp_id: "192.168.1.1".to_string(),
+            state: "test_state_data".to_string(),
+            created_at: crate::clock::now_rfc3339(),
+            user_handle_len: None,
+            attestation: Conveyance::None,
+            purpose: None,
+            payload: None,
+        };
+        storage.save_challenge("private", &state).unwrap();
+
+        let dir_mode = fs::metadata(&storage.challenge_dir).unwrap().permissions().mode() & 0o777;
+        let file_mode = fs::metadata(storage.challenge_dir.join("private.json"))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777;
+        assert_eq!(dir_mode, 0o700);
+        assert_eq!(file_mode, 0o600);
+
+        // A directory that already exists with a lax mode is tightened
+        fs::set_permissions(&storage.challenge_dir, fs::Permissions::from_mode(0o777)).unwrap();
+        storage.save_challenge("again", &state).unwrap();
+        assert_eq!(
+            fs::metadata(&storage.challenge_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        // A planted symlink is refused rather than followed
+        let (storage, dir) = test_storage();
+        fs::create_dir(dir.path().join("elsewhere")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("elsewhere"), &storage.challenge_dir).unwrap();
+        assert!(matches!(
+            storage.save_challenge("private", &state),
+            Err(AppError::InsecurePermissions(_))
+        ));
+        std::os::unix::fs::symlink(dir.path().join("elsewhere"), &storage.replay_dir).unwrap();
+        assert!(matches!(
+            storage.record_client_data("digest"),
+            Err(AppError::InsecurePermissions(_))
+        ));
+
+        // As does one another user owns
+        if expected_owner().0 == 0 {
+            let (storage, _dir) = test_storage();
+            fs::create_dir(&storage.challenge_dir).unwrap();
+            std::os::unix::fs::chown(&storage.challenge_dir, Some(65534), None).unwrap();
+            assert!(matches!(
+                storage.save_challenge("private", &state),
+                Err(AppError::InsecurePermissions(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_replay_cache() {
+        let (storage, _dir) = test_storage();
+        let digest = crate::secret::client_data_digest(b"client data");
+        assert!(!storage.client_data_seen(&digest).unwrap());
+        assert!(storage.record_client_data(&digest).unwrap());
+        assert!(storage.client_data_seen(&digest).unwrap());
+        assert!(!storage.record_client_data(&digest).unwrap());
+    }
+
+    #[test]
+    fn test_cleanup_skips_locked_challenge() {
+        let (storage, _dir) = test_storage();
+        fs::create_dir_all(&storage.challenge_dir).unwrap();
+        let path = storage.challenge_dir.join("busy.json");
+        fs::write(&path, "{}").unwrap();
+
+        let held = fs::File::open(&path).unwrap();
+        held.lock_shared().unwrap();
+        assert!(!remove_if_unlocked(&path).unwrap());
+        assert!(path.exists());
+
+        held.unlock().unwrap();
+        assert!(remove_if_unlocked(&path).unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_expired_challenge_is_rejected() {
+        let (storage, _dir) = test_storage();
+        crate::clock::set(crate::clock::FixedClock(crate::clock::now()));
+        let mut state = ChallengeState {
+            challenge_type: ChallengeType::Authentication,
+            username: "root".to_string(),
+            rp_id: "192.168.1.1".to_string(),
+            state: "test_state_data".to_string(),
+            created_at: crate::clock::format(
+                crate::clock::now() - time::Duration::minutes(5),
+                crate::clock::Precision::Seconds,
+            ),
+            user_handle_len: None,
+            attestation: Conveyance::None,
+            purpose: None,
+            payload: None,
+        };
+        storage.save_challenge("stale", &state).unwrap();
+        assert!(matches!(storage.load_challenge("stale"), Err(AppError::ChallengeExpired(_))));
+
+        // Only a clock that jumped explains a challenge from the future or from long ago
+        state.created_at = crate::clock::format(crate::clock::now() + time::Duration::hours(1), crate::clock::Precision::Seconds);
+        storage.save_challenge("future", &state).unwrap();
+        let err = storage.load_challenge("future").unwrap_err();
+        assert_eq!((err.error_code(), err.skew_secs()), ("CLOCK_SKEW", Some(3600)));
+        state.created_at = "2025-01-01T00:00:00Z".to_string();
+        storage.save_challenge("ancient", &state).unwrap();
+        let err = storage.load_challenge("ancient").unwrap_err();
+        assert!(err.skew_secs().is_some_and(|skew| skew < -24 * 60 * 60));
+
+        state.created_at = "garbage".to_string();
+        storage.save_challenge("garbled", &state).unwrap();
+        assert!(matches!(storage.load_challenge("garbled"), Err(AppError::ChallengeExpired(_))));
+    }
+
+    #[test]
+    fn test_challenge_not_found() {
+        let (storage, _dir) = test_storage();
+        let result = storage.load_challenge("nonexistent");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_permission_violations_and_fix() {
+        let (storage, _dir) = test_storage();
+        storage.save_credentials(&CredentialStore::default()).unwrap();
+        let path = storage.credentials_path().to_path_buf();
+        assert!(permission_violations(&path, SECRET_FILE_MODE).unwrap().is_empty());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(permission_violations(&path, SECRET_FILE_MODE).unwrap().len(), 1);
+
+        let fixed = storage.fix_permissions().unwrap();
+        assert_eq!(fixed.len(), 1);
+        assert!(permission_violations(&path, SECRET_FILE_MODE).unwrap().is_empty());
+        assert!(storage.fix_permissions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_challenge_stats() {
+        let (storage, _dir) = test_storage();
+        let stats = storage.challenge_stats().unwrap();
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.oldest_age_secs, None);
+        assert!(stats.free_bytes.is_some());
+
+        let state = ChallengeState {
+            challenge_type: ChallengeType::Registration,
+            username: "root".to_string(),
+            rp_id: "192.168.1.1".to_string(),
+            state: "test_state_data".to_string(),
+            created_at: crate::clock::now_rfc3339(),
+            user_handle_len: None,
+            attestation: Conveyance::None,
+            purpose: None,
+            payload: None,
+        };
+        storage.save_challenge("a", &state).unwrap();
+        storage.save_challenge("b", &state).unwrap();
+        let stats = storage.challenge_stats().unwrap();
+        assert_eq!(stats.pending, 2);
+        assert!(stats.oldest_age_secs.is_some_and(|age| age <= CHALLENGE_MAX_AGE_SECS));
+    }
+
+    #[test]
+    fn test_cleanup_challenges() {
+        let (storage, _dir) = test_storage();
+        let result = storage.cleanup_challenges().unwrap();
+        assert_eq!(result, 0);
+    }
+}
@@ -0,0 +1,140 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use super::*;
+
+/// Storage kept entirely in memory, so the command logic can be exercised on
+/// targets without a Unix filesystem (e.g. wasm32-wasi test runners).
+#[derive(Default)]
+pub struct MemoryStorage {
+    store: RefCell<CredentialStore>,
+    challenges: RefCell<HashMap<String, ChallengeState>>,
+    seen: RefCell<HashSet<String>>,
+    pinned_origins: RefCell<PinnedOrigins>,
+    audit_log: RefCell<Vec<AuditEntry>>,
+    audit_head: RefCell<Option<AuditHead>>,
+    audit_key: RefCell<Option<Vec<u8>>>,
+    mds_cache: RefCell<Option<MdsCache>>,
+    backup_state: RefCell<BackupState>,
+}
+
+impl StorageProvider for MemoryStorage {
+    fn load_credentials(&self) -> Result<CredentialStore, AppError> {
+        Ok(self.store.borrow().clone())
+    }
+
+    fn save_credentials(&self, store: &CredentialStore) -> Result<(), AppError> {
+        *self.store.borrow_mut() = store.clone();
+        Ok(())
+    }
+
+    fn modify_credentials(&self, mutate: &mut dyn FnMut(&mut CredentialStore) -> Result<(), AppError>) -> Result<(), AppError> {
+        let mut store = self.load_credentials()?;
+        mutate(&mut store)?;
+        self.save_credentials(&store)
+    }
+
+    fn load_challenge(&self, challenge_id: &str) -> Result<ChallengeState, AppError> {
+        self.challenges
+            .borrow()
+            .get(challenge_id)
+            .cloned()
+            .ok_or_else(|| AppError::ChallengeNotFound(challenge_id.to_string()))
+    }
+
+    fn save_challenge(&self, challenge_id: &str, state: &ChallengeState) -> Result<(), AppError> {
+        self.challenges.borrow_mut().insert(challenge_id.to_string(), state.clone());
+        Ok(())
+    }
+
+    fn delete_challenge(&self, challenge_id: &str) -> Result<(), AppError> {
+        self.challenges.borrow_mut().remove(challenge_id);
+        Ok(())
+    }
+
+    fn cleanup_challenges(&self) -> Result<usize, AppError> {
+        Ok(0)
+    }
+
+    fn challenge_stats(&self) -> Result<ChallengeStats, AppError> {
+        Ok(ChallengeStats {
+            pending: self.challenges.borrow().len(),
+            ..Default::default()
+        })
+    }
+
+    fn client_data_seen(&self, digest: &str) -> Result<bool, AppError> {
+        Ok(self.seen.borrow().contains(digest))
+    }
+
+    fn record_client_data(&self, digest: &str) -> Result<bool, AppError> {
+        Ok(self.seen.borrow_mut().insert(digest.to_string()))
+    }
+
+    fn load_pinned_origins(&self) -> Result<PinnedOrigins, AppError> {
+        Ok(self.pinned_origins.borrow().clone())
+    }
+
+    fn modify_pinned_origins(&self, mutate: &mut dyn FnMut(&mut PinnedOrigins) -> Result<(), AppError>) -> Result<(), AppError> {
+        let mut pinned = self.load_pinned_origins()?;
+        mutate(&mut pinned)?;
+        *self.pinned_origins.borrow_mut() = pinned;
+        Ok(())
+    }
+
+    fn load_audit_log(&self) -> Result<Vec<AuditEntry>, AppError> {
+        Ok(self.audit_log.borrow().clone())
+    }
+
+    fn append_audit_entry(&self, build: &mut dyn FnMut(Option<&AuditEntry>) -> (AuditEntry, AuditHead)) -> Result<(), AppError> {
+        let (entry, head) = build(self.audit_log.borrow().last());
+        self.audit_log.borrow_mut().push(entry);
+        *self.audit_head.borrow_mut() = Some(head);
+        Ok(())
+    }
+
+    fn load_audit_head(&self) -> Result<Option<AuditHead>, AppError> {
+        Ok(self.audit_head.borrow().clone())
+    }
+
+    fn audit_key(&self, create: bool) -> Result<Option<Zeroizing<Vec<u8>>>, AppError> {
+        let mut key = self.audit_key.borrow_mut();
+        if key.is_none() && create {
+            *key = Some([*uuid::Uuid::new_v4().as_bytes(), *uuid::Uuid::new_v4().as_bytes()].concat());
+        }
+        Ok(key.clone().map(Zeroizing::new))
+    }
+
+    fn load_mds_cache(&self) -> Result<Option<MdsCache>, AppError> {
+        Ok(self.mds_cache.borrow().clone())
+    }
+
+    fn save_mds_cache(&self, cache: &MdsCache) -> Result<(), AppError> {
+        *self.mds_cache.borrow_mut() = Some(cache.clone());
+        Ok(())
+    }
+
+    fn modify_backup_state(&self, mutate: &mut dyn FnMut(&mut BackupState) -> Result<(), AppError>) -> Result<(), AppError> {
+        let mut state = self.backup_state.borrow().clone();
+        mutate(&mut state)?;
+        *self.backup_state.borrow_mut() = state;
+        Ok(())
+    }
+
+    fn fix_permissions(&self) -> Result<Vec<String>, AppError> {
+        Ok(Vec::new())
+    }
+
+    fn compact(&self) -> Result<usize, AppError> {
+        Ok(0)
+    }
+
+    fn store_stats(&self) -> Result<StoreStats, AppError> {
+        Ok(StoreStats::default())
+    }
+
+    fn credentials_path(&self) -> &Path {
+        Path::new(":memory:")
+    }
+}
@@ -0,0 +1,681 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use webauthn_rp::bin::{Decode, Encode};
+use webauthn_rp::response::{AuthTransports, AuthenticatorTransport};
+
+use crate::errors::AppError;
+use crate::secret::Zeroizing;
+
+// File-backed storage needs Unix permissions and flock; the rest of the
+// crate, including the verification logic, builds for any target.
+#[cfg(unix)]
+mod file;
+#[cfg(unix)]
+pub use file::*;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "redis")]
+pub use redis::RedisStorage;
+#[cfg(test)]
+mod memory;
+#[cfg(test)]
+pub use memory::MemoryStorage;
+
+/// Challenges older than this are considered expired (2 minutes)
+const CHALLENGE_MAX_AGE_SECS: u64 = 120;
+
+/// A challenge dated further ahead than this was stamped by a clock that
+/// has since gone back
+const CLOCK_SKEW_TOLERANCE_SECS: u64 = 60;
+
+/// A challenge older than this (a day) outlived every cleanup, so the clock
+/// jumped ahead since, e.g. when NTP synced a router that booted without RTC
+const CLOCK_SKEW_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Rejects a challenge past [`CHALLENGE_MAX_AGE_SECS`], or one whose age
+/// only a clock that jumped since it was created can explain.
+fn check_challenge_age(challenge_id: &str, state: &ChallengeState) -> Result<(), AppError> {
+    let Some(created) = crate::clock::parse_rfc3339(&state.created_at) else {
+        return Err(AppError::ChallengeExpired(challenge_id.to_string()));
+    };
+    let now = crate::clock::now_secs();
+    let skew_secs = i64::try_from(created).unwrap_or(i64::MAX).saturating_sub_unsigned(now);
+    if created > now + CLOCK_SKEW_TOLERANCE_SECS {
+        return Err(AppError::ClockSkew {
+            skew_secs,
+            reason: format!(
+                "challenge {} was created {}s in the future; check the system time (NTP)",
+                challenge_id, skew_secs
+            ),
+        });
+    }
+    match now.saturating_sub(created) {
+        age if age > CLOCK_SKEW_MAX_AGE_SECS => Err(AppError::ClockSkew {
+            skew_secs,
+            reason: format!(
+                "challenge {} was created {}s ago; check the system time (NTP)",
+                challenge_id, age
+            ),
+        }),
+        age if age > CHALLENGE_MAX_AGE_SECS => Err(AppError::ChallengeExpired(challenge_id.to_string())),
+        _ => Ok(()),
+    }
+}
+
+// ─── Internal Storage Structs (snake_case) ───
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct CredentialStore {
+    pub users: std::collections::HashMap<String, UserRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub user_id: String,
+    pub credentials: Vec<StoredCredential>,
+    /// Argon2 PHC strings of the unused recovery codes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery_codes: Vec<String>,
+    /// Authorization hints for the web frontend, e.g. `admin`, returned on login
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Groups the user belongs to, returned on login for mapping to rpcd
+    /// session ACLs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredCredential {
+    pub credential_id: String,
+    /// RP the credential was registered for; empty for credentials created
+    /// before RP namespacing was introduced
+    #[serde(default)]
+    pub rp_id: String,
+    pub device_name: String,
+    pub static_state: String,
+    pub dynamic_state: String,
+    pub user_handle: String,
+    /// webauthn_rp's bit set encoding; records may also spell it as the
+    /// WebAuthn string array, e.g. `["nfc", "usb"]`, when imported
+    #[serde(deserialize_with = "transports_from_json")]
+    pub transports: u8,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub backup_eligible: bool,
+    /// Whether the authenticator last reported the credential as backed up
+    /// (BS flag); absent for credentials registered before it was tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backed_up: Option<bool>,
+    pub user_verified: bool,
+    pub sign_count: u32,
+    /// Successful logins; counted since this was introduced
+    #[serde(default)]
+    pub use_count: u64,
+    /// Failed verifications since the last successful login
+    #[serde(default)]
+    pub failed_attempts: u32,
+    /// Time of the last login in which the user was verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_user_verified: Option<String>,
+    /// Whether the authenticator verified the user while registering, i.e.
+    /// had a PIN or biometric set up; absent for older credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uv_initialized: Option<bool>,
+    /// credProtect policy the authenticator applied, in its WebAuthn spelling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<String>,
+    /// Authenticator model; empty for credentials registered before it was kept
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub aaguid: String,
+    /// Attestation statement kept for audits when `store_attestation` is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<StoredAttestation>,
+    /// Attachments (`platform`, `cross-platform`) browsers reported at logins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub seen_attachments: Vec<String>,
+    /// When a login came through a new attachment the transports do not
+    /// explain, e.g. a USB key asserting as a platform authenticator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport_drift_at: Option<String>,
+    /// Client address the web frontend reported for the last registration or login
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_client_ip: Option<String>,
+    /// User agent reported along with `last_client_ip`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_user_agent: Option<String>,
+    /// Disabled credentials stay stored but cannot log in; only written when false
+    #[serde(default = "enabled_by_default", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+    /// Whether the frontend may accept this key for configuration changes;
+    /// view-only otherwise. Only written when true
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub admin: bool,
+}
+
+/// Longest user agent kept; longer ones are cut
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Where a ceremony's response came from, as reported by the web frontend
+/// with `--client-ip` and `--user-agent`. It is not authenticated.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// Checks the address and strips control characters from the user agent,
+    /// so both are safe to show and log.
+    pub fn new(ip: Option<&str>, user_agent: Option<&str>) -> Result<Self, AppError> {
+        let ip = ip
+            .map(|ip| {
+                ip.parse::<std::net::IpAddr>()
+                    .map(|ip| ip.to_string())
+                    .map_err(|_| AppError::InvalidInput(format!("Invalid client IP address: {}", ip)))
+            })
+            .transpose()?;
+        let user_agent = user_agent
+            .map(|agent| {
+                agent
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(MAX_USER_AGENT_LEN)
+                    .collect::<String>()
+            })
+            .filter(|agent| !agent.trim().is_empty());
+        Ok(Self { ip, user_agent })
+    }
+
+    /// Replaces the credential's last client details, clearing those not given.
+    pub fn record(&self, cred: &mut StoredCredential) {
+        cred.last_client_ip = self.ip.clone();
+        cred.last_user_agent = self.user_agent.clone();
+    }
+
+    /// Hook and syslog details for the values given.
+    pub fn event_details(&self) -> Vec<(&'static str, &str)> {
+        [("CLIENT_IP", &self.ip), ("USER_AGENT", &self.user_agent)]
+            .into_iter()
+            .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+            .collect()
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Copy of a registration's attestation statement. Binary values are base64url.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StoredAttestation {
+    pub fmt: String,
+    pub aaguid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<i64>,
+    /// Certificate chain, leaf first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub x5c: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<String>,
+}
+
+impl StoredCredential {
+    /// Whether the credential is known to be able to satisfy a required-UV policy.
+    pub fn uv_capable(&self) -> bool {
+        self.user_verified || self.uv_initialized == Some(true) || self.cred_protect.as_deref() == Some("userVerificationRequired")
+    }
+
+    /// Whether this credential belongs to `rp_id`. Legacy credentials without
+    /// a recorded RP match every RP until their first successful login.
+    pub fn belongs_to(&self, rp_id: &str) -> bool {
+        self.rp_id.is_empty() || self.rp_id == rp_id
+    }
+
+    /// Transports the authenticator reported; none if the stored bits are invalid.
+    pub fn auth_transports(&self) -> AuthTransports {
+        AuthTransports::decode(self.transports).unwrap_or_else(|_| AuthTransports::decode(0u8).expect("zero is always valid"))
+    }
+
+    /// Records the attachment a login came through and returns whether it
+    /// is new and unexplained. `internal` transports explain `platform`,
+    /// roaming ones `cross-platform`, and `hybrid` (a phone) both; without
+    /// transports, the first attachment seen sets what is expected.
+    pub fn record_attachment(&mut self, attachment: &str) -> bool {
+        if self.seen_attachments.iter().any(|seen| seen == attachment) {
+            return false;
+        }
+        use AuthenticatorTransport::*;
+        let transports = self.auth_transports();
+        let explaining: &[AuthenticatorTransport] = if attachment == "platform" {
+            &[Internal, Hybrid]
+        } else {
+            &[Usb, Nfc, Ble, SmartCard, Hybrid]
+        };
+        let explained = if transports.is_empty() {
+            self.seen_attachments.is_empty()
+        } else {
+            explaining.iter().any(|transport| transports.contains(*transport))
+        };
+        self.seen_attachments.push(attachment.to_string());
+        self.seen_attachments.sort();
+        if !explained {
+            self.transport_drift_at = Some(crate::clock::now_rfc3339());
+        }
+        !explained
+    }
+}
+
+fn transports_from_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Transports {
+        Bits(u8),
+        Names(AuthTransports),
+    }
+    match Transports::deserialize(deserializer)? {
+        Transports::Bits(bits) => Ok(bits),
+        Transports::Names(transports) => Ok(transports.encode().expect("AuthTransports encode is infallible")),
+    }
+}
+
+// ─── Challenge State ───
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeState {
+    #[serde(rename = "type")]
+    pub challenge_type: ChallengeType,
+    pub username: String,
+    pub rp_id: String,
+    pub state: String,
+    pub created_at: String,
+    /// Length of the user's handle; absent in challenges created before
+    /// handle sizes became configurable, which always used 64 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_handle_len: Option<usize>,
+    /// Attestation a registration asked for, which decides how deeply
+    /// `register-finish` verifies the statement
+    #[serde(default, skip_serializing_if = "Conveyance::is_none")]
+    pub attestation: Conveyance,
+    /// Integrator-defined type of a `challenge create` challenge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    /// Data kept with a `challenge create` challenge until it is consumed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<serde_json::Value>,
+}
+
+/// Snapshot of the challenge directory for monitoring.
+#[derive(Debug, Clone, Default)]
+pub struct ChallengeStats {
+    /// Challenge files on disk, including expired ones cleanup has not removed yet
+    pub pending: usize,
+    pub oldest_age_secs: Option<u64>,
+    /// Space available to unprivileged writers on the challenge filesystem
+    pub free_bytes: Option<u64>,
+}
+
+/// On-disk footprint of the credential store.
+#[derive(Debug, Clone, Default)]
+pub struct StoreStats {
+    /// Bytes of the store files, without the write-ahead log
+    pub store_bytes: u64,
+    pub wal_entries: usize,
+    pub wal_bytes: u64,
+    /// Log entries a later entry for the same credential overrides; folding
+    /// reclaims them
+    pub superseded_entries: usize,
+    /// Some of the store is not yet in the configured layout or encoding
+    pub needs_rewrite: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeType {
+    Registration,
+    Authentication,
+    /// Made by `challenge create` for a flow outside WebAuthn
+    Generic,
+}
+
+/// WebAuthn attestation conveyance preference.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Conveyance {
+    #[default]
+    None,
+    Indirect,
+    Direct,
+    Enterprise,
+}
+
+impl Conveyance {
+    pub fn parse(value: Option<&str>) -> Result<Self, AppError> {
+        match value {
+            None | Some("none") => Ok(Self::None),
+            Some("indirect") => Ok(Self::Indirect),
+            Some("direct") => Ok(Self::Direct),
+            Some("enterprise") => Ok(Self::Enterprise),
+            Some(other) => Err(AppError::InvalidInput(format!(
+                "Unsupported attestation: {} (expected none, indirect, direct or enterprise)",
+                other
+            ))),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Indirect => "indirect",
+            Self::Direct => "direct",
+            Self::Enterprise => "enterprise",
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+}
+
+/// One administrative action in the audit log. Entries are hash-chained:
+/// `hash` covers every other field, including the previous entry's hash, and
+/// `mac` authenticates `hash` with the audit key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub time: String,
+    /// Who performed the action, as passed with `--actor`
+    pub actor: Option<String>,
+    /// Trace ID of the request that performed the action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    pub action: String,
+    pub target: String,
+    #[serde(default)]
+    pub details: serde_json::Value,
+    pub prev_hash: String,
+    pub hash: String,
+    /// HMAC-SHA256 (hex) of `hash`; every entry has one, it is only left
+    /// out of the JSON that `hash` covers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+/// The last audit entry as of the last append, authenticated with the audit
+/// key, so cutting entries from the end of the log is detectable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub hash: String,
+    pub mac: String,
+}
+
+/// Vouches for the first entry kept after older audit entries were pruned:
+/// it names that entry's sequence number and previous hash, authenticated
+/// with the audit key like the head.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditAnchor {
+    pub seq: u64,
+    pub prev_hash: String,
+    pub mac: String,
+}
+
+/// Authenticator metadata from a verified FIDO Metadata Service blob, as
+/// cached by `mds update`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MdsCache {
+    /// Serial number of the blob; a later blob must not have a lower one
+    pub no: u64,
+    pub next_update: String,
+    pub fetched_at: String,
+    /// Keyed by AAGUID
+    pub entries: std::collections::BTreeMap<String, MdsEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MdsEntry {
+    pub description: String,
+    /// Most recent status report, e.g. `FIDO_CERTIFIED_L1` or `REVOKED`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Base64 DER certificates attestation chains of this model lead to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attestation_roots: Vec<String>,
+}
+
+/// Progress towards the next automatic backup push.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BackupState {
+    /// Changes to the credential store since the last successful push
+    pub pending_changes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_push_at: Option<String>,
+}
+
+// ─── StorageProvider Trait ───
+
+/// Origin pinned for each RP ID, keyed by RP ID.
+pub type PinnedOrigins = std::collections::BTreeMap<String, String>;
+
+pub trait StorageProvider {
+    fn load_credentials(&self) -> Result<CredentialStore, AppError>;
+    fn save_credentials(&self, store: &CredentialStore) -> Result<(), AppError>;
+    /// Loads the store, applies `mutate` and saves the result while holding an
+    /// exclusive lock for the whole cycle. Nothing is written if `mutate` fails.
+    fn modify_credentials(&self, mutate: &mut dyn FnMut(&mut CredentialStore) -> Result<(), AppError>) -> Result<(), AppError>;
+    /// Loads a single user's record. Layouts that store users separately
+    /// override this to avoid reading the whole store.
+    fn load_user(&self, username: &str) -> Result<Option<UserRecord>, AppError> {
+        Ok(self.load_credentials()?.users.remove(username))
+    }
+    /// Like `modify_credentials`, restricted to one existing user.
+    fn modify_user(&self, username: &str, mutate: &mut dyn FnMut(&mut UserRecord) -> Result<(), AppError>) -> Result<(), AppError> {
+        self.modify_credentials(&mut |store| {
+            let record = store
+                .users
+                .get_mut(username)
+                .ok_or_else(|| AppError::UserNotFound(username.to_string()))?;
+            mutate(record)
+        })
+    }
+    fn load_challenge(&self, challenge_id: &str) -> Result<ChallengeState, AppError>;
+    fn save_challenge(&self, challenge_id: &str, state: &ChallengeState) -> Result<(), AppError>;
+    fn delete_challenge(&self, challenge_id: &str) -> Result<(), AppError>;
+    fn cleanup_challenges(&self) -> Result<usize, AppError>;
+    fn challenge_stats(&self) -> Result<ChallengeStats, AppError>;
+    /// Whether a verified response with this clientDataJSON digest was seen recently.
+    fn client_data_seen(&self, digest: &str) -> Result<bool, AppError>;
+    /// Remembers a verified response digest; returns false if it was already recorded.
+    fn record_client_data(&self, digest: &str) -> Result<bool, AppError>;
+    /// Origins pinned per RP ID by trust-on-first-use mode.
+    fn load_pinned_origins(&self) -> Result<PinnedOrigins, AppError>;
+    /// Loads, mutates and saves the pinned origins under an exclusive lock.
+    fn modify_pinned_origins(&self, mutate: &mut dyn FnMut(&mut PinnedOrigins) -> Result<(), AppError>) -> Result<(), AppError>;
+    fn load_audit_log(&self) -> Result<Vec<AuditEntry>, AppError>;
+    /// Appends the entry `build` makes from the current last entry and
+    /// replaces the head with the one it returns, under an exclusive lock so
+    /// concurrent appends keep the chain intact.
+    fn append_audit_entry(&self, build: &mut dyn FnMut(Option<&AuditEntry>) -> (AuditEntry, AuditHead)) -> Result<(), AppError>;
+    fn load_audit_head(&self) -> Result<Option<AuditHead>, AppError>;
+    /// Rotates the audit log and prunes rotated logs as configured; when
+    /// entries are dropped, `anchor` vouches for the first one kept. Stores
+    /// without rotation keep every entry.
+    fn rotate_audit_log(&self, _anchor: &mut dyn FnMut(&AuditEntry) -> AuditAnchor) -> Result<(), AppError> {
+        Ok(())
+    }
+    /// The anchor of a pruned audit log; none while it starts at the first entry.
+    fn load_audit_anchor(&self) -> Result<Option<AuditAnchor>, AppError> {
+        Ok(None)
+    }
+    /// Secret key authenticating the audit log; with `create`, a missing key
+    /// is generated.
+    fn audit_key(&self, create: bool) -> Result<Option<Zeroizing<Vec<u8>>>, AppError>;
+    fn load_mds_cache(&self) -> Result<Option<MdsCache>, AppError>;
+    fn save_mds_cache(&self, cache: &MdsCache) -> Result<(), AppError>;
+    /// Loads, mutates and saves the backup state under an exclusive lock.
+    fn modify_backup_state(&self, mutate: &mut dyn FnMut(&mut BackupState) -> Result<(), AppError>) -> Result<(), AppError>;
+    fn fix_permissions(&self) -> Result<Vec<String>, AppError>;
+    /// Folds pending write-ahead log entries into the store; returns how many were folded.
+    fn compact(&self) -> Result<usize, AppError>;
+    fn store_stats(&self) -> Result<StoreStats, AppError>;
+    fn credentials_path(&self) -> &Path;
+    /// Every location of the credential store in order of preference, the
+    /// first being `credentials_path`.
+    fn credential_locations(&self) -> Vec<PathBuf> {
+        vec![self.credentials_path().to_path_buf()]
+    }
+    /// Whether the store was opened with `read_only_store`.
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// Whether a store that is not a local file currently accepts writes;
+    /// `None` leaves the check to the caller, on `credentials_path`.
+    fn probe_writable(&self) -> Option<bool> {
+        None
+    }
+    /// Files whose content is kept in memory between calls; a long-running
+    /// server calls `reload` when another process changes one of them.
+    fn cached_files(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+    /// Forgets what is kept in memory of `cached_files`.
+    fn reload(&self) {}
+}
+
+/// Error for anything that would change a read-only store.
+pub fn read_only_error() -> AppError {
+    AppError::ReadOnly("the credential store is read-only; registration and credential changes are disabled".to_string())
+}
+
+// ─── Helper Functions ───
+
+/// Runs `f` against one existing user's record inside a single locked
+/// load-mutate-save pass and returns its result.
+pub fn update_user<T>(
+    storage: &dyn StorageProvider,
+    username: &str,
+    f: impl FnOnce(&mut UserRecord) -> Result<T, AppError>,
+) -> Result<T, AppError> {
+    let mut f = Some(f);
+    let mut result = None;
+    storage.modify_user(username, &mut |record| {
+        let f = f.take().expect("modify_user runs the closure once");
+        result = Some(f(record)?);
+        Ok(())
+    })?;
+    Ok(result.expect("modify_user ran the closure"))
+}
+
+/// Runs `f` against the credential store inside a single locked
+/// load-mutate-save pass and returns its result.
+pub fn update_store<T>(storage: &dyn StorageProvider, f: impl FnOnce(&mut CredentialStore) -> Result<T, AppError>) -> Result<T, AppError> {
+    let mut f = Some(f);
+    let mut result = None;
+    storage.modify_credentials(&mut |store| {
+        let f = f.take().expect("modify_credentials runs the closure once");
+        result = Some(f(store)?);
+        Ok(())
+    })?;
+    Ok(result.expect("modify_credentials ran the closure"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unexplained_attachment_is_drift() {
+        let mut usb_key = crate::wal::tests::credential("a");
+        let usb: AuthTransports = serde_json::from_value(serde_json::json!(["usb"])).unwrap();
+        usb_key.transports = usb.encode().unwrap();
+        assert!(!usb_key.record_attachment("cross-platform"));
+        assert!(!usb_key.record_attachment("cross-platform"));
+        assert!(usb_key.transport_drift_at.is_none());
+        assert!(usb_key.record_attachment("platform"));
+        assert_eq!(usb_key.seen_attachments, ["cross-platform", "platform"]);
+        assert!(usb_key.transport_drift_at.is_some());
+
+        // A phone reached over hybrid can show up as either
+        let mut phone = crate::wal::tests::credential("b");
+        let hybrid: AuthTransports = serde_json::from_value(serde_json::json!(["hybrid", "internal"])).unwrap();
+        phone.transports = hybrid.encode().unwrap();
+        assert!(!phone.record_attachment("platform"));
+        assert!(!phone.record_attachment("cross-platform"));
+
+        // Without transports, the first attachment is the baseline
+        let mut unknown = crate::wal::tests::credential("c");
+        unknown.transports = 0;
+        assert!(!unknown.record_attachment("platform"));
+        assert!(unknown.record_attachment("cross-platform"));
+    }
+
+    #[test]
+    fn test_credential_rp_namespacing() {
+        let legacy: StoredCredential = serde_json::from_value(serde_json::json!({
+            "credential_id": "cid",
+            "device_name": "key",
+            "static_state": "ss",
+            "dynamic_state": "ds",
+            "user_handle": "uh",
+            "transports": 0,
+            "created_at": "2025-01-01T00:00:00Z",
+            "last_used_at": null,
+            "backup_eligible": false,
+            "user_verified": false,
+            "sign_count": 0
+        }))
+        .unwrap();
+        assert!(legacy.rp_id.is_empty());
+        assert!(legacy.belongs_to("router.lan"));
+
+        let scoped = StoredCredential {
+            rp_id: "router.lan".to_string(),
+            ..legacy
+        };
+        assert!(scoped.belongs_to("router.lan"));
+        assert!(!scoped.belongs_to("app.router.lan"));
+    }
+
+    #[test]
+    fn test_transports_accept_names() {
+        let mut record = serde_json::to_value(crate::wal::tests::credential("a")).unwrap();
+        record["transports"] = serde_json::json!(["usb", "nfc"]);
+        let cred: StoredCredential = serde_json::from_value(record.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(cred.auth_transports()).unwrap(),
+            serde_json::json!(["nfc", "usb"])
+        );
+        // Stored back in the compact encoding
+        assert!(serde_json::to_value(&cred).unwrap()["transports"].is_u64());
+
+        record["transports"] = serde_json::json!(["carrier-pigeon"]);
+        assert!(serde_json::from_value::<StoredCredential>(record).is_err());
+    }
+
+    #[test]
+    fn test_uv_capable() {
+        let mut cred = crate::wal::tests::credential("a");
+        assert!(!cred.uv_capable());
+        cred.uv_initialized = Some(false);
+        cred.cred_protect = Some("userVerificationOptional".to_string());
+        assert!(!cred.uv_capable());
+        cred.cred_protect = Some("userVerificationRequired".to_string());
+        assert!(cred.uv_capable());
+        cred.cred_protect = None;
+        cred.uv_initialized = Some(true);
+        assert!(cred.uv_capable());
+        cred.uv_initialized = None;
+        cred.user_verified = true;
+        assert!(cred.uv_capable());
+    }
+}
@@ -0,0 +1,528 @@
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+
+use super::*;
+
+/// Prefix of every key, so the database can be shared with other software
+const KEY_PREFIX: &str = "webauthn:";
+const DEFAULT_PORT: u16 = 6379;
+/// Connect, read and write timeout of the server connection
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// A lock left behind by a crashed holder expires after this long
+const LOCK_TTL_MS: u64 = 10_000;
+/// How long to wait for a lock another router holds
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+/// Largest value accepted from the server
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+
+/// Deletes the lock only while it still holds our token, so a lock that
+/// expired and was taken over is left alone
+const RELEASE_SCRIPT: &str = "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+/// A parsed `redis://[[user]:password@]host[:port][/db]` URL.
+#[derive(Debug, PartialEq)]
+struct RedisUrl {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+    db: u32,
+}
+
+impl RedisUrl {
+    fn parse(url: &str) -> Result<Self, AppError> {
+        let invalid = |why: &str| AppError::Config(format!("Invalid storage URL: {}", why));
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| invalid("only redis:// is supported"))?;
+        let (auth, rest) = match rest.rsplit_once('@') {
+            Some((auth, rest)) => (Some(auth), rest),
+            None => (None, rest),
+        };
+        let (user, password) = match auth {
+            None => (None, None),
+            Some(auth) => {
+                let (user, password) = auth
+                    .split_once(':')
+                    .ok_or_else(|| invalid("credentials must be [user]:password"))?;
+                ((!user.is_empty()).then(|| user.to_string()), Some(password.to_string()))
+            }
+        };
+        let (address, db) = rest.split_once('/').unwrap_or((rest, ""));
+        let db = match db {
+            "" => 0,
+            db => db.parse().map_err(|_| invalid("the database must be a number"))?,
+        };
+        // [::1]:6379 for IPv6 addresses
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed.split_once(']').ok_or_else(|| invalid("unterminated IPv6 address"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let port = match port {
+            None => DEFAULT_PORT,
+            Some(port) => port.parse().map_err(|_| invalid("the port must be a number"))?,
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            user,
+            password,
+            db,
+        })
+    }
+}
+
+/// A reply in the Redis serialization protocol (RESP2).
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+fn protocol_error(what: &str) -> AppError {
+    AppError::Storage(format!("Redis protocol error: {}", what))
+}
+
+fn unexpected(reply: Reply) -> AppError {
+    protocol_error(&format!("unexpected reply {:?}", reply))
+}
+
+fn encode_command<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn read_reply(reader: &mut impl BufRead) -> Result<Reply, AppError> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| protocol_error("connection closed mid-reply"))?;
+    let (&kind, rest) = line.split_first().ok_or_else(|| protocol_error("empty reply"))?;
+    let text = String::from_utf8_lossy(rest);
+    let number = || text.parse::<i64>().map_err(|_| protocol_error("invalid length"));
+    match kind {
+        b'+' => Ok(Reply::Status(text.into_owned())),
+        b'-' => Err(AppError::Storage(format!("Redis: {}", text))),
+        b':' => Ok(Reply::Integer(number()?)),
+        b'$' => {
+            let Ok(len) = usize::try_from(number()?) else {
+                return Ok(Reply::Bulk(None));
+            };
+            if len > MAX_BULK_LEN {
+                return Err(protocol_error("value too large"));
+            }
+            let mut data = vec![0u8; len + 2];
+            reader.read_exact(&mut data)?;
+            data.truncate(len);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        b'*' => {
+            let Ok(len) = usize::try_from(number()?) else {
+                return Ok(Reply::Array(None));
+            };
+            (0..len)
+                .map(|_| read_reply(reader))
+                .collect::<Result<_, _>>()
+                .map(|items| Reply::Array(Some(items)))
+        }
+        _ => Err(protocol_error("unknown reply type")),
+    }
+}
+
+fn key(name: &str) -> String {
+    format!("{}{}", KEY_PREFIX, name)
+}
+
+/// Credential store and state kept on a Redis server, so several routers
+/// (e.g. an HA pair) share one credential database. Values are JSON; stores
+/// that change something take a lock key, the counterpart of the file
+/// store's `flock`, and challenges and replay entries expire on the server.
+pub struct RedisStorage {
+    url: RedisUrl,
+    /// Resolved once, as the process may be confined before reconnecting
+    addrs: Vec<SocketAddr>,
+    /// Reported as the store's path, without the password
+    display: PathBuf,
+    /// Dropped after any error, reopened by the next command
+    conn: RefCell<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisStorage {
+    /// Resolves `url` and connects right away, before privileges are dropped
+    /// and the filesystem (with the resolver configuration) is confined.
+    pub fn connect(url: &str) -> Result<Self, AppError> {
+        let parsed = RedisUrl::parse(url)?;
+        let addrs: Vec<SocketAddr> = (parsed.host.as_str(), parsed.port)
+            .to_socket_addrs()
+            .map_err(|e| AppError::Storage(format!("Cannot resolve {}: {}", parsed.host, e)))?
+            .collect();
+        let host = if parsed.host.contains(':') {
+            format!("[{}]", parsed.host)
+        } else {
+            parsed.host.clone()
+        };
+        let storage = Self {
+            display: PathBuf::from(format!("redis://{}:{}/{}", host, parsed.port, parsed.db)),
+            url: parsed,
+            addrs,
+            conn: RefCell::new(None),
+        };
+        storage.command(&["PING"])?;
+        Ok(storage)
+    }
+
+    fn open(&self) -> Result<BufReader<TcpStream>, AppError> {
+        let mut last_error = None;
+        for addr in &self.addrs {
+            match TcpStream::connect_timeout(addr, IO_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                    stream.set_nodelay(true)?;
+                    let mut conn = BufReader::new(stream);
+                    if let Some(password) = &self.url.password {
+                        match &self.url.user {
+                            Some(user) => exchange(&mut conn, &["AUTH", user, password])?,
+                            None => exchange(&mut conn, &["AUTH", password])?,
+                        };
+                    }
+                    if self.url.db != 0 {
+                        exchange(&mut conn, &["SELECT", &self.url.db.to_string()])?;
+                    }
+                    return Ok(conn);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(AppError::Storage(format!(
+            "Cannot connect to {}: {}",
+            self.display.display(),
+            last_error.map_or_else(|| "no address".to_string(), |e| e.to_string())
+        )))
+    }
+
+    fn command<A: AsRef<[u8]>>(&self, args: &[A]) -> Result<Reply, AppError> {
+        let mut conn = self.conn.borrow_mut();
+        if conn.is_none() {
+            *conn = Some(self.open()?);
+        }
+        let result = exchange(conn.as_mut().expect("opened above"), args);
+        // The connection may be out of step with the protocol now
+        if result.is_err() {
+            *conn = None;
+        }
+        result
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, AppError> {
+        match self.command(&["GET", &key(name)])? {
+            Reply::Bulk(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, AppError> {
+        self.get(name)?
+            .map(|data| serde_json::from_slice(&data).map_err(AppError::from))
+            .transpose()
+    }
+
+    fn set_json<T: Serialize>(&self, name: &str, value: &T) -> Result<(), AppError> {
+        let data = serde_json::to_vec(value)?;
+        self.command(&[b"SET".as_slice(), key(name).as_bytes(), &data])?;
+        Ok(())
+    }
+
+    /// Runs `f` while holding the lock named `name`, waiting for another
+    /// holder to release it.
+    fn with_lock<T>(&self, name: &str, f: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+        let lock = key(&format!("lock:{}", name));
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let deadline = Instant::now() + LOCK_WAIT;
+        loop {
+            match self.command(&["SET", &lock, &token, "NX", "PX", &LOCK_TTL_MS.to_string()])? {
+                Reply::Status(_) => break,
+                Reply::Bulk(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+                Reply::Bulk(None) => return Err(AppError::Storage(format!("Timed out waiting for the Redis lock {}", lock))),
+                other => return Err(unexpected(other)),
+            }
+        }
+        let result = f();
+        let released = self.command(&["EVAL", RELEASE_SCRIPT, "1", &lock, &token]);
+        let value = result?;
+        released?;
+        Ok(value)
+    }
+
+    /// Counts the keys matching `pattern` without blocking the server.
+    fn count_keys(&self, pattern: &str) -> Result<usize, AppError> {
+        let mut cursor = "0".to_string();
+        let mut count = 0;
+        loop {
+            let Reply::Array(Some(mut page)) = self.command(&["SCAN", &cursor, "MATCH", pattern, "COUNT", "100"])? else {
+                return Err(protocol_error("malformed SCAN reply"));
+            };
+            let (Some(Reply::Array(Some(keys))), Some(Reply::Bulk(Some(next)))) = (page.pop(), page.pop()) else {
+                return Err(protocol_error("malformed SCAN reply"));
+            };
+            count += keys.len();
+            cursor = String::from_utf8_lossy(&next).into_owned();
+            if cursor == "0" {
+                return Ok(count);
+            }
+        }
+    }
+}
+
+/// Sends one command and reads its reply.
+fn exchange<A: AsRef<[u8]>>(conn: &mut BufReader<TcpStream>, args: &[A]) -> Result<Reply, AppError> {
+    conn.get_mut().write_all(&encode_command(args))?;
+    read_reply(conn)
+}
+
+impl StorageProvider for RedisStorage {
+    fn load_credentials(&self) -> Result<CredentialStore, AppError> {
+        Ok(self.get_json("credentials")?.unwrap_or_default())
+    }
+
+    fn save_credentials(&self, store: &CredentialStore) -> Result<(), AppError> {
+        self.with_lock("credentials", || self.set_json("credentials", store))
+    }
+
+    fn modify_credentials(&self, mutate: &mut dyn FnMut(&mut CredentialStore) -> Result<(), AppError>) -> Result<(), AppError> {
+        self.with_lock("credentials", || {
+            let mut store = self.load_credentials()?;
+            mutate(&mut store)?;
+            self.set_json("credentials", &store)
+        })
+    }
+
+    fn load_challenge(&self, challenge_id: &str) -> Result<ChallengeState, AppError> {
+        let state: ChallengeState = self
+            .get_json(&format!("challenge:{}", challenge_id))?
+            .ok_or_else(|| AppError::ChallengeNotFound(challenge_id.to_string()))?;
+        check_challenge_age(challenge_id, &state)?;
+        Ok(state)
+    }
+
+    fn save_challenge(&self, challenge_id: &str, state: &ChallengeState) -> Result<(), AppError> {
+        let data = serde_json::to_vec(state)?;
+        let name = key(&format!("challenge:{}", challenge_id));
+        let ttl = CHALLENGE_MAX_AGE_SECS.to_string();
+        self.command(&[b"SET".as_slice(), name.as_bytes(), &data, b"EX", ttl.as_bytes()])?;
+        Ok(())
+    }
+
+    fn delete_challenge(&self, challenge_id: &str) -> Result<(), AppError> {
+        self.command(&["DEL", &key(&format!("challenge:{}", challenge_id))])?;
+        Ok(())
+    }
+
+    fn cleanup_challenges(&self) -> Result<usize, AppError> {
+        // The server expires them
+        Ok(0)
+    }
+
+    fn challenge_stats(&self) -> Result<ChallengeStats, AppError> {
+        Ok(ChallengeStats {
+            pending: self.count_keys(&key("challenge:*"))?,
+            ..Default::default()
+        })
+    }
+
+    fn client_data_seen(&self, digest: &str) -> Result<bool, AppError> {
+        match self.command(&["EXISTS", &key(&format!("replay:{}", digest))])? {
+            Reply::Integer(count) => Ok(count > 0),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn record_client_data(&self, digest: &str) -> Result<bool, AppError> {
+        let name = key(&format!("replay:{}", digest));
+        // NX makes recording atomic: only one of two racing finishes wins
+        match self.command(&["SET", &name, "1", "NX", "EX", &CHALLENGE_MAX_AGE_SECS.to_string()])? {
+            Reply::Status(_) => Ok(true),
+            Reply::Bulk(None) => Ok(false),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn load_pinned_origins(&self) -> Result<PinnedOrigins, AppError> {
+        Ok(self.get_json("pinned_origins")?.unwrap_or_default())
+    }
+
+    fn modify_pinned_origins(&self, mutate: &mut dyn FnMut(&mut PinnedOrigins) -> Result<(), AppError>) -> Result<(), AppError> {
+        self.with_lock("pinned_origins", || {
+            let mut pinned = self.load_pinned_origins()?;
+            mutate(&mut pinned)?;
+            self.set_json("pinned_origins", &pinned)
+        })
+    }
+
+    fn load_audit_log(&self) -> Result<Vec<AuditEntry>, AppError> {
+        let Reply::Array(Some(entries)) = self.command(&["LRANGE", &key("audit:log"), "0", "-1"])? else {
+            return Err(protocol_error("malformed LRANGE reply"));
+        };
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                Reply::Bulk(Some(data)) => serde_json::from_slice(&data).map_err(AppError::from),
+                other => Err(unexpected(other)),
+            })
+            .collect()
+    }
+
+    fn append_audit_entry(&self, build: &mut dyn FnMut(Option<&AuditEntry>) -> (AuditEntry, AuditHead)) -> Result<(), AppError> {
+        self.with_lock("audit", || {
+            let last: Option<AuditEntry> = match self.command(&["LINDEX", &key("audit:log"), "-1"])? {
+                Reply::Bulk(Some(data)) => Some(serde_json::from_slice(&data)?),
+                Reply::Bulk(None) => None,
+                other => return Err(unexpected(other)),
+            };
+            let (entry, head) = build(last.as_ref());
+            let entry = serde_json::to_vec(&entry)?;
+            self.command(&[b"RPUSH".as_slice(), key("audit:log").as_bytes(), &entry])?;
+            // Still under the lock, so the head always names the last entry
+            self.set_json("audit:head", &head)
+        })
+    }
+
+    fn load_audit_head(&self) -> Result<Option<AuditHead>, AppError> {
+        self.get_json("audit:head")
+    }
+
+    fn audit_key(&self, create: bool) -> Result<Option<Zeroizing<Vec<u8>>>, AppError> {
+        if let Some(existing) = self.get("audit:key")? {
+            return Ok(Some(Zeroizing::new(existing)));
+        }
+        if !create {
+            return Ok(None);
+        }
+        let fresh = crate::commands::register::random_user_handle(32);
+        // Another router may create it first; its key wins
+        self.command(&[b"SET".as_slice(), key("audit:key").as_bytes(), &fresh, b"NX"])?;
+        Ok(self.get("audit:key")?.map(Zeroizing::new))
+    }
+
+    fn load_mds_cache(&self) -> Result<Option<MdsCache>, AppError> {
+        self.get_json("mds")
+    }
+
+    fn save_mds_cache(&self, cache: &MdsCache) -> Result<(), AppError> {
+        self.set_json("mds", cache)
+    }
+
+    fn modify_backup_state(&self, mutate: &mut dyn FnMut(&mut BackupState) -> Result<(), AppError>) -> Result<(), AppError> {
+        self.with_lock("backup", || {
+            let mut state = self.get_json("backup")?.unwrap_or_default();
+            mutate(&mut state)?;
+            self.set_json("backup", &state)
+        })
+    }
+
+    fn fix_permissions(&self) -> Result<Vec<String>, AppError> {
+        Ok(Vec::new())
+    }
+
+    fn compact(&self) -> Result<usize, AppError> {
+        Ok(0)
+    }
+
+    fn store_stats(&self) -> Result<StoreStats, AppError> {
+        match self.command(&["STRLEN", &key("credentials")])? {
+            Reply::Integer(len) => Ok(StoreStats {
+                store_bytes: len.max(0) as u64,
+                ..Default::default()
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn credentials_path(&self) -> &Path {
+        &self.display
+    }
+
+    fn probe_writable(&self) -> Option<bool> {
+        // A replica answers READONLY
+        Some(self.command(&["SET", &key("health"), "1", "EX", "10"]).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            RedisUrl::parse("redis://10.0.0.2").unwrap(),
+            RedisUrl {
+                host: "10.0.0.2".to_string(),
+                port: DEFAULT_PORT,
+                user: None,
+                password: None,
+                db: 0
+            }
+        );
+        let url = RedisUrl::parse("redis://helper:s3:cret@[fd00::2]:6380/3").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.db), ("fd00::2", 6380, 3));
+        assert_eq!(
+            (url.user.as_deref(), url.password.as_deref()),
+            (Some("helper"), Some("s3:cret"))
+        );
+        assert_eq!(RedisUrl::parse("redis://:pw@cache.lan/1").unwrap().user, None);
+        for invalid in [
+            "http://cache.lan",
+            "redis://",
+            "redis://pw@cache.lan",
+            "redis://cache.lan:x",
+            "redis://cache.lan/x",
+        ] {
+            assert!(matches!(RedisUrl::parse(invalid), Err(AppError::Config(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_protocol() {
+        assert_eq!(
+            encode_command(&["GET", "webauthn:mds"]),
+            b"*2\r\n$3\r\nGET\r\n$12\r\nwebauthn:mds\r\n"
+        );
+
+        let mut replies: &[u8] = b"+OK\r\n:2\r\n$-1\r\n$3\r\na\r\n\r\n*2\r\n$1\r\n0\r\n*0\r\n*-1\r\n-WRONGTYPE bad\r\n$5\r\nab";
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Status("OK".to_string()));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Integer(2));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(None));
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Bulk(Some(b"a\r\n".to_vec())));
+        assert_eq!(
+            read_reply(&mut replies).unwrap(),
+            Reply::Array(Some(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(Some(vec![]))]))
+        );
+        assert_eq!(read_reply(&mut replies).unwrap(), Reply::Array(None));
+        assert!(matches!(read_reply(&mut replies), Err(AppError::Storage(msg)) if msg == "Redis: WRONGTYPE bad"));
+        assert!(read_reply(&mut replies).is_err());
+    }
+}
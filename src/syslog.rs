@@ -0,0 +1,249 @@
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::errors::AppError;
+
+/// SD-ID of the structured data element; 32473 is the enterprise number
+/// RFC 5612 reserves for documentation and private use
+const SD_ID: &str = "webauthn@32473";
+
+/// How long a TCP collector may take to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const FACILITIES: [(&str, u8); 12] = [
+    ("user", 1),
+    ("daemon", 3),
+    ("auth", 4),
+    ("authpriv", 10),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+/// RFC 5424 severity of a message.
+#[derive(Clone, Copy)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// The collector configured with `syslog_server`.
+struct Remote {
+    transport: Transport,
+    address: String,
+    facility: u8,
+    hostname: String,
+}
+
+static REMOTE: OnceLock<Remote> = OnceLock::new();
+
+fn facility(name: &str) -> Result<u8, AppError> {
+    FACILITIES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, code)| *code)
+        .ok_or_else(|| AppError::Config(format!("Unknown syslog_facility: {}", name)))
+}
+
+/// Splits `udp://host:port` or `tcp://host:port`.
+fn parse_server(server: &str) -> Result<(Transport, &str), AppError> {
+    let (transport, address) = match server.split_once("://") {
+        Some(("udp", address)) => (Transport::Udp, address),
+        Some(("tcp", address)) => (Transport::Tcp, address),
+        _ => {
+            return Err(AppError::Config(format!(
+                "syslog_server must look like udp://host:port or tcp://host:port, got {}",
+                server
+            )))
+        }
+    };
+    if !address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+    {
+        return Err(AppError::Config(format!("syslog_server needs a host and port, got {}", server)));
+    }
+    Ok((transport, address))
+}
+
+pub fn validate(config: &Config) -> Result<(), AppError> {
+    facility(&config.syslog_facility)?;
+    config.syslog_server.as_deref().map(parse_server).transpose()?;
+    Ok(())
+}
+
+/// Sends this invocation's warnings, errors and auth events to
+/// `syslog_server`, if one is configured.
+pub fn init(config: &Config) -> Result<(), AppError> {
+    let Some(server) = &config.syslog_server else {
+        return Ok(());
+    };
+    let (transport, address) = parse_server(server)?;
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+        .unwrap_or_else(|| "-".to_string());
+    let _ = REMOTE.set(Remote {
+        transport,
+        address: address.to_string(),
+        facility: facility(&config.syslog_facility)?,
+        hostname,
+    });
+    Ok(())
+}
+
+/// Escapes an SD-PARAM value (RFC 5424 §6.3.3).
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+/// One RFC 5424 message. `details` become SD-PARAMs with lowercase names.
+fn format(remote: &Remote, severity: Severity, msg_id: &str, details: &[(&str, &str)], message: &str) -> String {
+    let data = if details.is_empty() {
+        "-".to_string()
+    } else {
+        let params: Vec<String> = details
+            .iter()
+            .map(|(name, value)| format!("{}=\"{}\"", name.to_ascii_lowercase(), escape(value)))
+            .collect();
+        format!("[{} {}]", SD_ID, params.join(" "))
+    };
+    format!(
+        "<{}>1 {} {} webauthn-helper {} {} {} {}",
+        u16::from(remote.facility) * 8 + severity as u16,
+        crate::clock::format(crate::clock::now(), crate::clock::Precision::Micros),
+        remote.hostname,
+        std::process::id(),
+        msg_id,
+        data,
+        message
+    )
+}
+
+/// Best effort: a collector that is down must not fail the command.
+fn deliver(remote: &Remote, line: &str) -> std::io::Result<()> {
+    let target: SocketAddr = remote
+        .address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "collector address did not resolve"))?;
+    match remote.transport {
+        Transport::Udp => {
+            let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            UdpSocket::bind(local)?.send_to(line.as_bytes(), target)?;
+        }
+        Transport::Tcp => {
+            // Octet-counting framing (RFC 6587)
+            let mut stream = TcpStream::connect_timeout(&target, CONNECT_TIMEOUT)?;
+            stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+            stream.write_all(format!("{} {}", line.len(), line).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Forwards a message with the trace ID of the request in progress.
+fn send(severity: Severity, msg_id: &str, details: &[(&str, &str)], message: &str) {
+    if let Some(remote) = REMOTE.get() {
+        let trace_id = crate::trace::current();
+        let mut details = details.to_vec();
+        if let Some(trace_id) = &trace_id {
+            details.push(("TRACE_ID", trace_id));
+        }
+        let _ = deliver(remote, &format(remote, severity, msg_id, &details, message));
+    }
+}
+
+/// `[<trace ID>] ` to start a line on stderr with.
+fn trace_prefix() -> String {
+    crate::trace::current().map(|id| format!("[{}] ", id)).unwrap_or_default()
+}
+
+/// Prints a warning on stderr and forwards it.
+pub fn warning(message: &str) {
+    eprintln!("{}warning: {}", trace_prefix(), message);
+    send(Severity::Warning, "-", &[], message);
+}
+
+/// Prints a failed command's error on stderr and forwards it.
+pub fn error(err: &AppError) {
+    eprintln!("{}{}", trace_prefix(), err);
+    send(Severity::Error, err.error_code(), &[], &err.to_string());
+}
+
+/// Forwards an authentication event with its details.
+pub fn event(name: &str, severity: Severity, details: &[(&str, &str)]) {
+    send(severity, name, details, name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(transport: Transport, address: String) -> Remote {
+        Remote {
+            transport,
+            address,
+            facility: 10,
+            hostname: "router".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_server() {
+        assert_eq!(parse_server("udp://10.0.0.2:514").unwrap(), (Transport::Udp, "10.0.0.2:514"));
+        assert_eq!(parse_server("tcp://[::1]:601").unwrap(), (Transport::Tcp, "[::1]:601"));
+        assert!(parse_server("10.0.0.2:514").is_err());
+        assert!(parse_server("udp://10.0.0.2").is_err());
+        assert!(facility("local8").is_err());
+    }
+
+    #[test]
+    fn test_format_follows_rfc5424() {
+        let line = format(
+            &remote(Transport::Udp, String::new()),
+            Severity::Warning,
+            "login_failure",
+            &[("USERNAME", "ro\"ot]"), ("ERROR_CODE", "WEBAUTHN_ERROR")],
+            "login_failure",
+        );
+        // authpriv (10) * 8 + warning (4)
+        assert!(line.starts_with("<84>1 "), "{}", line);
+        assert!(line.contains(" router webauthn-helper "));
+        assert!(line.ends_with(" login_failure [webauthn@32473 username=\"ro\\\"ot\\]\" error_code=\"WEBAUTHN_ERROR\"] login_failure"));
+    }
+
+    #[test]
+    fn test_deliver_over_udp_and_tcp() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let udp = remote(Transport::Udp, collector.local_addr().unwrap().to_string());
+        deliver(&udp, "<86>1 hello").unwrap();
+        let mut buf = [0u8; 64];
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"<86>1 hello");
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = remote(Transport::Tcp, listener.local_addr().unwrap().to_string());
+        deliver(&tcp, "<86>1 hello").unwrap();
+        let mut received = String::new();
+        std::io::Read::read_to_string(&mut listener.accept().unwrap().0, &mut received).unwrap();
+        assert_eq!(received, "11 <86>1 hello");
+    }
+}
@@ -0,0 +1,62 @@
+use std::cell::RefCell;
+
+use crate::errors::AppError;
+
+/// Longest trace ID accepted from a caller
+const MAX_LEN: usize = 64;
+
+thread_local! {
+    /// Requests are handled one at a time per thread, so the trace ID of the
+    /// one in progress is the thread's
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// 16 random bytes in hex, the size of a W3C Trace Context trace-id.
+fn generate() -> String {
+    let bytes = crate::commands::register::random_user_handle(16);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Makes `requested` (`--trace-id`, or `traceId` in a call), or a fresh ID
+/// without one, the trace ID of the request in progress. IDs are copied into
+/// log lines and syslog structured data, so only letters, digits, `.`, `_`
+/// and `-` are accepted.
+pub fn select(requested: Option<&str>) -> Result<(), AppError> {
+    let id = match requested {
+        None => generate(),
+        Some(id) => {
+            if id.is_empty() || id.len() > MAX_LEN || !id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+                return Err(AppError::InvalidInput(format!(
+                    "Trace ID must be 1-{} letters, digits, '.', '_' or '-'",
+                    MAX_LEN
+                )));
+            }
+            id.to_string()
+        }
+    };
+    CURRENT.with(|current| *current.borrow_mut() = Some(id));
+    Ok(())
+}
+
+/// The trace ID of the request in progress, if one was selected.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_accepts_or_generates_ids() {
+        select(Some("req-42.a_b")).unwrap();
+        assert_eq!(current().as_deref(), Some("req-42.a_b"));
+        assert!(matches!(select(Some("bad id")), Err(AppError::InvalidInput(_))));
+        assert!(select(Some(&"a".repeat(MAX_LEN + 1))).is_err());
+        assert_eq!(current().as_deref(), Some("req-42.a_b"));
+        select(None).unwrap();
+        let generated = current().unwrap();
+        assert_eq!(generated.len(), 32);
+        assert!(generated.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -0,0 +1,256 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::AppError;
+use crate::storage::{CredentialStore, StoredCredential, UserRecord};
+
+// ─── Write-Ahead Log of Credential Usage (one JSON object per line) ───
+
+/// A credential's usage state after a login attempt. Entries carry
+/// absolute values, so replaying them in order is idempotent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageEntry {
+    pub username: String,
+    pub credential_id: String,
+    pub rp_id: String,
+    pub dynamic_state: String,
+    pub sign_count: u32,
+    pub user_verified: bool,
+    pub backup_eligible: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backed_up: Option<bool>,
+    pub last_used_at: Option<String>,
+    #[serde(default)]
+    pub use_count: u64,
+    #[serde(default)]
+    pub failed_attempts: u32,
+    #[serde(default)]
+    pub last_user_verified: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub seen_attachments: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport_drift_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_client_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_user_agent: Option<String>,
+}
+
+impl UsageEntry {
+    pub fn of(username: &str, cred: &StoredCredential) -> Self {
+        Self {
+            username: username.to_string(),
+            credential_id: cred.credential_id.clone(),
+            rp_id: cred.rp_id.clone(),
+            dynamic_state: cred.dynamic_state.clone(),
+            sign_count: cred.sign_count,
+            user_verified: cred.user_verified,
+            backup_eligible: cred.backup_eligible,
+            backed_up: cred.backed_up,
+            last_used_at: cred.last_used_at.clone(),
+            use_count: cred.use_count,
+            failed_attempts: cred.failed_attempts,
+            last_user_verified: cred.last_user_verified.clone(),
+            seen_attachments: cred.seen_attachments.clone(),
+            transport_drift_at: cred.transport_drift_at.clone(),
+            last_client_ip: cred.last_client_ip.clone(),
+            last_user_agent: cred.last_user_agent.clone(),
+        }
+    }
+
+    fn apply_to(&self, cred: &mut StoredCredential) {
+        cred.rp_id = self.rp_id.clone();
+        cred.dynamic_state = self.dynamic_state.clone();
+        cred.sign_count = self.sign_count;
+        cred.user_verified = self.user_verified;
+        cred.backup_eligible = self.backup_eligible;
+        // Entries written before BS and attachments were tracked leave them alone
+        if self.backed_up.is_some() {
+            cred.backed_up = self.backed_up;
+        }
+        if !self.seen_attachments.is_empty() {
+            cred.seen_attachments = self.seen_attachments.clone();
+        }
+        if self.transport_drift_at.is_some() {
+            cred.transport_drift_at = self.transport_drift_at.clone();
+        }
+        cred.last_used_at = self.last_used_at.clone();
+        cred.use_count = self.use_count;
+        cred.failed_attempts = self.failed_attempts;
+        cred.last_user_verified = self.last_user_verified.clone();
+        cred.last_client_ip = self.last_client_ip.clone();
+        cred.last_user_agent = self.last_user_agent.clone();
+    }
+
+    /// Updates the matching credential of `record`. Entries for credentials
+    /// deleted since are ignored.
+    pub fn apply(&self, record: &mut UserRecord) {
+        if let Some(cred) = record.credentials.iter_mut().find(|c| c.credential_id == self.credential_id) {
+            self.apply_to(cred);
+        }
+    }
+}
+
+/// Applies every entry to the store, in log order.
+pub fn replay(store: &mut CredentialStore, entries: &[UsageEntry]) {
+    for entry in entries {
+        if let Some(record) = store.users.get_mut(&entry.username) {
+            entry.apply(record);
+        }
+    }
+}
+
+/// The log entries describing the difference between `before` and `after`,
+/// or `None` if the change touches more than usage state and therefore has
+/// to be written to the store itself.
+pub fn usage_changes(username: &str, before: &UserRecord, after: &UserRecord) -> Option<Vec<UsageEntry>> {
+    if before.user_id != after.user_id
+        || before.recovery_codes != after.recovery_codes
+        || before.credentials.len() != after.credentials.len()
+    {
+        return None;
+    }
+    let mut changes = Vec::new();
+    for (old, new) in before.credentials.iter().zip(&after.credentials) {
+        let entry = UsageEntry::of(username, new);
+        let mut expected = old.clone();
+        entry.apply_to(&mut expected);
+        if expected != *new {
+            return None;
+        }
+        if old != new {
+            changes.push(entry);
+        }
+    }
+    Some(changes)
+}
+
+/// Reads all complete entries. A trailing line without a newline is the
+/// remains of an interrupted append and is ignored.
+pub fn read_entries(file: &mut fs::File) -> Result<Vec<UsageEntry>, AppError> {
+    let mut data = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut data)?;
+    let complete = match data.rfind('\n') {
+        Some(end) => &data[..end],
+        None => return Ok(Vec::new()),
+    };
+    complete
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| AppError::Storage(format!("Corrupt write-ahead log entry: {}", e))))
+        .collect()
+}
+
+/// Appends entries with a single write, so concurrent readers see either
+/// none or all of them. The caller holds the log's exclusive lock.
+pub fn append(file: &mut fs::File, entries: &[UsageEntry]) -> Result<(), AppError> {
+    // Drop the remains of an interrupted append before they get glued to a new entry
+    let mut existing = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut existing)?;
+    if existing.last().is_some_and(|&b| b != b'\n') {
+        let keep = existing.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        file.set_len(keep as u64)?;
+    }
+
+    let mut data = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut data, entry)?;
+        data.push(b'\n');
+    }
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn credential(id: &str) -> StoredCredential {
+        StoredCredential {
+            credential_id: id.to_string(),
+            rp_id: "router.lan".to_string(),
+            device_name: "key".to_string(),
+            static_state: "ss".to_string(),
+            dynamic_state: "ds0".to_string(),
+            user_handle: "uh".to_string(),
+            transports: 0,
+            created_at: "2025-01-01T00:00:00Z".to_string(),
+            last_used_at: None,
+            backup_eligible: false,
+            user_verified: false,
+            sign_count: 0,
+            use_count: 0,
+            failed_attempts: 0,
+            last_user_verified: None,
+            backed_up: None,
+            uv_initialized: None,
+            cred_protect: None,
+            aaguid: String::new(),
+            attestation: None,
+            seen_attachments: vec![],
+            transport_drift_at: None,
+            last_client_ip: None,
+            last_user_agent: None,
+            enabled: true,
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn test_usage_changes() {
+        let before = UserRecord {
+            user_id: "uid".to_string(),
+            recovery_codes: vec![],
+            roles: vec![],
+            groups: vec![],
+            credentials: vec![credential("a"), credential("b")],
+        };
+
+        let mut used = before.clone();
+        used.credentials[1].sign_count = 5;
+        used.credentials[1].dynamic_state = "ds5".to_string();
+        used.credentials[1].use_count = 1;
+        used.credentials[1].last_user_verified = Some("2025-01-02T00:00:00Z".to_string());
+        let changes = usage_changes("root", &before, &used).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].credential_id, "b");
+
+        let mut replayed = before.clone();
+        changes[0].apply(&mut replayed);
+        assert_eq!(replayed, used);
+
+        let mut renamed = before.clone();
+        renamed.credentials[0].device_name = "other".to_string();
+        assert!(usage_changes("root", &before, &renamed).is_none());
+
+        let mut removed = before.clone();
+        removed.credentials.pop();
+        assert!(usage_changes("root", &before, &removed).is_none());
+    }
+
+    #[test]
+    fn test_torn_append_is_ignored() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("credentials.wal");
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let entry = UsageEntry::of("root", &credential("a"));
+        append(&mut file, std::slice::from_ref(&entry)).unwrap();
+        file.write_all(b"{\"username\":\"ro").unwrap();
+        assert_eq!(read_entries(&mut file).unwrap(), vec![entry.clone()]);
+
+        append(&mut file, std::slice::from_ref(&entry)).unwrap();
+        assert_eq!(read_entries(&mut file).unwrap(), vec![entry.clone(), entry]);
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg(unix)]
+
 /// Integration tests for the webauthn-helper CLI binary.
 ///
 /// Uses `assert_cmd` to invoke the binary and verify:
@@ -88,6 +90,156 @@ fn register_begin_valid_domain_rp_id() {
     }
 }
 
+#[test]
+fn register_begin_allow_reregistration_flag_is_accepted() {
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "testuser",
+            "--rp-id",
+            "example.com",
+            "--allow-reregistration",
+        ])
+        .output()
+        .unwrap();
+
+    // With the flag, no existing credential may be excluded
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    if result.status.success() {
+        assert!(parsed["data"]["publicKey"]["excludeCredentials"]
+            .as_array()
+            .is_none_or(|creds| creds.is_empty()));
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+#[test]
+fn register_begin_rejects_unsupported_user_handle() {
+    // 20 bytes: valid base64url, but not a supported handle size
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "handle-test-user",
+            "--rp-id",
+            "example.com",
+            "--user-handle",
+            "AAECAwQFBgcICQoLDA0ODxAREhM",
+        ])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(parsed["success"], false);
+    assert!(parsed["error"]["code"].is_string());
+}
+
+#[test]
+fn register_begin_rejects_short_external_challenge() {
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "testuser",
+            "--rp-id",
+            "example.com",
+            "--challenge",
+            "AAECAwQFBgc",
+        ])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(parsed["success"], false);
+    assert!(parsed["error"]["code"].is_string());
+}
+
+#[test]
+fn register_begin_hints_and_qr() {
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "testuser",
+            "--rp-id",
+            "example.com",
+            "--hints",
+            "usb",
+        ])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "testuser",
+            "--rp-id",
+            "example.com",
+            "--hints",
+            "hybrid,security-key",
+            "--qr",
+            "png",
+        ])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    if result.status.success() {
+        assert_eq!(
+            parsed["data"]["publicKey"]["hints"],
+            serde_json::json!(["hybrid", "security-key"])
+        );
+        assert!(parsed["data"]["qrPng"].as_str().unwrap().starts_with("data:image/png;base64,"));
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+#[test]
+fn register_begin_client_format() {
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "testuser",
+            "--rp-id",
+            "example.com",
+            "--client-format",
+            "webauthn-json",
+        ])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    if result.status.success() {
+        assert!(parsed["data"]["options"]["publicKey"]["challenge"].is_string());
+        assert!(parsed["data"]["challengeId"].is_string());
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+
+    let result = cmd()
+        .args([
+            "register-begin",
+            "--username",
+            "testuser",
+            "--rp-id",
+            "example.com",
+            "--client-format",
+            "bogus",
+        ])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+}
+
 // ============================================================
 // 3. Register-Finish Tests — Error Paths
 // ============================================================
@@ -253,6 +405,61 @@ fn credential_update_nonexistent() {
     assert_eq!(parsed["error"]["code"], "CREDENTIAL_NOT_FOUND");
 }
 
+#[test]
+fn credential_show_nonexistent() {
+    let result = cmd()
+        .args(["credential-manage", "show", "--id", "nonexistent-id"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "CREDENTIAL_NOT_FOUND");
+}
+
+#[test]
+fn credential_export_key_checks_format_and_id() {
+    let result = cmd()
+        .args(["credential-manage", "export-key", "--id", "nonexistent-id", "--format", "der"])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+
+    let result = cmd()
+        .args(["credential-manage", "export-key", "--id", "nonexistent-id", "--format", "cose"])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "CREDENTIAL_NOT_FOUND");
+}
+
+#[test]
+fn verify_assertion_unknown_credential() {
+    let result = cmd()
+        .args([
+            "verify-assertion",
+            "--credential-id",
+            "nonexistent-id",
+            "--challenge",
+            "AQEBAQEBAQEBAQEBAQEBAQ",
+            "--origin",
+            "https://router.lan",
+        ])
+        .write_stdin("{}")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "CREDENTIAL_NOT_FOUND");
+}
+
 #[test]
 fn credential_list_empty_user() {
     let result = cmd()
@@ -268,6 +475,31 @@ fn credential_list_empty_user() {
     assert!(parsed["data"].as_array().unwrap().is_empty());
 }
 
+#[test]
+fn credential_list_table_format() {
+    let result = cmd()
+        .args([
+            "credential-manage",
+            "list",
+            "--username",
+            "nonexistent_user",
+            "--format",
+            "table",
+        ])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.starts_with("CREDENTIAL ID  DEVICE  RP ID  CREATED  LAST USED  AUTHENTICATOR  UV"));
+
+    let result = cmd()
+        .args(["credential-manage", "list", "--username", "nonexistent_user", "--format", "xml"])
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+}
+
 // ============================================================
 // 7. Health Check
 // ============================================================
@@ -283,6 +515,8 @@ fn health_check_returns_json() {
     assert!(parsed["data"]["version"].is_string());
     assert!(parsed["data"]["status"].is_string());
     assert!(parsed["data"]["storage"].is_object());
+    assert!(parsed["data"]["challenges"]["pending"].is_u64());
+    assert_eq!(parsed["data"]["challenges"]["lengthBytes"], 16);
 }
 
 // ============================================================
@@ -322,6 +556,7 @@ fn all_error_responses_have_consistent_json_shape() {
 
         // Every error response must have these fields
         assert!(parsed.get("success").is_some(), "Missing 'success' field for {:?}", args);
+        assert_eq!(parsed["apiVersion"], 1, "Missing 'apiVersion' field for {:?}", args);
         assert!(parsed.get("error").is_some(), "Missing 'error' field for {:?}", args);
         assert!(parsed["error"].get("code").is_some(), "Missing 'error.code' for {:?}", args);
         assert!(
@@ -332,6 +567,45 @@ fn all_error_responses_have_consistent_json_shape() {
     }
 }
 
+#[test]
+fn api_version_selector() {
+    let result = cmd().args(["--api-version", "1", "health-check"]).output().unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["success"], true);
+    assert_eq!(parsed["apiVersion"], 1);
+    assert_eq!(parsed["data"]["apiVersions"]["supported"], serde_json::json!([1, 2]));
+
+    // v2 errors drop the prefix from the message and say who has to act
+    let result = cmd()
+        .args([
+            "--api-version",
+            "2",
+            "login-finish",
+            "--challenge-id",
+            "x",
+            "--origin",
+            "https://a.com",
+        ])
+        .write_stdin("{}")
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["apiVersion"], 2);
+    assert!(parsed["error"]["category"].is_string(), "{}", parsed);
+    assert!(parsed["error"]["retryable"].is_boolean(), "{}", parsed);
+    if parsed["error"]["code"] == "CHALLENGE_NOT_FOUND" {
+        assert_eq!(parsed["error"]["message"], "x");
+        assert_eq!(parsed["error"]["category"], "client");
+    }
+
+    for unsupported in ["0", "99", "latest"] {
+        let result = cmd().args(["health-check", "--api-version", unsupported]).output().unwrap();
+        assert!(!result.status.success());
+        let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+        assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+    }
+}
+
 // ============================================================
 // 9. Simulated Register → Login Flow (End-to-End)
 // ============================================================
@@ -375,3 +649,350 @@ fn credential_cleanup_returns_json() {
     assert_eq!(parsed["success"], true);
     assert!(parsed["data"]["removedCount"].is_number());
 }
+
+// ============================================================
+// 11. Storage Maintenance
+// ============================================================
+
+#[test]
+fn storage_fix_perms_returns_json() {
+    let result = cmd().args(["storage", "fix-perms"]).output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    if result.status.success() {
+        assert_eq!(parsed["success"], true);
+        assert!(parsed["data"]["fixed"].is_array());
+    } else {
+        // Without root the owner cannot be repaired
+        assert_eq!(parsed["success"], false);
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+#[test]
+fn storage_compact_returns_json() {
+    let result = cmd().args(["storage", "compact"]).output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    if result.status.success() {
+        assert!(parsed["data"]["foldedEntries"].is_u64());
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+#[test]
+fn storage_stats_returns_json() {
+    let result = cmd().args(["storage", "stats"]).output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    if result.status.success() {
+        assert!(parsed["data"]["storeBytes"].is_u64());
+        assert!(parsed["data"]["wal"]["supersededEntries"].is_u64());
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+#[test]
+fn storage_maintain_returns_json() {
+    let result = cmd().args(["storage", "maintain"]).output().unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    if result.status.success() {
+        assert!(parsed["data"]["removedChallenges"].is_u64());
+        assert!(parsed["data"]["foldedEntries"].is_u64());
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+#[test]
+fn storage_unknown_subcommand_fails() {
+    cmd().args(["storage", "bogus"]).assert().failure();
+}
+
+// ============================================================
+// 12. Configuration File
+// ============================================================
+
+#[test]
+fn invalid_config_file_returns_config_error() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let config_path = dir.path().join("config.json");
+    std::fs::write(&config_path, r#"{"challenge_file_mode":"not-octal"}"#).unwrap();
+
+    let result = cmd()
+        .args(["--config", config_path.to_str().unwrap(), "health-check"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "CONFIG_ERROR");
+}
+
+#[test]
+fn oversized_input_is_rejected() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let config_path = dir.path().join("config.json");
+    std::fs::write(&config_path, r#"{"max_input_bytes":16}"#).unwrap();
+
+    let result = cmd()
+        .args(["--config", config_path.to_str().unwrap(), "call", "health_check"])
+        .write_stdin(format!(r#"{{"padding":"{}"}}"#, "x".repeat(64)))
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+}
+
+#[test]
+fn missing_input_times_out() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let config_path = dir.path().join("config.json");
+    std::fs::write(&config_path, r#"{"input_timeout_secs":1}"#).unwrap();
+
+    // Keep stdin open without writing to it, like a caller that forgot the body
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_webauthn-helper"))
+        .args(["--config", config_path.to_str().unwrap(), "call", "health_check"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let _stdin = child.stdin.take();
+    let mut stdout = String::new();
+    std::io::Read::read_to_string(child.stdout.as_mut().unwrap(), &mut stdout).unwrap();
+    child.wait().unwrap();
+
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+}
+
+#[test]
+fn invalid_origins_file_returns_config_error() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let origins_path = dir.path().join("origins.list");
+    std::fs::write(&origins_path, "https://home.example.net\nnot-an-origin\n").unwrap();
+
+    let result = cmd()
+        .args(["--origins-file", origins_path.to_str().unwrap(), "health-check"])
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+
+    assert_eq!(parsed["success"], false);
+    assert_eq!(parsed["error"]["code"], "CONFIG_ERROR");
+}
+
+// ============================================================
+// 13. rpcd Plugin Protocol
+// ============================================================
+
+#[test]
+fn rpcd_list_describes_methods() {
+    let result = cmd().arg("list").output().unwrap();
+    assert!(result.status.success());
+
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["login_begin"]["username"], "str");
+    assert!(parsed["health_check"].is_object());
+}
+
+#[test]
+fn rpcd_call_returns_command_json() {
+    let result = cmd().args(["call", "health_check"]).write_stdin("{}").output().unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["success"], true);
+    assert!(parsed["data"]["version"].is_string());
+
+    let result = cmd().args(["call", "bogus"]).write_stdin("{}").output().unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+}
+
+#[test]
+fn rpcd_acl_uses_object_name() {
+    let result = cmd().args(["rpcd-acl", "--object", "webauthn"]).output().unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert!(parsed["unauthenticated"]["read"]["ubus"]["webauthn"].is_array());
+}
+
+// ============================================================
+// 14. Audit Log
+// ============================================================
+
+#[test]
+fn audit_verify_reports_chain_state() {
+    let result = cmd().args(["audit", "verify"]).output().unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+
+    if parsed["success"] == true {
+        assert!(parsed["data"]["entries"].is_u64());
+        assert!(parsed["data"]["valid"].is_boolean());
+    } else {
+        assert!(parsed["error"]["code"].is_string());
+    }
+}
+
+// ============================================================
+// 15. Service Mode
+// ============================================================
+
+#[test]
+fn init_script_runs_serve_in_foreground() {
+    let result = cmd().args(["init-script", "--socket", "/var/run/wh.sock"]).output().unwrap();
+    assert!(result.status.success());
+    let script = String::from_utf8_lossy(&result.stdout);
+    assert!(script.starts_with("#!/bin/sh /etc/rc.common"));
+    assert!(script.contains("serve --foreground --socket /var/run/wh.sock"));
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_answers_calls_and_stops_on_sigterm() {
+    use std::io::{Read, Write};
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let socket = dir.path().join("helper.sock");
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_webauthn-helper"))
+        .args(["serve", "--foreground", "--socket", socket.to_str().unwrap()])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(s) = std::os::unix::net::UnixStream::connect(&socket) {
+            stream = Some(s);
+            break;
+        }
+        if child.try_wait().unwrap().is_some() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let Some(mut stream) = stream else {
+        // Storage not accessible in this environment; serve exits with an error JSON
+        let output = child.wait_with_output().unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout)).unwrap();
+        assert!(parsed["error"]["code"].is_string());
+        return;
+    };
+
+    stream.write_all(b"{\"method\":\"bogus\"}\n").unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert_eq!(parsed["error"]["code"], "INVALID_INPUT");
+
+    std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout)).unwrap();
+    assert_eq!(parsed["data"]["served"], 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_accepts_activated_socket_and_exits_when_idle() {
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let socket = dir.path().join("activated.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    let fd = listener.as_raw_fd();
+
+    // `exec` keeps the shell's pid, so $$ is the helper's pid as LISTEN_PID requires
+    let mut command = std::process::Command::new("sh");
+    command
+        .args([
+            "-c",
+            "LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" serve --foreground --idle-exit 1 --socket /nonexistent/unused.sock",
+            env!("CARGO_BIN_EXE_webauthn-helper"),
+        ])
+        .stdout(std::process::Stdio::piped());
+    // SAFETY: dup2 and fcntl are async-signal-safe and fd is valid in the child.
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 onto itself would leave close-on-exec set
+            let rc = if fd == 3 { libc::fcntl(fd, libc::F_SETFD, 0) } else { libc::dup2(fd, 3) };
+            if rc == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().unwrap();
+    drop(listener);
+
+    let mut stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+    stream.write_all(b"{\"method\":\"bogus\"}\n").unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&output.stdout)).unwrap();
+    if !output.status.success() {
+        // Storage not accessible in this environment
+        assert!(parsed["error"]["code"].is_string());
+        return;
+    }
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert_eq!(reply["error"]["code"], "INVALID_INPUT");
+    assert_eq!(parsed["data"]["served"], 1);
+}
+
+// ============================================================
+// 16. Deterministic Mode
+// ============================================================
+
+#[cfg(feature = "deterministic")]
+#[test]
+fn deterministic_mode_reproduces_output() {
+    let run = |seed: &str| {
+        cmd()
+            .env("WEBAUTHN_HELPER_SEED", seed)
+            .env("WEBAUTHN_HELPER_NOW", "2025-01-01T00:00:00Z")
+            .args(["register-begin", "--username", "golden_user", "--rp-id", "example.com"])
+            .output()
+            .unwrap()
+    };
+    let first = run("golden");
+    if !first.status.success() {
+        // Storage not accessible in this environment
+        return;
+    }
+    assert_eq!(first.stdout, run("golden").stdout);
+    assert_ne!(first.stdout, run("other").stdout);
+
+    let result = cmd()
+        .env("WEBAUTHN_HELPER_NOW", "yesterday")
+        .arg("health-check")
+        .output()
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&String::from_utf8_lossy(&result.stdout)).unwrap();
+    assert_eq!(parsed["error"]["code"], "CONFIG_ERROR");
+}
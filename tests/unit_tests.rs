@@ -9,9 +9,6 @@
 /// - CamelCase ↔ snake_case JSON contract verification
 /// - Error message detail and JSON output format
 /// - Mock data: 1 legal + 3 illegal WebAuthn response payloads
-use std::io::Write;
-use std::sync::{Arc, Barrier};
-
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use tempfile::TempDir;
@@ -216,9 +213,12 @@ fn truncated_credentials_json_returns_error() {
 // 4. Concurrent File Write (fs2 locking simulation)
 // ============================================================
 
+#[cfg(unix)]
 #[test]
 fn concurrent_file_writes_are_serialized_by_flock() {
     use fs2::FileExt;
+    use std::io::Write;
+    use std::sync::{Arc, Barrier};
 
     let dir = TempDir::new().unwrap();
     let file_path = dir.path().join("credentials.json");
@@ -396,7 +396,7 @@ fn health_check_data_serializes_to_camel_case() {
         version: "1.0.0".to_string(),
         storage: StorageStatusData {
             writable: true,
-            path: "/etc/webauthn/credentials.json".to_string(),
+            path: "/etc/webauthn/data/credentials.json".to_string(),
             count: 3,
         },
     };
@@ -472,14 +472,34 @@ fn error_codes_are_descriptive() {
     // Verify all documented error codes produce non-empty messages.
     let test_cases = vec![
         ("CHALLENGE_NOT_FOUND", "Challenge not found: test-id"),
+        ("CHALLENGE_EXPIRED", "Challenge expired: test-id"),
         ("USER_NOT_FOUND", "User not found: admin"),
         ("CREDENTIAL_NOT_FOUND", "Credential not found: cred-id"),
+        ("CREDENTIAL_DISABLED", "Credential disabled: cred-id"),
         ("INVALID_ORIGIN", "Invalid origin: http://evil.com"),
         ("WEBAUTHN_ERROR", "WebAuthn error: Signature verification failed"),
         ("STORAGE_ERROR", "Storage error: disk full"),
         ("JSON_ERROR", "JSON error: expected value at line 1 column 1"),
         ("IO_ERROR", "IO error: permission denied"),
         ("INVALID_INPUT", "Invalid input: missing userHandle"),
+        ("INSECURE_PERMISSIONS", "Insecure permissions: credentials.json has mode 0644"),
+        (
+            "CONFIG_ERROR",
+            "Configuration error: challenge_file_mode must grant the owner rw",
+        ),
+        ("REPLAY_DETECTED", "Replay detected: Assertion was already used"),
+        ("METADATA_ERROR", "Metadata error: blob signature does not verify"),
+        ("POLICY_VIOLATION", "Policy violation: credential is backup eligible"),
+        ("READ_ONLY", "Read-only: the credential store is read-only"),
+        ("BACKUP_ERROR", "Backup error: the backup endpoint answered 401"),
+        (
+            "CLOCK_SKEW",
+            "Clock skew: challenge abc was created 3600s in the future; check the system time (NTP)",
+        ),
+        (
+            "ATTESTATION_UNTRUSTED",
+            "Untrusted attestation: the authenticator sent no attestation",
+        ),
     ];
 
     for (code, message) in test_cases {
@@ -671,7 +691,7 @@ fn credential_store_with_multiple_users_and_credentials() {
     let json = serde_json::to_string(&store).unwrap();
     let loaded: CredentialStore = serde_json::from_str(&json).unwrap();
     assert_eq!(loaded.users.len(), 3);
-    for (_, record) in &loaded.users {
+    for record in loaded.users.values() {
         assert_eq!(record.credentials.len(), 2);
     }
 }